@@ -1,10 +1,22 @@
 //! User account
 
-use lofire::brokerstore::BrokerStore;
+use lofire::brokerstore::{BrokerStore, BrokerStoreTransaction};
 use lofire::store::*;
 use lofire::types::*;
 use lofire_net::types::*;
-use serde_bare::to_vec;
+use serde::{Deserialize, Serialize};
+use serde_bare::{from_slice, to_vec};
+
+/// Maximum numbers of overlays and topics of a user
+// TODO: versioning V0
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccountLimits {
+    /// Maximum number of overlays joined by the user
+    pub max_overlays: u32,
+
+    /// Maximum number of topics subscribed by the user
+    pub max_topics: u32,
+}
 
 pub struct Account<'a> {
     /// User ID
@@ -19,8 +31,16 @@ impl<'a> Account<'a> {
     const CLIENT: u8 = b"c"[0];
     const ADMIN: u8 = b"a"[0];
     const OVERLAY: u8 = b"o"[0];
+    const TOPIC: u8 = b"t"[0];
+    const LIMITS: u8 = b"l"[0];
 
-    const ALL_PROPERTIES: [u8; 3] = [Self::CLIENT, Self::ADMIN, Self::OVERLAY];
+    const ALL_PROPERTIES: [u8; 5] = [
+        Self::CLIENT,
+        Self::ADMIN,
+        Self::OVERLAY,
+        Self::TOPIC,
+        Self::LIMITS,
+    ];
 
     const SUFFIX_FOR_EXIST_CHECK: u8 = Self::ADMIN;
 
@@ -95,6 +115,14 @@ impl<'a> Account<'a> {
         )
     }
 
+    pub fn clients(&self) -> Result<Vec<ClientId>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &to_vec(&self.id)?, Some(Self::CLIENT))?
+            .iter()
+            .map(|c| Ok(from_slice::<ClientId>(c)?))
+            .collect()
+    }
+
     pub fn add_overlay(&self, overlay: &OverlayId) -> Result<(), StorageError> {
         if !self.exists() {
             return Err(StorageError::BackendError);
@@ -124,6 +152,75 @@ impl<'a> Account<'a> {
         )
     }
 
+    pub fn overlays(&self) -> Result<Vec<OverlayId>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &to_vec(&self.id)?, Some(Self::OVERLAY))?
+            .iter()
+            .map(|o| Ok(from_slice::<OverlayId>(o)?))
+            .collect()
+    }
+
+    /// Topics are stored together with the overlay they belong to
+    pub fn add_topic(&self, overlay: &OverlayId, topic: &TopicId) -> Result<(), StorageError> {
+        if !self.exists() {
+            return Err(StorageError::BackendError);
+        }
+        self.store.put(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::TOPIC),
+            to_vec(&(overlay, topic))?,
+        )
+    }
+    pub fn remove_topic(&self, overlay: &OverlayId, topic: &TopicId) -> Result<(), StorageError> {
+        self.store.del_property_value(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::TOPIC),
+            to_vec(&(overlay, topic))?,
+        )
+    }
+
+    pub fn has_topic(&self, overlay: &OverlayId, topic: &TopicId) -> Result<(), StorageError> {
+        self.store.has_property_value(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::TOPIC),
+            to_vec(&(overlay, topic))?,
+        )
+    }
+
+    pub fn topics(&self) -> Result<Vec<(OverlayId, TopicId)>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &to_vec(&self.id)?, Some(Self::TOPIC))?
+            .iter()
+            .map(|t| Ok(from_slice::<(OverlayId, TopicId)>(t)?))
+            .collect()
+    }
+
+    /// Limits of the user, None if the defaults of the broker apply
+    pub fn limits(&self) -> Result<Option<AccountLimits>, StorageError> {
+        match self
+            .store
+            .get(Self::PREFIX, &to_vec(&self.id)?, Some(Self::LIMITS))
+        {
+            Ok(limits) => Ok(Some(from_slice::<AccountLimits>(&limits)?)),
+            Err(StorageError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    pub fn set_limits(&self, limits: &AccountLimits) -> Result<(), StorageError> {
+        if !self.exists() {
+            return Err(StorageError::BackendError);
+        }
+        self.store.replace(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::LIMITS),
+            to_vec(limits)?,
+        )
+    }
+
     pub fn is_admin(&self) -> Result<bool, StorageError> {
         if self
             .store
@@ -144,6 +241,10 @@ impl<'a> Account<'a> {
         self.store
             .del_all(Self::PREFIX, &to_vec(&self.id)?, &Self::ALL_PROPERTIES)
     }
+    /// Deletes the account with all its clients, overlays and topics in a transaction
+    pub fn del_in(&self, tx: &mut dyn BrokerStoreTransaction) -> Result<(), StorageError> {
+        tx.del_all(Self::PREFIX, &to_vec(&self.id)?, &Self::ALL_PROPERTIES)
+    }
 }
 
 #[cfg(test)]
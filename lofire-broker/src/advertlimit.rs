@@ -0,0 +1,66 @@
+//! Token bucket bounding the PeerAdverts relayed by a broker
+
+use lofire::types::*;
+use lofire::utils::*;
+use std::cmp::min;
+use std::sync::RwLock;
+
+pub struct AdvertRelayLimiter {
+    /// Adverts relayed per minute, also the size of a burst
+    rate: u32,
+    /// Tokens left with the time they were last refilled
+    bucket: RwLock<(u32, Timestamp)>,
+}
+
+impl AdvertRelayLimiter {
+    pub fn new(rate: u32) -> AdvertRelayLimiter {
+        AdvertRelayLimiter {
+            rate,
+            bucket: RwLock::new((rate, Timestamp::MIN)),
+        }
+    }
+
+    /// Take a token for relaying one advert.
+    /// Returns false once the adverts of the current minute are used up
+    pub fn try_acquire(&self, clock: &impl Clock) -> bool {
+        let now = clock.now();
+        let mut bucket = self.bucket.write().unwrap();
+        let (tokens, refilled_at) = *bucket;
+        let elapsed = now.minutes_since(refilled_at);
+        let tokens = min(
+            self.rate,
+            tokens.saturating_add(elapsed.saturating_mul(self.rate)),
+        );
+        if tokens == 0 {
+            *bucket = (0, now);
+            return false;
+        }
+        *bucket = (tokens - 1, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::advertlimit::*;
+
+    #[test]
+    pub fn test_advert_relay_limiter() {
+        let clock = MockClock::new(Timestamp::from_minutes(100));
+        let limiter = AdvertRelayLimiter::new(3);
+
+        let relayed = (0..10).filter(|_| limiter.try_acquire(&clock)).count();
+        assert_eq!(relayed, 3);
+
+        // tokens come back with time, but never more than one burst
+        clock.advance(1);
+        assert!(limiter.try_acquire(&clock));
+        clock.advance(5);
+        let relayed = (0..10).filter(|_| limiter.try_acquire(&clock)).count();
+        assert_eq!(relayed, 3);
+
+        let closed = AdvertRelayLimiter::new(0);
+        assert!(!closed.try_acquire(&clock));
+    }
+}
@@ -43,6 +43,7 @@ pub struct AuthProtocolHandler {
     machine: StateMachine<AuthProtocolServer>,
     nonce: Option<Vec<u8>>,
     user: Option<PubKey>,
+    client: Option<PubKey>,
 }
 
 impl AuthProtocolHandler {
@@ -51,6 +52,7 @@ impl AuthProtocolHandler {
             machine: StateMachine::new(),
             nonce: None,
             user: None,
+            client: None,
         }
     }
 
@@ -58,6 +60,10 @@ impl AuthProtocolHandler {
         self.user
     }
 
+    pub fn get_client(&self) -> Option<PubKey> {
+        self.client
+    }
+
     pub fn handle_init(&mut self, client_hello: ClientHello) -> Result<Vec<u8>, ProtocolError> {
         let _ = self
             .machine
@@ -69,6 +75,7 @@ impl AuthProtocolHandler {
         let nonce = random_buf.to_vec();
         let reply = ServerHello::V0(ServerHelloV0 {
             nonce: nonce.clone(),
+            now: now_timestamp(),
         });
         self.nonce = Some(nonce);
 
@@ -91,7 +98,7 @@ impl AuthProtocolHandler {
     ) {
         fn prepare_reply(res: Result<Vec<u8>, ProtocolError>) -> AuthResult {
             let (result, metadata) = match res {
-                Ok(m) => (0, m),
+                Ok(m) => (ProtocolError::Success.into(), m),
                 Err(e) => (e.into(), vec![]),
             };
             AuthResult::V0(AuthResultV0 { result, metadata })
@@ -135,7 +142,7 @@ impl AuthProtocolHandler {
                         return Err(ProtocolError::AccessDenied);
                     }
 
-                    // TODO check that the device has been registered for this user. if not, return AccessDenied
+                    // the client is checked against the account by the ProtocolHandler
 
                     // all is good, we advance the FSM and send back response
                     let _ = handler
@@ -144,6 +151,7 @@ impl AuthProtocolHandler {
                         .map_err(|_e| ProtocolError::InvalidState)?;
 
                     handler.user = Some(message.user());
+                    handler.client = Some(message.client());
 
                     Ok(vec![]) // without any metadata
                 }
@@ -0,0 +1,84 @@
+//! Reverse index of the objects containing a block, in an overlay
+
+use lofire::brokerstore::BrokerStore;
+use lofire::store::*;
+use lofire::types::*;
+use lofire_net::types::*;
+use serde_bare::{from_slice, to_vec};
+
+pub struct BlockIndex<'a> {
+    /// Overlay ID
+    overlay: OverlayId,
+    /// Block ID
+    block: BlockId,
+    store: &'a dyn BrokerStore,
+}
+
+impl<'a> BlockIndex<'a> {
+    const PREFIX: u8 = b"b"[0];
+
+    // propertie's suffixes
+    const OBJECT: u8 = b"o"[0];
+
+    const ALL_PROPERTIES: [u8; 1] = [Self::OBJECT];
+
+    /// Index entry of a block, that doesn't need to exist yet
+    pub fn new(overlay: &OverlayId, block: &BlockId, store: &'a dyn BrokerStore) -> BlockIndex<'a> {
+        BlockIndex {
+            overlay: overlay.clone(),
+            block: block.clone(),
+            store,
+        }
+    }
+    fn key(&self) -> Result<Vec<u8>, StorageError> {
+        Ok(to_vec(&(self.overlay, self.block))?)
+    }
+    pub fn overlay(&self) -> OverlayId {
+        self.overlay
+    }
+    pub fn block(&self) -> BlockId {
+        self.block
+    }
+
+    pub fn add_object(&self, object: &ObjectId) -> Result<(), StorageError> {
+        if self.has_object(object).is_ok() {
+            return Ok(());
+        }
+        self.store.put(
+            Self::PREFIX,
+            &self.key()?,
+            Some(Self::OBJECT),
+            to_vec(object)?,
+        )
+    }
+    pub fn remove_object(&self, object: &ObjectId) -> Result<(), StorageError> {
+        self.store.del_property_value(
+            Self::PREFIX,
+            &self.key()?,
+            Some(Self::OBJECT),
+            to_vec(object)?,
+        )
+    }
+    pub fn has_object(&self, object: &ObjectId) -> Result<(), StorageError> {
+        self.store.has_property_value(
+            Self::PREFIX,
+            &self.key()?,
+            Some(Self::OBJECT),
+            to_vec(object)?,
+        )
+    }
+
+    /// Objects containing the block, empty if the block is not indexed
+    pub fn objects(&self) -> Result<Vec<ObjectId>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &self.key()?, Some(Self::OBJECT))?
+            .iter()
+            .map(|o| Ok(from_slice::<ObjectId>(o)?))
+            .collect()
+    }
+
+    pub fn del(&self) -> Result<(), StorageError> {
+        self.store
+            .del_all(Self::PREFIX, &self.key()?, &Self::ALL_PROPERTIES)
+    }
+}
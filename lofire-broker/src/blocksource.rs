@@ -0,0 +1,19 @@
+//! Fallback sources of blocks missing from the broker's store
+
+use async_channel::Receiver;
+use lofire::types::*;
+use lofire_net::types::*;
+
+/// Source of blocks the broker doesn't have locally, such as its peers in the overlay
+///
+/// Searched by the broker on a local miss, see `BrokerServer::set_block_fallback`
+pub trait BlockSource: Send + Sync {
+    /// Search for a block, and all its children if `include_children` is set.
+    /// Found blocks are sent on the returned channel, which is closed at the end of the search
+    fn search_block(
+        &self,
+        overlay: &OverlayId,
+        id: &BlockId,
+        include_children: bool,
+    ) -> Receiver<Block>;
+}
@@ -0,0 +1,152 @@
+//! Replication checkpoint of a replica broker for an overlay
+
+use lofire::brokerstore::BrokerStore;
+use lofire::store::*;
+use lofire::types::*;
+use lofire_net::types::*;
+use serde::{Deserialize, Serialize};
+use serde_bare::{from_slice, to_vec};
+
+// TODO: versioning V0
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CheckpointMeta {
+    /// Timestamp of the last replication
+    pub last: Timestamp,
+    /// Blocks stored at the `last` timestamp that were already sent to the replica
+    pub seen: Vec<BlockId>,
+}
+
+// TODO: versioning V0
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PendingCheckpoint {
+    /// Checkpoint reached by the replication
+    pub meta: CheckpointMeta,
+    /// Gaps reported by the replication
+    pub gaps: Vec<BlockId>,
+}
+
+pub struct Checkpoint<'a> {
+    /// Replica ID
+    replica: PubKey,
+    /// Overlay ID
+    overlay: OverlayId,
+    store: &'a dyn BrokerStore,
+}
+
+impl<'a> Checkpoint<'a> {
+    const PREFIX: u8 = b"k"[0];
+
+    // propertie's suffixes
+    const META: u8 = b"m"[0];
+    const PENDING: u8 = b"p"[0];
+
+    const ALL_PROPERTIES: [u8; 2] = [Self::META, Self::PENDING];
+
+    const SUFFIX_FOR_EXIST_CHECK: u8 = Self::META;
+
+    pub fn open(
+        replica: &PubKey,
+        overlay: &OverlayId,
+        store: &'a dyn BrokerStore,
+    ) -> Result<Checkpoint<'a>, StorageError> {
+        let opening = Checkpoint {
+            replica: replica.clone(),
+            overlay: overlay.clone(),
+            store,
+        };
+        if !opening.exists() {
+            return Err(StorageError::NotFound);
+        }
+        Ok(opening)
+    }
+    pub fn create(
+        replica: &PubKey,
+        overlay: &OverlayId,
+        meta: &CheckpointMeta,
+        store: &'a dyn BrokerStore,
+    ) -> Result<Checkpoint<'a>, StorageError> {
+        let acc = Checkpoint {
+            replica: replica.clone(),
+            overlay: overlay.clone(),
+            store,
+        };
+        if acc.exists() {
+            return Err(StorageError::BackendError);
+        }
+        store.put(Self::PREFIX, &acc.key()?, Some(Self::META), to_vec(meta)?)?;
+        Ok(acc)
+    }
+    fn key(&self) -> Result<Vec<u8>, StorageError> {
+        Ok(to_vec(&(self.replica, self.overlay))?)
+    }
+    pub fn exists(&self) -> bool {
+        self.store
+            .get(
+                Self::PREFIX,
+                &self.key().unwrap(),
+                Some(Self::SUFFIX_FOR_EXIST_CHECK),
+            )
+            .is_ok()
+    }
+    pub fn replica(&self) -> PubKey {
+        self.replica
+    }
+    pub fn overlay(&self) -> OverlayId {
+        self.overlay
+    }
+    pub fn metadata(&self) -> Result<CheckpointMeta, StorageError> {
+        match self.store.get(Self::PREFIX, &self.key()?, Some(Self::META)) {
+            Ok(meta) => Ok(from_slice::<CheckpointMeta>(&meta)?),
+            Err(e) => Err(e),
+        }
+    }
+    pub fn set_metadata(&self, meta: &CheckpointMeta) -> Result<(), StorageError> {
+        if !self.exists() {
+            return Err(StorageError::BackendError);
+        }
+        self.store
+            .replace(Self::PREFIX, &self.key()?, Some(Self::META), to_vec(meta)?)
+    }
+
+    /// Saves the checkpoint reached by a replication that the replica hasn't acknowledged yet,
+    /// replacing the one of any previous unacknowledged replication
+    pub fn set_pending(
+        replica: &PubKey,
+        overlay: &OverlayId,
+        pending: &PendingCheckpoint,
+        store: &'a dyn BrokerStore,
+    ) -> Result<(), StorageError> {
+        store.replace(
+            Self::PREFIX,
+            &to_vec(&(replica, overlay))?,
+            Some(Self::PENDING),
+            to_vec(pending)?,
+        )
+    }
+
+    /// Advances the checkpoint of the replica to the pending one, once the replica acknowledged it.
+    /// Returns the pending checkpoint, or NotFound if there is none
+    pub fn acknowledge(
+        replica: &PubKey,
+        overlay: &OverlayId,
+        store: &'a dyn BrokerStore,
+    ) -> Result<PendingCheckpoint, StorageError> {
+        let key = to_vec(&(replica, overlay))?;
+        let pending = from_slice::<PendingCheckpoint>(&store.get(
+            Self::PREFIX,
+            &key,
+            Some(Self::PENDING),
+        )?)?;
+        let meta = to_vec(&pending.meta)?;
+        store.transaction(&mut |tx| {
+            tx.del(Self::PREFIX, &key, Some(Self::PENDING))?;
+            tx.replace(Self::PREFIX, &key, Some(Self::META), meta.clone())
+        })?;
+        Ok(pending)
+    }
+
+    pub fn del(&self) -> Result<(), StorageError> {
+        self.store
+            .del_all(Self::PREFIX, &self.key()?, &Self::ALL_PROPERTIES)
+    }
+}
@@ -0,0 +1,145 @@
+//! Frame codecs
+//!
+//! Convert between protocol frames and the messages of a transport,
+//! so connection loops don't depend on the transport
+
+use async_tungstenite::tungstenite::protocol::Message;
+
+/// Converts protocol frames to and from the messages of a transport
+pub trait FrameCodec: Clone + Send + Sync + 'static {
+    /// Message of the underlying transport
+    type TransportMessage: Send;
+
+    /// Encode a frame into a transport message.
+    /// An empty frame closes the connection
+    fn encode(&self, frame: Vec<u8>) -> Self::TransportMessage;
+
+    /// Decode a transport message into a frame.
+    /// Returns None for messages without a frame, such as control or close messages
+    fn decode(&self, message: Self::TransportMessage) -> Option<Vec<u8>>;
+
+    /// Whether the transport message closes the connection
+    fn is_close(&self, message: &Self::TransportMessage) -> bool;
+
+    /// Transport message closing the connection
+    fn close(&self) -> Self::TransportMessage {
+        self.encode(vec![])
+    }
+
+    /// Keepalive message. The transport of the peer replies with a pong by itself
+    fn ping(&self) -> Self::TransportMessage;
+}
+
+/// Raw frame of a keepalive ping, answered by `tcp::split` with `RAW_PONG`.
+/// It is not a valid BARE message, so it can't be mistaken for a protocol frame
+pub const RAW_PING: [u8; 1] = [0xff];
+
+/// Raw frame replying to `RAW_PING`
+pub const RAW_PONG: [u8; 1] = [0xfe];
+
+/// Frames sent as binary WebSocket messages
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WebSocketCodec;
+
+impl FrameCodec for WebSocketCodec {
+    type TransportMessage = Message;
+
+    fn encode(&self, frame: Vec<u8>) -> Message {
+        if frame.is_empty() {
+            Message::Close(None)
+        } else {
+            Message::binary(frame)
+        }
+    }
+
+    fn decode(&self, message: Message) -> Option<Vec<u8>> {
+        match message {
+            Message::Binary(frame) if !frame.is_empty() => Some(frame),
+            _ => None,
+        }
+    }
+
+    fn is_close(&self, message: &Message) -> bool {
+        message.is_close()
+    }
+
+    fn ping(&self) -> Message {
+        Message::Ping(vec![])
+    }
+}
+
+/// Frames already delimited by the transport, such as length-prefixed raw TCP
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawFrameCodec;
+
+impl FrameCodec for RawFrameCodec {
+    type TransportMessage = Vec<u8>;
+
+    fn encode(&self, frame: Vec<u8>) -> Vec<u8> {
+        frame
+    }
+
+    fn decode(&self, message: Vec<u8>) -> Option<Vec<u8>> {
+        if message.is_empty() || message == RAW_PING || message == RAW_PONG {
+            None
+        } else {
+            Some(message)
+        }
+    }
+
+    fn is_close(&self, message: &Vec<u8>) -> bool {
+        message.is_empty()
+    }
+
+    fn ping(&self) -> Vec<u8> {
+        RAW_PING.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_tungstenite::tungstenite::protocol::Message;
+
+    use crate::codec::*;
+
+    #[test]
+    pub fn test_websocket_codec() {
+        let codec = WebSocketCodec;
+
+        // binary frame
+        let message = codec.encode(vec![1, 2, 3]);
+        assert!(message.is_binary());
+        assert!(!codec.is_close(&message));
+        assert_eq!(codec.decode(message), Some(vec![1, 2, 3]));
+
+        // close frame
+        let message = Message::Close(None);
+        assert!(codec.is_close(&message));
+        assert_eq!(codec.decode(message), None);
+        assert!(codec.is_close(&codec.close()));
+
+        // empty frame
+        let message = codec.encode(vec![]);
+        assert!(codec.is_close(&message));
+        assert_eq!(codec.decode(Message::binary(vec![])), None);
+
+        // control messages carry no frame
+        assert_eq!(codec.decode(Message::Ping(vec![1])), None);
+        assert!(codec.ping().is_ping());
+    }
+
+    #[test]
+    pub fn test_raw_frame_codec() {
+        let codec = RawFrameCodec;
+        assert_eq!(
+            codec.decode(codec.encode(vec![1, 2, 3])),
+            Some(vec![1, 2, 3])
+        );
+        assert!(codec.is_close(&codec.close()));
+
+        // keepalive frames carry no frame
+        assert_eq!(codec.decode(codec.ping()), None);
+        assert_eq!(codec.decode(RAW_PONG.to_vec()), None);
+        assert!(!codec.is_close(&codec.ping()));
+    }
+}
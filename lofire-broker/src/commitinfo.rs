@@ -0,0 +1,98 @@
+//! Type and body of the commits published in an overlay
+
+use lofire::brokerstore::BrokerStore;
+use lofire::store::*;
+use lofire::types::*;
+use lofire_net::types::*;
+use serde::{Deserialize, Serialize};
+use serde_bare::{from_slice, to_vec};
+
+// TODO: versioning V0
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CommitInfoMeta {
+    /// Type of the commit body
+    pub commit_type: CommitType,
+    /// ID of the commit body object
+    pub body: ObjectId,
+}
+
+pub struct CommitInfo<'a> {
+    /// Overlay ID
+    overlay: OverlayId,
+    /// Commit ID
+    commit: ObjectId,
+    store: &'a dyn BrokerStore,
+}
+
+impl<'a> CommitInfo<'a> {
+    const PREFIX: u8 = b"i"[0];
+
+    // propertie's suffixes
+    const META: u8 = b"m"[0];
+
+    const ALL_PROPERTIES: [u8; 1] = [Self::META];
+
+    const SUFFIX_FOR_EXIST_CHECK: u8 = Self::META;
+
+    pub fn open(
+        overlay: &OverlayId,
+        commit: &ObjectId,
+        store: &'a dyn BrokerStore,
+    ) -> Result<CommitInfo<'a>, StorageError> {
+        let opening = CommitInfo {
+            overlay: overlay.clone(),
+            commit: commit.clone(),
+            store,
+        };
+        if !opening.exists() {
+            return Err(StorageError::NotFound);
+        }
+        Ok(opening)
+    }
+    pub fn create(
+        overlay: &OverlayId,
+        commit: &ObjectId,
+        meta: &CommitInfoMeta,
+        store: &'a dyn BrokerStore,
+    ) -> Result<CommitInfo<'a>, StorageError> {
+        let info = CommitInfo {
+            overlay: overlay.clone(),
+            commit: commit.clone(),
+            store,
+        };
+        if info.exists() {
+            return Err(StorageError::BackendError);
+        }
+        store.put(Self::PREFIX, &info.key()?, Some(Self::META), to_vec(meta)?)?;
+        Ok(info)
+    }
+    fn key(&self) -> Result<Vec<u8>, StorageError> {
+        Ok(to_vec(&(self.overlay, self.commit))?)
+    }
+    pub fn exists(&self) -> bool {
+        self.store
+            .get(
+                Self::PREFIX,
+                &self.key().unwrap(),
+                Some(Self::SUFFIX_FOR_EXIST_CHECK),
+            )
+            .is_ok()
+    }
+    pub fn overlay(&self) -> OverlayId {
+        self.overlay
+    }
+    pub fn commit(&self) -> ObjectId {
+        self.commit
+    }
+    pub fn metadata(&self) -> Result<CommitInfoMeta, StorageError> {
+        match self.store.get(Self::PREFIX, &self.key()?, Some(Self::META)) {
+            Ok(meta) => Ok(from_slice::<CommitInfoMeta>(&meta)?),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn del(&self) -> Result<(), StorageError> {
+        self.store
+            .del_all(Self::PREFIX, &self.key()?, &Self::ALL_PROPERTIES)
+    }
+}
@@ -25,8 +25,9 @@ impl<'a> Config<'a> {
 
     // propertie's suffixes
     const MODE: u8 = b"m"[0];
+    const ADMIN: u8 = b"a"[0];
 
-    const ALL_PROPERTIES: [u8; 1] = [Self::MODE];
+    const ALL_PROPERTIES: [u8; 2] = [Self::MODE, Self::ADMIN];
 
     const SUFFIX_FOR_EXIST_CHECK: u8 = Self::MODE;
 
@@ -91,4 +92,31 @@ impl<'a> Config<'a> {
             Err(e) => Err(e),
         }
     }
+    /// Admins signing the AddUser and DelUser requests
+    pub fn admins(&self) -> Result<Vec<PubKey>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &to_vec(&Self::KEY)?, Some(Self::ADMIN))?
+            .iter()
+            .map(|a| Ok(from_slice::<PubKey>(a)?))
+            .collect()
+    }
+    pub fn add_admin(&self, admin: &PubKey) -> Result<(), StorageError> {
+        if !self.exists() {
+            return Err(StorageError::BackendError);
+        }
+        self.store.put(
+            Self::PREFIX,
+            &to_vec(&Self::KEY)?,
+            Some(Self::ADMIN),
+            to_vec(admin)?,
+        )
+    }
+    pub fn remove_admin(&self, admin: &PubKey) -> Result<(), StorageError> {
+        self.store.del_property_value(
+            Self::PREFIX,
+            &to_vec(&Self::KEY)?,
+            Some(Self::ADMIN),
+            to_vec(admin)?,
+        )
+    }
 }
@@ -1,7 +1,5 @@
 //! Connection to a Broker, can be local or remote.
 //! If remote, it will use a Stream and Sink of framed messages
-use async_std::task;
-use async_std::sync::Mutex;
 use futures::{
     ready,
     stream::Stream,
@@ -11,13 +9,22 @@ use futures::{
 };
 use futures::channel::mpsc;
 use std::pin::Pin;
+use std::time::Duration;
 use std::{collections::HashSet, fmt::Debug};
 
+use crate::runtime;
+use crate::runtime::Mutex;
 use crate::server::BrokerServer;
-use async_broadcast::{broadcast, Receiver};
+use crate::server::ReplicationStreamItem;
+use crate::server::SyncStreamItem;
+use async_broadcast::{broadcast, Receiver, Sender};
 use async_oneshot::oneshot;
 use debug_print::*;
+use futures::io::{AsyncRead, AsyncReadExt};
 use futures::{pin_mut, stream, Sink, SinkExt, StreamExt};
+use lofire::chunking::ChunkingStrategy;
+use lofire::commit::*;
+use lofire::compression::ChunkCompression;
 use lofire::object::*;
 use lofire::store::*;
 use lofire::types::*;
@@ -57,6 +64,12 @@ struct BrokerMessageStreamActor {
     s: async_channel::Sender<Block>,
     error_r: Option<async_oneshot::Receiver<Option<ProtocolError>>>,
     error_s: Option<async_oneshot::Sender<Option<ProtocolError>>>,
+    /// progress of a sync, only kept when someone listens to it
+    progress_s: Option<async_channel::Sender<SyncProgress>>,
+    /// continuation token ending a truncated stream, only kept when someone listens to it
+    continuation_s: Option<async_channel::Sender<u32>>,
+    /// tombstones streamed by a replication, only kept when someone listens to them
+    tombstone_s: Option<async_channel::Sender<Tombstone>>,
 }
 
 impl Actor for BrokerMessageStreamActor {}
@@ -70,6 +83,9 @@ impl BrokerMessageStreamActor {
             s,
             error_r: Some(error_r),
             error_s: Some(error_s),
+            progress_s: None,
+            continuation_s: None,
+            tombstone_s: None,
         }
     }
     async fn partial(&mut self, block: Block) -> Result<(), ProtocolError> {
@@ -88,6 +104,24 @@ impl BrokerMessageStreamActor {
         self.error_r.take().unwrap()
     }
 
+    fn progress_receiver(&mut self) -> async_channel::Receiver<SyncProgress> {
+        let (s, r) = async_channel::unbounded::<SyncProgress>();
+        self.progress_s = Some(s);
+        r
+    }
+
+    fn continuation_receiver(&mut self) -> async_channel::Receiver<u32> {
+        let (s, r) = async_channel::bounded::<u32>(1);
+        self.continuation_s = Some(s);
+        r
+    }
+
+    fn tombstone_receiver(&mut self) -> async_channel::Receiver<Tombstone> {
+        let (s, r) = async_channel::unbounded::<Tombstone>();
+        self.tombstone_s = Some(s);
+        r
+    }
+
     fn send_error(&mut self, err: Option<ProtocolError>) {
         if self.error_s.is_some() {
             let _ = self.error_s.take().unwrap().send(err);
@@ -97,6 +131,94 @@ impl BrokerMessageStreamActor {
 
     fn close(&mut self) {
         self.s.close();
+        if let Some(progress) = &self.progress_s {
+            progress.close();
+        }
+        if let Some(continuation) = &self.continuation_s {
+            continuation.close();
+        }
+        if let Some(tombstone) = &self.tombstone_s {
+            tombstone.close();
+        }
+    }
+}
+
+/// Response failing the request `id` with `err`
+fn error_response(id: u64, err: ProtocolError) -> BrokerMessage {
+    BrokerMessage::V0(BrokerMessageV0 {
+        padding: vec![],
+        content: BrokerMessageContentV0::BrokerResponse(BrokerResponse::V0(BrokerResponseV0 {
+            id,
+            result: err.into(),
+        })),
+    })
+}
+
+/// Nonce of a signed admin request
+fn random_nonce() -> u64 {
+    let mut random_buf = [0u8; 8];
+    getrandom::getrandom(&mut random_buf).unwrap();
+    u64::from_le_bytes(random_buf)
+}
+
+/// Requests of a remote connection waiting for their response.
+///
+/// The handle can be cloned and used while the connection is busy sending another request.
+#[derive(Clone)]
+pub struct PendingRequests {
+    actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageActor>>>>,
+    stream_actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageStreamActor>>>>,
+    /// cancelled requests whose final response hasn't arrived yet, and must be dropped
+    cancelled: Arc<RwLock<HashSet<u64>>>,
+}
+
+impl PendingRequests {
+    /// IDs of the pending requests, in increasing order
+    pub fn ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self
+            .actors
+            .read()
+            .expect("RwLock poisoned")
+            .keys()
+            .chain(self.stream_actors.read().expect("RwLock poisoned").keys())
+            .copied()
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Cancels a pending request: the caller waiting for its response gets ProtocolError::Cancelled,
+    /// or the end of the stream when blocks were already received.
+    /// The responses of the broker that arrive afterwards for this request are dropped.
+    /// Returns ProtocolError::NotFound if the request isn't pending
+    pub fn cancel(&self, id: u64) -> Result<(), ProtocolError> {
+        let removed = self.actors.write().expect("RwLock poisoned").remove(&id);
+        if let Some(a) = removed {
+            self.cancelled.write().expect("RwLock poisoned").insert(id);
+            if let Some(addr) = a.upgrade() {
+                let _ = addr.send(BrokerMessageXActor(error_response(
+                    id,
+                    ProtocolError::Cancelled,
+                )));
+            }
+            return Ok(());
+        }
+        let removed = self
+            .stream_actors
+            .write()
+            .expect("RwLock poisoned")
+            .remove(&id);
+        if let Some(a) = removed {
+            self.cancelled.write().expect("RwLock poisoned").insert(id);
+            if let Some(addr) = a.upgrade() {
+                let _ = addr.send(BrokerMessageXActor(error_response(
+                    id,
+                    ProtocolError::Cancelled,
+                )));
+            }
+            return Ok(());
+        }
+        Err(ProtocolError::NotFound)
     }
 }
 
@@ -113,6 +235,30 @@ impl Handler<BrokerMessageXActor> for BrokerMessageActor {
 impl Handler<BrokerMessageXActor> for BrokerMessageStreamActor {
     async fn handle(&mut self, ctx: &mut xactor::Context<Self>, msg: BrokerMessageXActor) {
         //println!("handling {:?}", msg.0);
+        if let Some(progress) = msg.0.response_sync_progress() {
+            self.send_error(None);
+            if let Some(s) = &self.progress_s {
+                let _ = s.try_send(*progress);
+            }
+            return;
+        }
+        if let Some(tombstone) = msg.0.response_tombstone() {
+            self.send_error(None);
+            if let Some(s) = &self.tombstone_s {
+                let _ = s.try_send(*tombstone);
+            }
+            return;
+        }
+        if let Some(continuation) = msg.0.response_continuation() {
+            // end of a truncated stream
+            self.send_error(None);
+            if let Some(s) = &self.continuation_s {
+                let _ = s.try_send(continuation);
+            }
+            ctx.stop(None);
+            self.close();
+            return;
+        }
         let res: Result<Option<Block>, ProtocolError> = msg.0.into();
         match res {
             Err(e) => {
@@ -138,6 +284,144 @@ impl Handler<BrokerMessageXActor> for BrokerMessageStreamActor {
     }
 }
 
+/// Default number of failed block puts retried during one put_object
+pub const DEFAULT_PUT_RETRIES: usize = 8;
+
+/// Default delay before retrying a failed block put, doubled at each new attempt for the same block
+pub const DEFAULT_PUT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Number of blocks of a get_object that can arrive before their parent
+pub const DEFAULT_REORDER_BUFFER: usize = 1024;
+
+/// False positive rate of the bloom filter of known blocks sent by get_missing_blocks
+pub const DEFAULT_KNOWN_BLOCKS_FP_RATE: f64 = 0.01;
+
+/// Retry budget shared by all the block puts of a put_object
+#[derive(Clone, Copy, Debug)]
+pub struct RetryBudget {
+    /// Maximum number of retries for the whole object
+    pub retries: usize,
+
+    /// Delay before the first retry of a block
+    pub backoff: Duration,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            retries: DEFAULT_PUT_RETRIES,
+            backoff: DEFAULT_PUT_BACKOFF,
+        }
+    }
+}
+
+/// Error fetching an object with `OverlayConnectionClient::get_object`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GetObjectError {
+    /// The request failed
+    Protocol(ProtocolError),
+
+    /// The broker didn't send these blocks of the object
+    MissingBlocks(Vec<BlockId>),
+}
+
+impl From<ProtocolError> for GetObjectError {
+    fn from(e: ProtocolError) -> Self {
+        GetObjectError::Protocol(e)
+    }
+}
+
+impl From<GetObjectError> for ProtocolError {
+    fn from(e: GetObjectError) -> Self {
+        match e {
+            GetObjectError::Protocol(e) => e,
+            GetObjectError::MissingBlocks(_) => ProtocolError::MissingBlocks,
+        }
+    }
+}
+
+/// Padding of the messages sent on a remote connection,
+/// so that the size of the frames doesn't leak the length of their content.
+/// The padding is ignored by the receiver
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Messages are sent as is
+    None,
+
+    /// Frames are padded to a multiple of the given size
+    Fixed(usize),
+
+    /// Frames are padded to the smallest of the given sizes that fits them, in increasing order.
+    /// Larger frames are padded to a multiple of the largest size
+    BucketTo(Vec<usize>),
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        PaddingPolicy::None
+    }
+}
+
+impl PaddingPolicy {
+    /// Buckets of the powers of two between min and max
+    pub fn powers_of_two(min: usize, max: usize) -> PaddingPolicy {
+        let mut buckets = vec![];
+        let mut size = min.max(1).next_power_of_two();
+        while size <= max {
+            buckets.push(size);
+            size *= 2;
+        }
+        PaddingPolicy::BucketTo(buckets)
+    }
+
+    /// Size a frame of `len` bytes is padded to
+    fn bucket(&self, len: usize) -> usize {
+        let round_up = |n: usize| if n == 0 { len } else { (len + n - 1) / n * n };
+        match self {
+            PaddingPolicy::None => len,
+            PaddingPolicy::Fixed(n) => round_up(*n),
+            PaddingPolicy::BucketTo(buckets) => match buckets.iter().find(|b| **b >= len) {
+                Some(b) => *b,
+                None => round_up(buckets.last().copied().unwrap_or(0)),
+            },
+        }
+    }
+
+    /// Serializes a message, with the padding of the policy
+    pub fn serialize(&self, message: &BrokerMessage) -> Result<Vec<u8>, ProtocolError> {
+        let mut padded = match (self, message) {
+            (PaddingPolicy::None, _) | (_, BrokerMessage::Close) => {
+                return Ok(serde_bare::to_vec(message)?)
+            }
+            (_, BrokerMessage::V0(m)) => m.clone(),
+        };
+        padded.padding = vec![];
+        let len = serde_bare::to_vec(&BrokerMessage::V0(padded.clone()))?.len();
+        // the padding is prefixed by its length, which grows with it
+        let prefix = |p: usize| {
+            let mut n = 1;
+            while p >> (7 * n) > 0 {
+                n += 1;
+            }
+            n - 1
+        };
+        let mut target = self.bucket(len);
+        loop {
+            let extra = target - len;
+            let mut p = extra;
+            while p + prefix(p) > extra {
+                p -= 1;
+            }
+            if p + prefix(p) == extra {
+                padded.padding = vec![0; p];
+                return Ok(serde_bare::to_vec(&BrokerMessage::V0(padded))?);
+            }
+            // the length prefix skipped over the target
+            target = self.bucket(target + 1);
+        }
+    }
+}
+
 pub struct OverlayConnectionClient<'a, T>
 where
     T: BrokerConnection,
@@ -164,11 +448,38 @@ where
         overlay
     }
 
+    /// Time of the broker, as estimated by the client, see `BrokerConnection::now`
+    pub fn now(&self) -> Timestamp {
+        self.broker.now()
+    }
+
+    /// Heads of the branch of a topic that are not in `known_heads`, to sync with `sync_branch`
+    pub async fn branch_heads(
+        &mut self,
+        topic: TopicId,
+        known_heads: Vec<ObjectId>,
+    ) -> Result<Vec<ObjectId>, ProtocolError> {
+        self.broker
+            .process_overlay_request_block_ids_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BranchHeadsReq(BranchHeadsReq::V0(
+                    BranchHeadsReqV0 { topic, known_heads },
+                )),
+            )
+            .await
+    }
+
+    /// Sync the commits of a branch, and the bodies of the commits of the given types.
+    /// An interrupted sync is resumed with the same request and a checkpoint,
+    /// see `BranchSyncReq::checkpoint_after`.
+    /// So is a sync truncated at the block limit of the broker, see `sync_branch_with_progress`
     pub async fn sync_branch(
         &mut self,
         heads: Vec<ObjectId>,
         known_heads: Vec<ObjectId>,
         known_commits: BloomFilter,
+        commit_types: Option<Vec<CommitType>>,
+        checkpoint: Option<Vec<u8>>,
     ) -> Result<Pin<Box<T::BlockStream>>, ProtocolError> {
         self.broker
             .process_overlay_request_stream_response(
@@ -177,6 +488,175 @@ where
                     heads,
                     known_heads,
                     known_commits,
+                    commit_types,
+                    checkpoint,
+                })),
+            )
+            .await
+    }
+
+    /// Same as `sync_branch`, with the progress of the sync reported by the broker.
+    ///
+    /// The total of the progress is the number of blocks the broker streams,
+    /// and the last progress received is marked as truncated if the broker cut the stream at its block limit
+    pub async fn sync_branch_with_progress(
+        &mut self,
+        heads: Vec<ObjectId>,
+        known_heads: Vec<ObjectId>,
+        known_commits: BloomFilter,
+        commit_types: Option<Vec<CommitType>>,
+        checkpoint: Option<Vec<u8>>,
+    ) -> Result<
+        (
+            Pin<Box<T::BlockStream>>,
+            async_channel::Receiver<SyncProgress>,
+        ),
+        ProtocolError,
+    > {
+        self.broker
+            .process_overlay_request_stream_response_with_progress(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BranchSyncReq(BranchSyncReq::V0(BranchSyncReqV0 {
+                    heads,
+                    known_heads,
+                    known_commits,
+                    commit_types,
+                    checkpoint,
+                })),
+            )
+            .await
+    }
+
+    /// Same as `sync_branch`, checking that the received commits belong to the repo.
+    ///
+    /// The root blocks of the commits reachable from `heads` and of their bodies
+    /// have to decrypt under the key of their reference with the convergence key of the repo,
+    /// otherwise the sync is rejected with `ProtocolError::ForeignBlock`.
+    /// Returns the received blocks once verified.
+    pub async fn sync_branch_verified(
+        &mut self,
+        heads: Vec<ObjectRef>,
+        known_heads: Vec<ObjectId>,
+        known_commits: BloomFilter,
+        commit_types: Option<Vec<CommitType>>,
+        checkpoint: Option<Vec<u8>>,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<Vec<Block>, ProtocolError> {
+        let head_ids = heads.iter().map(|h| h.id).collect();
+        let mut stream = self
+            .sync_branch(
+                head_ids,
+                known_heads,
+                known_commits,
+                commit_types,
+                checkpoint,
+            )
+            .await?;
+        let store = HashMapRepoStore::new();
+        let mut blocks = vec![];
+        while let Some(block) = stream.next().await {
+            store.put(&block).map_err(|_e| ProtocolError::StoreError)?;
+            blocks.push(block);
+        }
+
+        let verify = |obj_ref: &ObjectRef| match store.get(&obj_ref.id) {
+            Ok(root) => Object::verify_root_key(&root, &obj_ref.key, repo_pubkey, repo_secret),
+            // not sent by the broker, already known
+            Err(_) => true,
+        };
+        let mut visited = HashSet::new();
+        let mut refs = heads;
+        while let Some(commit_ref) = refs.pop() {
+            if !visited.insert(commit_ref.id) {
+                continue;
+            }
+            if !verify(&commit_ref) {
+                debug_println!("Foreign root block: {:?}", commit_ref.id);
+                return Err(ProtocolError::ForeignBlock);
+            }
+            if let Ok(commit) = Commit::load(commit_ref, &store) {
+                if !verify(&commit.content().body) {
+                    debug_println!("Foreign root block: {:?}", commit.content().body.id);
+                    return Err(ProtocolError::ForeignBlock);
+                }
+                refs.extend(commit.deps_acks());
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Fetch all the blocks of the overlay, for replication.
+    /// If since is given, only the blocks stored by the broker after that timestamp are sent.
+    /// The tombstones of the objects deleted in the overlay are received along with the blocks,
+    /// to add to the replica with `BrokerServer::add_tombstone`
+    pub async fn replicate(
+        &mut self,
+        since: Option<Timestamp>,
+    ) -> Result<(Pin<Box<T::BlockStream>>, async_channel::Receiver<Tombstone>), ProtocolError> {
+        self.broker
+            .process_overlay_request_stream_response_with_tombstones(
+                self.overlay,
+                BrokerOverlayRequestContentV0::OverlayReplicate(OverlayReplicate::V0(
+                    OverlayReplicateV0 {
+                        since,
+                        checkpoint: false,
+                    },
+                )),
+            )
+            .await
+    }
+
+    /// Fetch the blocks of the overlay that this replica hasn't received yet,
+    /// from the checkpoint the broker keeps for it.
+    /// Once all the blocks are stored, call `ack_replication` so that the checkpoint advances,
+    /// otherwise the next replication sends them again.
+    /// The tombstones are received as with `replicate`
+    pub async fn replicate_from_checkpoint(
+        &mut self,
+    ) -> Result<(Pin<Box<T::BlockStream>>, async_channel::Receiver<Tombstone>), ProtocolError> {
+        self.broker
+            .process_overlay_request_stream_response_with_tombstones(
+                self.overlay,
+                BrokerOverlayRequestContentV0::OverlayReplicate(OverlayReplicate::V0(
+                    OverlayReplicateV0 {
+                        since: None,
+                        checkpoint: true,
+                    },
+                )),
+            )
+            .await
+    }
+
+    /// Acknowledge that the blocks of the last `replicate_from_checkpoint` were all stored.
+    /// Returns the gaps of that replication: the blocks the replica will never receive,
+    /// as they were removed before it could
+    pub async fn ack_replication(&mut self) -> Result<Vec<BlockId>, ProtocolError> {
+        self.broker
+            .process_overlay_request_block_ids_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::ReplicationAck(ReplicationAck::V0(
+                    ReplicationAckV0 {},
+                )),
+            )
+            .await
+    }
+
+    /// List one page of at most `limit` IDs of the blocks stored in the overlay by the broker,
+    /// starting right after the `after` cursor returned with the previous page.
+    /// The broker caps the limit, so the page can be shorter;
+    /// the listing is complete when the page has no next cursor
+    pub async fn list_blocks(
+        &mut self,
+        limit: u32,
+        after: Option<Cursor>,
+    ) -> Result<BlockListResp, ProtocolError> {
+        self.broker
+            .process_overlay_request_block_list_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BlockList(BlockList::V0(BlockListV0 {
+                    limit,
+                    after,
                 })),
             )
             .await
@@ -184,24 +664,93 @@ where
 
     pub fn leave(&self) {}
 
-    pub fn topic_connect(&self, id: TopicId) -> TopicSubscription<T> {
-        let (s, mut r1) = broadcast(128); // FIXME this should be done only once, in the Broker
-        TopicSubscription {
-            id,
-            overlay_cnx: self,
-            event_stream: r1.clone(),
+    /// Subscribe to a topic and connect to it.
+    /// The events the broker receives in the topic from now on are streamed by the subscription
+    pub async fn topic_connect(&mut self, id: TopicId) -> Result<TopicSubscription, ProtocolError> {
+        self.broker
+            .process_overlay_request(
+                self.overlay,
+                BrokerOverlayRequestContentV0::TopicSub(TopicSub::V0(TopicSubV0 {
+                    topic: id,
+                    advert: None,
+                })),
+            )
+            .await?;
+        let subscriptions = Arc::clone(self.broker.subscription_registry());
+        subscriptions.add(self.overlay, id);
+        let event_stream = subscriptions.event_stream(&id);
+        if let Err(e) = self
+            .broker
+            .process_overlay_request(
+                self.overlay,
+                BrokerOverlayRequestContentV0::TopicConnect(TopicConnect::V0(TopicConnectV0 {
+                    topic: id,
+                })),
+            )
+            .await
+        {
+            subscriptions.release(&id);
+            return Err(e);
         }
+        Ok(TopicSubscription {
+            id,
+            overlay: self.overlay,
+            subscriptions,
+            event_stream,
+            released: false,
+        })
+    }
+
+    /// Publish an event, signed by the topic key, to the connections connected to its topic
+    pub async fn publish_event(&mut self, event: Event) -> Result<(), ProtocolError> {
+        self.broker
+            .process_overlay_request(self.overlay, BrokerOverlayRequestContentV0::Event(event))
+            .await
     }
 
     pub async fn delete_object(&mut self, id: ObjectId) -> Result<(), ProtocolError> {
         self.broker
             .process_overlay_request(
                 self.overlay,
-                BrokerOverlayRequestContentV0::ObjectDel(ObjectDel::V0(ObjectDelV0 { id })),
+                BrokerOverlayRequestContentV0::ObjectDel(ObjectDel::V0(ObjectDelV0 {
+                    id,
+                    tombstone: None,
+                })),
+            )
+            .await
+    }
+
+    /// Delete an object and leave a tombstone for it,
+    /// so that the broker doesn't accept it back from a replication
+    pub async fn delete_object_with_tombstone(
+        &mut self,
+        tombstone: Tombstone,
+    ) -> Result<(), ProtocolError> {
+        self.broker
+            .process_overlay_request(
+                self.overlay,
+                BrokerOverlayRequestContentV0::ObjectDel(ObjectDel::V0(ObjectDelV0 {
+                    id: tombstone.object(),
+                    tombstone: Some(tombstone),
+                })),
             )
             .await
     }
 
+    /// Adverts of the peers the broker knows to host this overlay.
+    /// For a public overlay, the ID is derived from the repo pubkey alone,
+    /// so this lets a fresh client find the peers to bootstrap from
+    pub async fn discover_peers(&mut self) -> Result<Vec<PeerAdvert>, ProtocolError> {
+        let status = self
+            .broker
+            .process_overlay_request_status_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::OverlayStatusReq(OverlayStatusReq::V0()),
+            )
+            .await?;
+        Ok(status.peers().clone())
+    }
+
     pub async fn pin_object(&mut self, id: ObjectId) -> Result<(), ProtocolError> {
         self.broker
             .process_overlay_request(
@@ -249,90 +798,644 @@ where
                     id,
                     include_children,
                     topic,
+                    max_blocks: None,
+                    continuation: None,
+                    known_blocks: None,
+                    range: None,
                 })),
             )
             .await
     }
 
-    pub async fn get_object(
+    /// Fetch the blocks of an object covering `len` bytes of its serialized content from `offset`,
+    /// with the blocks on their path from the root, to seek into a large object
+    /// without fetching all of it
+    pub async fn get_block_range(
         &mut self,
         id: ObjectId,
         topic: Option<PubKey>,
-    ) -> Result<Object, ProtocolError> {
-        let mut blockstream = self.get_block(id, true, topic).await?;
-        let mut store = HashMapRepoStore::new();
-        while let Some(block) = blockstream.next().await {
-            store.put(&block).unwrap();
-        }
-        Object::load(id, None, &store).map_err(|e| match e {
-            ObjectParseError::MissingBlocks(_missing) => ProtocolError::MissingBlocks,
-            _ => ProtocolError::ObjectParseError,
-        })
+        offset: u64,
+        len: u64,
+    ) -> Result<Pin<Box<T::BlockStream>>, ProtocolError> {
+        self.broker
+            .process_overlay_request_stream_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BlockGet(BlockGet::V0(BlockGetV0 {
+                    id,
+                    include_children: true,
+                    topic,
+                    max_blocks: None,
+                    continuation: None,
+                    known_blocks: None,
+                    range: Some((offset, len)),
+                })),
+            )
+            .await
     }
 
-    pub async fn put_block(&mut self, block: &Block) -> Result<BlockId, ProtocolError> {
-        self.broker
-            .process_overlay_request(
+    /// Fetch at most `max_blocks` blocks of an object in tree order,
+    /// skipping the blocks already received according to the `continuation` token.
+    /// Returns the blocks, and the continuation token sent by the broker to fetch the next ones
+    /// if it truncated the page, at `max_blocks` or at its own limit
+    pub async fn get_block_page(
+        &mut self,
+        id: BlockId,
+        topic: Option<PubKey>,
+        max_blocks: u32,
+        continuation: Option<u32>,
+    ) -> Result<(Vec<Block>, Option<u32>), ProtocolError> {
+        let (mut blockstream, next) = self
+            .broker
+            .process_overlay_request_stream_response_with_continuation(
                 self.overlay,
-                BrokerOverlayRequestContentV0::BlockPut(BlockPut::V0(block.clone())),
+                BrokerOverlayRequestContentV0::BlockGet(BlockGet::V0(BlockGetV0 {
+                    id,
+                    include_children: true,
+                    topic,
+                    max_blocks: Some(max_blocks),
+                    continuation,
+                    known_blocks: None,
+                    range: None,
+                })),
             )
             .await?;
-        Ok(block.id())
+        let mut blocks = vec![];
+        while let Some(block) = blockstream.next().await {
+            blocks.push(block);
+        }
+        Ok((blocks, next.recv().await.ok()))
     }
 
-    // TODO maybe implement a put_block_with_children ? that would behave like put_object, but taking in a parent Blockk instead of a content
+    /// Fetch an object.
+    /// Returns `GetObjectError::MissingBlocks` with the IDs of the blocks the broker didn't send
+    pub async fn get_object(
+        &mut self,
+        id: ObjectId,
+        topic: Option<PubKey>,
+    ) -> Result<Object, GetObjectError> {
+        let store = HashMapRepoStore::new();
+        self.get_object_into(id, None, topic, &store).await?;
+        Ok(Object::load(id, None, &store).map_err(ProtocolError::from)?)
+    }
 
-    pub async fn put_object(
+    /// Fetch the blocks of an object into `store`, verified as they arrive in tree order,
+    /// also against the keys of the tree when the key of the object is given.
+    /// Only the blocks received before their parent are held in memory.
+    /// Returns `GetObjectError::MissingBlocks` with the IDs of the blocks the broker didn't send
+    pub async fn get_object_into(
         &mut self,
-        content: ObjectContent,
-        deps: Vec<ObjectId>,
-        expiry: Option<Timestamp>,
-        max_object_size: usize,
-        repo_pubkey: PubKey,
-        repo_secret: SymKey,
-    ) -> Result<ObjectId, ProtocolError> {
-        let obj = Object::new(
-            content,
-            deps,
-            expiry,
-            max_object_size,
-            repo_pubkey,
-            repo_secret,
-        );
-        debug_println!("object has {} blocks", obj.blocks().len());
-        let mut deduplicated: HashSet<ObjectId> = HashSet::new();
-        for block in obj.blocks() {
-            let id = block.id();
-            if deduplicated.get(&id).is_none() {
-                let _ = self.put_block(block).await?;
-                deduplicated.insert(id);
+        id: ObjectId,
+        key: Option<SymKey>,
+        topic: Option<PubKey>,
+        store: &impl RepoStore,
+    ) -> Result<(), GetObjectError> {
+        let mut blockstream = self.get_block(id, true, topic).await?;
+        let mut assembler =
+            ObjectAssembler::with_reorder_buffer(id, key, store, DEFAULT_REORDER_BUFFER);
+        while let Some(block) = blockstream.next().await {
+            if !assembler
+                .add_unordered(block)
+                .map_err(ProtocolError::from)?
+            {
+                debug_println!("get_object: discarding duplicate block");
+            }
+        }
+        match assembler.finish() {
+            Ok(_) => Ok(()),
+            Err(ObjectParseError::MissingBlocks(missing)) => {
+                Err(GetObjectError::MissingBlocks(missing))
             }
+            Err(e) => Err(ProtocolError::from(e).into()),
         }
-        Ok(obj.id())
     }
-}
 
-pub struct TopicSubscription<'a, T>
-where
-    T: BrokerConnection,
-{
+    /// Fetch the blocks of an object that are missing from `store`, and put them there.
+    /// The IDs of the blocks already in `store` are sent in a bloom filter, and the broker skips them.
+    /// The blocks skipped because of a false positive of the filter are then requested one at a time.
+    /// Returns the number of blocks received
+    pub async fn get_missing_blocks(
+        &mut self,
+        id: ObjectId,
+        topic: Option<PubKey>,
+        known: &[BlockId],
+        store: &impl RepoStore,
+    ) -> Result<usize, ProtocolError> {
+        let known_blocks = BloomFilter::from_ids(known.iter(), DEFAULT_KNOWN_BLOCKS_FP_RATE);
+        let mut blockstream = self
+            .broker
+            .process_overlay_request_stream_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BlockGet(BlockGet::V0(BlockGetV0 {
+                    id,
+                    include_children: true,
+                    topic,
+                    max_blocks: None,
+                    continuation: None,
+                    known_blocks: Some(known_blocks),
+                    range: None,
+                })),
+            )
+            .await?;
+        let mut received = 0;
+        while let Some(block) = blockstream.next().await {
+            store.put(&block)?;
+            received += 1;
+        }
+        loop {
+            match Object::load(id, None, store) {
+                Ok(_) => return Ok(received),
+                Err(ObjectParseError::MissingBlocks(missing)) => {
+                    debug_println!(
+                        "get_missing_blocks: requesting {} skipped blocks",
+                        missing.len()
+                    );
+                    for id in missing {
+                        let mut blockstream = self.get_block(id, false, topic).await?;
+                        let block = blockstream
+                            .next()
+                            .await
+                            .ok_or(ProtocolError::MissingBlocks)?;
+                        if block.get_id() != id {
+                            return Err(ProtocolError::InvalidResponse);
+                        }
+                        store.put(&block)?;
+                        received += 1;
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Fetch `len` bytes of the serialized content of an object from offset `start`,
+    /// requesting only the blocks on the paths to the leaves of the range, one at a time.
+    /// Each block is verified against the ID referenced by its parent, up to the object ID:
+    /// a block that doesn't match fails with ProtocolError::InvalidResponse
+    pub async fn get_object_range(
+        &mut self,
+        object_ref: ObjectRef,
+        start: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let store = HashMapRepoStore::new();
+        loop {
+            match Object::read_range(object_ref.id, object_ref.key, &store, start, len) {
+                Ok(range) => return Ok(range),
+                Err(ObjectParseError::MissingBlocks(missing)) => {
+                    for id in missing {
+                        let mut blockstream = self.get_block(id, false, None).await?;
+                        let block = blockstream
+                            .next()
+                            .await
+                            .ok_or(ProtocolError::MissingBlocks)?;
+                        if block.get_id() != id {
+                            return Err(ProtocolError::InvalidResponse);
+                        }
+                        store.put(&block)?;
+                    }
+                }
+                Err(ObjectParseError::InvalidBlockId) => {
+                    return Err(ProtocolError::InvalidResponse)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Fetch a commit and its body in one request.
+    /// The commit signature is verified, and the body must be the one referenced by the commit
+    pub async fn get_commit(
+        &mut self,
+        commit_ref: ObjectRef,
+    ) -> Result<(Commit, CommitBody), ProtocolError> {
+        let mut blockstream = self
+            .broker
+            .process_overlay_request_stream_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::CommitGet(CommitGet::V0(CommitGetV0 {
+                    id: commit_ref.id,
+                })),
+            )
+            .await?;
+        let store = HashMapRepoStore::new();
+        while let Some(block) = blockstream.next().await {
+            store.put(&block)?;
+        }
+        let commit = Commit::load(commit_ref, &store).map_err(|e| match e {
+            CommitLoadError::MissingBlocks(_) => ProtocolError::MissingBlocks,
+            _ => ProtocolError::ObjectParseError,
+        })?;
+        commit
+            .verify_sig()
+            .map_err(|_e| ProtocolError::InvalidSignature)?;
+        let body = commit.load_body(&store).map_err(|e| match e {
+            CommitLoadError::MissingBlocks(_) => ProtocolError::MissingBlocks,
+            _ => ProtocolError::ObjectParseError,
+        })?;
+        Ok((commit, body))
+    }
+
+    /// Fetch `len` bytes of the content of a File object from `offset`, or less at its end,
+    /// receiving only the blocks covering the range with their path from the root.
+    /// The blocks are verified against the object ID, and decrypted with `key`
+    pub async fn get_file_range(
+        &mut self,
+        id: ObjectId,
+        key: SymKey,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let mut blockstream =
+            self.broker
+                .process_overlay_request_stream_response(
+                    self.overlay,
+                    BrokerOverlayRequestContentV0::BlockRangeGet(BlockRangeGet::V0(
+                        BlockRangeGetV0 { id, offset, len },
+                    )),
+                )
+                .await?;
+        let store = HashMapRepoStore::new();
+        while let Some(block) = blockstream.next().await {
+            store.put(&block)?;
+        }
+        // the range is requested in the serialized content, where the file content
+        // follows its header, the blocks still missing once it is known are fetched one by one
+        loop {
+            let res = Object::reader(id, key, &store).and_then(|mut reader| {
+                reader.open_file_verified()?;
+                reader.read_range(offset, len as usize)
+            });
+            match res {
+                Ok(bytes) => return Ok(bytes),
+                Err(ObjectParseError::MissingBlocks(missing)) => {
+                    for block_id in missing {
+                        let mut blockstream = self.get_block(block_id, false, None).await?;
+                        while let Some(block) = blockstream.next().await {
+                            store.put(&block)?;
+                        }
+                        if store.get(&block_id).is_err() {
+                            return Err(ProtocolError::NotFound);
+                        }
+                    }
+                }
+                Err(_) => return Err(ProtocolError::ObjectParseError),
+            }
+        }
+    }
+
+    pub async fn put_block(&mut self, block: &Block) -> Result<BlockId, ProtocolError> {
+        self.broker
+            .process_overlay_request(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BlockPut(BlockPut::V0(block.clone())),
+            )
+            .await?;
+        Ok(block.id())
+    }
+
+    /// Put several blocks in one request.
+    /// The broker stores the valid blocks in a single transaction.
+    /// Returns the result of each block, in the same order, so that only the failed ones can be sent again
+    pub async fn put_blocks(
+        &mut self,
+        blocks: &[Block],
+    ) -> Result<Vec<Result<BlockId, ProtocolError>>, ProtocolError> {
+        let results = self
+            .broker
+            .process_overlay_request_block_results_response(
+                self.overlay,
+                BrokerOverlayRequestContentV0::BlocksPut(BlocksPut::V0(BlocksPutV0 {
+                    blocks: blocks.to_vec(),
+                })),
+            )
+            .await?;
+        if results.len() != blocks.len() {
+            return Err(ProtocolError::InvalidResponse);
+        }
+        Ok(results
+            .into_iter()
+            .zip(blocks)
+            .map(|(result, block)| match ProtocolError::from(result) {
+                ProtocolError::Success => Ok(block.id()),
+                err => Err(err),
+            })
+            .collect())
+    }
+
+    // TODO maybe implement a put_block_with_children ? that would behave like put_object, but taking in a parent Blockk instead of a content
+
+    pub async fn put_object(
+        &mut self,
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        max_object_size: usize,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<ObjectId, ProtocolError> {
+        self.put_object_with_retry(
+            content,
+            deps,
+            expiry,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+            RetryBudget::default(),
+        )
+        .await
+        .map(|(id, _)| id)
+    }
+
+    /// Put an object, retrying the block puts that fail with a transient error within the retry budget.
+    /// Other errors abort the upload immediately.
+    /// Returns the object ID and the number of retries
+    pub async fn put_object_with_retry(
+        &mut self,
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        max_object_size: usize,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        budget: RetryBudget,
+    ) -> Result<(ObjectId, usize), ProtocolError> {
+        let obj = Object::new(
+            content,
+            deps,
+            expiry,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+        );
+        debug_println!("object has {} blocks", obj.blocks().len());
+        let mut deduplicated: HashSet<ObjectId> = HashSet::new();
+        let mut retries = 0;
+        for block in obj.blocks() {
+            let id = block.id();
+            if deduplicated.get(&id).is_some() {
+                continue;
+            }
+            self.put_block_with_retry(block, &budget, &mut retries)
+                .await?;
+            deduplicated.insert(id);
+        }
+        Ok((obj.id(), retries))
+    }
+
+    /// Put an object whose serialized content is read from `reader`,
+    /// uploading its blocks as they are made instead of building the whole object first.
+    ///
+    /// The content is read in pieces of about one block,
+    /// and only about one block per level of the Merkle tree is kept in memory.
+    /// The reader gives the serialized `ObjectContent`,
+    /// for the same content the object ID is the same as with `put_object`
+    pub async fn put_object_stream(
+        &mut self,
+        mut reader: impl AsyncRead + Unpin,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        chunking: ChunkingStrategy,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<ObjectId, ProtocolError> {
+        let mut builder = ObjectBuilder::new(
+            deps,
+            expiry,
+            chunking,
+            ChunkCompression::default(),
+            repo_pubkey,
+            repo_secret,
+        );
+        let budget = RetryBudget::default();
+        let mut retries = 0;
+        let mut buf = vec![0; builder.block_size()];
+        loop {
+            let len = reader
+                .read(&mut buf)
+                .await
+                .map_err(|_e| ProtocolError::InvalidValue)?;
+            if len == 0 {
+                break;
+            }
+            for block in builder.add(&buf[..len]) {
+                self.put_block_with_retry(&block, &budget, &mut retries)
+                    .await?;
+            }
+        }
+        let (id, blocks) = builder.finish();
+        for block in blocks {
+            self.put_block_with_retry(&block, &budget, &mut retries)
+                .await?;
+        }
+        Ok(id)
+    }
+
+    /// Put a block, retrying after transient errors while the retry budget isn't used up
+    async fn put_block_with_retry(
+        &mut self,
+        block: &Block,
+        budget: &RetryBudget,
+        retries: &mut usize,
+    ) -> Result<(), ProtocolError> {
+        let mut backoff = budget.backoff;
+        loop {
+            match self.put_block(block).await {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_transient() && *retries < budget.retries => {
+                    debug_println!("put_object: retrying block {} after {:?}", block.id(), e);
+                    *retries += 1;
+                    runtime::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+pub struct TopicSubscription {
     id: TopicId,
-    overlay_cnx: &'a OverlayConnectionClient<'a, T>,
+    overlay: OverlayId,
+    /// subscriptions of the connection
+    subscriptions: Arc<Subscriptions>,
     event_stream: Receiver<Event>,
+    /// set once the subscription was ended explicitly, so that dropping it doesn't release it again
+    released: bool,
 }
 
-impl<'a, T> TopicSubscription<'a, T>
-where
-    T: BrokerConnection,
-{
-    pub fn unsubscribe(&self) {}
+impl TopicSubscription {
+    pub fn id(&self) -> TopicId {
+        self.id
+    }
+
+    /// Unsubscribe from the topic, and close the event stream.
+    /// The TopicUnsub is only sent if this was the last subscription to the topic over the connection
+    pub async fn unsubscribe<T: BrokerConnection>(
+        mut self,
+        overlay_cnx: &mut OverlayConnectionClient<'_, T>,
+    ) -> Result<(), ProtocolError> {
+        self.released = true;
+        if self.subscriptions.release(&self.id).is_none() {
+            return Ok(());
+        }
+        overlay_cnx
+            .broker
+            .process_overlay_request(
+                self.overlay,
+                BrokerOverlayRequestContentV0::TopicUnsub(TopicUnsub::V0(TopicUnsubV0 {
+                    topic: self.id,
+                })),
+            )
+            .await
+    }
 
-    pub fn disconnect(&self) {}
+    /// Stop receiving the events of the topic, and close the event stream.
+    /// The user stays subscribed to the topic.
+    /// The TopicDisconnect is only sent if this was the last subscription to the topic over the connection
+    pub async fn disconnect<T: BrokerConnection>(
+        mut self,
+        overlay_cnx: &mut OverlayConnectionClient<'_, T>,
+    ) -> Result<(), ProtocolError> {
+        self.released = true;
+        if self.subscriptions.release(&self.id).is_none() {
+            return Ok(());
+        }
+        overlay_cnx
+            .broker
+            .process_overlay_request(
+                self.overlay,
+                BrokerOverlayRequestContentV0::TopicDisconnect(TopicDisconnect::V0(
+                    TopicDisconnectV0 { topic: self.id },
+                )),
+            )
+            .await
+    }
 
     pub fn get_branch_heads(&self) {}
 
-    pub fn get_event_stream(&self) -> &Receiver<Event> {
-        &self.event_stream
+    pub fn get_event_stream(&mut self) -> &mut Receiver<Event> {
+        &mut self.event_stream
+    }
+}
+
+impl Drop for TopicSubscription {
+    /// Releases the subscription, the TopicUnsub is queued when it was the last one to the topic
+    fn drop(&mut self) {
+        if !self.released {
+            self.subscriptions.remove(&self.id);
+        }
+    }
+}
+
+/// Topics subscribed over a broker connection
+#[derive(Default)]
+pub struct Subscriptions {
+    /// Subscribed topics, with their overlay and the number of TopicSubscriptions to them
+    topics: RwLock<HashMap<TopicId, (OverlayId, usize)>>,
+    /// Unsubscriptions of dropped TopicSubscriptions, not sent yet
+    pending_unsubs: RwLock<Vec<(OverlayId, TopicId)>>,
+    /// Notified when an unsubscription is queued, for the connection to send it
+    unsub_notifier: RwLock<Option<async_channel::Sender<()>>>,
+    /// Event streams of the connected topics, new subscriptions get a clone of the receiver
+    event_streams: RwLock<HashMap<TopicId, (Sender<Event>, Receiver<Event>)>>,
+}
+
+impl Subscriptions {
+    pub fn add(&self, overlay: OverlayId, topic: TopicId) {
+        self.topics
+            .write()
+            .unwrap()
+            .entry(topic)
+            .or_insert((overlay, 0))
+            .1 += 1;
+        self.pending_unsubs
+            .write()
+            .unwrap()
+            .retain(|(_, t)| *t != topic);
+    }
+
+    /// Releases a dropped subscription.
+    /// When it was the last one to the topic, its TopicUnsub is queued and the connection notified
+    pub fn remove(&self, topic: &TopicId) {
+        if let Some(overlay) = self.release(topic) {
+            self.pending_unsubs.write().unwrap().push((overlay, *topic));
+            if let Some(notifier) = &*self.unsub_notifier.read().unwrap() {
+                let _ = notifier.try_send(());
+            }
+        }
+    }
+
+    /// Releases a subscription without queuing a TopicUnsub, for a subscription ended explicitly.
+    /// Returns the overlay of the topic when it was the last subscription to it,
+    /// the topic is then removed and its event stream closed
+    fn release(&self, topic: &TopicId) -> Option<OverlayId> {
+        let mut topics = self.topics.write().unwrap();
+        let (overlay, count) = topics.get_mut(topic)?;
+        *count -= 1;
+        if *count > 0 {
+            return None;
+        }
+        let overlay = *overlay;
+        topics.remove(topic);
+        drop(topics);
+        self.close_event_stream(topic);
+        Some(overlay)
+    }
+
+    /// Set the channel notified when an unsubscription is queued
+    fn set_unsub_notifier(&self, notifier: async_channel::Sender<()>) {
+        *self.unsub_notifier.write().unwrap() = Some(notifier);
+    }
+
+    /// Stream of the events of a topic, shared by all the subscriptions to the topic
+    fn event_stream(&self, topic: &TopicId) -> Receiver<Event> {
+        let mut streams = self.event_streams.write().unwrap();
+        match streams.get(topic) {
+            Some((_, r)) => r.clone(),
+            None => {
+                let (mut s, r) = broadcast(128);
+                // a subscription that doesn't read its stream doesn't hold back the others
+                s.set_overflow(true);
+                streams.insert(*topic, (s, r.clone()));
+                r
+            }
+        }
+    }
+
+    fn close_event_stream(&self, topic: &TopicId) {
+        if let Some((s, _)) = self.event_streams.write().unwrap().remove(topic) {
+            s.close();
+        }
+    }
+
+    /// Sends an event received from the broker to the event stream of its topic.
+    /// Returns false if the topic has no event stream anymore
+    pub fn dispatch(&self, event: &Event) -> bool {
+        match self.event_streams.read().unwrap().get(event.topic()) {
+            // the oldest events are dropped when the stream is full
+            Some((s, _)) => !matches!(
+                s.try_broadcast(*event),
+                Err(async_broadcast::TrySendError::Closed(_))
+            ),
+            None => false,
+        }
+    }
+
+    pub fn topics(&self) -> Vec<TopicId> {
+        self.topics.read().unwrap().keys().cloned().collect()
+    }
+
+    fn all(&self) -> Vec<(OverlayId, TopicId)> {
+        self.topics
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(topic, (overlay, _))| (*overlay, *topic))
+            .collect()
+    }
+
+    fn take_pending_unsubs(&self) -> Vec<(OverlayId, TopicId)> {
+        std::mem::take(&mut *self.pending_unsubs.write().unwrap())
     }
 }
 
@@ -349,11 +1452,23 @@ pub trait BrokerConnection {
         admin_user_pk: PrivKey,
     ) -> Result<(), ProtocolError>;
 
-    async fn del_user(&mut self, user_id: PubKey, admin_user_pk: PrivKey);
+    async fn del_user(
+        &mut self,
+        user_id: PubKey,
+        admin_user_pk: PrivKey,
+    ) -> Result<(), ProtocolError>;
 
-    async fn add_client(&mut self, client_id: ClientId, user_pk: PrivKey);
+    async fn add_client(
+        &mut self,
+        client_id: ClientId,
+        user_pk: PrivKey,
+    ) -> Result<(), ProtocolError>;
 
-    async fn del_client(&mut self, client_id: ClientId, user_pk: PrivKey);
+    async fn del_client(
+        &mut self,
+        client_id: ClientId,
+        user_pk: PrivKey,
+    ) -> Result<(), ProtocolError>;
 
     async fn overlay_connect(
         &mut self,
@@ -374,41 +1489,152 @@ pub trait BrokerConnection {
         request: BrokerOverlayRequestContentV0,
     ) -> Result<Pin<Box<Self::BlockStream>>, ProtocolError>;
 
+    /// Same as `process_overlay_request_stream_response`,
+    /// with the progress the broker streams along with the blocks of a BranchSyncReq
+    async fn process_overlay_request_stream_response_with_progress(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<
+        (
+            Pin<Box<Self::BlockStream>>,
+            async_channel::Receiver<SyncProgress>,
+        ),
+        ProtocolError,
+    >;
+
+    /// Same as `process_overlay_request_stream_response`,
+    /// with the continuation token received if the broker truncated the stream
+    async fn process_overlay_request_stream_response_with_continuation(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<(Pin<Box<Self::BlockStream>>, async_channel::Receiver<u32>), ProtocolError>;
+
+    /// Same as `process_overlay_request_stream_response`,
+    /// with the tombstones the broker streams before the blocks of an OverlayReplicate
+    async fn process_overlay_request_stream_response_with_tombstones(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<
+        (
+            Pin<Box<Self::BlockStream>>,
+            async_channel::Receiver<Tombstone>,
+        ),
+        ProtocolError,
+    >;
+
     async fn process_overlay_request_objectid_response(
         &mut self,
         overlay: OverlayId,
         request: BrokerOverlayRequestContentV0,
     ) -> Result<ObjectId, ProtocolError>;
 
-    async fn process_overlay_connect(
+    async fn process_overlay_request_block_results_response(
         &mut self,
-        repo_link: &RepoLink,
-        public: bool,
-    ) -> Result<OverlayId, ProtocolError> {
-        let overlay: OverlayId = match public {
-            true => Digest::Blake3Digest32(*blake3::hash(repo_link.id().slice()).as_bytes()),
-            false => {
-                let key: [u8; blake3::OUT_LEN] =
-                    blake3::derive_key("LoFiRe OverlayId BLAKE3 key", repo_link.secret().slice());
-                let keyed_hash = blake3::keyed_hash(&key, repo_link.id().slice());
-                Digest::Blake3Digest32(*keyed_hash.as_bytes())
-            }
-        };
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<Vec<u16>, ProtocolError>;
 
-        let res = self
-            .process_overlay_request(
-                overlay,
-                BrokerOverlayRequestContentV0::OverlayConnect(OverlayConnect::V0()),
-            )
-            .await;
+    async fn process_overlay_request_block_ids_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<Vec<BlockId>, ProtocolError>;
 
-        match res {
-            Err(e) => {
-                if e == ProtocolError::OverlayNotJoined {
-                    debug_println!("OverlayNotJoined");
-                    let res2 = self
-                        .process_overlay_request(
-                            overlay,
+    async fn process_overlay_request_block_list_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<BlockListResp, ProtocolError>;
+
+    async fn process_overlay_request_status_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<OverlayStatusResp, ProtocolError>;
+
+    /// Topics subscribed over this connection
+    fn subscription_registry(&self) -> &Arc<Subscriptions>;
+
+    /// List the topics subscribed over this connection
+    fn subscriptions(&self) -> Vec<TopicId> {
+        self.subscription_registry().topics()
+    }
+
+    /// Time of the broker, as estimated by the client from its own clock.
+    /// Timestamps sent to the broker, such as expiries, should be based on it
+    fn now(&self) -> Timestamp {
+        now_timestamp()
+    }
+
+    /// Send the TopicUnsub of the dropped TopicSubscriptions
+    async fn send_pending_unsubscriptions(&mut self) -> Result<(), ProtocolError> {
+        let unsubs = self.subscription_registry().take_pending_unsubs();
+        for (overlay, topic) in unsubs {
+            self.process_overlay_request(
+                overlay,
+                BrokerOverlayRequestContentV0::TopicUnsub(TopicUnsub::V0(TopicUnsubV0 { topic })),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe again to all the topics subscribed over this connection, e.g. after a reconnect
+    async fn resubscribe_all(&mut self) -> Result<(), ProtocolError> {
+        self.send_pending_unsubscriptions().await?;
+        let topics = self.subscription_registry().all();
+        for (overlay, topic) in topics {
+            self.process_overlay_request(
+                overlay,
+                BrokerOverlayRequestContentV0::TopicSub(TopicSub::V0(TopicSubV0 {
+                    topic,
+                    advert: None,
+                })),
+            )
+            .await?;
+            self.process_overlay_request(
+                overlay,
+                BrokerOverlayRequestContentV0::TopicConnect(TopicConnect::V0(TopicConnectV0 {
+                    topic,
+                })),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn process_overlay_connect(
+        &mut self,
+        repo_link: &RepoLink,
+        public: bool,
+    ) -> Result<OverlayId, ProtocolError> {
+        let overlay: OverlayId = match public {
+            true => Digest::Blake3Digest32(*blake3::hash(repo_link.id().slice()).as_bytes()),
+            false => {
+                let key: [u8; blake3::OUT_LEN] =
+                    blake3::derive_key("LoFiRe OverlayId BLAKE3 key", repo_link.secret().slice());
+                let keyed_hash = blake3::keyed_hash(&key, repo_link.id().slice());
+                Digest::Blake3Digest32(*keyed_hash.as_bytes())
+            }
+        };
+
+        let res = self
+            .process_overlay_request(
+                overlay,
+                BrokerOverlayRequestContentV0::OverlayConnect(OverlayConnect::V0()),
+            )
+            .await;
+
+        match res {
+            Err(e) => {
+                if e == ProtocolError::OverlayNotJoined {
+                    debug_println!("OverlayNotJoined");
+                    let res2 = self
+                        .process_overlay_request(
+                            overlay,
                             BrokerOverlayRequestContentV0::OverlayJoin(OverlayJoin::V0(
                                 OverlayJoinV0 {
                                     secret: repo_link.secret(),
@@ -431,8 +1657,11 @@ pub trait BrokerConnection {
 }
 
 pub struct BrokerConnectionLocal<'a> {
-    broker: &'a mut BrokerServer,
+    broker: &'a BrokerServer,
     user: PubKey,
+    subscriptions: Arc<Subscriptions>,
+    /// event streams of the connected topics, received from the broker
+    topic_streams: HashMap<(OverlayId, TopicId), async_channel::Receiver<Event>>,
 }
 
 #[async_trait::async_trait]
@@ -442,15 +1671,24 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
 
     async fn close(&mut self) {}
 
+    fn subscription_registry(&self) -> &Arc<Subscriptions> {
+        &self.subscriptions
+    }
+
     async fn add_user(
         &mut self,
         user_id: PubKey,
         admin_user_pk: PrivKey,
     ) -> Result<(), ProtocolError> {
-        let op_content = AddUserContentV0 { user: user_id };
-        let sig = sign(admin_user_pk, self.user, &serde_bare::to_vec(&op_content)?)?;
+        let op_content = AddUserContentV0 {
+            user: user_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+        let admin_user = pubkey_from_privkey(admin_user_pk);
+        let sig = sign(admin_user_pk, admin_user, &serde_bare::to_vec(&op_content)?)?;
 
-        self.broker.add_user(self.user, user_id, sig)
+        self.broker.add_user(op_content, sig)
     }
 
     async fn process_overlay_request(
@@ -458,6 +1696,7 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
         overlay: OverlayId,
         request: BrokerOverlayRequestContentV0,
     ) -> Result<(), ProtocolError> {
+        self.unsubscribe_dropped();
         match request {
             BrokerOverlayRequestContentV0::OverlayConnect(_) => {
                 self.broker.connect_overlay(self.user, overlay)
@@ -472,12 +1711,31 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
             BrokerOverlayRequestContentV0::ObjectUnpin(op) => {
                 self.broker.unpin_object(self.user, overlay, op.id())
             }
-            BrokerOverlayRequestContentV0::ObjectDel(op) => {
-                self.broker.del_object(self.user, overlay, op.id())
-            }
+            BrokerOverlayRequestContentV0::ObjectDel(op) => match op.tombstone() {
+                Some(t) if t.object() != op.id() => Err(ProtocolError::InvalidValue),
+                Some(t) => self.broker.add_tombstone(self.user, overlay, t),
+                None => self.broker.del_object(self.user, overlay, op.id()),
+            },
             BrokerOverlayRequestContentV0::BlockPut(b) => {
                 self.broker.put_block(self.user, overlay, b.block())
             }
+            BrokerOverlayRequestContentV0::TopicSub(t) => {
+                self.broker.subscribe_topic(self.user, overlay, t.topic())
+            }
+            BrokerOverlayRequestContentV0::TopicUnsub(t) => {
+                self.disconnect_topic(overlay, t.topic());
+                self.broker.unsubscribe_topic(self.user, overlay, t.topic())
+            }
+            BrokerOverlayRequestContentV0::TopicConnect(t) => {
+                self.connect_topic(overlay, t.topic())
+            }
+            BrokerOverlayRequestContentV0::TopicDisconnect(t) => {
+                self.disconnect_topic(overlay, t.topic());
+                Ok(())
+            }
+            BrokerOverlayRequestContentV0::Event(e) => {
+                self.broker.publish_event(self.user, overlay, &e)
+            }
             _ => Err(ProtocolError::InvalidState),
         }
     }
@@ -496,6 +1754,70 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
         }
     }
 
+    async fn process_overlay_request_block_results_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<Vec<u16>, ProtocolError> {
+        match request {
+            BrokerOverlayRequestContentV0::BlocksPut(b) => {
+                let results = self.broker.put_blocks(self.user, overlay, b.blocks())?;
+                Ok(results
+                    .into_iter()
+                    .map(|r| match r {
+                        Ok(_) => ProtocolError::Success.into(),
+                        Err(e) => e.into(),
+                    })
+                    .collect())
+            }
+            _ => Err(ProtocolError::InvalidState),
+        }
+    }
+
+    async fn process_overlay_request_block_ids_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<Vec<BlockId>, ProtocolError> {
+        match request {
+            BrokerOverlayRequestContentV0::ReplicationAck(_) => {
+                self.broker.ack_replication(self.user, &overlay)
+            }
+            BrokerOverlayRequestContentV0::BranchHeadsReq(b) => {
+                self.broker
+                    .branch_heads(self.user, &overlay, b.topic(), b.known_heads())
+            }
+            _ => Err(ProtocolError::InvalidState),
+        }
+    }
+
+    async fn process_overlay_request_block_list_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<BlockListResp, ProtocolError> {
+        match request {
+            BrokerOverlayRequestContentV0::BlockList(l) => {
+                self.broker
+                    .list_blocks(self.user, &overlay, l.limit(), l.after())
+            }
+            _ => Err(ProtocolError::InvalidState),
+        }
+    }
+
+    async fn process_overlay_request_status_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<OverlayStatusResp, ProtocolError> {
+        match request {
+            BrokerOverlayRequestContentV0::OverlayStatusReq(_) => {
+                self.broker.overlay_status(self.user, overlay)
+            }
+            _ => Err(ProtocolError::InvalidState),
+        }
+    }
+
     async fn process_overlay_request_stream_response(
         &mut self,
         overlay: OverlayId,
@@ -503,10 +1825,26 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
     ) -> Result<Pin<Box<Self::BlockStream>>, ProtocolError> {
         match request {
            
+            BrokerOverlayRequestContentV0::BlockGet(b) if b.range().is_some() => {
+                let (offset, len) = b.range().unwrap();
+                self.broker
+                    .get_block_range(self.user, &overlay, b.id(), offset, len)
+                    .map(|r| Box::pin(r))
+            }
             BrokerOverlayRequestContentV0::BlockGet(b) => self
                 .broker
-                .get_block(self.user, overlay, b.id(), b.include_children(), b.topic())
-                .map(|r| Box::pin(r)),
+                .get_block_with_fallback(
+                    self.user,
+                    overlay,
+                    b.id(),
+                    b.include_children(),
+                    b.topic(),
+                    b.max_blocks(),
+                    b.continuation(),
+                    b.known_blocks(),
+                )
+                .await
+                .map(|(r, _)| Box::pin(r)),
             BrokerOverlayRequestContentV0::BranchSyncReq(b) => self
                 .broker
                 .sync_branch(
@@ -515,17 +1853,194 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
                     b.heads(),
                     b.known_heads(),
                     b.known_commits(),
+                    b.commit_types(),
+                    b.checkpoint(),
                 )
                 .map(|r| Box::pin(r)),
+            BrokerOverlayRequestContentV0::OverlayReplicate(_) => self
+                .process_overlay_request_stream_response_with_tombstones(overlay, request)
+                .await
+                .map(|(blocks, _)| blocks),
+            BrokerOverlayRequestContentV0::CommitGet(c) => self
+                .broker
+                .get_commit(self.user, &overlay, c.id())
+                .map(|r| Box::pin(r)),
+            BrokerOverlayRequestContentV0::BlockRangeGet(b) => self
+                .broker
+                .get_block_range(self.user, &overlay, b.id(), b.offset(), b.len())
+                .map(|r| Box::pin(r)),
             _ => Err(ProtocolError::InvalidState),
         }
     }
 
-    async fn del_user(&mut self, user_id: PubKey, admin_user_pk: PrivKey) {}
+    async fn process_overlay_request_stream_response_with_progress(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<
+        (
+            Pin<Box<Self::BlockStream>>,
+            async_channel::Receiver<SyncProgress>,
+        ),
+        ProtocolError,
+    > {
+        let (blocks_s, blocks_r) = async_channel::unbounded::<Block>();
+        let (progress_s, progress_r) = async_channel::unbounded::<SyncProgress>();
+        match request {
+            BrokerOverlayRequestContentV0::BranchSyncReq(b) => {
+                let items = self.broker.sync_branch_with_progress(
+                    self.user,
+                    &overlay,
+                    b.heads(),
+                    b.known_heads(),
+                    b.known_commits(),
+                    b.commit_types(),
+                    b.checkpoint(),
+                )?;
+                while let Ok(item) = items.try_recv() {
+                    match item {
+                        SyncStreamItem::Block(block) => {
+                            let _ = blocks_s.try_send(block);
+                        }
+                        SyncStreamItem::Progress(progress) => {
+                            let _ = progress_s.try_send(progress);
+                        }
+                    }
+                }
+                Ok((Box::pin(blocks_r), progress_r))
+            }
+            // the other streams don't have a progress
+            _ => Ok((
+                self.process_overlay_request_stream_response(overlay, request)
+                    .await?,
+                progress_r,
+            )),
+        }
+    }
+
+    async fn process_overlay_request_stream_response_with_continuation(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<(Pin<Box<Self::BlockStream>>, async_channel::Receiver<u32>), ProtocolError> {
+        let (continuation_s, continuation_r) = async_channel::bounded::<u32>(1);
+        match request {
+            BrokerOverlayRequestContentV0::BlockGet(b) if b.range().is_none() => {
+                let (blocks, continuation) = self
+                    .broker
+                    .get_block_with_fallback(
+                        self.user,
+                        overlay,
+                        b.id(),
+                        b.include_children(),
+                        b.topic(),
+                        b.max_blocks(),
+                        b.continuation(),
+                        b.known_blocks(),
+                    )
+                    .await?;
+                if let Some(continuation) = continuation {
+                    let _ = continuation_s.try_send(continuation);
+                }
+                Ok((Box::pin(blocks), continuation_r))
+            }
+            // the other streams don't have a continuation token
+            _ => Ok((
+                self.process_overlay_request_stream_response(overlay, request)
+                    .await?,
+                continuation_r,
+            )),
+        }
+    }
+
+    async fn process_overlay_request_stream_response_with_tombstones(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<
+        (
+            Pin<Box<Self::BlockStream>>,
+            async_channel::Receiver<Tombstone>,
+        ),
+        ProtocolError,
+    > {
+        let (blocks_s, blocks_r) = async_channel::unbounded::<Block>();
+        let (tombstone_s, tombstone_r) = async_channel::unbounded::<Tombstone>();
+        let items = match request {
+            BrokerOverlayRequestContentV0::OverlayReplicate(r) if r.checkpoint() => self
+                .broker
+                .replicate_from_checkpoint(self.user, &overlay)
+                .map(|(r, _)| r)?,
+            BrokerOverlayRequestContentV0::OverlayReplicate(r) => {
+                self.broker.replicate(self.user, &overlay, r.since())?
+            }
+            // the other streams don't have tombstones
+            _ => {
+                return Ok((
+                    self.process_overlay_request_stream_response(overlay, request)
+                        .await?,
+                    tombstone_r,
+                ))
+            }
+        };
+        while let Ok(item) = items.try_recv() {
+            match item {
+                ReplicationStreamItem::Tombstone(tombstone) => {
+                    let _ = tombstone_s.try_send(tombstone);
+                }
+                ReplicationStreamItem::Block(block) => {
+                    let _ = blocks_s.try_send(block);
+                }
+            }
+        }
+        Ok((Box::pin(blocks_r), tombstone_r))
+    }
+
+    async fn del_user(
+        &mut self,
+        user_id: PubKey,
+        admin_user_pk: PrivKey,
+    ) -> Result<(), ProtocolError> {
+        let op_content = DelUserContentV0 {
+            user: user_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+        let admin_user = pubkey_from_privkey(admin_user_pk);
+        let sig = sign(admin_user_pk, admin_user, &serde_bare::to_vec(&op_content)?)?;
+
+        self.broker.del_user(op_content, sig)
+    }
+
+    async fn add_client(
+        &mut self,
+        client_id: ClientId,
+        user_pk: PrivKey,
+    ) -> Result<(), ProtocolError> {
+        let op_content = AddClientContentV0 {
+            client: client_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+        let sig = sign(user_pk, self.user, &serde_bare::to_vec(&op_content)?)?;
 
-    async fn add_client(&mut self, user_id: PubKey, admin_user_pk: PrivKey) {}
+        self.broker.add_client(self.user, client_id, sig)
+    }
+
+    async fn del_client(
+        &mut self,
+        client_id: ClientId,
+        user_pk: PrivKey,
+    ) -> Result<(), ProtocolError> {
+        let op_content = DelClientContentV0 {
+            client: client_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+        let sig = sign(user_pk, self.user, &serde_bare::to_vec(&op_content)?)?;
 
-    async fn del_client(&mut self, user_id: PubKey, admin_user_pk: PrivKey) {}
+        self.broker.del_client(self.user, client_id, sig)
+    }
 
     async fn overlay_connect(
         &mut self,
@@ -542,14 +2057,59 @@ impl<'a> BrokerConnection for BrokerConnectionLocal<'a> {
 }
 
 impl<'a> BrokerConnectionLocal<'a> {
-    pub fn new(broker: &'a mut BrokerServer, user: PubKey) -> BrokerConnectionLocal<'a> {
-        BrokerConnectionLocal { broker, user }
+    pub fn new(broker: &'a BrokerServer, user: PubKey) -> BrokerConnectionLocal<'a> {
+        BrokerConnectionLocal {
+            broker,
+            user,
+            subscriptions: Arc::new(Subscriptions::default()),
+            topic_streams: HashMap::new(),
+        }
+    }
+
+    /// Forward the events the broker publishes in the topic to its event stream
+    fn connect_topic(&mut self, overlay: OverlayId, topic: TopicId) -> Result<(), ProtocolError> {
+        let events = self.broker.connect_topic(self.user, overlay, topic)?;
+        if let Some(previous) = self.topic_streams.insert((overlay, topic), events.clone()) {
+            previous.close();
+        }
+        let subscriptions = Arc::clone(&self.subscriptions);
+        runtime::spawn(async move {
+            while let Ok(event) = events.recv().await {
+                if !subscriptions.dispatch(&event) {
+                    break;
+                }
+            }
+            events.close();
+        });
+        Ok(())
+    }
+
+    fn disconnect_topic(&mut self, overlay: OverlayId, topic: TopicId) {
+        if let Some(events) = self.topic_streams.remove(&(overlay, topic)) {
+            events.close();
+        }
+    }
+
+    /// Unsubscribe from the topics whose last subscription was dropped.
+    /// The connection borrows the broker and can't be reached from the drop,
+    /// so they are unsubscribed before its next request
+    fn unsubscribe_dropped(&mut self) {
+        for (overlay, topic) in self.subscriptions.take_pending_unsubs() {
+            self.disconnect_topic(overlay, topic);
+            let _ = self.broker.unsubscribe_topic(self.user, overlay, topic);
+        }
     }
 }
 
 pub struct ConnectionRemote {}
 
 impl ConnectionRemote {
+    /// Send a request as a non-member, e.g. the request of an `ObjectLink`,
+    /// on a new connection that is closed afterwards.
+    ///
+    /// Returns the responses: blocks are streamed in one response each,
+    /// so all of them are collected until the end of the stream.
+    /// A stream truncated by the broker fails with Truncated
     pub async fn ext_request<
         B: Stream<Item = Vec<u8>> + StreamExt + Send + Sync,
         A: Sink<Vec<u8>, Error = ProtocolError> + Send,
@@ -557,8 +2117,71 @@ impl ConnectionRemote {
         w: A,
         r: B,
         request: ExtRequest,
-    ) -> Result<ExtResponse, ProtocolError> {
-        unimplemented!();
+    ) -> Result<Vec<ExtResponse>, ProtocolError> {
+        let id = request.id();
+        let mut writer = Box::pin(w);
+        writer
+            .send(serde_bare::to_vec(&StartProtocol::Ext(request))?)
+            .await
+            .map_err(|_e| ProtocolError::WriteError)?;
+
+        let mut reader = Box::pin(r);
+        let mut responses = vec![];
+        loop {
+            let frame = match reader.next().await {
+                Some(frame) if !frame.is_empty() => frame,
+                _ => return Err(Self::close(writer, ProtocolError::ConnectionClosed).await),
+            };
+            let response = match serde_bare::from_slice::<ExtResponse>(&frame) {
+                Ok(response) if response.id() == id => response,
+                _ => return Err(Self::close(writer, ProtocolError::InvalidResponse).await),
+            };
+            match ProtocolError::from(response.result()) {
+                ProtocolError::PartialContent => responses.push(response),
+                ProtocolError::EndOfStream => break,
+                ProtocolError::Success => {
+                    responses.push(response);
+                    break;
+                }
+                err => return Err(Self::close(writer, err).await),
+            }
+        }
+        Self::close(writer, ProtocolError::Closing).await;
+        Ok(responses)
+    }
+
+    /// Send an `ExtObjectGet` request, like `ext_request`,
+    /// and sort the blocks received by the requested object they belong to.
+    ///
+    /// Each object of the result can be loaded from its own blocks.
+    /// A block that is not tagged with one of the requested objects fails with InvalidResponse
+    pub async fn ext_object_get<
+        B: Stream<Item = Vec<u8>> + StreamExt + Send + Sync,
+        A: Sink<Vec<u8>, Error = ProtocolError> + Send,
+    >(
+        w: A,
+        r: B,
+        request: ExtRequest,
+    ) -> Result<HashMap<ObjectId, Vec<Block>>, ProtocolError> {
+        let mut objects: HashMap<ObjectId, Vec<Block>> = match request.content() {
+            ExtRequestContentV0::ExtObjectGet(ExtObjectGet::V0(get)) => {
+                get.ids.iter().map(|id| (*id, vec![])).collect()
+            }
+            _ => return Err(ProtocolError::InvalidState),
+        };
+        for response in Self::ext_request(w, r, request).await? {
+            let blocks = response
+                .object()
+                .and_then(|id| objects.get_mut(&id))
+                .ok_or(ProtocolError::InvalidResponse)?;
+            blocks.push(
+                response
+                    .block()
+                    .ok_or(ProtocolError::InvalidResponse)?
+                    .clone(),
+            );
+        }
+        Ok(objects)
     }
 
     async fn close<S>(w: S, err: ProtocolError) -> ProtocolError
@@ -571,6 +2194,8 @@ impl ConnectionRemote {
         err
     }
 
+    /// Opens a connection authenticated with the user and client keys.
+    /// The messages sent on it are padded with the given policy
     pub async fn open_broker_connection<
         B: Stream<Item = Vec<u8>> + StreamExt + Send + Sync + 'static,
         A: Sink<Vec<u8>, Error = ProtocolError> + Send + 'static,
@@ -580,6 +2205,27 @@ impl ConnectionRemote {
         user: PubKey,
         user_pk: PrivKey,
         client: PubKey,
+        padding: PaddingPolicy,
+    ) -> Result<impl BrokerConnection, ProtocolError> {
+        Self::open_broker_connection_with_clock(w, r, user, user_pk, client, padding, SystemClock)
+            .await
+    }
+
+    /// Same as `open_broker_connection`, with the local clock of the client.
+    /// The connection corrects it by the skew to the clock of the broker, measured in the handshake,
+    /// see `BrokerConnection::now`
+    pub async fn open_broker_connection_with_clock<
+        B: Stream<Item = Vec<u8>> + StreamExt + Send + Sync + 'static,
+        A: Sink<Vec<u8>, Error = ProtocolError> + Send + 'static,
+        C: Clock + Send + Sync + 'static,
+    >(
+        w: A,
+        r: B,
+        user: PubKey,
+        user_pk: PrivKey,
+        client: PubKey,
+        padding: PaddingPolicy,
+        clock: C,
     ) -> Result<impl BrokerConnection, ProtocolError> {
         let mut writer = Box::pin(w);
         writer
@@ -593,7 +2239,11 @@ impl ConnectionRemote {
             return Err(Self::close(writer, ProtocolError::InvalidState).await);
         }
 
-        let server_hello = serde_bare::from_slice::<ServerHello>(&answer.unwrap())?;
+        let server_hello = match serde_bare::from_slice::<ServerHello>(&answer.unwrap()) {
+            Ok(hello) => hello,
+            Err(_e) => return Err(Self::close(writer, ProtocolError::SerializationError).await),
+        };
+        let clock = OffsetClock::synced_to(clock, server_hello.now());
 
         //debug_println!("received nonce from server: {:?}", server_hello.nonce());
 
@@ -619,36 +2269,45 @@ impl ConnectionRemote {
             return Err(Self::close(writer, ProtocolError::InvalidState).await);
         }
 
-        let auth_result = serde_bare::from_slice::<AuthResult>(&answer.unwrap())?;
+        let auth_result = match serde_bare::from_slice::<AuthResult>(&answer.unwrap()) {
+            Ok(result) => result,
+            Err(_e) => return Err(Self::close(writer, ProtocolError::SerializationError).await),
+        };
 
-        match auth_result.result() {
-            0 => {
-                async fn transform(message: BrokerMessage) -> Result<Vec<u8>, ProtocolError> {
+        match ProtocolError::from(auth_result.result()) {
+            ProtocolError::Success => {
+                async fn transform(
+                    message: BrokerMessage,
+                    padding: PaddingPolicy,
+                ) -> Result<Vec<u8>, ProtocolError> {
                     if message.is_close() {
                         Ok(vec![])
                     } else {
-                        Ok(serde_bare::to_vec(&message)?)
+                        padding.serialize(&message)
                     }
                 }
-                let messages_stream_write = writer.with(|message| transform(message));
+                let messages_stream_write =
+                    writer.with(move |message| transform(message, padding.clone()));
 
                 let mut messages_stream_read = reader.map(|message| {
                     if message.len() == 0 {
                         BrokerMessage::Close
                     } else {
+                        // a malformed message closes the connection
                         match serde_bare::from_slice::<BrokerMessage>(&message) {
-                            Err(e) => BrokerMessage::Close,
-                            Ok(m) => m
+                            Err(_e) => BrokerMessage::Close,
+                            Ok(m) => m,
                         }
                     }
                 });
 
-                let cnx =
+                let mut cnx =
                     BrokerConnectionRemote::open(messages_stream_write, messages_stream_read, user);
+                cnx.set_clock(clock);
 
                 Ok(cnx)
             }
-            err => Err(Self::close(writer, ProtocolError::try_from(err).unwrap()).await),
+            err => Err(Self::close(writer, err).await),
         }
     }
 }
@@ -661,7 +2320,13 @@ where
     user: PubKey,
     actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageActor>>>>,
     stream_actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageStreamActor>>>>,
+    cancelled: Arc<RwLock<HashSet<u64>>>,
     shutdown: mpsc::UnboundedSender<Void>,
+    subscriptions: Arc<Subscriptions>,
+    /// set once a send failed, the connection can't be used anymore
+    dead: bool,
+    /// local clock corrected to the clock of the broker
+    clock: Arc<dyn Clock + Send + Sync>,
 }
 
 #[async_trait::async_trait]
@@ -672,6 +2337,14 @@ where
     type OC = BrokerConnectionRemote<T>;
     type BlockStream = async_channel::Receiver<Block>;
 
+    fn subscription_registry(&self) -> &Arc<Subscriptions> {
+        &self.subscriptions
+    }
+
+    fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
     async fn close(&mut self) {
         let _ = self.shutdown.close().await;
         let mut w = self.writer.lock().await;
@@ -684,62 +2357,53 @@ where
         overlay: OverlayId,
         request: BrokerOverlayRequestContentV0,
     ) -> Result<Pin<Box<Self::BlockStream>>, ProtocolError> {
-        let mut actor = BrokerMessageStreamActor::new();
-        let receiver = actor.receiver();
-        let error_receiver = actor.error_receiver();
-        let mut addr = actor
-            .start()
+        self.stream_request(overlay, request, BrokerMessageStreamActor::new())
             .await
-            .map_err(|_e| ProtocolError::ActorError)?;
+    }
 
-        let request_id = addr.actor_id();
-        //debug_println!("actor ID {}", request_id);
+    async fn process_overlay_request_stream_response_with_progress(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<
+        (
+            Pin<Box<Self::BlockStream>>,
+            async_channel::Receiver<SyncProgress>,
+        ),
+        ProtocolError,
+    > {
+        let mut actor = BrokerMessageStreamActor::new();
+        let progress = actor.progress_receiver();
+        let blocks = self.stream_request(overlay, request, actor).await?;
+        Ok((blocks, progress))
+    }
 
-        {
-            let mut map = self.stream_actors.write().expect("RwLock poisoned");
-            map.insert(request_id, addr.downgrade());
-        }
+    async fn process_overlay_request_stream_response_with_continuation(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<(Pin<Box<Self::BlockStream>>, async_channel::Receiver<u32>), ProtocolError> {
+        let mut actor = BrokerMessageStreamActor::new();
+        let continuation = actor.continuation_receiver();
+        let blocks = self.stream_request(overlay, request, actor).await?;
+        Ok((blocks, continuation))
+    }
 
-        let mut w = self.writer.lock().await;
-        w.send(BrokerMessage::V0(BrokerMessageV0 {
-                padding: vec![], //FIXME implement padding
-                content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
-                    BrokerOverlayMessageV0 {
-                        overlay,
-                        content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
-                            BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
-                                id: request_id,
-                                content: request,
-                            }),
-                        ),
-                    },
-                )),
-            }))
-            .await
-            .map_err(|_e| ProtocolError::WriteError)?;
-
-        //debug_println!("waiting for first reply");
-        let reply = error_receiver.await;
-        match reply {
-            Err(_e) => {
-                Err(ProtocolError::Closing)
-            }
-            Ok(Some(e)) => {
-                let mut map = self.stream_actors.write().expect("RwLock poisoned");
-                map.remove(&request_id);
-                return Err(e);
-            }
-            Ok(None) => {
-                let stream_actors_in_thread = Arc::clone(&self.stream_actors);
-                task::spawn(async move {
-                    addr.wait_for_stop().await; // TODO add timeout
-                    let mut map = stream_actors_in_thread.write().expect("RwLock poisoned");
-                    map.remove(&request_id);
-                });
-
-                Ok(Box::pin(receiver))
-            }
-        }
+    async fn process_overlay_request_stream_response_with_tombstones(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<
+        (
+            Pin<Box<Self::BlockStream>>,
+            async_channel::Receiver<Tombstone>,
+        ),
+        ProtocolError,
+    > {
+        let mut actor = BrokerMessageStreamActor::new();
+        let tombstones = actor.tombstone_receiver();
+        let blocks = self.stream_request(overlay, request, actor).await?;
+        Ok((blocks, tombstones))
     }
 
     async fn process_overlay_request_objectid_response(
@@ -749,23 +2413,129 @@ where
     ) -> Result<ObjectId, ProtocolError> {
         before!(self, request_id, addr, receiver);
 
-        self.writer.lock().await
-            .send(BrokerMessage::V0(BrokerMessageV0 {
-                padding: vec![], // FIXME implement padding
-                content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
-                    BrokerOverlayMessageV0 {
-                        overlay,
-                        content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
-                            BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
-                                id: request_id,
-                                content: request,
-                            }),
-                        ),
-                    },
-                )),
-            }))
-            .await
-            .map_err(|_e| ProtocolError::WriteError)?;
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
+
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
+
+    async fn process_overlay_request_block_results_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<Vec<u16>, ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
+
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
+
+    async fn process_overlay_request_block_ids_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<Vec<BlockId>, ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
+
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
+
+    async fn process_overlay_request_block_list_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<BlockListResp, ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
+
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
+
+    async fn process_overlay_request_status_response(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+    ) -> Result<OverlayStatusResp, ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
 
         after!(self, request_id, addr, receiver, reply);
         reply.into()
@@ -778,23 +2548,21 @@ where
     ) -> Result<(), ProtocolError> {
         before!(self, request_id, addr, receiver);
 
-        self.writer.lock().await
-            .send(BrokerMessage::V0(BrokerMessageV0 {
-                padding: vec![], // FIXME implement padding
-                content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
-                    BrokerOverlayMessageV0 {
-                        overlay,
-                        content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
-                            BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
-                                id: request_id,
-                                content: request,
-                            }),
-                        ),
-                    },
-                )),
-            }))
-            .await
-            .map_err(|_e| ProtocolError::WriteError)?;
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
 
         after!(self, request_id, addr, receiver, reply);
         reply.into()
@@ -807,39 +2575,124 @@ where
     ) -> Result<(), ProtocolError> {
         before!(self, request_id, addr, receiver);
 
-        let op_content = AddUserContentV0 { user: user_id };
+        let op_content = AddUserContentV0 {
+            user: user_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
 
-        let sig = sign(
-            admin_user_pk,
-            self.user,
-            &serde_bare::to_vec(&op_content)?,
-        )?;
-
-        self.writer.lock().await
-            .send(BrokerMessage::V0(BrokerMessageV0 {
-                padding: vec![], // TODO implement padding
-                content: BrokerMessageContentV0::BrokerRequest(BrokerRequest::V0(
-                    BrokerRequestV0 {
-                        id: request_id,
-                        content: BrokerRequestContentV0::AddUser(AddUser::V0(AddUserV0 {
-                            content: op_content,
-                            sig,
-                        })),
-                    },
-                )),
-            }))
-            .await
-            .map_err(|_e| ProtocolError::WriteError)?;
+        let admin_user = pubkey_from_privkey(admin_user_pk);
+        let sig = sign(admin_user_pk, admin_user, &serde_bare::to_vec(&op_content)?)?;
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerRequest(BrokerRequest::V0(BrokerRequestV0 {
+                id: request_id,
+                content: BrokerRequestContentV0::AddUser(AddUser::V0(AddUserV0 {
+                    content: op_content,
+                    sig,
+                })),
+            })),
+        }))
+        .await?;
+
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
+
+    async fn del_user(
+        &mut self,
+        user_id: PubKey,
+        admin_user_pk: PrivKey,
+    ) -> Result<(), ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        let op_content = DelUserContentV0 {
+            user: user_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+
+        let admin_user = pubkey_from_privkey(admin_user_pk);
+        let sig = sign(admin_user_pk, admin_user, &serde_bare::to_vec(&op_content)?)?;
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerRequest(BrokerRequest::V0(BrokerRequestV0 {
+                id: request_id,
+                content: BrokerRequestContentV0::DelUser(DelUser::V0(DelUserV0 {
+                    content: op_content,
+                    sig,
+                })),
+            })),
+        }))
+        .await?;
+
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
+
+    async fn add_client(
+        &mut self,
+        client_id: ClientId,
+        user_pk: PrivKey,
+    ) -> Result<(), ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        let op_content = AddClientContentV0 {
+            client: client_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+
+        let sig = sign(user_pk, self.user, &serde_bare::to_vec(&op_content)?)?;
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerRequest(BrokerRequest::V0(BrokerRequestV0 {
+                id: request_id,
+                content: BrokerRequestContentV0::AddClient(AddClient::V0(AddClientV0 {
+                    content: op_content,
+                    sig,
+                })),
+            })),
+        }))
+        .await?;
 
         after!(self, request_id, addr, receiver, reply);
         reply.into()
     }
 
-    async fn del_user(&mut self, user_id: PubKey, admin_user_pk: PrivKey) {}
+    async fn del_client(
+        &mut self,
+        client_id: ClientId,
+        user_pk: PrivKey,
+    ) -> Result<(), ProtocolError> {
+        before!(self, request_id, addr, receiver);
+
+        let op_content = DelClientContentV0 {
+            client: client_id,
+            timestamp: now_timestamp(),
+            nonce: random_nonce(),
+        };
+
+        let sig = sign(user_pk, self.user, &serde_bare::to_vec(&op_content)?)?;
 
-    async fn add_client(&mut self, client_id: ClientId, user_pk: PrivKey) {}
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerRequest(BrokerRequest::V0(BrokerRequestV0 {
+                id: request_id,
+                content: BrokerRequestContentV0::DelClient(DelClient::V0(DelClientV0 {
+                    content: op_content,
+                    sig,
+                })),
+            })),
+        }))
+        .await?;
 
-    async fn del_client(&mut self, client_id: ClientId, user_pk: PrivKey) {}
+        after!(self, request_id, addr, receiver, reply);
+        reply.into()
+    }
 
     async fn overlay_connect(
         &mut self,
@@ -863,12 +2716,113 @@ impl<T> BrokerConnectionRemote<T>
 where
     T: Sink<BrokerMessage> + Send,
 {
+    /// Sends a message to the broker.
+    /// When the writer fails, the connection is marked dead, the reader loop is stopped,
+    /// and all pending requests fail with ProtocolError::ConnectionClosed
+    async fn send(&mut self, message: BrokerMessage) -> Result<(), ProtocolError> {
+        if self.dead {
+            return Err(ProtocolError::ConnectionClosed);
+        }
+        if self.writer.lock().await.send(message).await.is_ok() {
+            return Ok(());
+        }
+        debug_println!("writer failed, closing connection");
+        self.dead = true;
+        self.shutdown.close_channel();
+        Self::fail_pending(
+            &self.actors,
+            &self.stream_actors,
+            ProtocolError::ConnectionClosed,
+        );
+        Err(ProtocolError::ConnectionClosed)
+    }
+
+    /// Sends a request answered with a stream of blocks, received by the actor
+    async fn stream_request(
+        &mut self,
+        overlay: OverlayId,
+        request: BrokerOverlayRequestContentV0,
+        mut actor: BrokerMessageStreamActor,
+    ) -> Result<Pin<Box<async_channel::Receiver<Block>>>, ProtocolError> {
+        let receiver = actor.receiver();
+        let error_receiver = actor.error_receiver();
+        let mut addr = actor
+            .start()
+            .await
+            .map_err(|_e| ProtocolError::ActorError)?;
+
+        let request_id = addr.actor_id();
+        //debug_println!("actor ID {}", request_id);
+
+        {
+            let mut map = self.stream_actors.write().expect("RwLock poisoned");
+            map.insert(request_id, addr.downgrade());
+        }
+
+        self.send(BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: request_id,
+                            content: request,
+                        }),
+                    ),
+                },
+            )),
+        }))
+        .await?;
+
+        //debug_println!("waiting for first reply");
+        let reply = error_receiver.await;
+        match reply {
+            Err(_e) => Err(ProtocolError::Closing),
+            Ok(Some(e)) => {
+                let mut map = self.stream_actors.write().expect("RwLock poisoned");
+                map.remove(&request_id);
+                return Err(e);
+            }
+            Ok(None) => {
+                let stream_actors_in_thread = Arc::clone(&self.stream_actors);
+                runtime::spawn(async move {
+                    addr.wait_for_stop().await; // TODO add timeout
+                    let mut map = stream_actors_in_thread.write().expect("RwLock poisoned");
+                    map.remove(&request_id);
+                });
+
+                Ok(Box::pin(receiver))
+            }
+        }
+    }
+
+    /// Answers all pending requests with an error response
+    fn fail_pending(
+        actors: &RwLock<HashMap<u64, WeakAddr<BrokerMessageActor>>>,
+        stream_actors: &RwLock<HashMap<u64, WeakAddr<BrokerMessageStreamActor>>>,
+        err: ProtocolError,
+    ) {
+        for (id, a) in actors.read().expect("RwLock poisoned").iter() {
+            if let Some(addr) = a.upgrade() {
+                let _ = addr.send(BrokerMessageXActor(error_response(*id, err.clone())));
+            }
+        }
+        for (id, a) in stream_actors.read().expect("RwLock poisoned").iter() {
+            if let Some(addr) = a.upgrade() {
+                let _ = addr.send(BrokerMessageXActor(error_response(*id, err.clone())));
+            }
+        }
+    }
+
     async fn connection_reader_loop<
         U: Stream<Item = BrokerMessage> + StreamExt + Send + Sync + Unpin + 'static,
     >(
         stream: U,
         actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageActor>>>>,
         stream_actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageStreamActor>>>>,
+        cancelled: Arc<RwLock<HashSet<u64>>>,
+        subscriptions: Arc<Subscriptions>,
         shutdown: mpsc::UnboundedReceiver<Void>,
     ) -> Result<(), ProtocolError> {
         let mut s = stream.fuse();
@@ -902,14 +2856,24 @@ where
                             return Err(ProtocolError::Closing);
                         }
 
-                        if message.is_request() {
-                            debug_println!("is request {}", message.id());
+                        if let Some(event) = message.event() {
+                            // events of the connected topics, the ones of closed subscriptions are dropped
+                            subscriptions.dispatch(event);
+                        } else if message.is_request() {
+                            debug_println!("is request {:?}", message.try_id());
                             // closing connection. a client is not supposed to receive requests.
                             return Err(ProtocolError::Closing);
                             
                         } else if message.is_response() {
-                            let id = message.id();
+                            let id = message.try_id()?;
                             //debug_println!("is response for {}", id);
+                            if cancelled.read().expect("RwLock poisoned").contains(&id) {
+                                // late response of a cancelled request, dropped until the last one
+                                if ProtocolError::from(message.result()) != ProtocolError::PartialContent {
+                                    cancelled.write().expect("RwLock poisoned").remove(&id);
+                                }
+                                continue;
+                            }
                             {
                                 let map = actors.read().expect("RwLock poisoned");
                                 match map.get(&id) {
@@ -959,6 +2923,30 @@ where
         Ok(())
     }
 
+    /// Handle on the pending requests, that can list and cancel them while a request is in progress
+    pub fn pending_requests(&self) -> PendingRequests {
+        PendingRequests {
+            actors: Arc::clone(&self.actors),
+            stream_actors: Arc::clone(&self.stream_actors),
+            cancelled: Arc::clone(&self.cancelled),
+        }
+    }
+
+    /// IDs of the requests waiting for their response, in increasing order
+    pub fn pending_request_ids(&self) -> Vec<u64> {
+        self.pending_requests().ids()
+    }
+
+    /// Cancels a pending request, see `PendingRequests::cancel`
+    pub fn cancel_request(&self, id: u64) -> Result<(), ProtocolError> {
+        self.pending_requests().cancel(id)
+    }
+
+    /// Set the clock the timestamps of the connection are based on, see `BrokerConnection::now`
+    pub fn set_clock(&mut self, clock: impl Clock + Send + Sync + 'static) {
+        self.clock = Arc::new(clock);
+    }
+
     pub fn open<U: Stream<Item = BrokerMessage> + StreamExt + Send + Sync + Unpin + 'static>(
         writer: T,
         reader: U,
@@ -970,18 +2958,73 @@ where
         let stream_actors: Arc<RwLock<HashMap<u64, WeakAddr<BrokerMessageStreamActor>>>> =
             Arc::new(RwLock::new(HashMap::new()));
 
+        let cancelled: Arc<RwLock<HashSet<u64>>> = Arc::new(RwLock::new(HashSet::new()));
+
         let (shutdown_sender, shutdown_receiver) = mpsc::unbounded::<Void>();
 
         let w = Arc::new(Mutex::new(Box::pin(writer)));
         let ws_in_task = Arc::clone(&w);
 
+        let subscriptions = Arc::new(Subscriptions::default());
+
+        // sends the TopicUnsub of the last dropped subscriptions to a topic,
+        // their responses are dropped like the ones of cancelled requests.
+        // The task ends with the subscriptions, that hold the sender of the notifications
+        let (unsub_s, unsub_r) = async_channel::unbounded::<()>();
+        subscriptions.set_unsub_notifier(unsub_s);
+        let subscriptions_in_unsub_task = Arc::downgrade(&subscriptions);
+        let cancelled_in_unsub_task = Arc::clone(&cancelled);
+        let ws_in_unsub_task = Arc::clone(&w);
+        runtime::spawn(async move {
+            while unsub_r.recv().await.is_ok() {
+                let unsubs = match subscriptions_in_unsub_task.upgrade() {
+                    Some(subscriptions) => subscriptions.take_pending_unsubs(),
+                    None => break,
+                };
+                for (overlay, topic) in unsubs {
+                    let id = random_nonce();
+                    cancelled_in_unsub_task
+                        .write()
+                        .expect("RwLock poisoned")
+                        .insert(id);
+                    let message = BrokerMessage::V0(BrokerMessageV0 {
+                        padding: vec![],
+                        content: BrokerMessageContentV0::BrokerOverlayMessage(
+                            BrokerOverlayMessage::V0(BrokerOverlayMessageV0 {
+                                overlay,
+                                content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                                    BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                                        id,
+                                        content: BrokerOverlayRequestContentV0::TopicUnsub(
+                                            TopicUnsub::V0(TopicUnsubV0 { topic }),
+                                        ),
+                                    }),
+                                ),
+                            }),
+                        ),
+                    });
+                    if ws_in_unsub_task.lock().await.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
         let actors_in_thread = Arc::clone(&actors);
         let stream_actors_in_thread = Arc::clone(&stream_actors);
-        task::spawn(async move {
+        let cancelled_in_thread = Arc::clone(&cancelled);
+        let subscriptions_in_thread = Arc::clone(&subscriptions);
+        runtime::spawn(async move {
             debug_println!("START of reader loop");
-            if let Err(e) =
-                Self::connection_reader_loop(reader, actors_in_thread, stream_actors_in_thread, shutdown_receiver)
-                    .await
+            if let Err(e) = Self::connection_reader_loop(
+                reader,
+                actors_in_thread,
+                stream_actors_in_thread,
+                cancelled_in_thread,
+                subscriptions_in_thread,
+                shutdown_receiver,
+            )
+            .await
             {
                 debug_println!("closing because of {}", e);
                 let _ = ws_in_task.lock().await.close().await;
@@ -994,7 +3037,1916 @@ where
             user,
             actors: Arc::clone(&actors),
             stream_actors: Arc::clone(&stream_actors),
+            cancelled,
             shutdown:shutdown_sender ,
+            subscriptions,
+            dead: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "async-std-runtime"))]
+mod test {
+
+    use crate::config::ConfigMode;
+    use crate::connection::*;
+    use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
+    use std::fs;
+    use tempfile::Builder;
+
+    #[test]
+    pub fn test_padding() {
+        let message = |len: usize| {
+            let block = Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                None,
+                vec![7; len],
+                None,
+            );
+            BrokerMessage::V0(BrokerMessageV0 {
+                padding: vec![],
+                content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                    BrokerOverlayMessageV0 {
+                        overlay: Digest::Blake3Digest32([2; 32]),
+                        content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                            BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                                id: 1,
+                                content: BrokerOverlayRequestContentV0::BlockPut(BlockPut::V0(
+                                    block,
+                                )),
+                            }),
+                        ),
+                    },
+                )),
+            })
+        };
+        let short = message(300);
+        let long = message(350);
+
+        let none = PaddingPolicy::None;
+        assert_ne!(
+            none.serialize(&short).unwrap().len(),
+            none.serialize(&long).unwrap().len()
+        );
+
+        let buckets = PaddingPolicy::powers_of_two(64, 1 << 20);
+        let short_frame = buckets.serialize(&short).unwrap();
+        let long_frame = buckets.serialize(&long).unwrap();
+        assert_eq!(short_frame.len(), long_frame.len());
+        assert_eq!(short_frame.len(), 512);
+
+        // the receiver ignores the padding
+        let received = serde_bare::from_slice::<BrokerMessage>(&short_frame).unwrap();
+        assert_eq!(
+            serde_bare::to_vec(&received.content()).unwrap(),
+            serde_bare::to_vec(&short.content()).unwrap()
+        );
+
+        // the frames hit the buckets, even when the length prefix of the padding grows
+        for len in 0..20000 {
+            let frame = buckets.serialize(&message(len)).unwrap();
+            assert!(frame.len().is_power_of_two(), "{} -> {}", len, frame.len());
+            let frame = PaddingPolicy::Fixed(100).serialize(&message(len)).unwrap();
+            assert_eq!(frame.len() % 100, 0);
         }
     }
+
+    #[async_std::test]
+    pub async fn test_subscriptions() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let mut cnx = server.local_connection(user);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: PubKey::Ed25519PubKey([3; 32]),
+            secret: SymKey::ChaCha20Key([4; 32]),
+            peers: vec![],
+        });
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        let topic1 = PubKey::Ed25519PubKey([5; 32]);
+        let topic2 = PubKey::Ed25519PubKey([6; 32]);
+        let sub1 = overlay_cnx.topic_connect(topic1).await.unwrap();
+        let mut sub1_again = overlay_cnx.topic_connect(topic1).await.unwrap();
+        let sub2 = overlay_cnx.topic_connect(topic2).await.unwrap();
+
+        let mut topics = overlay_cnx.broker.subscriptions();
+        topics.sort_by_key(|t| format!("{:?}", t));
+        assert_eq!(topics, vec![topic1, topic2]);
+
+        // the topic stays subscribed until its last subscription is dropped
+        drop(sub1);
+        assert_eq!(overlay_cnx.broker.subscriptions().len(), 2);
+        assert!(overlay_cnx
+            .broker
+            .subscription_registry()
+            .take_pending_unsubs()
+            .is_empty());
+        assert!(matches!(
+            sub1_again.get_event_stream().try_recv(),
+            Err(async_broadcast::TryRecvError::Empty)
+        ));
+
+        drop(sub2);
+        assert_eq!(overlay_cnx.broker.subscriptions(), vec![topic1]);
+        let pending = overlay_cnx
+            .broker
+            .subscription_registry()
+            .take_pending_unsubs();
+        assert_eq!(pending, vec![(overlay_cnx.overlay, topic2)]);
+
+        drop(sub1_again);
+        assert!(overlay_cnx.broker.subscriptions().is_empty());
+
+        // the unsubscription is sent before the next request of the connection
+        let _sub2 = overlay_cnx.topic_connect(topic2).await.unwrap();
+        assert!(overlay_cnx
+            .broker
+            .subscription_registry()
+            .take_pending_unsubs()
+            .is_empty());
+    }
+
+    #[async_std::test]
+    pub async fn test_topic_events() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let subscriber = PubKey::Ed25519PubKey([1; 32]);
+        let publisher = PubKey::Ed25519PubKey([2; 32]);
+        for user in [subscriber, publisher] {
+            server.add_admin(admin_pubkey).unwrap();
+            let op_content = AddUserContentV0 {
+                user,
+                timestamp: now_timestamp(),
+                nonce: 0,
+            };
+            let sig = sign(
+                admin_privkey,
+                admin_pubkey,
+                &serde_bare::to_vec(&op_content).unwrap(),
+            )
+            .unwrap();
+            server.add_user(op_content, sig).unwrap();
+        }
+
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: PubKey::Ed25519PubKey([3; 32]),
+            secret: SymKey::ChaCha20Key([4; 32]),
+            peers: vec![],
+        });
+        let mut sub_cnx = server.local_connection(subscriber);
+        let mut sub_overlay_cnx = sub_cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let mut pub_cnx = server.local_connection(publisher);
+        let mut pub_overlay_cnx = pub_cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        let (topic_privkey, topic) = generate_keypair();
+        let mut sub = sub_overlay_cnx.topic_connect(topic).await.unwrap();
+
+        let content = |seq: u32| EventContentV0 {
+            topic,
+            publisher: [7; 32],
+            seq,
+            body: EventBodyV0::Change,
+        };
+        pub_overlay_cnx
+            .publish_event(Event::new(content(1), topic_privkey).unwrap())
+            .await
+            .unwrap();
+        let event = sub.get_event_stream().recv().await.unwrap();
+        assert_eq!(event.topic(), &topic);
+        assert_eq!(event.seq(), 1);
+        assert!(matches!(
+            event,
+            Event::V0(EventV0 {
+                content: EventContentV0 {
+                    body: EventBodyV0::Change,
+                    ..
+                },
+                ..
+            })
+        ));
+
+        // events must be signed by the topic key
+        let forged = Event::V0(EventV0 {
+            content: content(2),
+            sig: Sig::Ed25519Sig([[0; 32], [0; 32]]),
+        });
+        assert_eq!(
+            pub_overlay_cnx.publish_event(forged).await,
+            Err(ProtocolError::InvalidSignature)
+        );
+
+        // once unsubscribed, the stream is closed
+        let mut events = sub.get_event_stream().clone();
+        sub.unsubscribe(&mut sub_overlay_cnx).await.unwrap();
+        pub_overlay_cnx
+            .publish_event(Event::new(content(3), topic_privkey).unwrap())
+            .await
+            .unwrap();
+        assert!(events.recv().await.is_err());
+        assert!(sub_overlay_cnx.broker.subscriptions().is_empty());
+    }
+
+    #[async_std::test]
+    pub async fn test_overlay_connect_no_account() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        // valid user key, but no account on the broker
+        let (_privkey, user) = generate_keypair();
+        let mut cnx = server.local_connection(user);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: PubKey::Ed25519PubKey([3; 32]),
+            secret: SymKey::ChaCha20Key([4; 32]),
+            peers: vec![],
+        });
+        match cnx.overlay_connect(&repo_link, true).await {
+            Err(e) => assert_eq!(e, ProtocolError::NoAccount),
+            Ok(_) => panic!("overlay_connect should fail without an account"),
+        }
+    }
+
+    #[async_std::test]
+    pub async fn test_del_user_and_clients() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let (user_privkey, user) = generate_keypair();
+        let add_user = |server: &BrokerServer| {
+            server.add_admin(admin_pubkey).unwrap();
+            let op_content = AddUserContentV0 {
+                user,
+                timestamp: now_timestamp(),
+                nonce: random_nonce(),
+            };
+            let sig = sign(
+                admin_privkey,
+                admin_pubkey,
+                &serde_bare::to_vec(&op_content).unwrap(),
+            )
+            .unwrap();
+            server.add_user(op_content, sig).unwrap();
+        };
+        add_user(&server);
+
+        let client1 = PubKey::Ed25519PubKey([5; 32]);
+        let client2 = PubKey::Ed25519PubKey([6; 32]);
+        {
+            let mut cnx = server.local_connection(user);
+            cnx.add_client(client1, user_privkey).await.unwrap();
+            cnx.add_client(client2, user_privkey).await.unwrap();
+            // only the user can add its clients
+            assert_eq!(
+                cnx.add_client(client1, generate_keypair().0).await,
+                Err(ProtocolError::InvalidSignature)
+            );
+
+            cnx.del_client(client1, user_privkey).await.unwrap();
+            assert_eq!(
+                cnx.del_client(client1, user_privkey).await,
+                Err(ProtocolError::NotFound)
+            );
+
+            cnx.del_user(user, user_privkey).await.unwrap();
+            assert_eq!(
+                cnx.add_client(client1, user_privkey).await,
+                Err(ProtocolError::NoAccount)
+            );
+        }
+        assert_eq!(server.check_account(user), Err(ProtocolError::NoAccount));
+
+        // the remaining client went away with the account
+        add_user(&server);
+        let mut cnx = server.local_connection(user);
+        assert_eq!(
+            cnx.del_client(client2, user_privkey).await,
+            Err(ProtocolError::NotFound)
+        );
+    }
+    /// Local connection failing the block puts with the given errors, then succeeding
+    struct FlakyBroker<'a> {
+        inner: BrokerConnectionLocal<'a>,
+        failures: Vec<ProtocolError>,
+        puts: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl<'a> BrokerConnection for FlakyBroker<'a> {
+        type OC = FlakyBroker<'a>;
+        type BlockStream = async_channel::Receiver<Block>;
+
+        async fn close(&mut self) {}
+
+        fn subscription_registry(&self) -> &Arc<Subscriptions> {
+            self.inner.subscription_registry()
+        }
+
+        async fn add_user(
+            &mut self,
+            user_id: PubKey,
+            admin_user_pk: PrivKey,
+        ) -> Result<(), ProtocolError> {
+            self.inner.add_user(user_id, admin_user_pk).await
+        }
+
+        async fn del_user(
+            &mut self,
+            user_id: PubKey,
+            admin_user_pk: PrivKey,
+        ) -> Result<(), ProtocolError> {
+            self.inner.del_user(user_id, admin_user_pk).await
+        }
+
+        async fn add_client(
+            &mut self,
+            client_id: ClientId,
+            user_pk: PrivKey,
+        ) -> Result<(), ProtocolError> {
+            self.inner.add_client(client_id, user_pk).await
+        }
+
+        async fn del_client(
+            &mut self,
+            client_id: ClientId,
+            user_pk: PrivKey,
+        ) -> Result<(), ProtocolError> {
+            self.inner.del_client(client_id, user_pk).await
+        }
+
+        async fn overlay_connect(
+            &mut self,
+            repo_link: &RepoLink,
+            public: bool,
+        ) -> Result<OverlayConnectionClient<FlakyBroker<'a>>, ProtocolError> {
+            let overlay = self.process_overlay_connect(repo_link, public).await?;
+            Ok(OverlayConnectionClient {
+                broker: self,
+                repo_link: repo_link.clone(),
+                overlay,
+            })
+        }
+
+        async fn process_overlay_request(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<(), ProtocolError> {
+            if let BrokerOverlayRequestContentV0::BlockPut(_) = request {
+                self.puts += 1;
+                if !self.failures.is_empty() {
+                    return Err(self.failures.remove(0));
+                }
+            }
+            self.inner.process_overlay_request(overlay, request).await
+        }
+
+        async fn process_overlay_request_objectid_response(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<ObjectId, ProtocolError> {
+            self.inner
+                .process_overlay_request_objectid_response(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_block_results_response(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<Vec<u16>, ProtocolError> {
+            self.inner
+                .process_overlay_request_block_results_response(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_block_ids_response(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<Vec<BlockId>, ProtocolError> {
+            self.inner
+                .process_overlay_request_block_ids_response(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_block_list_response(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<BlockListResp, ProtocolError> {
+            self.inner
+                .process_overlay_request_block_list_response(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_status_response(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<OverlayStatusResp, ProtocolError> {
+            self.inner
+                .process_overlay_request_status_response(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_stream_response(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<Pin<Box<Self::BlockStream>>, ProtocolError> {
+            self.inner
+                .process_overlay_request_stream_response(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_stream_response_with_progress(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<
+            (
+                Pin<Box<Self::BlockStream>>,
+                async_channel::Receiver<SyncProgress>,
+            ),
+            ProtocolError,
+        > {
+            self.inner
+                .process_overlay_request_stream_response_with_progress(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_stream_response_with_continuation(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<(Pin<Box<Self::BlockStream>>, async_channel::Receiver<u32>), ProtocolError>
+        {
+            self.inner
+                .process_overlay_request_stream_response_with_continuation(overlay, request)
+                .await
+        }
+
+        async fn process_overlay_request_stream_response_with_tombstones(
+            &mut self,
+            overlay: OverlayId,
+            request: BrokerOverlayRequestContentV0,
+        ) -> Result<
+            (
+                Pin<Box<Self::BlockStream>>,
+                async_channel::Receiver<Tombstone>,
+            ),
+            ProtocolError,
+        > {
+            self.inner
+                .process_overlay_request_stream_response_with_tombstones(overlay, request)
+                .await
+        }
+    }
+
+    #[async_std::test]
+    pub async fn test_put_object_retry() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo_pubkey = PubKey::Ed25519PubKey([3; 32]);
+        let repo_secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo_pubkey,
+            secret: repo_secret,
+            peers: vec![],
+        });
+        let content = |i: u8| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: vec![],
+                metadata: vec![i],
+                content: (0..10000).map(|i| (i % 251) as u8).collect(),
+            }))
+        };
+        let budget = RetryBudget {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+
+        let mut cnx = FlakyBroker {
+            inner: server.local_connection(user),
+            failures: vec![ProtocolError::StoreError],
+            puts: 0,
+        };
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        // a transient failure is retried
+        let (id, retries) = overlay_cnx
+            .put_object_with_retry(
+                content(1),
+                vec![],
+                None,
+                1000,
+                repo_pubkey,
+                repo_secret,
+                budget,
+            )
+            .await
+            .unwrap();
+        assert_eq!(retries, 1);
+        let obj = overlay_cnx.get_object(id, None).await.unwrap();
+        assert_eq!(obj.id(), id);
+
+        // a persistent failure aborts at once
+        let puts = overlay_cnx.broker.puts;
+        overlay_cnx.broker.failures = vec![ProtocolError::AccessDenied];
+        let res = overlay_cnx
+            .put_object_with_retry(
+                content(2),
+                vec![],
+                None,
+                1000,
+                repo_pubkey,
+                repo_secret,
+                budget,
+            )
+            .await;
+        assert_eq!(res.err(), Some(ProtocolError::AccessDenied));
+        assert_eq!(overlay_cnx.broker.puts, puts + 1);
+
+        // transient failures beyond the budget abort
+        overlay_cnx.broker.failures = vec![ProtocolError::StoreError; 3];
+        let res = overlay_cnx
+            .put_object_with_retry(
+                content(3),
+                vec![],
+                None,
+                1000,
+                repo_pubkey,
+                repo_secret,
+                budget,
+            )
+            .await;
+        assert_eq!(res.err(), Some(ProtocolError::StoreError));
+    }
+
+    #[async_std::test]
+    pub async fn test_writer_failure() {
+        let (writer, mut sent) = mpsc::unbounded::<BrokerMessage>();
+        let (incoming, reader) = mpsc::unbounded::<BrokerMessage>();
+        let mut cnx = BrokerConnectionRemote::open(writer, reader, PubKey::Ed25519PubKey([1; 32]));
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1, 2, 3],
+            None,
+        );
+
+        // a BlockGet is answered with a first block, and waits for more
+        let broker = async {
+            let id = sent.next().await.unwrap().try_id().unwrap();
+            let response = BrokerMessage::V0(BrokerMessageV0 {
+                padding: vec![],
+                content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                    BrokerOverlayMessageV0 {
+                        overlay,
+                        content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                            BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                                id,
+                                result: ProtocolError::PartialContent.into(),
+                                content: Some(BrokerOverlayResponseContentV0::Block(block.clone())),
+                            }),
+                        ),
+                    },
+                )),
+            });
+            incoming.unbounded_send(response).unwrap();
+        };
+        let request = cnx.process_overlay_request_stream_response(
+            overlay,
+            BrokerOverlayRequestContentV0::BlockGet(BlockGet::V0(BlockGetV0 {
+                id: block.id(),
+                include_children: true,
+                topic: None,
+                max_blocks: None,
+                continuation: None,
+                known_blocks: None,
+                range: None,
+            })),
+        );
+        let (res, _) = futures::join!(request, broker);
+        let mut blocks = res.unwrap();
+        assert_eq!(blocks.next().await.unwrap().id(), block.id());
+
+        // the peer goes away: the next send fails
+        drop(sent);
+        let res = cnx
+            .add_user(PubKey::Ed25519PubKey([3; 32]), generate_keypair().0)
+            .await;
+        assert_eq!(res.err(), Some(ProtocolError::ConnectionClosed));
+
+        // the outstanding request ends right away instead of waiting for a timeout
+        let next = async_std::future::timeout(Duration::from_secs(5), blocks.next()).await;
+        assert!(matches!(next, Ok(None)));
+
+        // and the connection can't be used anymore
+        let res = cnx
+            .add_user(PubKey::Ed25519PubKey([3; 32]), generate_keypair().0)
+            .await;
+        assert_eq!(res.err(), Some(ProtocolError::ConnectionClosed));
+    }
+
+    #[async_std::test]
+    pub async fn test_cancel_request() {
+        let (writer, mut sent) = mpsc::unbounded::<BrokerMessage>();
+        let (incoming, reader) = mpsc::unbounded::<BrokerMessage>();
+        let mut cnx = BrokerConnectionRemote::open(writer, reader, PubKey::Ed25519PubKey([1; 32]));
+        let pending = cnx.pending_requests();
+        assert!(cnx.pending_request_ids().is_empty());
+
+        // the request is pending until the broker answers, which it never does
+        let broker = async {
+            let id = sent.next().await.unwrap().try_id().unwrap();
+            assert_eq!(pending.ids(), vec![id]);
+            pending.cancel(id).unwrap();
+            assert_eq!(pending.cancel(id), Err(ProtocolError::NotFound));
+            id
+        };
+        let request = cnx.add_user(PubKey::Ed25519PubKey([3; 32]), generate_keypair().0);
+        let (res, id) = futures::join!(request, broker);
+        assert_eq!(res.err(), Some(ProtocolError::Cancelled));
+        assert!(cnx.pending_request_ids().is_empty());
+        assert_eq!(cnx.cancel_request(id), Err(ProtocolError::NotFound));
+
+        // the late response is dropped, and the connection is still usable
+        incoming
+            .unbounded_send(error_response(id, ProtocolError::Success))
+            .unwrap();
+        let broker = async {
+            let id = sent.next().await.unwrap().try_id().unwrap();
+            incoming
+                .unbounded_send(error_response(id, ProtocolError::UserAlreadyExists))
+                .unwrap();
+        };
+        let request = cnx.add_user(PubKey::Ed25519PubKey([4; 32]), generate_keypair().0);
+        let (res, _) = futures::join!(request, broker);
+        assert_eq!(res.err(), Some(ProtocolError::UserAlreadyExists));
+    }
+
+    #[async_std::test]
+    pub async fn test_get_commit() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![MemberV0::new(
+                member_pubkey,
+                vec![CommitType::Transaction],
+                vec![],
+            )],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+        let put_object = |content: ObjectContent| {
+            let obj = Object::new(content, vec![], None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            obj.reference().unwrap()
+        };
+        let body = CommitBody::Transaction(Transaction::V0(vec![1; 10000]));
+        let body_ref = put_object(ObjectContent::CommitBody(body.clone()));
+        let commit = Commit::new(
+            member_privkey,
+            member_pubkey,
+            1,
+            branch_ref,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            body_ref,
+            None,
+        )
+        .unwrap();
+        let commit_ref = put_object(ObjectContent::Commit(commit));
+        server
+            .publish_commit(user, overlay, &branch, commit_ref)
+            .unwrap();
+        let unpublished = put_object(ObjectContent::Commit(
+            Commit::new(
+                member_privkey,
+                member_pubkey,
+                2,
+                branch_ref,
+                vec![commit_ref],
+                vec![],
+                vec![],
+                vec![],
+                body_ref,
+                None,
+            )
+            .unwrap(),
+        ));
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        // the commit and its transaction in one request
+        let (commit, got_body) = overlay_cnx.get_commit(commit_ref).await.unwrap();
+        assert_eq!(commit.content().body.id, body_ref.id);
+        assert_eq!(got_body, body);
+
+        // only published commits are served
+        assert_eq!(
+            overlay_cnx.get_commit(unpublished).await.err(),
+            Some(ProtocolError::NotFound)
+        );
+    }
+
+    #[async_std::test]
+    pub async fn test_get_object_range() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..200000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let obj = Object::new(content.clone(), vec![], None, 4000, repo, secret);
+        for block in obj.blocks() {
+            server.put_block(user, overlay, block).unwrap();
+        }
+        let content_ser = serde_bare::to_vec(&content).unwrap();
+
+        // the broker serves a corrupted middle block under its original ID
+        let leaf = obj.blocks()[30].clone();
+        let mut corrupted = leaf.clone();
+        match &mut corrupted {
+            Block::V0(b) => {
+                b.id = Some(leaf.id());
+                b.content[10] ^= 1;
+            }
+            Block::V1(b) => {
+                b.id = Some(leaf.id());
+                b.content[10] ^= 1;
+            }
+        }
+        server.put_block(user, overlay, &corrupted).unwrap();
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let object_ref = obj.reference().unwrap();
+
+        // a range before the corrupted block is read and verified
+        let range = overlay_cnx
+            .get_object_range(object_ref, 1000, 20000)
+            .await
+            .unwrap();
+        assert_eq!(range, content_ser[1000..21000]);
+
+        // a range over the corrupted block fails
+        assert_eq!(
+            overlay_cnx
+                .get_object_range(object_ref, 0, content_ser.len())
+                .await
+                .err(),
+            Some(ProtocolError::InvalidResponse)
+        );
+    }
+
+    #[async_std::test]
+    pub async fn test_get_file_range() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let data: Vec<u8> = (0..500000).map(|i| (i % 251) as u8).collect();
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: Vec::from("some meta data here"),
+            content: data.clone(),
+        }));
+        // the broker only finds the blocks of a range in uncompressed leaves
+        let obj = Object::new_with_chunking(
+            content,
+            vec![],
+            None,
+            ChunkingStrategy::Fixed(4000),
+            ChunkCompression::None,
+            repo,
+            secret,
+        );
+        for block in obj.blocks() {
+            server.put_block(user, overlay, block).unwrap();
+        }
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let (id, key) = (obj.id(), obj.key().unwrap());
+
+        // a middle range is read and verified
+        let range = overlay_cnx
+            .get_file_range(id, key, 250000, 10000)
+            .await
+            .unwrap();
+        assert_eq!(range, data[250000..260000]);
+
+        // the end of the file
+        let range = overlay_cnx
+            .get_file_range(id, key, 495000, 10000)
+            .await
+            .unwrap();
+        assert_eq!(range, data[495000..]);
+
+        // only the blocks of the paths are served
+        let store = HashMapRepoStore::new();
+        for block in obj.blocks() {
+            store.put(block).unwrap();
+        }
+        let served = Object::range_blocks(id, &store, 250000, 10000).unwrap();
+        assert!(served.len() < obj.blocks().len() / 10);
+
+        assert_eq!(
+            overlay_cnx
+                .get_file_range(Digest::Blake3Digest32([9; 32]), key, 0, 10)
+                .await
+                .err(),
+            Some(ProtocolError::NotFound)
+        );
+    }
+
+    #[async_std::test]
+    pub async fn test_get_block_range() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let data: Vec<u8> = (0..500000).map(|i| (i % 251) as u8).collect();
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: Vec::from("some meta data here"),
+            content: data,
+        }));
+        // the broker only finds the blocks of a range in uncompressed leaves
+        let obj = Object::new_with_chunking(
+            content,
+            vec![],
+            None,
+            ChunkingStrategy::Fixed(4000),
+            ChunkCompression::None,
+            repo,
+            secret,
+        );
+        for block in obj.blocks() {
+            server.put_block(user, overlay, block).unwrap();
+        }
+        let (id, key) = (obj.id(), obj.key().unwrap());
+        let full = HashMapRepoStore::new();
+        for block in obj.blocks() {
+            full.put(block).unwrap();
+        }
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        // the final 10 KB of the serialized content
+        let size = obj.content_size();
+        let mut blockstream = overlay_cnx
+            .get_block_range(id, None, size - 10000, 10000)
+            .await
+            .unwrap();
+        let received = HashMapRepoStore::new();
+        let mut ids = HashSet::new();
+        while let Some(block) = blockstream.next().await {
+            ids.insert(block.id());
+            received.put(&block).unwrap();
+        }
+
+        // only the leaves of the range and the spine are returned
+        let expected: HashSet<BlockId> = Object::range_blocks(id, &full, size - 10000, 10000)
+            .unwrap()
+            .iter()
+            .map(|b| b.id())
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(ids.contains(&id));
+        assert!(ids.len() < obj.blocks().len() / 10);
+        let leaves = obj
+            .blocks()
+            .iter()
+            .filter(|b| b.children().is_empty() && ids.contains(&b.id()))
+            .count();
+        assert!(leaves <= 6);
+
+        // they are enough to read and verify the range
+        assert_eq!(
+            Object::read_range(id, key, &received, size - 10000, 10000).unwrap(),
+            Object::read_range(id, key, &full, size - 10000, 10000).unwrap()
+        );
+    }
+
+    #[async_std::test]
+    pub async fn test_pin_object() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        let now = now_timestamp();
+        let id = overlay_cnx
+            .put_object(
+                ObjectContent::File(File::V0(FileV0 {
+                    content_type: vec![],
+                    metadata: vec![],
+                    content: (0..20000).map(|i| (i % 251) as u8).collect(),
+                })),
+                vec![],
+                Some(now + RelTime::Minutes(10)),
+                4000,
+                repo,
+                secret,
+            )
+            .await
+            .unwrap();
+        let object = overlay_cnx.get_object(id, None).await.unwrap();
+        let blocks: HashSet<BlockId> = object.blocks().iter().map(|b| b.id()).collect();
+        let blocks = blocks.len();
+        assert!(blocks > 1);
+
+        // the pinned object survives its expiry
+        overlay_cnx.pin_object(id).await.unwrap();
+        let report = server.garbage_collect(now + RelTime::Minutes(20)).unwrap();
+        assert_eq!(report.blocks, 0);
+        assert!(overlay_cnx.get_object(id, None).await.is_ok());
+
+        // once unpinned, it is collected
+        overlay_cnx.unpin_object(id).await.unwrap();
+        let report = server.garbage_collect(now + RelTime::Minutes(20)).unwrap();
+        assert_eq!(report.blocks, blocks);
+        assert_eq!(
+            overlay_cnx.get_object(id, None).await.err(),
+            Some(GetObjectError::Protocol(ProtocolError::NotFound))
+        );
+
+        assert_eq!(
+            overlay_cnx
+                .pin_object(Digest::Blake3Digest32([9; 32]))
+                .await
+                .err(),
+            Some(ProtocolError::NotFound)
+        );
+    }
+
+    #[async_std::test]
+    pub async fn test_put_blocks() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..50000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let obj = Object::new(content, vec![], None, 4000, repo, secret);
+        // two children but no room for their keys
+        let invalid = Block::new(
+            vec![Digest::Blake3Digest32([5; 32]); 2],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        let mut blocks = obj.blocks().clone();
+        blocks.insert(1, invalid);
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let results = overlay_cnx.put_blocks(&blocks).await.unwrap();
+        assert_eq!(results.len(), blocks.len());
+        for (i, (result, block)) in results.iter().zip(&blocks).enumerate() {
+            match i {
+                1 => assert_eq!(*result, Err(ProtocolError::InvalidBlock)),
+                _ => assert_eq!(*result, Ok(block.id())),
+            }
+        }
+
+        // the valid blocks make up the whole object
+        let fetched = overlay_cnx.get_object(obj.id(), None).await.unwrap();
+        assert_eq!(fetched.id(), obj.id());
+    }
+
+    #[async_std::test]
+    pub async fn test_replicate_from_checkpoint() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let (replica_privkey, replica) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user: replica,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(replica, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        let mut ids = vec![];
+        for i in 0..3 {
+            let block = Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                None,
+                vec![i; 100],
+                None,
+            );
+            server.put_block(replica, overlay, &block).unwrap();
+            ids.push(block.id());
+        }
+
+        let mut cnx = server.local_connection(replica);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        async fn count<S: Stream<Item = Block> + Unpin>(mut stream: S) -> usize {
+            let mut count = 0;
+            while stream.next().await.is_some() {
+                count += 1;
+            }
+            count
+        }
+
+        // without the acknowledgement, the checkpoint doesn't advance
+        let (stream, _) = overlay_cnx.replicate_from_checkpoint().await.unwrap();
+        assert_eq!(count(stream).await, 3);
+        let (stream, _) = overlay_cnx.replicate_from_checkpoint().await.unwrap();
+        assert_eq!(count(stream).await, 3);
+
+        assert!(overlay_cnx.ack_replication().await.unwrap().is_empty());
+        let (stream, tombstones) = overlay_cnx.replicate_from_checkpoint().await.unwrap();
+        assert_eq!(count(stream).await, 0);
+        assert!(tombstones.try_recv().is_err());
+
+        // the tombstone of a deleted object is sent with the next replication
+        let tombstone = Tombstone::new(&overlay, ids[0], replica_privkey, replica).unwrap();
+        server.add_tombstone(replica, overlay, &tombstone).unwrap();
+        let (stream, tombstones) = overlay_cnx.replicate_from_checkpoint().await.unwrap();
+        assert_eq!(count(stream).await, 0);
+        assert_eq!(tombstones.recv().await.unwrap(), tombstone);
+    }
+
+    #[async_std::test]
+    pub async fn test_list_blocks() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let (_, user) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        let mut ids = HashSet::new();
+        for i in 0..250u32 {
+            let block = Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                None,
+                i.to_le_bytes().to_vec(),
+                None,
+            );
+            server.put_block(user, overlay, &block).unwrap();
+            ids.insert(block.id());
+        }
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        assert_eq!(
+            overlay_cnx.list_blocks(0, None).await.err().unwrap(),
+            ProtocolError::InvalidValue
+        );
+
+        // pages of 100 visit each block exactly once
+        let mut listed = HashSet::new();
+        let mut pages = 0;
+        let mut after = None;
+        loop {
+            let page = overlay_cnx.list_blocks(100, after).await.unwrap();
+            pages += 1;
+            for id in page.ids() {
+                assert!(listed.insert(*id));
+            }
+            match page.next() {
+                Some(next) => after = Some(next.clone()),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(listed, ids);
+    }
+
+    #[async_std::test]
+    pub async fn test_put_object_stream() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let file = |len: u32| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content: (0..len).map(|i| (i % 251) as u8).collect(),
+            }))
+        };
+        for len in [100, 300000] {
+            let content = file(len);
+            let streamed = overlay_cnx
+                .put_object_stream(
+                    futures::io::Cursor::new(serde_bare::to_vec(&content).unwrap()),
+                    vec![],
+                    None,
+                    ChunkingStrategy::Fixed(4000),
+                    repo,
+                    secret,
+                )
+                .await
+                .unwrap();
+            let buffered = overlay_cnx
+                .put_object(content.clone(), vec![], None, 4000, repo, secret)
+                .await
+                .unwrap();
+            assert_eq!(streamed, buffered);
+
+            let fetched = overlay_cnx.get_object(streamed, None).await.unwrap();
+            assert_eq!(fetched.id(), streamed);
+        }
+    }
+
+    #[async_std::test]
+    pub async fn test_get_missing_blocks() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let mut x: u32 = 1;
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..200000)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    x as u8
+                })
+                .collect(),
+        }));
+        let obj = Object::new(content, vec![], None, 4000, repo, secret);
+        for block in obj.blocks() {
+            server.put_block(user, overlay, block).unwrap();
+        }
+
+        // the client already has every other block
+        let mut unique = HashSet::new();
+        let client = HashMapRepoStore::new();
+        let mut known = vec![];
+        for block in obj.blocks() {
+            if unique.insert(block.id()) && unique.len() % 2 == 0 {
+                client.put(block).unwrap();
+                known.push(block.id());
+            }
+        }
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let received = overlay_cnx
+            .get_missing_blocks(obj.id(), None, &known, &client)
+            .await
+            .unwrap();
+        // only the other half is received, false positives included
+        assert_eq!(received, unique.len() - known.len());
+        assert_eq!(client.len().unwrap(), unique.len());
+        assert!(Object::load(obj.id(), None, &client).is_ok());
+    }
+
+    #[async_std::test]
+    pub async fn test_sync_branch() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+        let put_object = |content: ObjectContent, deps: Vec<ObjectId>| {
+            let obj = Object::new(content, deps, None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            let blocks: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+            (
+                ObjectRef {
+                    id: obj.id(),
+                    key: obj.key().unwrap(),
+                },
+                blocks,
+            )
+        };
+        let put_commit = |seq, deps: Vec<ObjectRef>, acks: Vec<ObjectRef>, body| {
+            let (body_ref, _) = put_object(ObjectContent::CommitBody(body), vec![]);
+            let dep_ids = deps.iter().chain(acks.iter()).map(|d| d.id).collect();
+            let commit = Commit::new(
+                member_privkey,
+                member_pubkey,
+                seq,
+                branch_ref,
+                deps,
+                acks,
+                vec![],
+                vec![],
+                body_ref,
+                None,
+            )
+            .unwrap();
+            put_object(ObjectContent::Commit(commit), dep_ids)
+        };
+        let trans = |i| CommitBody::Transaction(Transaction::V0(vec![i]));
+        let ack = || CommitBody::Ack(Ack::V0());
+
+        //      br
+        //     /  \
+        //   t1   t2
+        //   / \  / \
+        //  a3  t4<--t5-->(t1)
+        //      / \
+        //    a6   a7
+        let (br, br_blocks) = put_commit(0, vec![], vec![], trans(0));
+        let (t1, t1_blocks) = put_commit(1, vec![br], vec![], trans(1));
+        let (t2, t2_blocks) = put_commit(2, vec![br], vec![], trans(2));
+        let (_a3, a3_blocks) = put_commit(3, vec![t1], vec![], ack());
+        let (t4, t4_blocks) = put_commit(4, vec![t2], vec![t1], trans(4));
+        let (t5, t5_blocks) = put_commit(5, vec![t1, t2], vec![t4], trans(5));
+        let (a6, a6_blocks) = put_commit(6, vec![t4], vec![], ack());
+        let (a7, a7_blocks) = put_commit(7, vec![t4], vec![], ack());
+
+        let known_commits = BloomFilter::from_ids([br.id, t5.id].iter(), 0.01);
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let mut blockstream = overlay_cnx
+            .sync_branch(vec![a6.id, a7.id], vec![br.id], known_commits, None, None)
+            .await
+            .unwrap();
+        let mut received = HashSet::new();
+        while let Some(block) = blockstream.next().await {
+            assert!(received.insert(block.id()));
+        }
+
+        // the commits between the known head and the heads, without the known commits
+        let expected: HashSet<BlockId> = [t1_blocks, t2_blocks, t4_blocks, a6_blocks, a7_blocks]
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(received, expected);
+        for blocks in [br_blocks, a3_blocks, t5_blocks] {
+            assert!(received.is_disjoint(&blocks));
+        }
+    }
+
+    #[async_std::test]
+    pub async fn test_sync_branch_foreign_block() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let foreign_secret = SymKey::ChaCha20Key([5; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+        let put_object = |content: ObjectContent, deps: Vec<ObjectId>, secret: SymKey| {
+            let obj = Object::new(content, deps, None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        let put_commit = |seq, deps: Vec<ObjectRef>, secret: SymKey| {
+            let body = CommitBody::Transaction(Transaction::V0(vec![seq as u8]));
+            let body_ref = put_object(ObjectContent::CommitBody(body), vec![], secret);
+            let dep_ids = deps.iter().map(|d| d.id).collect();
+            let commit = Commit::new(
+                member_privkey,
+                member_pubkey,
+                seq,
+                branch_ref,
+                deps,
+                vec![],
+                vec![],
+                vec![],
+                body_ref,
+                None,
+            )
+            .unwrap();
+            put_object(ObjectContent::Commit(commit), dep_ids, secret)
+        };
+
+        // br <-- t1 <-- (f) <-- t2
+        let br = put_commit(0, vec![], secret);
+        let t1 = put_commit(1, vec![br], secret);
+        let f = put_commit(2, vec![t1], foreign_secret);
+        let t2 = put_commit(3, vec![f], secret);
+
+        let mut cnx = server.local_connection(user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        // t1: only commits of the repo,
+        // f: a foreign head,
+        // t2: a foreign commit among the dependencies
+        for (head, foreign) in [(t1, false), (f, true), (t2, true)] {
+            let res = overlay_cnx
+                .sync_branch_verified(
+                    vec![head],
+                    vec![],
+                    BloomFilter::from_ids(Vec::<ObjectId>::new().iter(), 0.01),
+                    None,
+                    None,
+                    repo,
+                    secret,
+                )
+                .await;
+            if foreign {
+                assert!(matches!(res, Err(ProtocolError::ForeignBlock)));
+            } else {
+                let blocks = res.ok().unwrap();
+                assert!(blocks.iter().any(|b| b.id() == t1.id));
+                assert!(blocks.iter().any(|b| b.id() == br.id));
+            }
+        }
+    }
+
+    #[async_std::test]
+    pub async fn test_discover_peers() {
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        // both nodes compute the same overlay ID from the repo pubkey
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+
+        let roots = [
+            Builder::new().prefix("test-env").tempdir().unwrap(),
+            Builder::new().prefix("test-env").tempdir().unwrap(),
+        ];
+        let mut nodes = vec![];
+        for (i, root) in roots.iter().enumerate() {
+            let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+            let mut server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+            let (priv_key, _) = generate_keypair();
+            let listen = vec![IPTransportAddr {
+                ip: IP::IPv4([127, 0, 0, 1]),
+                port: 3012 + i as u16,
+                protocol: IPTransportProtocol::TLS,
+            }];
+            server.set_self_peer(priv_key, listen.clone());
+            let advert = server.self_advert(&listen, priv_key);
+
+            let (admin_privkey, admin_pubkey) = generate_keypair();
+            let user = PubKey::Ed25519PubKey([1; 32]);
+            server.add_admin(admin_pubkey).unwrap();
+            let op_content = AddUserContentV0 {
+                user,
+                timestamp: now_timestamp(),
+                nonce: 0,
+            };
+            let sig = sign(
+                admin_privkey,
+                admin_pubkey,
+                &serde_bare::to_vec(&op_content).unwrap(),
+            )
+            .unwrap();
+            server.add_user(op_content, sig).unwrap();
+            server
+                .join_overlay(user, overlay, Some(repo), secret, &vec![])
+                .unwrap();
+            nodes.push((server, advert, user));
+        }
+        let (a, a_advert, a_user) = &nodes[0];
+        let (_, b_advert, _) = &nodes[1];
+
+        // the advert of b reaches a through the overlay gossip
+        a.relay_peer_advert(overlay, b_advert).unwrap();
+
+        // a client of a, knowing only the repo pubkey, discovers b
+        let mut cnx = a.local_connection(*a_user);
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let peers = overlay_cnx.discover_peers().await.unwrap();
+        let b = peers.iter().find(|p| p.peer() == b_advert.peer()).unwrap();
+        assert_eq!(b.address(), b_advert.address());
+        assert!(peers.iter().any(|p| p.peer() == a_advert.peer()));
+    }
+
+    #[async_std::test]
+    pub async fn test_admin_add_user() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        // without an admin, no signature is accepted, not even the one of the user itself
+        let (user_privkey, user) = generate_keypair();
+        let mut cnx = server.local_connection(user);
+        let (_, new_user) = generate_keypair();
+        assert_eq!(
+            cnx.add_user(new_user, user_privkey).await.err(),
+            Some(ProtocolError::AccessDenied)
+        );
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+
+        // a user connected to the broker adds accounts with the key of the admin
+        cnx.add_user(new_user, admin_privkey).await.unwrap();
+        cnx.del_user(new_user, admin_privkey).await.unwrap();
+
+        // the signature of another key is refused
+        let (other_privkey, _) = generate_keypair();
+        assert_eq!(
+            cnx.add_user(new_user, other_privkey).await.err(),
+            Some(ProtocolError::InvalidSignature)
+        );
+
+        // a signature of the admin for another user is refused
+        let (_, tampered_user) = generate_keypair();
+        let op_content = AddUserContentV0 {
+            user: new_user,
+            timestamp: now_timestamp(),
+            nonce: 1,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        let tampered = AddUserContentV0 {
+            user: tampered_user,
+            ..op_content
+        };
+        assert_eq!(
+            server.add_user(tampered, sig).err(),
+            Some(ProtocolError::InvalidSignature)
+        );
+        server.add_user(op_content, sig).unwrap();
+
+        // a captured DelUser is only accepted once
+        let op_content = DelUserContentV0 {
+            user: new_user,
+            timestamp: now_timestamp(),
+            nonce: 2,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.del_user(op_content, sig).unwrap();
+        cnx.add_user(new_user, admin_privkey).await.unwrap();
+        assert_eq!(
+            server.del_user(op_content, sig).err(),
+            Some(ProtocolError::InvalidSignature)
+        );
+
+        // so is a stale one
+        let op_content = DelUserContentV0 {
+            timestamp: now_timestamp() - RelTime::Minutes(30),
+            nonce: 3,
+            ..op_content
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            server.del_user(op_content, sig).err(),
+            Some(ProtocolError::InvalidSignature)
+        );
+        server.check_account(new_user).unwrap();
+    }
 }
@@ -0,0 +1,151 @@
+//! Filter of the peer IPs allowed to connect, checked before the connection is served
+
+use lofire_net::errors::*;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// IPv4 or IPv6 range in CIDR notation, like `10.0.0.0/8` or `fd00::/8`.
+/// A single address without prefix length is a range of one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<IpRange, ProtocolError> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max {
+            return Err(ProtocolError::InvalidValue);
+        }
+        Ok(IpRange { addr, prefix_len })
+    }
+
+    /// Check whether the address is in the range.
+    /// IPv4 addresses are never in an IPv6 range, and the other way around
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<IpRange, ProtocolError> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ProtocolError::InvalidValue)?;
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| ProtocolError::InvalidValue)?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpRange::new(addr, prefix_len)
+    }
+}
+
+/// Peer IPs allowed to connect.
+/// The denylist wins over the allowlist, and an empty allowlist allows all the IPs not denied
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<IpRange>, deny: Vec<IpRange>) -> IpFilter {
+        IpFilter { allow, deny }
+    }
+
+    /// Parse the ranges of the allowlist and the denylist, see `IpRange`
+    pub fn parse(allow: &[&str], deny: &[&str]) -> Result<IpFilter, ProtocolError> {
+        let parse_all = |ranges: &[&str]| {
+            ranges
+                .iter()
+                .map(|r| r.parse())
+                .collect::<Result<Vec<IpRange>, ProtocolError>>()
+        };
+        Ok(IpFilter::new(parse_all(allow)?, parse_all(deny)?))
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|r| r.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|r| r.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::ipfilter::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_ip_range() {
+        let range: IpRange = "192.168.1.0/24".parse().unwrap();
+        assert!(range.contains(&ip("192.168.1.0")));
+        assert!(range.contains(&ip("192.168.1.255")));
+        assert!(!range.contains(&ip("192.168.2.1")));
+        assert!(!range.contains(&ip("::ffff:192.168.1.1")));
+
+        let range: IpRange = "fd00::/8".parse().unwrap();
+        assert!(range.contains(&ip("fd12:3456::1")));
+        assert!(!range.contains(&ip("fe80::1")));
+        assert!(!range.contains(&ip("10.0.0.1")));
+
+        let single: IpRange = "::1".parse().unwrap();
+        assert!(single.contains(&ip("::1")));
+        assert!(!single.contains(&ip("::2")));
+
+        let all: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("203.0.113.7")));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    pub fn test_ip_filter() {
+        // no list, everything is allowed
+        assert!(IpFilter::default().is_allowed(&ip("203.0.113.7")));
+
+        let filter = IpFilter::parse(&["10.0.0.0/8", "fd00::/8"], &["10.1.0.0/16"]).unwrap();
+        assert!(filter.is_allowed(&ip("10.2.3.4")));
+        assert!(filter.is_allowed(&ip("fd00::1")));
+        assert!(!filter.is_allowed(&ip("10.1.2.3")));
+        assert!(!filter.is_allowed(&ip("127.0.0.1")));
+
+        let filter = IpFilter::parse(&[], &["127.0.0.0/8", "::1"]).unwrap();
+        assert!(!filter.is_allowed(&ip("127.0.0.1")));
+        assert!(!filter.is_allowed(&ip("::1")));
+        assert!(filter.is_allowed(&ip("192.0.2.1")));
+
+        assert!(IpFilter::parse(&["not an ip"], &[]).is_err());
+    }
+}
@@ -51,3 +51,32 @@ pub mod config;
 pub mod repostoreinfo;
 
 pub mod auth;
+
+pub mod checkpoint;
+
+pub mod commitinfo;
+
+pub mod blockindex;
+
+pub mod notfound;
+
+pub mod metrics;
+
+pub mod advertlimit;
+
+pub mod ipfilter;
+
+pub mod blocksource;
+
+pub mod tombstone;
+
+pub mod storelimit;
+
+pub mod codec;
+
+pub mod tcp;
+
+#[cfg(feature = "quic")]
+pub mod quic;
+
+pub mod runtime;
@@ -0,0 +1,84 @@
+//! Counters of the requests answered by the broker, for monitoring
+
+use lofire_net::errors::ProtocolError;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of overlay requests answered, by result label
+    overlay_requests: RwLock<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    /// Result label of a response code: `success` for a success or the start of a stream,
+    /// the stable name of the error otherwise
+    pub fn result_label(code: u16) -> &'static str {
+        let result = ProtocolError::from(code);
+        if result.is_stream() {
+            return ProtocolError::Success.as_str();
+        }
+        result.as_str()
+    }
+
+    /// Count an overlay request answered with the response code
+    pub fn count_overlay_request(&self, code: u16) {
+        *self
+            .overlay_requests
+            .write()
+            .unwrap()
+            .entry(Self::result_label(code))
+            .or_insert(0) += 1;
+    }
+
+    /// Number of overlay requests answered with the result label
+    pub fn overlay_requests_total(&self, result: &str) -> u64 {
+        *self
+            .overlay_requests
+            .read()
+            .unwrap()
+            .get(result)
+            .unwrap_or(&0)
+    }
+
+    /// The counters in the Prometheus text format,
+    /// as `overlay_requests_total{result="not_found"} 3`
+    pub fn render(&self) -> String {
+        let mut text = String::from("# TYPE overlay_requests_total counter\n");
+        for (result, count) in self.overlay_requests.read().unwrap().iter() {
+            text.push_str(&format!(
+                "overlay_requests_total{{result=\"{}\"}} {}\n",
+                result, count
+            ));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::metrics::*;
+
+    #[test]
+    pub fn test_overlay_requests_total() {
+        let metrics = Metrics::default();
+        metrics.count_overlay_request(ProtocolError::Success.into());
+        metrics.count_overlay_request(ProtocolError::PartialContent.into());
+        metrics.count_overlay_request(ProtocolError::NotFound.into());
+        metrics.count_overlay_request(ProtocolError::NotFound.into());
+        metrics.count_overlay_request(ProtocolError::AccessDenied.into());
+
+        assert_eq!(metrics.overlay_requests_total("success"), 2);
+        assert_eq!(metrics.overlay_requests_total("not_found"), 2);
+        assert_eq!(metrics.overlay_requests_total("access_denied"), 1);
+        assert_eq!(metrics.overlay_requests_total("partial_content"), 0);
+        assert_eq!(
+            metrics.render(),
+            "# TYPE overlay_requests_total counter\n\
+             overlay_requests_total{result=\"access_denied\"} 1\n\
+             overlay_requests_total{result=\"not_found\"} 2\n\
+             overlay_requests_total{result=\"success\"} 2\n"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Negative cache of block IDs recently missed in an overlay
+
+use lofire::types::*;
+use lofire::utils::*;
+use lofire_net::types::*;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Maximum number of entries kept in the cache
+pub const NOT_FOUND_CACHE_MAX_ENTRIES: usize = 1024;
+
+pub struct NotFoundCache {
+    /// Time-to-live of an entry
+    ttl: RelTime,
+    /// Missed IDs with the time they were missed
+    entries: RwLock<HashMap<(OverlayId, BlockId), Timestamp>>,
+    /// Number of lookups answered by the cache
+    hits: RwLock<u64>,
+}
+
+impl NotFoundCache {
+    pub fn new(ttl: RelTime) -> NotFoundCache {
+        NotFoundCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+            hits: RwLock::new(0),
+        }
+    }
+
+    /// Check whether the ID was recently missed in the overlay
+    pub fn contains(&self, overlay: &OverlayId, id: &BlockId, clock: &impl Clock) -> bool {
+        let now = clock.now();
+        let found = match self.entries.read().unwrap().get(&(*overlay, *id)) {
+            Some(missed_at) => now < *missed_at + self.ttl,
+            None => false,
+        };
+        if found {
+            *self.hits.write().unwrap() += 1;
+        }
+        found
+    }
+
+    /// Record a miss of the ID in the overlay.
+    /// When the cache is full, expired entries are dropped, and the miss is not recorded if it is still full
+    pub fn insert(&self, overlay: OverlayId, id: BlockId, clock: &impl Clock) {
+        let now = clock.now();
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= NOT_FOUND_CACHE_MAX_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, missed_at| now < *missed_at + ttl);
+            if entries.len() >= NOT_FOUND_CACHE_MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert((overlay, id), now);
+    }
+
+    /// Forget a miss, when the ID has been stored in the overlay
+    pub fn invalidate(&self, overlay: &OverlayId, id: &BlockId) {
+        self.entries.write().unwrap().remove(&(*overlay, *id));
+    }
+
+    /// Number of lookups answered by the cache
+    pub fn hits(&self) -> u64 {
+        *self.hits.read().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::notfound::*;
+
+    #[test]
+    pub fn test_not_found_cache() {
+        let clock = MockClock::new(Timestamp::from_minutes(100));
+        let cache = NotFoundCache::new(RelTime::Minutes(2));
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        let id = Digest::Blake3Digest32([1; 32]);
+
+        assert!(!cache.contains(&overlay, &id, &clock));
+        cache.insert(overlay, id, &clock);
+        assert!(cache.contains(&overlay, &id, &clock));
+        assert_eq!(cache.hits(), 1);
+        // a miss is only cached for its overlay
+        assert!(!cache.contains(&id, &id, &clock));
+
+        clock.advance(1);
+        assert!(cache.contains(&overlay, &id, &clock));
+        clock.advance(1);
+        assert!(!cache.contains(&overlay, &id, &clock));
+        assert_eq!(cache.hits(), 2);
+
+        cache.insert(overlay, id, &clock);
+        cache.invalidate(&overlay, &id);
+        assert!(!cache.contains(&overlay, &id, &clock));
+
+        for i in 0..NOT_FOUND_CACHE_MAX_ENTRIES + 10 {
+            let mut bytes = [0u8; 32];
+            bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            cache.insert(overlay, Digest::Blake3Digest32(bytes), &clock);
+        }
+        assert_eq!(
+            cache.entries.read().unwrap().len(),
+            NOT_FOUND_CACHE_MAX_ENTRIES
+        );
+    }
+}
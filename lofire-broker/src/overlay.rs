@@ -1,6 +1,6 @@
 //! Overlay
 
-use lofire::brokerstore::BrokerStore;
+use lofire::brokerstore::{BrokerStore, BrokerStoreTransaction};
 use lofire::store::*;
 use lofire::types::*;
 use lofire::utils::now_timestamp;
@@ -13,6 +13,8 @@ use serde_bare::{from_slice, to_vec};
 pub struct OverlayMeta {
     pub users: u32,
     pub last_used: Timestamp,
+    /// Expiry of the objects put in the overlay without an expiry of their own
+    pub default_expiry: Option<RelTime>,
 }
 
 pub struct Overlay<'a> {
@@ -30,13 +32,15 @@ impl<'a> Overlay<'a> {
     const TOPIC: u8 = b"t"[0];
     const META: u8 = b"m"[0];
     const REPO: u8 = b"r"[0];
+    const CONNECTED: u8 = b"c"[0];
 
-    const ALL_PROPERTIES: [u8; 5] = [
+    const ALL_PROPERTIES: [u8; 6] = [
         Self::SECRET,
         Self::PEER,
         Self::TOPIC,
         Self::META,
         Self::REPO,
+        Self::CONNECTED,
     ];
 
     const SUFFIX_FOR_EXIST_CHECK: u8 = Self::SECRET;
@@ -64,32 +68,34 @@ impl<'a> Overlay<'a> {
         if acc.exists() {
             return Err(StorageError::BackendError);
         }
-        store.put(
-            Self::PREFIX,
-            &to_vec(&id)?,
-            Some(Self::SECRET),
-            to_vec(&secret)?,
-        )?;
-        if repo.is_some() {
-            store.put(
-                Self::PREFIX,
-                &to_vec(&id)?,
-                Some(Self::REPO),
-                to_vec(&repo.unwrap())?,
-            )?;
-            //TODO if failure, should remove the previously added SECRET property
-        }
         let meta = OverlayMeta {
             users: 1,
             last_used: now_timestamp(),
+            default_expiry: None,
         };
-        store.put(
-            Self::PREFIX,
-            &to_vec(&id)?,
-            Some(Self::META),
-            to_vec(&meta)?,
-        )?;
-        //TODO if failure, should remove the previously added SECRET and REPO properties
+        // the overlay is created with all its properties, or not at all
+        store.transaction(&mut |tx| {
+            tx.put(
+                Self::PREFIX,
+                &to_vec(&id)?,
+                Some(Self::SECRET),
+                to_vec(&secret)?,
+            )?;
+            if let Some(repo) = repo {
+                tx.put(
+                    Self::PREFIX,
+                    &to_vec(&id)?,
+                    Some(Self::REPO),
+                    to_vec(&repo)?,
+                )?;
+            }
+            tx.put(
+                Self::PREFIX,
+                &to_vec(&id)?,
+                Some(Self::META),
+                to_vec(&meta)?,
+            )
+        })?;
         Ok(acc)
     }
     pub fn exists(&self) -> bool {
@@ -133,6 +139,54 @@ impl<'a> Overlay<'a> {
         )
     }
 
+    /// Stores whether a peer of the overlay is connected.
+    /// Returns true if the flag flipped
+    pub fn set_peer_connected(&self, peer: &PeerId, connected: bool) -> Result<bool, StorageError> {
+        if connected == self.is_peer_connected(peer)? {
+            return Ok(false);
+        }
+        if connected {
+            if !self.exists() {
+                return Err(StorageError::BackendError);
+            }
+            self.store.put(
+                Self::PREFIX,
+                &to_vec(&self.id)?,
+                Some(Self::CONNECTED),
+                to_vec(peer)?,
+            )?;
+        } else {
+            self.store.del_property_value(
+                Self::PREFIX,
+                &to_vec(&self.id)?,
+                Some(Self::CONNECTED),
+                to_vec(peer)?,
+            )?;
+        }
+        Ok(true)
+    }
+
+    pub fn is_peer_connected(&self, peer: &PeerId) -> Result<bool, StorageError> {
+        match self.store.has_property_value(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::CONNECTED),
+            to_vec(peer)?,
+        ) {
+            Ok(()) => Ok(true),
+            Err(StorageError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn peers(&self) -> Result<Vec<PeerId>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &to_vec(&self.id)?, Some(Self::PEER))?
+            .iter()
+            .map(|p| Ok(from_slice::<PeerId>(p)?))
+            .collect()
+    }
+
     pub fn add_topic(&self, topic: &TopicId) -> Result<(), StorageError> {
         if !self.exists() {
             return Err(StorageError::BackendError);
@@ -152,6 +206,18 @@ impl<'a> Overlay<'a> {
             to_vec(topic)?,
         )
     }
+    pub fn remove_topic_in(
+        &self,
+        tx: &mut dyn BrokerStoreTransaction,
+        topic: &TopicId,
+    ) -> Result<(), StorageError> {
+        tx.del_property_value(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::TOPIC),
+            to_vec(topic)?,
+        )
+    }
 
     pub fn has_topic(&self, topic: &TopicId) -> Result<(), StorageError> {
         self.store.has_property_value(
@@ -192,6 +258,18 @@ impl<'a> Overlay<'a> {
             to_vec(meta)?,
         )
     }
+    pub fn set_metadata_in(
+        &self,
+        tx: &mut dyn BrokerStoreTransaction,
+        meta: &OverlayMeta,
+    ) -> Result<(), StorageError> {
+        tx.replace(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::META),
+            to_vec(meta)?,
+        )
+    }
 
     pub fn repo(&self) -> Result<PubKey, StorageError> {
         match self
@@ -204,7 +282,193 @@ impl<'a> Overlay<'a> {
     }
 
     pub fn del(&self) -> Result<(), StorageError> {
+        let key = to_vec(&self.id)?;
         self.store
-            .del_all(Self::PREFIX, &to_vec(&self.id)?, &Self::ALL_PROPERTIES)
+            .transaction(&mut |tx| tx.del_all(Self::PREFIX, &key, &Self::ALL_PROPERTIES))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use lofire::brokerstore::*;
+    use lofire::store::*;
+    use lofire::types::*;
+    use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
+    use tempfile::Builder;
+
+    use crate::overlay::Overlay;
+
+    /// Store failing the writes of its transactions after a number of them
+    struct FailingStore {
+        inner: LmdbBrokerStore,
+        writes_before_failure: usize,
+    }
+
+    struct FailingTransaction<'a> {
+        inner: &'a mut dyn BrokerStoreTransaction,
+        writes_left: usize,
+    }
+
+    impl<'a> FailingTransaction<'a> {
+        fn write(&mut self) -> Result<(), StorageError> {
+            if self.writes_left == 0 {
+                return Err(StorageError::BackendError);
+            }
+            self.writes_left -= 1;
+            Ok(())
+        }
+    }
+
+    impl<'a> BrokerStoreTransaction for FailingTransaction<'a> {
+        fn put(
+            &mut self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.put(prefix, key, suffix, value)
+        }
+        fn replace(
+            &mut self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.replace(prefix, key, suffix, value)
+        }
+        fn del(
+            &mut self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+        ) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.del(prefix, key, suffix)
+        }
+        fn del_property_value(
+            &mut self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.write()?;
+            self.inner.del_property_value(prefix, key, suffix, value)
+        }
+    }
+
+    impl BrokerStore for FailingStore {
+        fn get(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+        ) -> Result<Vec<u8>, StorageError> {
+            self.inner.get(prefix, key, suffix)
+        }
+        fn get_all(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+        ) -> Result<Vec<Vec<u8>>, StorageError> {
+            self.inner.get_all(prefix, key, suffix)
+        }
+        fn has_property_value(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.inner.has_property_value(prefix, key, suffix, value)
+        }
+        fn put(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.inner.put(prefix, key, suffix, value)
+        }
+        fn replace(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.inner.replace(prefix, key, suffix, value)
+        }
+        fn del(&self, prefix: u8, key: &Vec<u8>, suffix: Option<u8>) -> Result<(), StorageError> {
+            self.inner.del(prefix, key, suffix)
+        }
+        fn del_all(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            all_suffixes: &[u8],
+        ) -> Result<(), StorageError> {
+            self.inner.del_all(prefix, key, all_suffixes)
+        }
+        fn del_property_value(
+            &self,
+            prefix: u8,
+            key: &Vec<u8>,
+            suffix: Option<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), StorageError> {
+            self.inner.del_property_value(prefix, key, suffix, value)
+        }
+        fn transaction(
+            &self,
+            f: &mut dyn FnMut(&mut dyn BrokerStoreTransaction) -> Result<(), StorageError>,
+        ) -> Result<(), StorageError> {
+            self.inner.transaction(&mut |tx| {
+                f(&mut FailingTransaction {
+                    inner: tx,
+                    writes_left: self.writes_before_failure,
+                })
+            })
+        }
+    }
+
+    #[test]
+    pub fn test_overlay_create_atomic() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = FailingStore {
+            inner: LmdbBrokerStore::open(root.path(), [0; 32]),
+            writes_before_failure: 1,
+        };
+        let id = Digest::Blake3Digest32([2; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+
+        // the second write fails, the first one is rolled back
+        assert_eq!(
+            Overlay::create(&id, &secret, Some(repo), &store).err(),
+            Some(StorageError::BackendError)
+        );
+        assert_eq!(
+            Overlay::open(&id, &store.inner).err(),
+            Some(StorageError::NotFound)
+        );
+
+        // without failure, the overlay is created with all its properties
+        let overlay = Overlay::create(&id, &secret, Some(repo), &store.inner).unwrap();
+        assert_eq!(overlay.repo().unwrap(), repo);
+        assert_eq!(overlay.metadata().unwrap().users, 1);
+
+        overlay.del().unwrap();
+        assert_eq!(
+            Overlay::open(&id, &store.inner).err(),
+            Some(StorageError::NotFound)
+        );
     }
 }
@@ -0,0 +1,151 @@
+//! QUIC transport
+//!
+//! A connection carries the broker protocol on one bidirectional stream,
+//! opened by the client. The frames are the length-prefixed frames of the raw TCP transport,
+//! so they are decoded with `RawFrameCodec` and served by `connection_loop` like a TCP connection.
+//!
+//! The broker authenticates with a TLS certificate, usually self-signed with `self_signed`.
+//! Clients trust it explicitly with `client_config`.
+
+use crate::tcp;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::Sink;
+use lofire_net::errors::*;
+use quinn::{ClientConfig, Connecting, Connection, Endpoint, RecvStream, SendStream, ServerConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Bidirectional QUIC stream, read and written like a TCP stream
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    /// keeps the connection open as long as the stream is used
+    _connection: Connection,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(Pin::new(&mut self.send), cx)
+    }
+}
+
+/// Server config with a new self-signed certificate for the given names,
+/// returned with the certificate (DER) to give to the clients
+pub fn self_signed(names: Vec<String>) -> Result<(ServerConfig, Vec<u8>), ProtocolError> {
+    let cert =
+        rcgen::generate_simple_self_signed(names).map_err(|_e| ProtocolError::InvalidValue)?;
+    let cert_der = cert
+        .serialize_der()
+        .map_err(|_e| ProtocolError::InvalidValue)?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let config = ServerConfig::with_single_cert(vec![rustls::Certificate(cert_der.clone())], key)
+        .map_err(|_e| ProtocolError::InvalidValue)?;
+    Ok((config, cert_der))
+}
+
+/// Client config trusting the certificate (DER) of a broker
+pub fn client_config(trusted_cert: &[u8]) -> Result<ClientConfig, ProtocolError> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(&rustls::Certificate(trusted_cert.to_vec()))
+        .map_err(|_e| ProtocolError::InvalidValue)?;
+    Ok(ClientConfig::with_root_certificates(roots))
+}
+
+/// Endpoint accepting QUIC connections on `addr`
+pub fn listen(addr: SocketAddr, config: ServerConfig) -> io::Result<Endpoint> {
+    Endpoint::server(config, addr)
+}
+
+/// Endpoint for connecting to brokers, bound to any local port
+pub fn client_endpoint() -> io::Result<Endpoint> {
+    Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))
+}
+
+/// Accept the broker protocol stream of an incoming connection,
+/// and split it into frames like `tcp::split`
+pub async fn accept(
+    connecting: Connecting,
+    max_frame_size: usize,
+) -> Result<
+    (
+        impl Sink<Vec<u8>, Error = ProtocolError> + Send + Unpin + 'static,
+        async_channel::Receiver<Vec<u8>>,
+    ),
+    ProtocolError,
+> {
+    let connection = connecting
+        .await
+        .map_err(|_e| ProtocolError::ConnectionClosed)?;
+    let (send, recv) = connection
+        .accept_bi()
+        .await
+        .map_err(|_e| ProtocolError::ConnectionClosed)?;
+    Ok(tcp::split(
+        QuicStream {
+            send,
+            recv,
+            _connection: connection,
+        },
+        max_frame_size,
+    ))
+}
+
+/// Connect to the broker at `addr`, open the broker protocol stream,
+/// and split it into frames for `ConnectionRemote::open_broker_connection`.
+/// `server_name` must be one of the names of the broker certificate
+pub async fn connect(
+    endpoint: &Endpoint,
+    config: ClientConfig,
+    addr: SocketAddr,
+    server_name: &str,
+    max_frame_size: usize,
+) -> Result<
+    (
+        impl Sink<Vec<u8>, Error = ProtocolError> + Send + Unpin + 'static,
+        async_channel::Receiver<Vec<u8>>,
+    ),
+    ProtocolError,
+> {
+    let connection = endpoint
+        .connect_with(config, addr, server_name)
+        .map_err(|_e| ProtocolError::InvalidValue)?
+        .await
+        .map_err(|_e| ProtocolError::ConnectionClosed)?;
+    let (send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(|_e| ProtocolError::ConnectionClosed)?;
+    Ok(tcp::split(
+        QuicStream {
+            send,
+            recv,
+            _connection: connection,
+        },
+        max_frame_size,
+    ))
+}
@@ -0,0 +1,77 @@
+//! Async runtime
+//!
+//! Tasks, timers and blocking calls of the broker and of the connections go through this module,
+//! so that they run under async-std (`async-std-runtime` feature, the default)
+//! or under tokio (`tokio-runtime` feature).
+//! If both features are enabled, async-std is used.
+
+use futures::Future;
+use std::time::Duration;
+
+#[cfg(not(any(feature = "async-std-runtime", feature = "tokio-runtime")))]
+compile_error!("either the async-std-runtime or the tokio-runtime feature must be enabled");
+
+/// Mutex that can be held across await points, independent of the runtime
+pub use futures::lock::Mutex;
+
+/// Spawn a detached task
+#[cfg(feature = "async-std-runtime")]
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    async_std::task::spawn(future);
+}
+
+/// Spawn a detached task
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future);
+}
+
+/// Wait for `duration`
+#[cfg(feature = "async-std-runtime")]
+pub async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+
+/// Wait for `duration`
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+/// Run `future` for at most `duration`, returns None if it did not complete in time
+#[cfg(feature = "async-std-runtime")]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    async_std::future::timeout(duration, future).await.ok()
+}
+
+/// Run `future` for at most `duration`, returns None if it did not complete in time
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
+
+/// Block the current thread until `future` completes
+#[cfg(feature = "async-std-runtime")]
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    async_std::task::block_on(future)
+}
+
+/// Block the current thread until `future` completes.
+/// Outside of a tokio runtime, a temporary one drives the timers of `future`
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => futures::executor::block_on(future),
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("building tokio runtime")
+            .block_on(future),
+    }
+}
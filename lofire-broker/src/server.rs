@@ -1,27 +1,52 @@
 //! A Broker server
 
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::Duration;
 
 use crate::account::Account;
+use crate::account::AccountLimits;
+use crate::advertlimit::AdvertRelayLimiter;
 use crate::auth::*;
+use crate::blockindex::BlockIndex;
+use crate::blocksource::BlockSource;
+use crate::checkpoint::*;
+use crate::codec::FrameCodec;
+use crate::commitinfo::*;
 use crate::config::Config;
 use crate::config::ConfigMode;
 use crate::connection::BrokerConnectionLocal;
+use crate::ipfilter::IpFilter;
+use crate::metrics::Metrics;
+use crate::notfound::NotFoundCache;
 use crate::overlay::Overlay;
 use crate::peer::Peer;
 use crate::repostoreinfo::RepoStoreId;
 use crate::repostoreinfo::RepoStoreInfo;
-use async_std::task;
+use crate::runtime;
+use crate::runtime::Mutex;
+use crate::storelimit::*;
+use crate::topic::Topic;
+use crate::topic::TopicMeta;
 use debug_print::*;
 use futures::future::BoxFuture;
 use futures::future::OptionFuture;
 use futures::FutureExt;
+use futures::Sink;
+use futures::SinkExt;
 use futures::Stream;
+use futures::StreamExt;
+use lofire::brokerstore::BrokerStore;
+use lofire::commit::*;
 use lofire::object::Object;
+use lofire::object::ObjectParseError;
+use lofire::repo::*;
+use lofire::store::Cursor;
 use lofire::store::RepoStore;
 use lofire::store::StorageError;
 use lofire::types::*;
@@ -29,6 +54,8 @@ use lofire::utils::*;
 use lofire_net::errors::*;
 use lofire_net::types::*;
 use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
+use lofire_store_lmdb::durability::Durability;
+use lofire_store_lmdb::repostore::GcReport;
 use lofire_store_lmdb::repostore::LmdbRepoStore;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -57,6 +84,45 @@ impl From<lofire::store::StorageError> for BrokerError {
     }
 }
 
+/// Change of the connectivity of a peer of an overlay, see `BrokerServer::peer_events`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    Connected(PeerId),
+    Disconnected(PeerId),
+}
+
+/// Item of the block stream of a branch sync, see `BrokerServer::sync_branch_with_progress`
+#[derive(Clone, Debug)]
+pub enum SyncStreamItem {
+    Block(Block),
+    Progress(SyncProgress),
+}
+
+impl From<SyncStreamItem> for BrokerOverlayResponseContentV0 {
+    fn from(item: SyncStreamItem) -> Self {
+        match item {
+            SyncStreamItem::Block(b) => BrokerOverlayResponseContentV0::Block(b),
+            SyncStreamItem::Progress(p) => BrokerOverlayResponseContentV0::SyncProgress(p),
+        }
+    }
+}
+
+/// Item of the stream of a replication, see `BrokerServer::replicate`
+#[derive(Clone, Debug)]
+pub enum ReplicationStreamItem {
+    Tombstone(Tombstone),
+    Block(Block),
+}
+
+impl From<ReplicationStreamItem> for BrokerOverlayResponseContentV0 {
+    fn from(item: ReplicationStreamItem) -> Self {
+        match item {
+            ReplicationStreamItem::Tombstone(t) => BrokerOverlayResponseContentV0::Tombstone(t),
+            ReplicationStreamItem::Block(b) => BrokerOverlayResponseContentV0::Block(b),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum ProtocolType {
     Start,
@@ -81,6 +147,11 @@ impl ProtocolHandler {
         self.r.take().unwrap()
     }
 
+    /// Keepalive interval and maximum number of missed pings of the broker
+    pub fn keepalive(&self) -> Option<(Duration, u32)> {
+        self.broker.keepalive
+    }
+
     /// Handle incoming message
     pub async fn handle_incoming(
         &mut self,
@@ -90,6 +161,8 @@ impl ProtocolHandler {
         OptionFuture<BoxFuture<'static, u16>>,
     ) {
         //debug_println!("SERVER PROTOCOL {:?}", &self.protocol);
+        let broker = Arc::clone(&self.broker);
+        let _permit = broker.store_permit().await;
         match &self.protocol {
             ProtocolType::Start => {
                 let message = serde_bare::from_slice::<StartProtocol>(&frame);
@@ -104,15 +177,18 @@ impl ProtocolHandler {
                     }
                     Ok(StartProtocol::Ext(ext)) => {
                         self.protocol = ProtocolType::Ext;
-                        self.ext_protocol = Some(ExtProtocolHandler {});
+                        self.ext_protocol = Some(ExtProtocolHandler {
+                            broker: Arc::clone(&self.broker),
+                            async_frames_sender: self.s.clone(),
+                        });
                         let reply = self.ext_protocol.as_ref().unwrap().handle_incoming(ext);
+                        return (Ok(serde_bare::to_vec(&reply.0).unwrap()), reply.1);
+                    }
+                    Err(_e) => {
                         return (
-                            Ok(serde_bare::to_vec(&reply).unwrap()),
+                            Err(ProtocolError::SerializationError),
                             OptionFuture::from(None),
-                        );
-                    }
-                    Err(e) => {
-                        return (Err(ProtocolError::SerializationError),OptionFuture::from(None))
+                        )
                     }
                 }
             }
@@ -120,12 +196,26 @@ impl ProtocolHandler {
                 let res = self.auth_protocol.as_mut().unwrap().handle_incoming(frame);
                 match res.1.await {
                     None => {
+                        let auth = self.auth_protocol.as_ref().unwrap();
+                        let (user, client) = (auth.get_user().unwrap(), auth.get_client().unwrap());
+                        if let Err(e) = self.broker.check_client(user, client) {
+                            let reply = AuthResult::V0(AuthResultV0 {
+                                result: e.into(),
+                                metadata: vec![],
+                            });
+                            return (
+                                Ok(serde_bare::to_vec(&reply).unwrap()),
+                                OptionFuture::from(Some(async move { u16::from(e) }.boxed())),
+                            );
+                        }
+                        self.broker.open_session(user, client, self.s.clone());
                         // we switch to Broker protocol
                         self.protocol = ProtocolType::Broker;
                         self.broker_protocol = Some(BrokerProtocolHandler {
-                            user: self.auth_protocol.as_ref().unwrap().get_user().unwrap(),
+                            user,
                             broker: Arc::clone(&self.broker),
                             async_frames_sender: self.s.clone(),
+                            topic_streams: RwLock::new(HashMap::new()),
                         });
                         self.auth_protocol = None;
                         (res.0, OptionFuture::from(None))
@@ -134,8 +224,16 @@ impl ProtocolHandler {
                 }
             }
             ProtocolType::Broker => {
+                // the session was closed by the broker, see `BrokerServer::rotate_client`
+                if self.s.is_closed() {
+                    return (Err(ProtocolError::AccessDenied), OptionFuture::from(None));
+                }
                 let message = serde_bare::from_slice::<BrokerMessage>(&frame);
-                match (message) {
+                match message {
+                    // a well-formed Close has no ID to reply to, the client closes with an empty frame
+                    Ok(message) if message.is_close() => {
+                        (Err(ProtocolError::InvalidState), OptionFuture::from(None))
+                    }
                     Ok(message) => {
                         let reply = self
                             .broker_protocol
@@ -145,9 +243,10 @@ impl ProtocolHandler {
                             .await;
                         (Ok(serde_bare::to_vec(&reply.0).unwrap()), reply.1)
                     }
-                    Err(e_) => {
-                        (Err(ProtocolError::SerializationError),OptionFuture::from(None))
-                    }
+                    Err(_e) => (
+                        Err(ProtocolError::SerializationError),
+                        OptionFuture::from(None),
+                    ),
                 }
             }
             ProtocolType::Ext => {
@@ -162,11 +261,76 @@ impl ProtocolHandler {
     }
 }
 
-pub struct ExtProtocolHandler {}
+pub struct ExtProtocolHandler {
+    broker: Arc<BrokerServer>,
+    async_frames_sender: async_channel::Sender<Vec<u8>>,
+}
 
 impl ExtProtocolHandler {
-    pub fn handle_incoming(&self, msg: ExtRequest) -> ExtResponse {
-        unimplemented!()
+    fn prepare_reply(res: Result<(ObjectId, Block), ProtocolError>, id: u64) -> ExtResponse {
+        let (result, content) = match res {
+            Ok((object, block)) => (
+                ProtocolError::PartialContent.into(),
+                Some(ExtResponseContentV0::ObjectBlock(ExtObjectBlockV0 {
+                    object,
+                    block,
+                })),
+            ),
+            Err(e) => (e.into(), None),
+        };
+        ExtResponse::V0(ExtResponseV0 {
+            id,
+            result,
+            content,
+        })
+    }
+
+    /// Handle the request of a non-member.
+    ///
+    /// Only `ExtObjectGet` is supported: each block is sent in its own response with PartialContent,
+    /// tagged with the requested object it belongs to,
+    /// followed by a response with EndOfStream, or Truncated if the broker's limit was reached.
+    /// The objects are sent one after the other, in the order of the request
+    pub fn handle_incoming(
+        &self,
+        msg: ExtRequest,
+    ) -> (ExtResponse, OptionFuture<BoxFuture<'static, u16>>) {
+        let id = msg.id();
+        let (stream, truncated) = match self.broker.ext_object_get(&msg) {
+            Err(e) => return (Self::prepare_reply(Err(e), id), OptionFuture::from(None)),
+            Ok(res) => res,
+        };
+        let end = if truncated {
+            ProtocolError::Truncated
+        } else {
+            ProtocolError::EndOfStream
+        };
+        match stream.try_recv() {
+            Err(_) => (Self::prepare_reply(Err(end), id), OptionFuture::from(None)),
+            Ok(first) => {
+                let sender = self.async_frames_sender.clone();
+                let rest = async move {
+                    while let Ok(next) = stream.recv().await {
+                        let reply = Self::prepare_reply(Ok(next), id);
+                        if sender
+                            .send(serde_bare::to_vec(&reply).unwrap())
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    let reply = Self::prepare_reply(Err(end), id);
+                    let _ = sender.send(serde_bare::to_vec(&reply).unwrap()).await;
+                    0
+                }
+                .boxed();
+                (
+                    Self::prepare_reply(Ok(first), id),
+                    OptionFuture::from(Some(rest)),
+                )
+            }
+        }
     }
 }
 
@@ -174,17 +338,67 @@ pub struct BrokerProtocolHandler {
     broker: Arc<BrokerServer>,
     user: PubKey,
     async_frames_sender: async_channel::Sender<Vec<u8>>,
+    /// event streams of the topics the client is connected to
+    topic_streams: RwLock<HashMap<(OverlayId, TopicId), async_channel::Receiver<Event>>>,
 }
 use std::{thread, time};
 
 impl BrokerProtocolHandler {
+    /// Connect the client to a topic: the events published in it are sent to the client
+    /// until it disconnects, unsubscribes or closes the connection
+    fn connect_topic(&self, overlay: OverlayId, topic: TopicId) -> Result<(), ProtocolError> {
+        let events = self.broker.connect_topic(self.user, overlay, topic)?;
+        if let Some(previous) = self
+            .topic_streams
+            .write()
+            .unwrap()
+            .insert((overlay, topic), events.clone())
+        {
+            previous.close();
+        }
+        let sender = self.async_frames_sender.clone();
+        runtime::spawn(async move {
+            while let Ok(event) = events.recv().await {
+                let msg = BrokerMessage::V0(BrokerMessageV0 {
+                    padding: vec![],
+                    content: BrokerMessageContentV0::BrokerOverlayMessage(
+                        BrokerOverlayMessage::V0(BrokerOverlayMessageV0 {
+                            overlay,
+                            content: BrokerOverlayMessageContentV0::Event(event),
+                        }),
+                    ),
+                });
+                if sender
+                    .send(serde_bare::to_vec(&msg).unwrap())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            events.close();
+        });
+        Ok(())
+    }
+
+    fn disconnect_topic(&self, overlay: OverlayId, topic: TopicId) {
+        if let Some(events) = self
+            .topic_streams
+            .write()
+            .unwrap()
+            .remove(&(overlay, topic))
+        {
+            events.close();
+        }
+    }
+
     fn prepare_reply_broker_message(
         res: Result<(), ProtocolError>,
         id: u64,
         padding_size: usize,
     ) -> BrokerMessage {
         let result = match res {
-            Ok(_) => 0,
+            Ok(_) => ProtocolError::Success.into(),
             Err(e) => e.into(),
         };
         let msg = BrokerMessage::V0(BrokerMessageV0 {
@@ -205,7 +419,7 @@ impl BrokerProtocolHandler {
         padding_size: usize,
     ) -> BrokerMessage {
         let result = match res {
-            Ok(_) => 0,
+            Ok(_) => ProtocolError::Success.into(),
             Err(e) => e.into(),
         };
         let content = match block {
@@ -230,8 +444,166 @@ impl BrokerProtocolHandler {
         msg
     }
 
+    fn prepare_reply_broker_overlay_message_block_results(
+        res: Result<Vec<Result<(), ProtocolError>>, ProtocolError>,
+        id: u64,
+        overlay: OverlayId,
+        padding_size: usize,
+    ) -> BrokerMessage {
+        let (result, content) = match res {
+            Ok(results) => (
+                ProtocolError::Success.into(),
+                Some(BrokerOverlayResponseContentV0::BlockResults(
+                    results
+                        .into_iter()
+                        .map(|r| match r {
+                            Ok(_) => ProtocolError::Success.into(),
+                            Err(e) => e.into(),
+                        })
+                        .collect(),
+                )),
+            ),
+            Err(e) => (e.into(), None),
+        };
+        BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![0; padding_size],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                        BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                            id,
+                            result,
+                            content,
+                        }),
+                    ),
+                },
+            )),
+        })
+    }
+
+    fn prepare_reply_broker_overlay_message_object_id(
+        res: Result<ObjectId, ProtocolError>,
+        id: u64,
+        overlay: OverlayId,
+        padding_size: usize,
+    ) -> BrokerMessage {
+        let (result, content) = match res {
+            Ok(object_id) => (
+                ProtocolError::Success.into(),
+                Some(BrokerOverlayResponseContentV0::ObjectId(object_id)),
+            ),
+            Err(e) => (e.into(), None),
+        };
+        BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![0; padding_size],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                        BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                            id,
+                            result,
+                            content,
+                        }),
+                    ),
+                },
+            )),
+        })
+    }
+
+    fn prepare_reply_broker_overlay_message_status(
+        res: Result<OverlayStatusResp, ProtocolError>,
+        id: u64,
+        overlay: OverlayId,
+        padding_size: usize,
+    ) -> BrokerMessage {
+        let (result, content) = match res {
+            Ok(status) => (
+                ProtocolError::Success.into(),
+                Some(BrokerOverlayResponseContentV0::OverlayStatusResp(status)),
+            ),
+            Err(e) => (e.into(), None),
+        };
+        BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![0; padding_size],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                        BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                            id,
+                            result,
+                            content,
+                        }),
+                    ),
+                },
+            )),
+        })
+    }
+
+    fn prepare_reply_broker_overlay_message_block_list(
+        res: Result<BlockListResp, ProtocolError>,
+        id: u64,
+        overlay: OverlayId,
+        padding_size: usize,
+    ) -> BrokerMessage {
+        let (result, content) = match res {
+            Ok(page) => (
+                ProtocolError::Success.into(),
+                Some(BrokerOverlayResponseContentV0::BlockListResp(page)),
+            ),
+            Err(e) => (e.into(), None),
+        };
+        BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![0; padding_size],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                        BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                            id,
+                            result,
+                            content,
+                        }),
+                    ),
+                },
+            )),
+        })
+    }
+
+    fn prepare_reply_broker_overlay_message_block_ids(
+        res: Result<Vec<BlockId>, ProtocolError>,
+        id: u64,
+        overlay: OverlayId,
+        padding_size: usize,
+    ) -> BrokerMessage {
+        let (result, content) = match res {
+            Ok(ids) => (
+                ProtocolError::Success.into(),
+                Some(BrokerOverlayResponseContentV0::BlockIds(ids)),
+            ),
+            Err(e) => (e.into(), None),
+        };
+        BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![0; padding_size],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                        BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                            id,
+                            result,
+                            content,
+                        }),
+                    ),
+                },
+            )),
+        })
+    }
+
     fn prepare_reply_broker_overlay_message_stream(
-        res: Result<Block, ProtocolError>,
+        res: Result<BrokerOverlayResponseContentV0, ProtocolError>,
         id: u64,
         overlay: OverlayId,
         padding_size: usize,
@@ -240,10 +612,7 @@ impl BrokerProtocolHandler {
             Ok(r) => ProtocolError::PartialContent.into(),
             Err(e) => (*e).clone().into(),
         };
-        let content = match res {
-            Ok(r) => Some(BrokerOverlayResponseContentV0::Block(r)),
-            Err(_) => None,
-        };
+        let content = res.ok();
         let msg = BrokerMessage::V0(BrokerMessageV0 {
             padding: vec![0; padding_size],
             content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
@@ -262,73 +631,115 @@ impl BrokerProtocolHandler {
         msg
     }
 
-    async fn send_block_stream_response_to_client(
+    /// End of a stream, EndOfStream or Truncated, with the content telling how to resume a truncated one
+    fn prepare_reply_broker_overlay_message_stream_end(
+        end: ProtocolError,
+        content: Option<BrokerOverlayResponseContentV0>,
+        id: u64,
+        overlay: OverlayId,
+        padding_size: usize,
+    ) -> BrokerMessage {
+        BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![0; padding_size],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayResponse(
+                        BrokerOverlayResponse::V0(BrokerOverlayResponseV0 {
+                            id,
+                            result: end.into(),
+                            content,
+                        }),
+                    ),
+                },
+            )),
+        })
+    }
+
+    async fn send_block_stream_response_to_client<T>(
         &self,
-        res: Result<async_channel::Receiver<Block>, ProtocolError>,
+        res: Result<async_channel::Receiver<T>, ProtocolError>,
         id: u64,
         overlay: OverlayId,
         padding_size: usize,
-    ) -> (BrokerMessage, OptionFuture<BoxFuture<'static, u16>>) {
+        end: ProtocolError,
+        end_content: Option<BrokerOverlayResponseContentV0>,
+    ) -> (BrokerMessage, OptionFuture<BoxFuture<'static, u16>>)
+    where
+        T: Into<BrokerOverlayResponseContentV0> + Send + 'static,
+    {
         // return an error or the first block, and setup a spawner for the remaining blocks to be sent.
-        let one_reply: (
-            Result<Block, ProtocolError>,
-            OptionFuture<BoxFuture<'static, u16>>,
-        ) = match res {
-            Err(e) => (Err(e), OptionFuture::from(None)),
-            Ok(stream) => {
-                let one = stream
-                    .recv_blocking()
-                    .map_err(|e| ProtocolError::EndOfStream);
-
-                if one.is_ok() {
-                    let sender = self.async_frames_sender.clone();
-                    let a = OptionFuture::from(Some(
-                        async move {
-                            while let Ok(next) = stream.recv().await {
-                                let msg = Self::prepare_reply_broker_overlay_message_stream(
-                                    Ok(next),
-                                    id,
-                                    overlay,
-                                    padding_size,
-                                );
-                                let res = sender.send(serde_bare::to_vec(&msg).unwrap()).await;
-                                if res.is_err() {
-                                    break;
-                                }
-                            }
-                            // sending end of stream
-                            let msg = Self::prepare_reply_broker_overlay_message_stream(
-                                Err(ProtocolError::EndOfStream),
-                                id,
-                                overlay,
-                                padding_size,
-                            );
-                            let _ = sender.send(serde_bare::to_vec(&msg).unwrap()).await;
-                            0
-                        }
-                        .boxed(),
-                    ));
-                    (one, a)
-                } else {
-                    (one, OptionFuture::from(None))
-                }
+        let stream = match res {
+            Err(e) => {
+                return (
+                    Self::prepare_reply_broker_overlay_message_stream(
+                        Err(e),
+                        id,
+                        overlay,
+                        padding_size,
+                    ),
+                    OptionFuture::from(None),
+                )
             }
+            Ok(stream) => stream,
         };
-        return (
-            Self::prepare_reply_broker_overlay_message_stream(
-                one_reply.0,
-                id,
-                overlay,
-                padding_size,
-            ),
-            one_reply.1,
+        let end_msg = Self::prepare_reply_broker_overlay_message_stream_end(
+            end,
+            end_content,
+            id,
+            overlay,
+            padding_size,
         );
+        let one = match stream.recv_blocking() {
+            Ok(one) => one.into(),
+            // empty stream
+            Err(_) => return (end_msg, OptionFuture::from(None)),
+        };
+        let sender = self.async_frames_sender.clone();
+        let remaining = OptionFuture::from(Some(
+            async move {
+                while let Ok(next) = stream.recv().await {
+                    let msg = Self::prepare_reply_broker_overlay_message_stream(
+                        Ok(next.into()),
+                        id,
+                        overlay,
+                        padding_size,
+                    );
+                    let res = sender.send(serde_bare::to_vec(&msg).unwrap()).await;
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                // sending end of stream (EndOfStream or Truncated)
+                let _ = sender.send(serde_bare::to_vec(&end_msg).unwrap()).await;
+                0
+            }
+            .boxed(),
+        ));
+        (
+            Self::prepare_reply_broker_overlay_message_stream(Ok(one), id, overlay, padding_size),
+            remaining,
+        )
     }
 
+    /// Handle a message of the client.
+    /// The overlay requests are counted by result in the metrics of the broker
     pub async fn handle_incoming(
         &self,
         msg: BrokerMessage,
     ) -> (BrokerMessage, OptionFuture<BoxFuture<'static, u16>>) {
+        let overlay_request = msg.is_overlay() && msg.is_request();
+        let reply = self.handle_message(msg).await;
+        if overlay_request {
+            self.broker.metrics.count_overlay_request(reply.0.result());
+        }
+        reply
+    }
+
+    async fn handle_message(
+        &self,
+        msg: BrokerMessage,
+    ) -> (BrokerMessage, OptionFuture<BoxFuture<'static, u16>>) {
 
         let padding_size = 20; // TODO randomize, if config of server contains padding_max
 
@@ -339,16 +750,18 @@ impl BrokerProtocolHandler {
                 Self::prepare_reply_broker_message(
                     match req.content_v0() {
                         BrokerRequestContentV0::AddUser(cmd) => {
-                            self.broker.add_user(self.user, cmd.user(), cmd.sig())
+                            self.broker.add_user(cmd.content_v0(), cmd.sig())
                         }
                         BrokerRequestContentV0::DelUser(cmd) => {
-                            self.broker.del_user(self.user, cmd.user(), cmd.sig())
+                            self.broker.del_user(cmd.content_v0(), cmd.sig())
                         }
                         BrokerRequestContentV0::AddClient(cmd) => {
-                            self.broker.add_client(self.user, cmd.client(), cmd.sig())
+                            self.broker
+                                .add_client(self.user, cmd.content_v0(), cmd.sig())
                         }
                         BrokerRequestContentV0::DelClient(cmd) => {
-                            self.broker.del_client(self.user, cmd.client(), cmd.sig())
+                            self.broker
+                                .del_client(self.user, cmd.content_v0(), cmd.sig())
                         }
                     },
                     id,
@@ -370,6 +783,20 @@ impl BrokerProtocolHandler {
                 let mut res = Err(ProtocolError::InvalidState);
 
                 if omsg.is_request() {
+                    // the user must have an account before making any overlay request
+                    if let Err(e) = self.broker.check_account(self.user) {
+                        debug_println!("overlay request {} result: {}", id, e.as_str());
+                        return (
+                            Self::prepare_reply_broker_overlay_message(
+                                Err(e),
+                                id,
+                                overlay,
+                                block,
+                                padding_size,
+                            ),
+                            OptionFuture::from(None),
+                        );
+                    }
                     match omsg.overlay_request().content_v0() {
                         BrokerOverlayRequestContentV0::OverlayConnect(_) => {
                             res = self.broker.connect_overlay(self.user, overlay)
@@ -384,7 +811,27 @@ impl BrokerProtocolHandler {
                             )
                         }
                         BrokerOverlayRequestContentV0::ObjectDel(op) => {
-                            res = self.broker.del_object(self.user, overlay, op.id())
+                            res = match op.tombstone() {
+                                Some(t) if t.object() != op.id() => {
+                                    Err(ProtocolError::InvalidValue)
+                                }
+                                Some(t) => self.broker.add_tombstone(self.user, overlay, t),
+                                None => self.broker.del_object(self.user, overlay, op.id()),
+                            }
+                        }
+                        BrokerOverlayRequestContentV0::ObjectCopy(oc) => {
+                            let res =
+                                self.broker
+                                    .copy_object(self.user, overlay, oc.id(), oc.expiry());
+                            return (
+                                Self::prepare_reply_broker_overlay_message_object_id(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                ),
+                                OptionFuture::from(None),
+                            );
                         }
                         BrokerOverlayRequestContentV0::ObjectPin(op) => {
                             res = self.broker.pin_object(self.user, overlay, op.id())
@@ -395,30 +842,170 @@ impl BrokerProtocolHandler {
                         BrokerOverlayRequestContentV0::BlockPut(b) => {
                             res = self.broker.put_block(self.user, overlay, b.block())
                         }
-                        BrokerOverlayRequestContentV0::BranchSyncReq(b) => {
-                            let res = self.broker.sync_branch(
-                                self.user,
-                                &overlay,
-                                b.heads(),
-                                b.known_heads(),
-                                b.known_commits(),
-                            );
-                            return self
-                                .send_block_stream_response_to_client(
+                        BrokerOverlayRequestContentV0::ReplicationAck(_) => {
+                            let res = self.broker.ack_replication(self.user, &overlay);
+                            return (
+                                Self::prepare_reply_broker_overlay_message_block_ids(
                                     res,
                                     id,
                                     overlay,
                                     padding_size,
-                                )
-                                .await;
+                                ),
+                                OptionFuture::from(None),
+                            );
                         }
-                        BrokerOverlayRequestContentV0::BlockGet(b) => {
-                            let res = self.broker.get_block(
+                        BrokerOverlayRequestContentV0::BlockList(l) => {
+                            let res =
+                                self.broker
+                                    .list_blocks(self.user, &overlay, l.limit(), l.after());
+                            return (
+                                Self::prepare_reply_broker_overlay_message_block_list(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                ),
+                                OptionFuture::from(None),
+                            );
+                        }
+                        BrokerOverlayRequestContentV0::OverlayStatusReq(_) => {
+                            let res = self.broker.overlay_status(self.user, overlay);
+                            return (
+                                Self::prepare_reply_broker_overlay_message_status(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                ),
+                                OptionFuture::from(None),
+                            );
+                        }
+                        BrokerOverlayRequestContentV0::BlocksPut(b) => {
+                            let res = self.broker.put_blocks(self.user, overlay, b.blocks());
+                            return (
+                                Self::prepare_reply_broker_overlay_message_block_results(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                ),
+                                OptionFuture::from(None),
+                            );
+                        }
+                        BrokerOverlayRequestContentV0::TopicSub(t) => {
+                            res = self.broker.subscribe_topic(self.user, overlay, t.topic())
+                        }
+                        BrokerOverlayRequestContentV0::TopicUnsub(t) => {
+                            self.disconnect_topic(overlay, t.topic());
+                            res = self.broker.unsubscribe_topic(self.user, overlay, t.topic())
+                        }
+                        BrokerOverlayRequestContentV0::TopicConnect(t) => {
+                            res = self.connect_topic(overlay, t.topic())
+                        }
+                        BrokerOverlayRequestContentV0::TopicDisconnect(t) => {
+                            self.disconnect_topic(overlay, t.topic());
+                            res = Ok(())
+                        }
+                        BrokerOverlayRequestContentV0::Event(e) => {
+                            res = self.broker.publish_event(self.user, overlay, e)
+                        }
+                        BrokerOverlayRequestContentV0::BranchHeadsReq(b) => {
+                            let res = self.broker.branch_heads(
                                 self.user,
-                                overlay,
-                                b.id(),
-                                b.include_children(),
+                                &overlay,
                                 b.topic(),
+                                b.known_heads(),
+                            );
+                            return (
+                                Self::prepare_reply_broker_overlay_message_block_ids(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                ),
+                                OptionFuture::from(None),
+                            );
+                        }
+                        BrokerOverlayRequestContentV0::BranchSyncReq(b) => {
+                            let res = self.broker.sync_branch_with_progress(
+                                self.user,
+                                &overlay,
+                                b.heads(),
+                                b.known_heads(),
+                                b.known_commits(),
+                                b.commit_types(),
+                                b.checkpoint(),
+                            );
+                            return self
+                                .send_block_stream_response_to_client(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                    ProtocolError::EndOfStream,
+                                    None,
+                                )
+                                .await;
+                        }
+                        BrokerOverlayRequestContentV0::OverlayReplicate(r) => {
+                            let res = if r.checkpoint() {
+                                self.broker
+                                    .replicate_from_checkpoint(self.user, &overlay)
+                                    .map(|(r, _)| r)
+                            } else {
+                                self.broker.replicate(self.user, &overlay, r.since())
+                            };
+                            return self
+                                .send_block_stream_response_to_client(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                    ProtocolError::EndOfStream,
+                                    None,
+                                )
+                                .await;
+                        }
+                        BrokerOverlayRequestContentV0::CommitGet(c) => {
+                            let res = self.broker.get_commit(self.user, &overlay, c.id());
+                            return self
+                                .send_block_stream_response_to_client(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                    ProtocolError::EndOfStream,
+                                    None,
+                                )
+                                .await;
+                        }
+                        BrokerOverlayRequestContentV0::BlockRangeGet(b) => {
+                            let res = self.broker.get_block_range(
+                                self.user,
+                                &overlay,
+                                b.id(),
+                                b.offset(),
+                                b.len(),
+                            );
+                            return self
+                                .send_block_stream_response_to_client(
+                                    res,
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                    ProtocolError::EndOfStream,
+                                    None,
+                                )
+                                .await;
+                        }
+                        BrokerOverlayRequestContentV0::BlockGet(b) if b.range().is_some() => {
+                            let (offset, len) = b.range().unwrap();
+                            let res = self.broker.get_block_range(
+                                self.user,
+                                &overlay,
+                                b.id(),
+                                offset,
+                                len,
                             );
                             return self
                                 .send_block_stream_response_to_client(
@@ -426,6 +1013,43 @@ impl BrokerProtocolHandler {
                                     id,
                                     overlay,
                                     padding_size,
+                                    ProtocolError::EndOfStream,
+                                    None,
+                                )
+                                .await;
+                        }
+                        BrokerOverlayRequestContentV0::BlockGet(b) => {
+                            let res = self
+                                .broker
+                                .get_block_with_fallback(
+                                    self.user,
+                                    overlay,
+                                    b.id(),
+                                    b.include_children(),
+                                    b.topic(),
+                                    b.max_blocks(),
+                                    b.continuation(),
+                                    b.known_blocks(),
+                                )
+                                .await;
+                            // a truncated stream ends with the token to request the remaining blocks
+                            let (end, end_content) = match res {
+                                Ok((_, Some(continuation))) => (
+                                    ProtocolError::Truncated,
+                                    Some(BrokerOverlayResponseContentV0::Continuation(
+                                        continuation,
+                                    )),
+                                ),
+                                _ => (ProtocolError::EndOfStream, None),
+                            };
+                            return self
+                                .send_block_stream_response_to_client(
+                                    res.map(|(r, _)| r),
+                                    id,
+                                    overlay,
+                                    padding_size,
+                                    end,
+                                    end_content,
                                 )
                                 .await;
                         }
@@ -433,6 +1057,10 @@ impl BrokerProtocolHandler {
                     }
                 }
 
+                if let Err(e) = &res {
+                    debug_println!("overlay request {} result: {}", id, e.as_str());
+                }
+
                 (
                     Self::prepare_reply_broker_overlay_message(
                         res,
@@ -448,8 +1076,166 @@ impl BrokerProtocolHandler {
     }
 }
 
+/// Serve a client connection, given the sink and stream of transport messages and the codec of the transport.
+/// Returns when the connection is closed
+pub async fn connection_loop<C, W, R, E>(
+    codec: C,
+    tx: W,
+    mut rx: R,
+    mut handler: ProtocolHandler,
+) -> std::io::Result<()>
+where
+    C: FrameCodec,
+    W: Sink<C::TransportMessage> + Unpin + Send + 'static,
+    R: Stream<Item = Result<C::TransportMessage, E>> + Unpin,
+    E: std::fmt::Debug,
+{
+    let mut tx_mutex = Arc::new(Mutex::new(tx));
+
+    // setup the async frames task
+    let receiver = handler.async_frames_receiver();
+    let ws_in_task = Arc::clone(&tx_mutex);
+    let codec_in_task = codec.clone();
+    runtime::spawn(async move {
+        while let Ok(frame) = receiver.recv().await {
+            let mut sink = ws_in_task.lock().await;
+            if sink.send(codec_in_task.encode(frame)).await.is_err() {
+                break;
+            }
+        }
+        debug_println!("end of async frames loop");
+
+        let mut sink = ws_in_task.lock().await;
+        let _ = sink.send(codec_in_task.close()).await;
+        let _ = sink.close().await;
+    });
+
+    // pings sent since the last message of the client
+    let mut missed_pings = 0;
+    let keepalive = handler.keepalive();
+
+    loop {
+        let msg = match keepalive {
+            None => rx.next().await,
+            Some((interval, max_missed)) => match runtime::timeout(interval, rx.next()).await {
+                Some(msg) => msg,
+                None => {
+                    if missed_pings >= max_missed {
+                        debug_println!("closing idle connection after {} pings", missed_pings);
+                        break;
+                    }
+                    missed_pings += 1;
+                    if tx_mutex.lock().await.send(codec.ping()).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+        };
+        let msg = match msg {
+            None => break,
+            Some(msg) => msg,
+        };
+        // any message, including pongs, shows the client is alive
+        missed_pings = 0;
+        //debug_println!("RCV: {:?}", msg);
+        let msg = match msg {
+            Err(e) => {
+                debug_println!("Error on server stream: {:?}", e);
+                // Errors returned directly through the AsyncRead/Write API are fatal, generally an error on the underlying
+                // transport. closing connection
+                break;
+            }
+            Ok(m) => m,
+        };
+        if codec.is_close(&msg) {
+            debug_println!("CLOSE from CLIENT");
+            break;
+        } else if let Some(frame) = codec.decode(msg) {
+            //debug_println!("server received frame: {:?}", frame);
+
+            let replies = handler.handle_incoming(frame).await;
+
+            match replies.0 {
+                Err(e) => {
+                    debug_println!("Protocol Error: {}", e.as_str());
+                    // dealing with ProtocolErrors (closing the connection)
+                    break;
+                }
+                Ok(r) => {
+                    if tx_mutex.lock().await.send(codec.encode(r)).await.is_err() {
+                        //dealing with sending errors (closing the connection)
+                        break;
+                    }
+                }
+            }
+            match replies.1.await {
+                Some(errcode) => {
+                    let err = ProtocolError::from(errcode);
+                    if err != ProtocolError::Success {
+                        debug_println!("Close due to error code : {} {}", errcode, err.as_str());
+                        //closing connection
+                        break;
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    let mut sink = tx_mutex.lock().await;
+    let _ = sink.send(codec.close()).await;
+    let _ = sink.close().await;
+    debug_println!("end of sync read+write loop");
+    Ok(())
+}
+
 const REPO_STORES_SUBDIR: &str = "repos";
 
+/// Default maximum number of heads accepted in a BranchSyncReq or BranchHeadsReq
+pub const DEFAULT_MAX_SYNC_HEADS: usize = 256;
+
+/// Default maximum number of blocks streamed back for one BranchSyncReq
+pub const DEFAULT_MAX_SYNC_BLOCKS: usize = 100_000;
+
+/// Number of blocks of a BranchSyncReq streamed between two progress updates
+const SYNC_PROGRESS_INTERVAL: usize = 64;
+
+/// Default maximum number of blocks streamed back for one BlockGet including children
+pub const DEFAULT_MAX_GET_BLOCKS: usize = 100_000;
+
+/// Maximum number of block IDs in one page of a BlockList
+pub const MAX_LIST_BLOCKS: u32 = 1000;
+
+/// Default time a client connection can stay idle before the broker pings it
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default number of unanswered pings after which the broker closes a client connection
+pub const DEFAULT_KEEPALIVE_MAX_MISSED: u32 = 3;
+
+/// Initial time-to-live of the peer advertisements of this broker
+pub const DEFAULT_ADVERT_TTL: u8 = 8;
+
+/// Default maximum number of PeerAdverts relayed per minute in ConfigMode::Core
+pub const DEFAULT_ADVERT_RELAY_RATE: u32 = 600;
+
+/// Default time the tombstones of deleted objects are kept
+pub const DEFAULT_TOMBSTONE_RETENTION: RelTime = RelTime::Days(90);
+
+/// Default maximum number of requests handled at the same time, over all the connections
+pub const DEFAULT_STORE_CONCURRENCY: usize = 16;
+
+/// Default maximum time until the expiry of the blocks put through the broker
+pub const DEFAULT_MAX_EXPIRY: RelTime = RelTime::Days(255);
+
+/// Default maximum number of overlays joined by a user
+pub const DEFAULT_MAX_OVERLAYS: u32 = 256;
+
+/// Default maximum number of topics subscribed by a user
+pub const DEFAULT_MAX_TOPICS: u32 = 4096;
+
+/// Time around the broker clock within which a signed admin or client request is accepted
+pub const SIGNED_REQUEST_VALIDITY: RelTime = RelTime::Minutes(10);
+
 pub struct BrokerServer {
     store: LmdbBrokerStore,
     mode: ConfigMode,
@@ -458,6 +1244,78 @@ pub struct BrokerServer {
     // try to change it to this version below in order to avoid double hashmap lookup in local mode. but hard to do...
     //overlayid_to_repostore: HashMap<RepoStoreId, &'a LmdbRepoStore>,
     overlayid_to_repostore: Arc<RwLock<HashMap<OverlayId, RepoStoreId>>>,
+    /// maximum number of heads accepted in a sync request
+    max_sync_heads: usize,
+    /// maximum number of blocks streamed back for one sync request
+    max_sync_blocks: usize,
+    /// maximum number of blocks streamed back for one BlockGet including children
+    max_get_blocks: usize,
+    /// overlays served by this broker. empty means all overlays are allowed
+    overlay_allowlist: HashSet<OverlayId>,
+    /// peer IPs allowed to connect
+    ip_filter: IpFilter,
+    /// peer key and listen addresses of this broker, advertised in the overlays it joins
+    self_peer: Option<(PrivKey, Vec<IPTransportAddr>)>,
+    /// optional cache of recently missed block IDs
+    not_found_cache: Option<NotFoundCache>,
+    /// optional source searched for blocks missing locally, with the time allowed for the search
+    block_fallback: Option<(Box<dyn BlockSource>, Duration)>,
+    /// fallback searches in progress, closed when the search ends
+    in_flight: InFlightSearches,
+    /// optional channel of the messages to send to the upstream brokers of an overlay
+    upstream: Option<async_channel::Sender<(OverlayId, OverlayMessageContentV0)>>,
+    /// optional bound on the PeerAdverts relayed upstream
+    advert_relay_limiter: Option<AdvertRelayLimiter>,
+    /// durability of the writes to the repo stores opened by the broker
+    repo_store_durability: Durability,
+    /// maximum metadata sizes of the commits published through the broker
+    metadata_limits: MetadataLimits,
+    /// limits of the users without limits of their own
+    account_limits: AccountLimits,
+    /// maximum size of the content of the objects put through the broker
+    max_object_size: u64,
+    /// time the tombstones of deleted objects are kept
+    tombstone_retention: RelTime,
+    /// maximum time until the expiry of the blocks put through the broker
+    max_expiry: RelTime,
+    /// optional idle time before pinging a client, and number of unanswered pings before closing
+    keepalive: Option<(Duration, u32)>,
+    /// event streams of the connections connected to each topic
+    topic_events: RwLock<HashMap<(OverlayId, TopicId), Vec<async_channel::Sender<Event>>>>,
+    /// streams of the connectivity events of the peers of each overlay
+    peer_events: RwLock<HashMap<OverlayId, Vec<async_channel::Sender<PeerEvent>>>>,
+    /// optional bound on the requests accessing the stores at the same time
+    store_limiter: Option<StoreLimiter>,
+    /// async frames senders of the connections authenticated with each user and client
+    sessions: RwLock<HashMap<(PubKey, PubKey), Vec<async_channel::Sender<Vec<u8>>>>>,
+    /// serialized admin and client requests accepted within SIGNED_REQUEST_VALIDITY, with their timestamp
+    signed_requests: RwLock<HashMap<Vec<u8>, Timestamp>>,
+    /// counters of the requests answered
+    metrics: Metrics,
+}
+
+/// Fallback searches in progress, by overlay, block and `include_children`
+type InFlightSearches = RwLock<HashMap<(OverlayId, BlockId, bool), async_channel::Receiver<()>>>;
+
+/// Fallback search led by a caller of `fetch_from_fallback`.
+/// When dropped, even on a panic or when the caller's future is dropped,
+/// the search is removed from the searches in progress and its waiters are woken up
+struct InFlightSearch<'a> {
+    searches: &'a InFlightSearches,
+    key: (OverlayId, BlockId, bool),
+    done: async_channel::Sender<()>,
+}
+
+impl Drop for InFlightSearch<'_> {
+    fn drop(&mut self) {
+        // the lock is poisoned if a panic happened while it was held,
+        // the entry must go all the same
+        match self.searches.write() {
+            Ok(mut searches) => searches.remove(&self.key),
+            Err(poisoned) => poisoned.into_inner().remove(&self.key),
+        };
+        self.done.close();
+    }
 }
 
 impl BrokerServer {
@@ -472,411 +1330,4577 @@ impl BrokerServer {
             mode: configmode,
             repo_stores: Arc::new(RwLock::new(HashMap::new())),
             overlayid_to_repostore: Arc::new(RwLock::new(HashMap::new())),
+            max_sync_heads: DEFAULT_MAX_SYNC_HEADS,
+            max_sync_blocks: DEFAULT_MAX_SYNC_BLOCKS,
+            max_get_blocks: DEFAULT_MAX_GET_BLOCKS,
+            overlay_allowlist: HashSet::new(),
+            ip_filter: IpFilter::default(),
+            self_peer: None,
+            not_found_cache: None,
+            block_fallback: None,
+            in_flight: RwLock::new(HashMap::new()),
+            upstream: None,
+            advert_relay_limiter: Some(AdvertRelayLimiter::new(DEFAULT_ADVERT_RELAY_RATE)),
+            repo_store_durability: Durability::SyncOnCommit,
+            metadata_limits: MetadataLimits::default(),
+            account_limits: AccountLimits {
+                max_overlays: DEFAULT_MAX_OVERLAYS,
+                max_topics: DEFAULT_MAX_TOPICS,
+            },
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            tombstone_retention: DEFAULT_TOMBSTONE_RETENTION,
+            max_expiry: DEFAULT_MAX_EXPIRY,
+            keepalive: Some((DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_MAX_MISSED)),
+            topic_events: RwLock::new(HashMap::new()),
+            peer_events: RwLock::new(HashMap::new()),
+            store_limiter: Some(StoreLimiter::new(DEFAULT_STORE_CONCURRENCY)),
+            sessions: RwLock::new(HashMap::new()),
+            signed_requests: RwLock::new(HashMap::new()),
+            metrics: Metrics::default(),
         })
     }
 
-    fn open_or_create_repostore<F, R>(
-        &self,
-        repostore_id: RepoStoreId,
-        f: F,
-    ) -> Result<R, ProtocolError>
-    where
-        F: FnOnce(&LmdbRepoStore) -> Result<R, ProtocolError>,
-    {
-        // first let's find it in the BrokerStore.repostoreinfo table in order to get the encryption key
-        let info = RepoStoreInfo::open(&repostore_id, &self.store)
-            .map_err(|e| BrokerError::OverlayNotFound)?;
-        let key = info.key()?;
-        let mut path = self.store.path();
-        path.push(REPO_STORES_SUBDIR);
-        path.push::<String>(repostore_id.clone().into());
-        std::fs::create_dir_all(path.clone()).map_err(|_e| ProtocolError::WriteError )?;
-        println!("path for repo store: {}", path.to_str().unwrap());
-        let repo = LmdbRepoStore::open(&path, *key.slice());
-        let mut writer = self.repo_stores.write().expect("write repo_store hashmap");
-        writer.insert(repostore_id.clone(), repo);
+    /// Sets the maximum number of heads accepted in a BranchSyncReq or BranchHeadsReq.
+    /// Requests above it are rejected with ProtocolError::TooManyHeads
+    pub fn set_max_sync_heads(&mut self, max: usize) {
+        self.max_sync_heads = max;
+    }
 
-        f(writer.get(&repostore_id).unwrap())
+    /// Sets the maximum number of blocks streamed back for one BranchSyncReq.
+    /// The stream is cut once the limit is reached, with its last progress marked as truncated,
+    /// and the client has to resume the sync from a checkpoint
+    pub fn set_max_sync_blocks(&mut self, max: usize) {
+        self.max_sync_blocks = max;
     }
 
-    fn get_repostore_from_overlay_id<F, R>(
-        &self,
-        overlay_id: &OverlayId,
-        f: F,
-    ) -> Result<R, ProtocolError>
-    where
-        F: FnOnce(&LmdbRepoStore) -> Result<R, ProtocolError>,
-    {
-        if self.mode == ConfigMode::Core {
-            let repostore_id = RepoStoreId::Overlay(*overlay_id);
-            let reader = self.repo_stores.read().expect("read repo_store hashmap");
-            let rep = reader.get(&repostore_id);
-            match rep {
-                Some(repo) => return f(repo),
-                None => {
-                    // we need to open/create it
-                    // TODO: last_access
-                    return self.open_or_create_repostore(repostore_id, |repo| f(repo));
-                }
-            }
-        } else {
-            // it is ConfigMode::Local
-            {
-                let reader = self
-                    .overlayid_to_repostore
-                    .read()
-                    .expect("read overlayid_to_repostore hashmap");
-                match reader.get(&overlay_id) {
-                    Some(repostoreid) => {
-                        let reader = self.repo_stores.read().expect("read repo_store hashmap");
-                        match reader.get(repostoreid) {
-                            Some(repo) => return f(repo),
-                            None => return Err(ProtocolError::BrokerError),
-                        }
-                    }
-                    None => {}
-                };
-            }
+    /// Sets the maximum number of blocks streamed back for one BlockGet including children.
+    /// Longer responses are truncated, and the client continues with the continuation token
+    pub fn set_max_get_blocks(&mut self, max: usize) {
+        self.max_get_blocks = max;
+    }
 
-            // we need to open/create it
-            // first let's find it in the BrokerStore.overlay table to retrieve its repo_pubkey
-            debug_println!("searching for overlayId {}", overlay_id);
-            let overlay = Overlay::open(overlay_id, &self.store)?;
-            debug_println!("found overlayId {}", overlay_id);
-            let repo_id = overlay.repo()?;
-            let repostore_id = RepoStoreId::Repo(repo_id);
-            let mut writer = self
-                .overlayid_to_repostore
-                .write()
-                .expect("write overlayid_to_repostore hashmap");
-            writer.insert(*overlay_id, repostore_id.clone());
-            // now opening/creating the RepoStore
-            // TODO: last_access
-            return self.open_or_create_repostore(repostore_id, |repo| f(repo));
-        }
+    /// Sets the overlays this broker serves.
+    /// Joining, connecting or putting blocks to other overlays fails with ProtocolError::OverlayNotAllowed.
+    /// An empty allowlist allows all overlays
+    pub fn set_overlay_allowlist(&mut self, overlays: Vec<OverlayId>) {
+        self.overlay_allowlist = overlays.into_iter().collect();
     }
 
-    pub fn local_connection(&mut self, user: PubKey) -> BrokerConnectionLocal {
-        BrokerConnectionLocal::new(self, user)
+    /// Sets the peer IPs allowed to connect, checked with `is_peer_allowed` when accepting a connection
+    pub fn set_ip_filter(&mut self, filter: IpFilter) {
+        self.ip_filter = filter;
     }
 
-    pub fn protocol_handler(self: Arc<Self>) -> ProtocolHandler {
-        let (s, r) = async_channel::unbounded::<Vec<u8>>();
-        return ProtocolHandler {
-            broker: Arc::clone(&self),
-            protocol: ProtocolType::Start,
-            auth_protocol: None,
-            broker_protocol: None,
-            ext_protocol: None,
-            r: Some(r),
-            s,
-        };
+    /// Check whether a peer connecting from this IP may be served.
+    /// Connections from other peers are to be closed before the handshake
+    pub fn is_peer_allowed(&self, ip: &IpAddr) -> bool {
+        self.ip_filter.is_allowed(ip)
     }
 
-    pub fn add_user(
-        &self,
-        admin_user: PubKey,
-        user_id: PubKey,
-        sig: Sig,
-    ) -> Result<(), ProtocolError> {
-        debug_println!("ADDING USER {}", user_id);
-        // TODO add is_admin boolean
-        // TODO check that admin_user is indeed an admin
+    /// Counters of the requests answered by the broker, such as `overlay_requests_total{result}`
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
 
-        // verify signature
-        let op_content = AddUserContentV0 { user: user_id };
-        let _ = verify(&serde_bare::to_vec(&op_content).unwrap(), sig, admin_user)?;
+    /// Sets the peer key and listen addresses of this broker.
+    /// Once set, the broker adds its own PeerAdvert to the overlays it joins
+    pub fn set_self_peer(&mut self, priv_key: PrivKey, listen: Vec<IPTransportAddr>) {
+        self.self_peer = Some((priv_key, listen));
+    }
 
-        // check user_id is not already present
-        let account = Account::open(&user_id, &self.store);
-        if account.is_ok() {
-            Err(ProtocolError::UserAlreadyExists)
-        }
-        // if not, add to store
-        else {
-            let _ = Account::create(&user_id, false, &self.store)?;
-            Ok(())
-        }
+    /// Enables caching of recently missed block IDs for the given time-to-live, or disables it with None.
+    /// Repeated BlockGet for IDs the broker doesn't have then fail with NotFound without a store lookup
+    pub fn set_not_found_cache(&mut self, ttl: Option<RelTime>) {
+        self.not_found_cache = ttl.map(|ttl| NotFoundCache::new(ttl));
     }
 
-    pub fn del_user(
-        &self,
-        admin_user: PubKey,
-        user_id: PubKey,
-        sig: Sig,
-    ) -> Result<(), ProtocolError> {
-        // TODO implement del_user
-        Ok(())
+    /// Sets a source searched for blocks that are not found locally, such as the peers of a core broker.
+    /// Found blocks are stored locally before being returned to the client.
+    /// The search is given up after `timeout`, and the BlockGet then fails with NotFound
+    pub fn set_block_fallback(&mut self, source: Box<dyn BlockSource>, timeout: Duration) {
+        self.block_fallback = Some((source, timeout));
     }
-    pub fn add_client(
-        &self,
-        user: PubKey,
-        client_id: PubKey,
-        sig: Sig,
-    ) -> Result<(), ProtocolError> {
-        // TODO implement add_client
-        Ok(())
+
+    /// Sets the keepalive of the client connections served by `connection_loop`, or disables it with None.
+    /// A connection idle for `interval` is pinged, and closed when `max_missed` pings in a row are unanswered.
+    /// Pings are answered by the transport of the client
+    pub fn set_keepalive(&mut self, keepalive: Option<(Duration, u32)>) {
+        self.keepalive = keepalive;
     }
 
-    pub fn del_client(
-        &self,
-        user: PubKey,
-        client_id: PubKey,
-        sig: Sig,
-    ) -> Result<(), ProtocolError> {
-        // TODO implement del_client
-        Ok(())
+    /// Sets the maximum number of requests handled at the same time over all the connections,
+    /// or removes the limit with None.
+    /// Each connection runs in its own task, and the store accesses of a request are synchronous,
+    /// so the limit keeps a burst of requests from oversubscribing the disk.
+    /// Requests above it wait for their turn, the blocks of a stream are sent outside of the limit
+    pub fn set_store_concurrency(&mut self, max: Option<usize>) {
+        self.store_limiter = max.map(|max| StoreLimiter::new(max));
     }
 
-    pub fn connect_overlay(&self, user: PubKey, overlay: OverlayId) -> Result<(), ProtocolError> {
-        // TODO check that the broker has already joined this overlay. if not, send OverlayNotJoined
-        Err(ProtocolError::OverlayNotJoined)
+    /// Wait for the turn of a request to access the stores, see `set_store_concurrency`.
+    /// The turn ends when the permit is dropped
+    pub async fn store_permit(&self) -> Option<StorePermit<'_>> {
+        match &self.store_limiter {
+            Some(limiter) => Some(limiter.acquire().await),
+            None => None,
+        }
     }
 
-    pub fn del_object(
+    /// Search the fallback source for a block missing locally, and store the blocks found.
+    /// Concurrent calls for the same block share a single search.
+    /// Returns false if there is no fallback or nothing was found
+    async fn fetch_from_fallback(
         &self,
-        user: PubKey,
-        overlay: Digest,
-        id: ObjectId,
-    ) -> Result<(), ProtocolError> {
-        self.get_repostore_from_overlay_id(&overlay, |store| {
-            // TODO, only admin users can delete on a store on this broker
-            let obj = Object::load(id, None, store);
-            if obj.is_err() {
-                return Err(ProtocolError::NotFound);
-            }
-            let o = obj.ok().unwrap();
-            let mut deduplicated: HashSet<ObjectId> = HashSet::new();
-            for block in o.blocks() {
-                let id = block.id();
-                if deduplicated.get(&id).is_none() {
-                    store.del(&id)?;
-                    deduplicated.insert(id);
+        overlay: &OverlayId,
+        id: &BlockId,
+        include_children: bool,
+    ) -> bool {
+        let (source, timeout) = match &self.block_fallback {
+            Some(fallback) => fallback,
+            None => return false,
+        };
+        let key = (*overlay, *id, include_children);
+        let in_flight = {
+            let mut in_flight = self.in_flight.write().unwrap();
+            match in_flight.get(&key) {
+                Some(r) => Err(r.clone()),
+                None => {
+                    let (s, r) = async_channel::bounded::<()>(1);
+                    in_flight.insert(key, r);
+                    Ok(s)
                 }
             }
-            Ok(())
-        })
+        };
+        match in_flight {
+            Err(r) => {
+                // wait for the search in progress to end, the caller then looks up the store again
+                let _ = r.recv().await;
+                true
+            }
+            Ok(done) => {
+                let _search = InFlightSearch {
+                    searches: &self.in_flight,
+                    key,
+                    done,
+                };
+                self.search_fallback(source.as_ref(), *timeout, overlay, id, include_children)
+                    .await
+            }
+        }
     }
 
-    pub fn pin_object(
+    /// Collects the blocks the fallback source finds until the timeout,
+    /// and stores the requested block and, with `include_children`, the blocks reachable from it.
+    /// Other blocks sent by the source are dropped
+    async fn search_fallback(
         &self,
-        user: PubKey,
-        overlay: OverlayId,
-        id: ObjectId,
-    ) -> Result<(), ProtocolError> {
-        self.get_repostore_from_overlay_id(&overlay, |store| {
-            // TODO, store the user who pins, and manage reference counting on how many users pin/unpin
-            let obj = Object::load(id, None, store);
-            if obj.is_err() {
-                return Err(ProtocolError::NotFound);
-            }
-            let o = obj.ok().unwrap();
-            let mut deduplicated: HashSet<ObjectId> = HashSet::new();
-            for block in o.blocks() {
-                let id = block.id();
-                if deduplicated.get(&id).is_none() {
-                    store.pin(&id)?;
-                    deduplicated.insert(id);
+        source: &dyn BlockSource,
+        timeout: Duration,
+        overlay: &OverlayId,
+        id: &BlockId,
+        include_children: bool,
+    ) -> bool {
+        let r = source.search_block(overlay, id, include_children);
+        let mut found: HashMap<BlockId, Block> = HashMap::new();
+        let _ = runtime::timeout(timeout, async {
+            while let Ok(block) = r.recv().await {
+                if block.validate().is_ok() {
+                    found.insert(block.id(), block);
                 }
             }
-            Ok(())
         })
-    }
+        .await;
 
-    pub fn unpin_object(
-        &self,
-        user: PubKey,
-        overlay: OverlayId,
-        id: ObjectId,
-    ) -> Result<(), ProtocolError> {
-        self.get_repostore_from_overlay_id(&overlay, |store| {
-            // TODO, store the user who pins, and manage reference counting on how many users pin/unpin
-            let obj = Object::load(id, None, store);
-            if obj.is_err() {
-                return Err(ProtocolError::NotFound);
+        let mut blocks: Vec<Block> = vec![];
+        let mut next = vec![*id];
+        while let Some(block_id) = next.pop() {
+            if let Some(block) = found.remove(&block_id) {
+                if include_children {
+                    next.extend(block.children().iter().cloned());
+                }
+                blocks.push(block);
             }
-            let o = obj.ok().unwrap();
-            let mut deduplicated: HashSet<ObjectId> = HashSet::new();
-            for block in o.blocks() {
-                let id = block.id();
-                if deduplicated.get(&id).is_none() {
-                    store.unpin(&id)?;
-                    deduplicated.insert(id);
+        }
+        debug_println!("fallback found {} blocks for {}", blocks.len(), id);
+        if blocks.is_empty() {
+            return false;
+        }
+        self.get_repostore_from_overlay_id(overlay, |store| {
+            for block in blocks.iter() {
+                let _ = store.put(block)?;
+                if let Some(cache) = &self.not_found_cache {
+                    cache.invalidate(overlay, &block.id());
                 }
             }
             Ok(())
         })
+        .is_ok()
     }
 
-    pub fn copy_object(
-        &self,
-        user: PubKey,
-        overlay: OverlayId,
-        id: ObjectId,
-        expiry: Option<Timestamp>,
-    ) -> Result<ObjectId, ProtocolError> {
-        // self.get_repostore_from_overlay_id(&overlay, |store| {
-        //     //let obj = Object::from_store(id, None, store);
-        //     //Ok(Object::copy(id, expiry, store)?)
-        // });
-        todo!();
+    /// Sets the durability of the writes to the repo stores opened from now on.
+    /// With Durability::NoSync, blocks are ingested faster but may be lost on a system crash
+    /// unless `flush` is called
+    pub fn set_repo_store_durability(&mut self, durability: Durability) {
+        self.repo_store_durability = durability;
     }
 
-    pub fn put_block(
-        &self,
-        user: PubKey,
-        overlay: OverlayId,
-        block: &Block,
-    ) -> Result<(), ProtocolError> {
-        self.get_repostore_from_overlay_id(&overlay, |store| {
-            let _ = store.put(block)?;
-            Ok(())
-        })
+    /// Sets the maximum metadata sizes of the commits published through the broker,
+    /// usually the limits of the repo settings.
+    /// Commits with larger metadata are rejected with ProtocolError::MetadataTooLarge
+    pub fn set_metadata_limits(&mut self, limits: MetadataLimits) {
+        self.metadata_limits = limits;
     }
 
-    pub fn get_block(
-        &self,
-        user: PubKey,
-        overlay: OverlayId,
-        id: BlockId,
-        include_children: bool,
-        topic: Option<PubKey>,
-    ) -> Result<async_channel::Receiver<Block>, ProtocolError> {
-        self.get_repostore_from_overlay_id(&overlay, |store| {
-            let (s, r) = async_channel::unbounded::<Block>();
-            if !include_children {
-                let block = store.get(&id)?;
-                s.send_blocking(block)
-                    .map_err(|_e| ProtocolError::WriteError)?;
-                Ok(r)
-            } else {
-                let obj = Object::load(id, None, store);
-                // TODO return partial blocks when some are missing ?
-                if obj.is_err() {
-                    //&& obj.err().unwrap().len() == 1 && obj.err().unwrap()[0] == id {
-                    return Err(ProtocolError::NotFound);
-                }
-                // TODO use a task to send non blocking (streaming)
-                let o = obj.ok().unwrap();
-                //debug_println!("{} BLOCKS ", o.blocks().len());
-                let mut deduplicated: HashSet<BlockId> = HashSet::new();
-                for block in o.blocks() {
-                    let id = block.id();
-                    if deduplicated.get(&id).is_none() {
-                        s.send_blocking(block.clone())
-                            .map_err(|_e| ProtocolError::WriteError)?;
-                        deduplicated.insert(id);
-                    }
-                }
-                Ok(r)
-            }
-        })
+    /// Sets the maximum numbers of overlays and topics of the users without limits of their own.
+    /// Joins and subscriptions over the limits are rejected with ProtocolError::LimitExceeded
+    pub fn set_account_limits(&mut self, limits: AccountLimits) {
+        self.account_limits = limits;
     }
 
-    pub fn sync_branch(
+    /// Sets the limits of a user, overriding the ones set with `set_account_limits`
+    pub fn set_user_limits(
         &self,
         user: PubKey,
-        overlay: &OverlayId,
-        heads: &Vec<ObjectId>,
-        known_heads: &Vec<ObjectId>,
-        known_commits: &BloomFilter,
-    ) -> Result<async_channel::Receiver<Block>, ProtocolError> {
-        //debug_println!("heads {:?}", heads);
-        //debug_println!("known_heads {:?}", known_heads);
-        //debug_println!("known_commits {:?}", known_commits);
+        limits: AccountLimits,
+    ) -> Result<(), ProtocolError> {
+        let account = Account::open(&user, &self.store)?;
+        Ok(account.set_limits(&limits)?)
+    }
 
-        self.get_repostore_from_overlay_id(&overlay, |store| {
-            let (s, r) = async_channel::unbounded::<Block>();
+    fn limits_of(&self, account: &Account) -> Result<AccountLimits, ProtocolError> {
+        Ok(account.limits()?.unwrap_or(self.account_limits))
+    }
 
-            let res = Branch::sync_req(heads, known_heads, known_commits, store)
-                .map_err(|e| ProtocolError::ObjectParseError)?;
+    /// Sets the maximum size of the content of the objects put with `put_object`,
+    /// usually the limit of the repo settings.
+    /// Larger objects are rejected with ProtocolError::ObjectTooLarge
+    pub fn set_max_object_size(&mut self, max: u64) {
+        self.max_object_size = max;
+    }
 
-            // todo, use a task to send non blocking (streaming)
-            debug_println!("SYNCING {} COMMITS", res.len());
+    /// Sets the time the tombstones of deleted objects are kept.
+    /// Until then, the broker refuses the deleted objects when they are put again
+    pub fn set_tombstone_retention(&mut self, retention: RelTime) {
+        self.tombstone_retention = retention;
+    }
 
-            let mut deduplicated: HashSet<BlockId> = HashSet::new();
+    /// Sets the maximum time until the expiry of the blocks put through the broker.
+    /// Later expiries set by the clients are brought back to it, see `clamp_expiry`
+    pub fn set_max_expiry(&mut self, max: RelTime) {
+        self.max_expiry = max;
+    }
 
-            for objectid in res {
-                let object = Object::load(objectid, None, store)?;
+    /// Bring an expiry set by a client into the range accepted by the broker.
+    /// Clients base their timestamps on their own clock, which can be skewed:
+    /// an expiry already past at the broker is moved to the next minute,
+    /// and an expiry after the maximum expiry is moved back to it.
+    /// A maximum expiry under a minute brings every expiry to the next minute
+    fn clamp_expiry(&self, expiry: Timestamp) -> Timestamp {
+        let now = now_timestamp();
+        let earliest = now + RelTime::Minutes(1);
+        expiry.clamp(earliest, max(earliest, now + self.max_expiry))
+    }
 
-                for block in object.blocks() {
-                    let id = block.id();
-                    if deduplicated.get(&id).is_none() {
-                        s.send_blocking(block.clone())
-                            .map_err(|_e| ProtocolError::WriteError)?;
-                        deduplicated.insert(id);
-                    }
+    /// Override the stored expiry of the blocks whose own expiry is out of the accepted range
+    fn clamp_block_expiries<'a>(
+        &self,
+        store: &LmdbRepoStore,
+        blocks: impl Iterator<Item = &'a Block>,
+    ) -> Result<(), ProtocolError> {
+        for block in blocks {
+            if let Some(expiry) = block.expiry() {
+                let clamped = self.clamp_expiry(expiry);
+                if clamped != expiry {
+                    store.set_expiry(&[block.id()], Some(clamped))?;
                 }
             }
-            Ok(r)
-        })
+        }
+        Ok(())
     }
 
-    fn compute_repostore_id(&self, overlay: OverlayId, repo_id: Option<PubKey>) -> RepoStoreId {
-        match self.mode {
-            ConfigMode::Core => RepoStoreId::Overlay(overlay),
-            ConfigMode::Local => RepoStoreId::Repo(repo_id.unwrap()),
+    /// Forces the writes to the broker store and to all the open repo stores to disk.
+    /// To be called on graceful shutdown, and periodically when the stores don't sync on commit
+    pub fn flush(&self) -> Result<(), ProtocolError> {
+        self.store.flush()?;
+        for repo in self
+            .repo_stores
+            .read()
+            .expect("read repo_store hashmap")
+            .values()
+        {
+            repo.flush()?;
         }
+        Ok(())
     }
 
-    pub fn join_overlay(
+    /// Removes the expired blocks from all the open repo stores.
+    /// To be called periodically
+    pub fn remove_expired(&self) -> Result<(), ProtocolError> {
+        self.remove_expired_at(&SystemClock)
+    }
+
+    fn remove_expired_at(&self, clock: &impl Clock) -> Result<(), ProtocolError> {
+        for repo in self
+            .repo_stores
+            .read()
+            .expect("read repo_store hashmap")
+            .values()
+        {
+            repo.remove_expired_at(clock)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the blocks expired at `now` from all the open repo stores,
+    /// keeping the pinned objects. Returns the total of blocks deleted and space reclaimed.
+    /// Also prunes the records of the blocks removed longer ago than the tombstone retention,
+    /// which replicas resuming from an older checkpoint won't get as gaps anymore
+    pub fn garbage_collect(&self, now: Timestamp) -> Result<GcReport, ProtocolError> {
+        let mut total = GcReport::default();
+        for repo in self
+            .repo_stores
+            .read()
+            .expect("read repo_store hashmap")
+            .values()
+        {
+            let report = repo.garbage_collect(now)?;
+            repo.prune_removed(now - self.tombstone_retention)?;
+            total.blocks += report.blocks;
+            total.bytes += report.bytes;
+        }
+        Ok(total)
+    }
+
+    /// Removes the tombstones of an overlay that are older than the tombstone retention.
+    /// To be called periodically
+    pub fn remove_expired_tombstones(&self, overlay: &OverlayId) -> Result<(), ProtocolError> {
+        self.remove_expired_tombstones_at(overlay, &SystemClock)
+    }
+
+    fn remove_expired_tombstones_at(
         &self,
-        user: PubKey,
-        overlay_id: OverlayId,
-        repo_id: Option<PubKey>,
-        secret: SymKey,
-        peers: &Vec<PeerAdvert>,
+        overlay: &OverlayId,
+        clock: &impl Clock,
     ) -> Result<(), ProtocolError> {
-        // check if this overlay already exists
-        //debug_println!("SEARCHING OVERLAY");
-        let overlay_res = Overlay::open(&overlay_id, &self.store);
-        let overlay = match overlay_res {
-            Err(StorageError::NotFound) => {
-                // we have to add it
-                if self.mode == ConfigMode::Local && repo_id.is_none() {
-                    return Err(ProtocolError::RepoIdRequired);
-                }
-                let over = Overlay::create(
-                    &overlay_id,
-                    &secret,
-                    if self.mode == ConfigMode::Local {
-                        repo_id
-                    } else {
-                        None
-                    },
-                    &self.store,
-                )?;
-                // we need to add an encryption key for the repostore.
-                let mut random_buf = [0u8; 32];
-                getrandom::getrandom(&mut random_buf).unwrap();
-                let key = SymKey::ChaCha20Key(random_buf);
-
-                let _ = RepoStoreInfo::create(
-                    &self.compute_repostore_id(overlay_id, repo_id),
-                    &key,
-                    &self.store,
-                )?; // TODO in case of error, delete the previously created Overlay
-                    //debug_println!("KEY ADDED");
-                over
+        let tombstones = Tombstones::new(overlay, &self.store);
+        for tombstone in tombstones.list()? {
+            if self.is_tombstone_expired(&tombstone, clock) {
+                tombstones.remove(&tombstone.object())?;
             }
-            Err(e) => return Err(e.into()),
-            Ok(overlay) => overlay,
-        };
-        //debug_println!("OVERLAY FOUND");
-        // add the peers to the overlay
-        for advert in peers {
-            Peer::update_or_create(advert, &self.store)?;
-            overlay.add_peer(&advert.peer())?;
         }
-        //debug_println!("PEERS ADDED");
-
-        // now adding the overlay_id to the account
-        let account = Account::open(&user, &self.store)?; // TODO in case of error, delete the previously created Overlay
-        account.add_overlay(&overlay_id)?;
-        //debug_println!("USER <-> OVERLAY");
+        Ok(())
+    }
 
-        //TODO: connect to peers
+    fn is_tombstone_expired(&self, tombstone: &Tombstone, clock: &impl Clock) -> bool {
+        tombstone.deleted_at() + self.tombstone_retention < clock.now()
+    }
 
+    /// Sets the expiry of the objects put in an overlay without an expiry of their own,
+    /// or removes it with None. Only applies to the objects put from now on
+    pub fn set_overlay_default_expiry(
+        &self,
+        overlay_id: OverlayId,
+        expiry: Option<RelTime>,
+    ) -> Result<(), ProtocolError> {
+        let overlay = Overlay::open(&overlay_id, &self.store)?;
+        let mut meta = overlay.metadata()?;
+        meta.default_expiry = expiry;
+        overlay.set_metadata(&meta)?;
         Ok(())
     }
+
+    /// Sets the channel of the messages to send upstream, such as the UnsubReq of topics without subscribers left
+    pub fn set_upstream(
+        &mut self,
+        sender: async_channel::Sender<(OverlayId, OverlayMessageContentV0)>,
+    ) {
+        self.upstream = Some(sender);
+    }
+
+    /// Sets the maximum number of PeerAdverts relayed upstream per minute, or removes the bound with None.
+    /// Adverts above the rate are dropped, not queued
+    pub fn set_advert_relay_rate(&mut self, rate: Option<u32>) {
+        self.advert_relay_limiter = rate.map(|rate| AdvertRelayLimiter::new(rate));
+    }
+
+    fn send_upstream(&self, overlay: &OverlayId, msg: OverlayMessageContentV0) {
+        if let Some(upstream) = &self.upstream {
+            let _ = upstream.try_send((*overlay, msg));
+        }
+    }
+
+    fn is_not_found_cached(&self, overlay: &OverlayId, id: &BlockId) -> bool {
+        match &self.not_found_cache {
+            Some(cache) => cache.contains(overlay, id, &SystemClock),
+            None => false,
+        }
+    }
+
+    fn cache_not_found(&self, overlay: &OverlayId, id: &BlockId) {
+        if let Some(cache) = &self.not_found_cache {
+            cache.insert(*overlay, *id, &SystemClock);
+        }
+    }
+
+    /// Stores a PeerAdvert received in an overlay, and relays it upstream with a decremented TTL in ConfigMode::Core.
+    /// Adverts that are not newer than the stored advert of the peer are not relayed again.
+    /// Relaying is bounded by the advert relay rate: excess adverts are dropped,
+    /// while the most recent advert of each peer is kept in the store regardless.
+    /// Returns whether the advert was relayed
+    pub fn relay_peer_advert(
+        &self,
+        overlay_id: OverlayId,
+        advert: &PeerAdvert,
+    ) -> Result<bool, ProtocolError> {
+        self.relay_peer_advert_at(overlay_id, advert, &SystemClock)
+    }
+
+    fn relay_peer_advert_at(
+        &self,
+        overlay_id: OverlayId,
+        advert: &PeerAdvert,
+        clock: &impl Clock,
+    ) -> Result<bool, ProtocolError> {
+        let content = serde_bare::to_vec(advert.content())?;
+        verify(&content, advert.sig(), *advert.peer())
+            .map_err(|_e| ProtocolError::InvalidSignature)?;
+        let overlay = Overlay::open(&overlay_id, &self.store)?;
+        let newer = match Peer::open(advert.peer(), &self.store) {
+            Ok(peer) => peer.advert()?.version() < advert.version(),
+            Err(StorageError::NotFound) => true,
+            Err(e) => return Err(e.into()),
+        };
+        Peer::update_or_create(advert, &self.store)?;
+        overlay.add_peer(advert.peer())?;
+
+        if !newer || self.mode != ConfigMode::Core || advert.ttl() <= 1 {
+            return Ok(false);
+        }
+        if let Some(limiter) = &self.advert_relay_limiter {
+            if !limiter.try_acquire(clock) {
+                debug_println!("dropped advert of peer {}", advert.peer());
+                return Ok(false);
+            }
+        }
+        let relayed = match advert.clone() {
+            PeerAdvert::V0(mut a) => {
+                a.ttl -= 1;
+                PeerAdvert::V0(a)
+            }
+        };
+        self.send_upstream(&overlay_id, OverlayMessageContentV0::PeerAdvert(relayed));
+        Ok(true)
+    }
+
+    /// Build and sign the PeerAdvert of this broker, listening on the given addresses
+    pub fn self_advert(&self, listen: &[IPTransportAddr], priv_key: PrivKey) -> PeerAdvert {
+        let content = PeerAdvertContentV0 {
+            peer: pubkey_from_privkey(priv_key),
+            subs: [[0; 32]; 4],
+            address: listen.iter().map(|a| NetAddr::IPTransport(*a)).collect(),
+            // newer adverts replace older ones
+            version: now_timestamp().as_minutes(),
+            metadata: vec![],
+        };
+        let sig = sign(
+            priv_key,
+            content.peer,
+            &serde_bare::to_vec(&content).unwrap(),
+        )
+        .unwrap();
+        PeerAdvert::V0(PeerAdvertV0 {
+            content,
+            sig,
+            ttl: DEFAULT_ADVERT_TTL,
+        })
+    }
+
+    pub fn check_overlay_allowed(&self, overlay: &OverlayId) -> Result<(), ProtocolError> {
+        if !self.overlay_allowlist.is_empty() && !self.overlay_allowlist.contains(overlay) {
+            return Err(ProtocolError::OverlayNotAllowed);
+        }
+        Ok(())
+    }
+
+    pub fn check_heads_count(&self, heads: &Vec<ObjectId>) -> Result<(), ProtocolError> {
+        if heads.len() > self.max_sync_heads {
+            return Err(ProtocolError::TooManyHeads);
+        }
+        Ok(())
+    }
+
+    fn open_or_create_repostore<F, R>(
+        &self,
+        repostore_id: RepoStoreId,
+        f: F,
+    ) -> Result<R, ProtocolError>
+    where
+        F: FnOnce(&LmdbRepoStore) -> Result<R, ProtocolError>,
+    {
+        // first let's find it in the BrokerStore.repostoreinfo table in order to get the encryption key
+        let info = RepoStoreInfo::open(&repostore_id, &self.store)
+            .map_err(|e| BrokerError::OverlayNotFound)?;
+        let key = info.key()?;
+        let mut path = self.store.path();
+        path.push(REPO_STORES_SUBDIR);
+        path.push::<String>(repostore_id.clone().into());
+        std::fs::create_dir_all(path.clone()).map_err(|_e| ProtocolError::WriteError )?;
+        println!("path for repo store: {}", path.to_str().unwrap());
+        let repo =
+            LmdbRepoStore::open_with_durability(&path, *key.slice(), self.repo_store_durability);
+        let mut writer = self.repo_stores.write().expect("write repo_store hashmap");
+        writer.insert(repostore_id.clone(), repo);
+
+        f(writer.get(&repostore_id).unwrap())
+    }
+
+    fn get_repostore_from_overlay_id<F, R>(
+        &self,
+        overlay_id: &OverlayId,
+        f: F,
+    ) -> Result<R, ProtocolError>
+    where
+        F: FnOnce(&LmdbRepoStore) -> Result<R, ProtocolError>,
+    {
+        if self.mode == ConfigMode::Core {
+            let repostore_id = RepoStoreId::Overlay(*overlay_id);
+            let reader = self.repo_stores.read().expect("read repo_store hashmap");
+            let rep = reader.get(&repostore_id);
+            match rep {
+                Some(repo) => return f(repo),
+                None => {
+                    // we need to open/create it
+                    // TODO: last_access
+                    return self.open_or_create_repostore(repostore_id, |repo| f(repo));
+                }
+            }
+        } else {
+            // it is ConfigMode::Local
+            {
+                let reader = self
+                    .overlayid_to_repostore
+                    .read()
+                    .expect("read overlayid_to_repostore hashmap");
+                match reader.get(&overlay_id) {
+                    Some(repostoreid) => {
+                        let reader = self.repo_stores.read().expect("read repo_store hashmap");
+                        match reader.get(repostoreid) {
+                            Some(repo) => return f(repo),
+                            None => return Err(ProtocolError::BrokerError),
+                        }
+                    }
+                    None => {}
+                };
+            }
+
+            // we need to open/create it
+            // first let's find it in the BrokerStore.overlay table to retrieve its repo_pubkey
+            debug_println!("searching for overlayId {}", overlay_id);
+            let overlay = Overlay::open(overlay_id, &self.store)?;
+            debug_println!("found overlayId {}", overlay_id);
+            let repo_id = overlay.repo()?;
+            let repostore_id = RepoStoreId::Repo(repo_id);
+            let mut writer = self
+                .overlayid_to_repostore
+                .write()
+                .expect("write overlayid_to_repostore hashmap");
+            writer.insert(*overlay_id, repostore_id.clone());
+            // now opening/creating the RepoStore
+            // TODO: last_access
+            return self.open_or_create_repostore(repostore_id, |repo| f(repo));
+        }
+    }
+
+    pub fn local_connection(&self, user: PubKey) -> BrokerConnectionLocal {
+        BrokerConnectionLocal::new(self, user)
+    }
+
+    pub fn protocol_handler(self: Arc<Self>) -> ProtocolHandler {
+        let (s, r) = async_channel::unbounded::<Vec<u8>>();
+        return ProtocolHandler {
+            broker: Arc::clone(&self),
+            protocol: ProtocolType::Start,
+            auth_protocol: None,
+            broker_protocol: None,
+            ext_protocol: None,
+            r: Some(r),
+            s,
+        };
+    }
+
+    /// Adds an admin of the broker, with an admin account.
+    /// The AddUser and DelUser requests must be signed by one of the admins,
+    /// they are refused until one is added, from the configuration of the broker
+    pub fn add_admin(&self, admin: PubKey) -> Result<(), ProtocolError> {
+        Config::open(&self.store)?.add_admin(&admin)?;
+        match Account::open(&admin, &self.store) {
+            Err(StorageError::NotFound) => {
+                Account::create(&admin, true, &self.store)?;
+            }
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Verifies the signature of an admin request against the stored admins.
+    /// Requests are refused with ProtocolError::AccessDenied until an admin is added with `add_admin`.
+    /// The request must also be fresh, see `check_request_freshness`
+    fn verify_admin_request(
+        &self,
+        content: &Vec<u8>,
+        timestamp: Timestamp,
+        sig: Sig,
+    ) -> Result<(), ProtocolError> {
+        let admins = Config::open(&self.store)?.admins()?;
+        if admins.is_empty() {
+            return Err(ProtocolError::AccessDenied);
+        }
+        if !admins
+            .into_iter()
+            .any(|admin| verify(content, sig, admin).is_ok())
+        {
+            return Err(ProtocolError::InvalidSignature);
+        }
+        self.check_request_freshness(content, timestamp)
+    }
+
+    /// Checks that a signed request was made within SIGNED_REQUEST_VALIDITY of the broker clock,
+    /// and accepts it only once, so that a captured request can't be replayed
+    fn check_request_freshness(
+        &self,
+        content: &Vec<u8>,
+        timestamp: Timestamp,
+    ) -> Result<(), ProtocolError> {
+        let now = now_timestamp();
+        if timestamp + SIGNED_REQUEST_VALIDITY < now || timestamp > now + SIGNED_REQUEST_VALIDITY {
+            return Err(ProtocolError::InvalidSignature);
+        }
+        // the requests older than the validity are refused above, they don't need to be kept
+        let mut accepted = self.signed_requests.write().expect("write signed_requests");
+        accepted.retain(|_, t| *t + SIGNED_REQUEST_VALIDITY >= now);
+        if accepted.insert(content.clone(), timestamp).is_some() {
+            return Err(ProtocolError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// Adds a user account, with a signature of an admin
+    pub fn add_user(&self, op_content: AddUserContentV0, sig: Sig) -> Result<(), ProtocolError> {
+        let user_id = op_content.user;
+        debug_println!("ADDING USER {}", user_id);
+
+        // verify signature
+        self.verify_admin_request(&serde_bare::to_vec(&op_content)?, op_content.timestamp, sig)?;
+
+        // check user_id is not already present
+        let account = Account::open(&user_id, &self.store);
+        if account.is_ok() {
+            Err(ProtocolError::UserAlreadyExists)
+        }
+        // if not, add to store
+        else {
+            let _ = Account::create(&user_id, false, &self.store)?;
+            Ok(())
+        }
+    }
+
+    /// Deletes the user account.
+    /// The user is unsubscribed from all its topics, removed from its overlays,
+    /// and its account is deleted with its clients, all in a single transaction.
+    /// The sessions of the user are then closed
+    pub fn del_user(&self, op_content: DelUserContentV0, sig: Sig) -> Result<(), ProtocolError> {
+        let user_id = op_content.user;
+        debug_println!("DELETING USER {}", user_id);
+
+        // verify signature
+        self.verify_admin_request(&serde_bare::to_vec(&op_content)?, op_content.timestamp, sig)?;
+
+        let account = Account::open(&user_id, &self.store)?;
+        // subscriber counts of the topics, the ones left without subscribers are removed from their overlay
+        let mut topics = vec![];
+        for (overlay_id, topic_id) in account.topics()? {
+            let topic = Topic::open(&topic_id, &self.store)?;
+            let users = topic.metadata()?.users.saturating_sub(1);
+            let overlay = if users == 0 {
+                Overlay::open(&overlay_id, &self.store).ok()
+            } else {
+                None
+            };
+            topics.push((overlay_id, topic, users, overlay));
+        }
+        let mut overlays = vec![];
+        for overlay_id in account.overlays()? {
+            if let Ok(overlay) = Overlay::open(&overlay_id, &self.store) {
+                let mut meta = overlay.metadata()?;
+                meta.users = meta.users.saturating_sub(1);
+                overlays.push((overlay, meta));
+            }
+        }
+
+        self.store.transaction(&mut |tx| {
+            for (_, topic, users, overlay) in &topics {
+                if *users > 0 {
+                    topic.set_metadata_in(tx, &TopicMeta { users: *users })?;
+                    continue;
+                }
+                topic.del_in(tx)?;
+                if let Some(overlay) = overlay {
+                    overlay.remove_topic_in(tx, &topic.id())?;
+                }
+            }
+            for (overlay, meta) in &overlays {
+                overlay.set_metadata_in(tx, meta)?;
+            }
+            account.del_in(tx)
+        })?;
+
+        for (overlay_id, topic, users, _) in &topics {
+            if *users == 0 {
+                debug_println!("no subscribers left for topic {}", topic.id());
+                self.send_upstream(
+                    overlay_id,
+                    OverlayMessageContentV0::UnsubReq(UnsubReq::V0(UnsubReqV0 {
+                        topic: topic.id(),
+                    })),
+                );
+            }
+        }
+        self.sessions
+            .write()
+            .unwrap()
+            .retain(|(user, _client), sessions| {
+                if *user != user_id {
+                    return true;
+                }
+                for session in sessions.iter() {
+                    session.close();
+                }
+                false
+            });
+        Ok(())
+    }
+
+    /// Subscribes the user to a topic of an overlay
+    pub fn subscribe_topic(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+        topic_id: TopicId,
+    ) -> Result<(), ProtocolError> {
+        self.check_account(user)?;
+        let account = Account::open(&user, &self.store)?;
+        if account.has_topic(&overlay_id, &topic_id).is_ok() {
+            return Ok(());
+        }
+        if account.topics()?.len() >= self.limits_of(&account)?.max_topics as usize {
+            return Err(ProtocolError::LimitExceeded);
+        }
+        let overlay = Overlay::open(&overlay_id, &self.store)?;
+        let topic = match Topic::open(&topic_id, &self.store) {
+            Err(StorageError::NotFound) => Topic::create(&topic_id, &self.store)?,
+            Err(e) => return Err(e.into()),
+            Ok(topic) => topic,
+        };
+        let meta = topic.metadata()?;
+        topic.set_metadata(&TopicMeta {
+            users: meta.users + 1,
+        })?;
+        overlay.add_topic(&topic_id)?;
+        account.add_topic(&overlay_id, &topic_id)?;
+        Ok(())
+    }
+
+    /// Unsubscribes the user from a topic of an overlay.
+    /// Once the topic has no subscribers left, it is removed and an UnsubReq is sent upstream
+    pub fn unsubscribe_topic(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+        topic_id: TopicId,
+    ) -> Result<(), ProtocolError> {
+        let account = Account::open(&user, &self.store)?;
+        if account.has_topic(&overlay_id, &topic_id).is_err() {
+            return Ok(());
+        }
+        account.remove_topic(&overlay_id, &topic_id)?;
+        let topic = Topic::open(&topic_id, &self.store)?;
+        let users = topic.metadata()?.users.saturating_sub(1);
+        if users > 0 {
+            topic.set_metadata(&TopicMeta { users })?;
+            return Ok(());
+        }
+        debug_println!("no subscribers left for topic {}", topic_id);
+        topic.del()?;
+        if let Ok(overlay) = Overlay::open(&overlay_id, &self.store) {
+            overlay.remove_topic(&topic_id)?;
+        }
+        self.send_upstream(
+            &overlay_id,
+            OverlayMessageContentV0::UnsubReq(UnsubReq::V0(UnsubReqV0 { topic: topic_id })),
+        );
+        Ok(())
+    }
+
+    /// Connects the user to a topic it has subscribed.
+    /// Returns the stream of the events published in the topic from now on,
+    /// which stops receiving them once closed
+    pub fn connect_topic(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+        topic_id: TopicId,
+    ) -> Result<async_channel::Receiver<Event>, ProtocolError> {
+        let account =
+            Account::open(&user, &self.store).map_err(|_e| ProtocolError::AccessDenied)?;
+        account
+            .has_topic(&overlay_id, &topic_id)
+            .map_err(|_e| ProtocolError::InvalidState)?;
+        let (s, r) = async_channel::unbounded::<Event>();
+        self.topic_events
+            .write()
+            .unwrap()
+            .entry((overlay_id, topic_id))
+            .or_default()
+            .push(s);
+        Ok(r)
+    }
+
+    /// Publishes an event signed by the topic key, to all the connections connected to the topic.
+    /// The streams closed since the last event are dropped
+    pub fn publish_event(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+        event: &Event,
+    ) -> Result<(), ProtocolError> {
+        self.check_overlay_access(user, &overlay_id)?;
+        event
+            .verify()
+            .map_err(|_e| ProtocolError::InvalidSignature)?;
+        let key = (overlay_id, *event.topic());
+        let mut topic_events = self.topic_events.write().unwrap();
+        if let Some(senders) = topic_events.get_mut(&key) {
+            senders.retain(|s| s.try_send(*event).is_ok());
+            if senders.is_empty() {
+                topic_events.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a stream of the connections and disconnections of the peers of an overlay
+    pub fn peer_events(&self, overlay_id: OverlayId) -> impl Stream<Item = PeerEvent> {
+        let (s, r) = async_channel::unbounded::<PeerEvent>();
+        self.peer_events
+            .write()
+            .unwrap()
+            .entry(overlay_id)
+            .or_default()
+            .push(s);
+        r
+    }
+
+    /// Records whether a peer of an overlay is connected.
+    /// When the stored flag flips, the event is sent to the streams returned by `peer_events`,
+    /// and the streams closed since the last event are dropped
+    pub fn set_peer_connected(
+        &self,
+        overlay_id: OverlayId,
+        peer: &PeerId,
+        connected: bool,
+    ) -> Result<(), ProtocolError> {
+        let overlay = Overlay::open(&overlay_id, &self.store)?;
+        if !overlay.set_peer_connected(peer, connected)? {
+            return Ok(());
+        }
+        let event = if connected {
+            PeerEvent::Connected(*peer)
+        } else {
+            PeerEvent::Disconnected(*peer)
+        };
+        let mut peer_events = self.peer_events.write().unwrap();
+        if let Some(senders) = peer_events.get_mut(&overlay_id) {
+            senders.retain(|s| s.try_send(event.clone()).is_ok());
+            if senders.is_empty() {
+                peer_events.remove(&overlay_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Accepts a new commit in the topic of a branch, once its blocks are stored in the overlay.
+    /// The commit is verified against the branch, and becomes a head of the topic.
+    /// Its type and body are recorded, for the syncs filtered by commit type.
+    /// A SealBranch commit seals the topic, after which new commits are rejected with ProtocolError::BranchSealed
+    /// Commits with metadata larger than the limits set with `set_metadata_limits` are rejected
+    pub fn publish_commit(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+        branch: &Branch,
+        commit_ref: ObjectRef,
+    ) -> Result<(), ProtocolError> {
+        self.check_overlay_access(user, &overlay_id)?;
+        let topic_id = branch.topic();
+        let topic = match Topic::open(&topic_id, &self.store) {
+            Err(StorageError::NotFound) => Topic::create(&topic_id, &self.store)?,
+            Err(e) => return Err(e.into()),
+            Ok(topic) => topic,
+        };
+        if topic.sealed()?.is_some() {
+            return Err(ProtocolError::BranchSealed);
+        }
+        let (commit, body) = self.get_repostore_from_overlay_id(&overlay_id, |store| {
+            let commit = Commit::load(commit_ref, store).map_err(|e| match e {
+                CommitLoadError::MissingBlocks(_) => ProtocolError::MissingBlocks,
+                _ => ProtocolError::ObjectParseError,
+            })?;
+            self.metadata_limits.check_commit(&commit)?;
+            commit.verify(branch, store).map_err(|e| match e {
+                CommitVerifyError::InvalidSignature => ProtocolError::InvalidSignature,
+                CommitVerifyError::PermissionDenied | CommitVerifyError::UnauthorizedDevice => {
+                    ProtocolError::AccessDenied
+                }
+                CommitVerifyError::InvalidTimestamp => ProtocolError::InvalidTimestamp,
+                CommitVerifyError::BodyLoadError(_) | CommitVerifyError::DepLoadError(_) => {
+                    ProtocolError::MissingBlocks
+                }
+            })?;
+            let body = commit
+                .load_body(store)
+                .map_err(|_e| ProtocolError::ObjectParseError)?;
+            Ok((commit, body))
+        })?;
+        topic.add_head(&commit_ref.id)?;
+        // the commits it depends on are not heads anymore
+        for dep in commit.deps_acks() {
+            match topic.remove_head(&dep.id) {
+                Ok(()) | Err(StorageError::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let info = CommitInfoMeta {
+            commit_type: body.to_type(),
+            body: commit.content().body.id,
+        };
+        if CommitInfo::open(&overlay_id, &commit_ref.id, &self.store).is_err() {
+            CommitInfo::create(&overlay_id, &commit_ref.id, &info, &self.store)?;
+        }
+        if body.to_type() == CommitType::SealBranch {
+            debug_println!("sealing topic {}", topic_id);
+            topic.seal(&commit_ref.id)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a client to the account of the user, who signs the request.
+    /// The request must be fresh, see `check_request_freshness`.
+    /// Adding a client twice is a no-op
+    pub fn add_client(
+        &self,
+        user: PubKey,
+        op_content: AddClientContentV0,
+        sig: Sig,
+    ) -> Result<(), ProtocolError> {
+        let client_id = op_content.client;
+        debug_println!("ADDING CLIENT {} TO USER {}", client_id, user);
+        let content = serde_bare::to_vec(&op_content)?;
+        let _ = verify(&content, sig, user)?;
+        self.check_request_freshness(&content, op_content.timestamp)?;
+
+        self.check_account(user)?;
+        let account = Account::open(&user, &self.store)?;
+        if account.has_client(&client_id).is_ok() {
+            return Ok(());
+        }
+        account.add_client(&client_id)?;
+        Ok(())
+    }
+
+    /// Removes a client from the account of the user, who signs the request.
+    /// The request must be fresh, see `check_request_freshness`.
+    /// The sessions authenticated with the client are closed.
+    /// Fails with ProtocolError::NotFound if the user has no such client
+    pub fn del_client(
+        &self,
+        user: PubKey,
+        op_content: DelClientContentV0,
+        sig: Sig,
+    ) -> Result<(), ProtocolError> {
+        let client_id = op_content.client;
+        debug_println!("DELETING CLIENT {} OF USER {}", client_id, user);
+        let content = serde_bare::to_vec(&op_content)?;
+        let _ = verify(&content, sig, user)?;
+        self.check_request_freshness(&content, op_content.timestamp)?;
+
+        self.check_account(user)?;
+        let account = Account::open(&user, &self.store)?;
+        if account.has_client(&client_id).is_err() {
+            return Err(ProtocolError::NotFound);
+        }
+        account.remove_client(&client_id)?;
+
+        let sessions = self.sessions.write().unwrap().remove(&(user, client_id));
+        for session in sessions.unwrap_or_default() {
+            session.close();
+        }
+        Ok(())
+    }
+
+    /// Replaces a client of the user with a new one, the user signs the request.
+    /// The request must be fresh, see `check_request_freshness`.
+    /// The sessions authenticated with the old client are closed.
+    /// Fails with ProtocolError::NotFound if the user has no such old client
+    pub fn rotate_client(
+        &self,
+        user: PubKey,
+        op_content: RotateClientContentV0,
+        sig: Sig,
+    ) -> Result<(), ProtocolError> {
+        let (old_client, new_client) = (op_content.old_client, op_content.new_client);
+        debug_println!(
+            "ROTATING CLIENT {} TO {} OF USER {}",
+            old_client,
+            new_client,
+            user
+        );
+        let content = serde_bare::to_vec(&op_content)?;
+        let _ = verify(&content, sig, user)?;
+        if old_client == new_client {
+            return Err(ProtocolError::InvalidValue);
+        }
+        self.check_request_freshness(&content, op_content.timestamp)?;
+
+        self.check_account(user)?;
+        let account = Account::open(&user, &self.store)?;
+        if account.has_client(&old_client).is_err() {
+            return Err(ProtocolError::NotFound);
+        }
+        // adding first, an interrupted rotation leaves both clients rather than none
+        if account.has_client(&new_client).is_err() {
+            account.add_client(&new_client)?;
+        }
+        account.remove_client(&old_client)?;
+
+        let sessions = self.sessions.write().unwrap().remove(&(user, old_client));
+        for session in sessions.unwrap_or_default() {
+            session.close();
+        }
+        Ok(())
+    }
+
+    /// Check that a client may authenticate for the user.
+    /// Once the user registered clients, only those are accepted.
+    /// Users without an account are refused by their requests instead
+    fn check_client(&self, user: PubKey, client: PubKey) -> Result<(), ProtocolError> {
+        let account = match Account::open(&user, &self.store) {
+            Ok(account) => account,
+            Err(_) => return Ok(()),
+        };
+        let clients = account.clients()?;
+        if clients.is_empty() || clients.contains(&client) {
+            Ok(())
+        } else {
+            Err(ProtocolError::AccessDenied)
+        }
+    }
+
+    /// Register an authenticated connection, closed through its async frames sender
+    /// by `rotate_client`, `del_client` or `del_user`
+    fn open_session(&self, user: PubKey, client: PubKey, frames: async_channel::Sender<Vec<u8>>) {
+        let mut sessions = self.sessions.write().unwrap();
+        let senders = sessions.entry((user, client)).or_insert_with(Vec::new);
+        senders.retain(|s| !s.is_closed());
+        senders.push(frames);
+    }
+
+    pub fn connect_overlay(&self, user: PubKey, overlay: OverlayId) -> Result<(), ProtocolError> {
+        self.check_account(user)?;
+        self.check_overlay_allowed(&overlay)?;
+        // TODO check that the broker has already joined this overlay. if not, send OverlayNotJoined
+        Err(ProtocolError::OverlayNotJoined)
+    }
+
+    /// Put all the blocks of an object, and index the object under each of its blocks.
+    /// See `objects_containing`
+    pub fn put_object(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        object: &Object,
+    ) -> Result<ObjectId, ProtocolError> {
+        if object.content_size() > self.max_object_size {
+            return Err(ProtocolError::ObjectTooLarge);
+        }
+        let mut deduplicated: HashSet<BlockId> = HashSet::new();
+        for block in object.blocks() {
+            let block_id = block.id();
+            if deduplicated.insert(block_id) {
+                self.put_block(user, overlay, block)?;
+            }
+        }
+        let id = object.id();
+        for block_id in deduplicated.iter() {
+            BlockIndex::new(&overlay, block_id, &self.store).add_object(&id)?;
+        }
+        Ok(id)
+    }
+
+    /// Objects put with `put_object` that contain the block
+    pub fn objects_containing(
+        &self,
+        overlay: &OverlayId,
+        block: &BlockId,
+    ) -> Result<Vec<ObjectId>, ProtocolError> {
+        Ok(BlockIndex::new(overlay, block, &self.store).objects()?)
+    }
+
+    /// Delete the blocks of an object.
+    /// Blocks that other objects put with `put_object` still contain are kept
+    pub fn del_object(
+        &self,
+        user: PubKey,
+        overlay: Digest,
+        id: ObjectId,
+    ) -> Result<(), ProtocolError> {
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            // TODO, only admin users can delete on a store on this broker
+            let obj = Object::load(id, None, store);
+            if obj.is_err() {
+                return Err(ProtocolError::NotFound);
+            }
+            let o = obj.ok().unwrap();
+            let mut deduplicated: HashSet<ObjectId> = HashSet::new();
+            for block in o.blocks() {
+                let block_id = block.id();
+                if deduplicated.get(&block_id).is_none() {
+                    let index = BlockIndex::new(&overlay, &block_id, &self.store);
+                    // the object may not be indexed
+                    let _ = index.remove_object(&id);
+                    if index.objects()?.is_empty() {
+                        store.del(&block_id)?;
+                    }
+                    deduplicated.insert(block_id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Delete an object and keep its tombstone, so that it isn't put back by a replication
+    /// from a broker that still has it.
+    /// Tombstones received from other brokers are added the same way, to delete the object here too.
+    /// The author of the tombstone must have joined the overlay,
+    /// and the time of the deletion can't be ahead of the broker clock by more than
+    /// MAX_COMMIT_CLOCK_SKEW, so that a tombstone can't be kept longer than the retention
+    pub fn add_tombstone(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        tombstone: &Tombstone,
+    ) -> Result<(), ProtocolError> {
+        self.add_tombstone_at(user, overlay, tombstone, &SystemClock)
+    }
+
+    fn add_tombstone_at(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        tombstone: &Tombstone,
+        clock: &impl Clock,
+    ) -> Result<(), ProtocolError> {
+        self.check_overlay_access(tombstone.author(), &overlay)?;
+        tombstone
+            .verify(&overlay)
+            .map_err(|_e| ProtocolError::InvalidSignature)?;
+        if tombstone.deleted_at() > clock.now() + MAX_COMMIT_CLOCK_SKEW {
+            return Err(ProtocolError::InvalidValue);
+        }
+        if self.is_tombstone_expired(tombstone, clock) {
+            return Ok(());
+        }
+        Tombstones::new(&overlay, &self.store).add(tombstone)?;
+        match self.del_object(user, overlay, tombstone.object()) {
+            Err(ProtocolError::NotFound) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Tombstones of the objects deleted in an overlay, to send to the other brokers of the overlay
+    pub fn tombstones(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+    ) -> Result<Vec<Tombstone>, ProtocolError> {
+        self.check_overlay_access(user, overlay)?;
+        Ok(Tombstones::new(overlay, &self.store).list()?)
+    }
+
+    fn check_not_deleted(&self, overlay: &OverlayId, id: &BlockId) -> Result<(), ProtocolError> {
+        match Tombstones::new(overlay, &self.store).get(id) {
+            Ok(_) => Err(ProtocolError::ObjectDeleted),
+            Err(StorageError::NotFound) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn pin_object(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        id: ObjectId,
+    ) -> Result<(), ProtocolError> {
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            // TODO, store the user who pins, and manage reference counting on how many users pin/unpin
+            let obj = Object::load(id, None, store);
+            if obj.is_err() {
+                return Err(ProtocolError::NotFound);
+            }
+            let o = obj.ok().unwrap();
+            let mut deduplicated: HashSet<ObjectId> = HashSet::new();
+            for block in o.blocks() {
+                let id = block.id();
+                if deduplicated.get(&id).is_none() {
+                    store.pin(&id)?;
+                    deduplicated.insert(id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn unpin_object(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        id: ObjectId,
+    ) -> Result<(), ProtocolError> {
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            // TODO, store the user who pins, and manage reference counting on how many users pin/unpin
+            let obj = Object::load(id, None, store);
+            if obj.is_err() {
+                return Err(ProtocolError::NotFound);
+            }
+            let o = obj.ok().unwrap();
+            let mut deduplicated: HashSet<ObjectId> = HashSet::new();
+            for block in o.blocks() {
+                let id = block.id();
+                if deduplicated.get(&id).is_none() {
+                    store.unpin(&id)?;
+                    deduplicated.insert(id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets a new expiry on all the blocks of an object, without uploading them again.
+    /// This is how an object is kept from expiring, or made to never expire with None.
+    /// The blocks keep their IDs, so the returned ID is the ID of the object
+    pub fn copy_object(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        id: ObjectId,
+        expiry: Option<Timestamp>,
+    ) -> Result<ObjectId, ProtocolError> {
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            let obj = Object::load(id, None, store);
+            if obj.is_err() {
+                return Err(ProtocolError::NotFound);
+            }
+            let o = obj.ok().unwrap();
+            let mut deduplicated: HashSet<BlockId> = HashSet::new();
+            let block_ids: Vec<BlockId> = o
+                .blocks()
+                .iter()
+                .map(|block| block.id())
+                .filter(|block_id| deduplicated.insert(*block_id))
+                .collect();
+            store.set_expiry(&block_ids, expiry.map(|expiry| self.clamp_expiry(expiry)))?;
+            Ok(id)
+        })
+    }
+
+    pub fn put_block(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        block: &Block,
+    ) -> Result<(), ProtocolError> {
+        self.put_block_with_expiry(user, overlay, block, None)
+    }
+
+    /// Put a block, like `put_block`.
+    /// A block without an expiry of its own expires after `default_expiry`,
+    /// or after the default expiry of the overlay when None, if the overlay has one
+    pub fn put_block_with_expiry(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        block: &Block,
+        default_expiry: Option<RelTime>,
+    ) -> Result<(), ProtocolError> {
+        self.check_overlay_allowed(&overlay)?;
+        block.validate()?;
+        self.check_not_deleted(&overlay, &block.id())?;
+        let expiry = self.default_expiry(&overlay, default_expiry)?;
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            let _ = store.put_with_default_expiry(block, expiry)?;
+            self.clamp_block_expiries(store, std::iter::once(block))?;
+            if let Some(cache) = &self.not_found_cache {
+                cache.invalidate(&overlay, &block.id());
+            }
+            Ok(())
+        })
+    }
+
+    /// Put several blocks at once, like `put_block`.
+    /// The valid blocks are stored in a single transaction: if storing fails, none of them is stored.
+    /// Returns the result of each block, in the same order, so only the failed ones need to be sent again
+    pub fn put_blocks(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        blocks: &[Block],
+    ) -> Result<Vec<Result<(), ProtocolError>>, ProtocolError> {
+        self.check_overlay_allowed(&overlay)?;
+        let mut results: Vec<Result<(), ProtocolError>> = blocks
+            .iter()
+            .map(|block| {
+                block.validate()?;
+                self.check_not_deleted(&overlay, &block.id())
+            })
+            .collect();
+        let valid: Vec<Block> = blocks
+            .iter()
+            .zip(&results)
+            .filter(|(_, res)| res.is_ok())
+            .map(|(block, _)| block.clone())
+            .collect();
+        let expiry = self.default_expiry(&overlay, None)?;
+        let stored = self.get_repostore_from_overlay_id(&overlay, |store| {
+            let stored = store.put_all_with_default_expiry(&valid, expiry);
+            if stored.is_ok() {
+                self.clamp_block_expiries(store, valid.iter())?;
+                if let Some(cache) = &self.not_found_cache {
+                    for block in &valid {
+                        cache.invalidate(&overlay, &block.id());
+                    }
+                }
+            }
+            Ok(stored)
+        })?;
+        if let Err(e) = stored {
+            let e: ProtocolError = e.into();
+            for res in results.iter_mut().filter(|res| res.is_ok()) {
+                *res = Err(e.clone());
+            }
+        }
+        Ok(results)
+    }
+
+    /// Expiry of the blocks put now without an expiry of their own:
+    /// after `default_expiry`, or after the default expiry of the overlay when None, if the overlay has one
+    fn default_expiry(
+        &self,
+        overlay: &OverlayId,
+        default_expiry: Option<RelTime>,
+    ) -> Result<Option<Timestamp>, ProtocolError> {
+        let default_expiry = match default_expiry {
+            Some(expiry) => Some(expiry),
+            None => match Overlay::open(overlay, &self.store) {
+                Ok(o) => o.metadata()?.default_expiry,
+                Err(_) => None,
+            },
+        };
+        Ok(default_expiry.map(|expiry| now_timestamp() + expiry))
+    }
+
+    /// Get a block, or a whole object if `include_children` is set, like `get_block`.
+    /// Blocks missing locally are searched in the fallback source, if any,
+    /// without blocking the task while the source answers
+    pub async fn get_block_with_fallback(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        id: BlockId,
+        include_children: bool,
+        topic: Option<PubKey>,
+        max_blocks: Option<u32>,
+        continuation: Option<u32>,
+        known_blocks: Option<&BloomFilter>,
+    ) -> Result<(async_channel::Receiver<Block>, Option<u32>), ProtocolError> {
+        let get = || {
+            self.get_block(
+                user,
+                overlay,
+                id,
+                include_children,
+                topic,
+                max_blocks,
+                continuation,
+                known_blocks,
+            )
+        };
+        if self.is_not_found_cached(&overlay, &id) {
+            return Err(ProtocolError::NotFound);
+        }
+        match get() {
+            Err(ProtocolError::NotFound)
+                if self
+                    .fetch_from_fallback(&overlay, &id, include_children)
+                    .await =>
+            {
+                get()
+            }
+            res => res,
+        }
+    }
+
+    /// Get a block from the local store, or a whole object if `include_children` is set.
+    ///
+    /// Blocks of an object are sent in tree order, root first, up to `max_blocks`
+    /// (bounded by the broker's own limit), after skipping the first `continuation` blocks.
+    /// The blocks in `known_blocks` are not sent, but still count for the continuation.
+    /// Returns the continuation token to request the remaining blocks if the response was truncated.
+    /// The fallback source is only searched by `get_block_with_fallback`
+    pub fn get_block(
+        &self,
+        user: PubKey,
+        overlay: OverlayId,
+        id: BlockId,
+        include_children: bool,
+        topic: Option<PubKey>,
+        max_blocks: Option<u32>,
+        continuation: Option<u32>,
+        known_blocks: Option<&BloomFilter>,
+    ) -> Result<(async_channel::Receiver<Block>, Option<u32>), ProtocolError> {
+        if self.is_not_found_cached(&overlay, &id) {
+            return Err(ProtocolError::NotFound);
+        }
+        self.get_local_block(
+            overlay,
+            id,
+            include_children,
+            max_blocks,
+            continuation,
+            known_blocks,
+        )
+    }
+
+    /// Get the objects requested by an `ExtObjectGet` from a non-member, e.g. through an `ObjectLink`.
+    ///
+    /// The request must be for a repository with a public overlay on this broker,
+    /// and its MAC must match the repository keys.
+    /// Returns the blocks of all the objects, each with the ID of the requested object it belongs to,
+    /// and whether some were left out because of the broker's limit of blocks per object
+    pub fn ext_object_get(
+        &self,
+        req: &ExtRequest,
+    ) -> Result<(async_channel::Receiver<(ObjectId, Block)>, bool), ProtocolError> {
+        let get = match req.content() {
+            ExtRequestContentV0::ExtObjectGet(ExtObjectGet::V0(get)) => get,
+            _ => return Err(ProtocolError::InvalidState),
+        };
+        let overlay_id = Digest::Blake3Digest32(*blake3::hash(get.repo.slice()).as_bytes());
+        let secret = Overlay::open(&overlay_id, &self.store)
+            .and_then(|overlay| overlay.secret())
+            .map_err(|_e| ProtocolError::OverlayNotFound)?;
+        req.verify_mac(get.repo, secret)
+            .map_err(|_e| ProtocolError::AccessDenied)?;
+        if get
+            .expiry
+            .map_or(false, |expiry| expiry.is_past(now_timestamp()))
+        {
+            return Err(ProtocolError::AccessDenied);
+        }
+        let (s, r) = async_channel::unbounded::<(ObjectId, Block)>();
+        let mut truncated = false;
+        for id in get.ids.iter() {
+            let (blocks, next) =
+                self.get_local_block(overlay_id, *id, get.include_children, None, None, None)?;
+            truncated = truncated || next.is_some();
+            while let Ok(block) = blocks.try_recv() {
+                s.send_blocking((*id, block))
+                    .map_err(|_e| ProtocolError::WriteError)?;
+            }
+        }
+        Ok((r, truncated))
+    }
+
+    fn get_local_block(
+        &self,
+        overlay: OverlayId,
+        id: BlockId,
+        include_children: bool,
+        max_blocks: Option<u32>,
+        continuation: Option<u32>,
+        known_blocks: Option<&BloomFilter>,
+    ) -> Result<(async_channel::Receiver<Block>, Option<u32>), ProtocolError> {
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            let (s, r) = async_channel::unbounded::<Block>();
+            if !include_children {
+                let block = store.get(&id).map_err(|e| {
+                    if e == StorageError::NotFound {
+                        self.cache_not_found(&overlay, &id);
+                    }
+                    e
+                })?;
+                s.send_blocking(block)
+                    .map_err(|_e| ProtocolError::WriteError)?;
+                Ok((r, None))
+            } else {
+                let obj = Object::load(id, None, store);
+                // TODO return partial blocks when some are missing ?
+                if obj.is_err() {
+                    //&& obj.err().unwrap().len() == 1 && obj.err().unwrap()[0] == id {
+                    if let Err(ObjectParseError::MissingBlocks(missing)) = &obj {
+                        // only cache the miss if the root block itself is missing
+                        if missing.contains(&id) {
+                            self.cache_not_found(&overlay, &id);
+                        }
+                    }
+                    return Err(ProtocolError::NotFound);
+                }
+                let limit = match max_blocks {
+                    Some(max) => min(max as usize, self.max_get_blocks),
+                    None => self.max_get_blocks,
+                };
+                let skip = continuation.unwrap_or(0) as usize;
+                // TODO use a task to send non blocking (streaming)
+                let o = obj.ok().unwrap();
+                //debug_println!("{} BLOCKS ", o.blocks().len());
+                let mut deduplicated: HashSet<BlockId> = HashSet::new();
+                let mut next = None;
+                // send blocks in tree order, root first
+                for block in o.blocks().iter().rev() {
+                    let id = block.id();
+                    if deduplicated.get(&id).is_none() {
+                        let index = deduplicated.len();
+                        if index >= skip + limit {
+                            next = Some(index as u32);
+                            break;
+                        }
+                        let known = known_blocks.map_or(false, |f| f.contains(&id));
+                        if index >= skip && !known {
+                            s.send_blocking(block.clone())
+                                .map_err(|_e| ProtocolError::WriteError)?;
+                        }
+                        deduplicated.insert(id);
+                    }
+                }
+                Ok((r, next))
+            }
+        })
+    }
+
+    /// Heads of the branch of a topic that are not in the known heads of the requestor,
+    /// to sync with a BranchSyncReq.
+    /// The heads are the published commits that no other published commit depends on
+    pub fn branch_heads(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        topic: &TopicId,
+        known_heads: &Vec<ObjectId>,
+    ) -> Result<Vec<ObjectId>, ProtocolError> {
+        self.check_overlay_access(user, overlay)?;
+        self.check_heads_count(known_heads)?;
+        let heads = match Topic::open(topic, &self.store) {
+            Ok(topic) => topic.heads()?,
+            Err(StorageError::NotFound) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        Ok(heads
+            .into_iter()
+            .filter(|h| !known_heads.contains(h))
+            .collect())
+    }
+
+    pub fn sync_branch(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        heads: &Vec<ObjectId>,
+        known_heads: &Vec<ObjectId>,
+        known_commits: &BloomFilter,
+        commit_types: Option<&Vec<CommitType>>,
+        checkpoint: Option<&Vec<u8>>,
+    ) -> Result<async_channel::Receiver<Block>, ProtocolError> {
+        let items = self.sync_branch_with_progress(
+            user,
+            overlay,
+            heads,
+            known_heads,
+            known_commits,
+            commit_types,
+            checkpoint,
+        )?;
+        let (s, r) = async_channel::unbounded::<Block>();
+        while let Ok(item) = items.try_recv() {
+            if let SyncStreamItem::Block(block) = item {
+                s.send_blocking(block)
+                    .map_err(|_e| ProtocolError::WriteError)?;
+            }
+        }
+        Ok(r)
+    }
+
+    /// Same as `sync_branch`, with the progress of the sync interleaved with the blocks.
+    ///
+    /// The objects are loaded before streaming, so that the total announced before the first block
+    /// is the number of blocks the stream sends, after deduplication, the checkpoint and the block limit.
+    /// The last progress of the stream repeats it, and marks a truncated sync
+    pub fn sync_branch_with_progress(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        heads: &Vec<ObjectId>,
+        known_heads: &Vec<ObjectId>,
+        known_commits: &BloomFilter,
+        commit_types: Option<&Vec<CommitType>>,
+        checkpoint: Option<&Vec<u8>>,
+    ) -> Result<async_channel::Receiver<SyncStreamItem>, ProtocolError> {
+        //debug_println!("heads {:?}", heads);
+        //debug_println!("known_heads {:?}", known_heads);
+        //debug_println!("known_commits {:?}", known_commits);
+
+        self.check_heads_count(heads)?;
+        self.check_heads_count(known_heads)?;
+
+        // blocks of the stream already received before the sync was interrupted
+        let skip = match checkpoint {
+            Some(token) => SyncCheckpoint::resume_index(
+                token,
+                heads,
+                known_heads,
+                known_commits,
+                commit_types,
+            )? as usize,
+            None => 0,
+        };
+
+        self.get_repostore_from_overlay_id(&overlay, |store| {
+            let (s, r) = async_channel::unbounded::<SyncStreamItem>();
+            let send = |item| {
+                s.send_blocking(item)
+                    .map_err(|_e| ProtocolError::WriteError)
+            };
+
+            let heads: Vec<WeakObjectRef> =
+                heads.iter().map(|id| WeakObjectRef { id: *id }).collect();
+            let known_heads: Vec<WeakObjectRef> = known_heads
+                .iter()
+                .map(|id| WeakObjectRef { id: *id })
+                .collect();
+            let mut res = Branch::sync_req(&heads, &known_heads, known_commits, store)
+                .map_err(|e| ProtocolError::ObjectParseError)?;
+            // same order for the same request, so that a checkpoint can resume the stream
+            res.sort_by_key(|r| r.id);
+
+            // todo, use a task to send non blocking (streaming)
+            debug_println!("SYNCING {} COMMITS", res.len());
+
+            let mut deduplicated: HashSet<BlockId> = HashSet::new();
+
+            // the bodies of the commits of the requested types, as recorded when they were published
+            let mut objects: Vec<ObjectId> = vec![];
+            for objectref in res {
+                objects.push(objectref.id);
+                if let Some(types) = commit_types {
+                    if let Ok(info) = CommitInfo::open(overlay, &objectref.id, &self.store) {
+                        let info = info.metadata()?;
+                        if types.contains(&info.commit_type) {
+                            objects.push(info.body);
+                        }
+                    }
+                }
+            }
+
+            // the blocks of the stream, in order, up to the first one past the block limit
+            let limit = skip + self.max_sync_blocks;
+            let mut block_ids: Vec<BlockId> = vec![];
+            'objects: for id in objects.iter() {
+                let object = Object::load(*id, None, store)?;
+                for block in object.blocks() {
+                    let id = block.id();
+                    if deduplicated.insert(id) {
+                        block_ids.push(id);
+                        if block_ids.len() > limit {
+                            break 'objects;
+                        }
+                    }
+                }
+            }
+            let truncated = block_ids.len() > limit;
+            if truncated {
+                debug_println!("SYNC TRUNCATED AT {} BLOCKS", limit);
+                block_ids.truncate(limit);
+            }
+
+            let total = block_ids.len().saturating_sub(skip);
+            let mut sent = 0;
+            send(SyncStreamItem::Progress(SyncProgress::new(total as u32, 0)))?;
+
+            for id in block_ids.iter().skip(skip) {
+                let block = store.get(id)?;
+                send(SyncStreamItem::Block(block))?;
+                sent += 1;
+                if sent % SYNC_PROGRESS_INTERVAL == 0 {
+                    send(SyncStreamItem::Progress(SyncProgress::new(
+                        total as u32,
+                        sent as u32,
+                    )))?;
+                }
+            }
+
+            // a truncated sync is marked in its last progress, for the client to resume it
+            let last = if truncated {
+                SyncProgress::new_truncated(sent as u32)
+            } else {
+                SyncProgress::new(sent as u32, sent as u32)
+            };
+            send(SyncStreamItem::Progress(last))?;
+            Ok(r)
+        })
+    }
+
+    /// Streams the blocks of a published commit, followed by the blocks of its body.
+    /// Only the commits accepted by `publish_commit` are served,
+    /// as the broker can't read the body reference of a commit by itself
+    pub fn get_commit(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        id: ObjectId,
+    ) -> Result<async_channel::Receiver<Block>, ProtocolError> {
+        self.check_overlay_allowed(overlay)?;
+        self.check_overlay_access(user, overlay)?;
+        let info = CommitInfo::open(overlay, &id, &self.store)
+            .map_err(|_e| ProtocolError::NotFound)?
+            .metadata()?;
+
+        self.get_repostore_from_overlay_id(overlay, |store| {
+            let (s, r) = async_channel::unbounded::<Block>();
+            let mut deduplicated: HashSet<BlockId> = HashSet::new();
+            for id in [id, info.body] {
+                let object = Object::load(id, None, store).map_err(|e| match e {
+                    ObjectParseError::MissingBlocks(_) => ProtocolError::MissingBlocks,
+                    _ => ProtocolError::ObjectParseError,
+                })?;
+                // TODO use a task to send non blocking (streaming)
+                for block in object.blocks().iter().rev() {
+                    if deduplicated.insert(block.id()) {
+                        s.send_blocking(block.clone())
+                            .map_err(|_e| ProtocolError::WriteError)?;
+                    }
+                }
+            }
+            Ok(r)
+        })
+    }
+
+    /// Streams the blocks of an object covering `len` bytes of its serialized content from `offset`,
+    /// with the blocks on their path from the root, and the leftmost and rightmost paths of the tree,
+    /// so that the client can open the object and verify the range against its ID.
+    /// Objects with compressed leaves are sent whole
+    pub fn get_block_range(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        id: ObjectId,
+        offset: u64,
+        len: u64,
+    ) -> Result<async_channel::Receiver<Block>, ProtocolError> {
+        self.check_overlay_allowed(overlay)?;
+        self.check_overlay_access(user, overlay)?;
+        self.get_repostore_from_overlay_id(overlay, |store| {
+            let blocks = Object::range_blocks(id, store, offset, len).map_err(|e| match e {
+                ObjectParseError::MissingBlocks(_) => ProtocolError::NotFound,
+                _ => ProtocolError::ObjectParseError,
+            })?;
+            let (s, r) = async_channel::unbounded::<Block>();
+            for block in blocks {
+                s.send_blocking(block)
+                    .map_err(|_e| ProtocolError::WriteError)?;
+            }
+            Ok(r)
+        })
+    }
+
+    /// Sends the tombstones of the overlay at the start of a replication stream,
+    /// so that the replica deletes the objects it still has, before receiving the blocks
+    fn send_tombstones(
+        &self,
+        overlay: &OverlayId,
+        s: &async_channel::Sender<ReplicationStreamItem>,
+    ) -> Result<(), ProtocolError> {
+        for tombstone in Tombstones::new(overlay, &self.store).list()? {
+            s.send_blocking(ReplicationStreamItem::Tombstone(tombstone))
+                .map_err(|_e| ProtocolError::WriteError)?;
+        }
+        Ok(())
+    }
+
+    /// Streams all the blocks of an overlay, for replication by another broker,
+    /// preceded by the tombstones of the objects deleted in the overlay, to add with `add_tombstone`.
+    /// If since is given, only the blocks stored after that timestamp are sent.
+    /// Only users that have joined the overlay can replicate it.
+    pub fn replicate(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        since: Option<Timestamp>,
+    ) -> Result<async_channel::Receiver<ReplicationStreamItem>, ProtocolError> {
+        self.check_overlay_access(user, overlay)?;
+
+        let (s, r) = async_channel::unbounded::<ReplicationStreamItem>();
+        self.send_tombstones(overlay, &s)?;
+        self.get_repostore_from_overlay_id(overlay, |store| {
+            let block_ids = store.list_blocks(since)?;
+            debug_println!("REPLICATING {} BLOCKS", block_ids.len());
+            // TODO use a task to send non blocking (streaming)
+            for id in block_ids {
+                s.send_blocking(ReplicationStreamItem::Block(store.get(&id)?))
+                    .map_err(|_e| ProtocolError::WriteError)?;
+            }
+            Ok(r)
+        })
+    }
+
+    /// Streams the blocks of an overlay that a replica hasn't received yet,
+    /// resuming from the checkpoint saved at its previous replication.
+    /// Also returns the gaps: the blocks stored after the checkpoint that have been removed since,
+    /// and that the replica will never receive.
+    /// Without a checkpoint, all the blocks are sent.
+    /// As with `replicate`, the tombstones of the overlay are sent first.
+    ///
+    /// The checkpoint reached is only kept as pending: it replaces the current one
+    /// when the replica acknowledges it received the stream with `ack_replication`.
+    /// Until then, the next replication resumes from the same checkpoint again.
+    pub fn replicate_from_checkpoint(
+        &self,
+        replica: PubKey,
+        overlay: &OverlayId,
+    ) -> Result<(async_channel::Receiver<ReplicationStreamItem>, Vec<BlockId>), ProtocolError> {
+        self.check_overlay_access(replica, overlay)?;
+
+        let previous = match Checkpoint::open(&replica, overlay, &self.store) {
+            Ok(checkpoint) => Some(checkpoint.metadata()?),
+            Err(StorageError::NotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let already_seen = |id: &BlockId, stored_at: Timestamp| match &previous {
+            Some(p) => stored_at < p.last || (stored_at == p.last && p.seen.contains(id)),
+            None => false,
+        };
+        let now = now_timestamp();
+
+        let (s, r) = async_channel::unbounded::<ReplicationStreamItem>();
+        self.send_tombstones(overlay, &s)?;
+        let (gaps, seen) = self.get_repostore_from_overlay_id(overlay, |store| {
+            let mut seen: Vec<BlockId> = match &previous {
+                Some(p) if p.last == now => p.seen.clone(),
+                _ => vec![],
+            };
+            let since = previous.as_ref().map(|p| p.last);
+            for (id, stored_at) in store.list_blocks_stored_at(since)? {
+                if already_seen(&id, stored_at) {
+                    continue;
+                }
+                s.send_blocking(ReplicationStreamItem::Block(store.get(&id)?))
+                    .map_err(|_e| ProtocolError::WriteError)?;
+                if stored_at == now {
+                    seen.push(id);
+                }
+            }
+            let mut gaps: Vec<BlockId> = vec![];
+            if let Some(since) = since {
+                for (id, stored_at) in store.list_removed(since)? {
+                    if already_seen(&id, stored_at) {
+                        continue;
+                    }
+                    // a gap is reported only once
+                    if stored_at == now {
+                        seen.push(id);
+                    }
+                    gaps.push(id);
+                }
+            }
+            Ok((gaps, seen))
+        })?;
+
+        let pending = PendingCheckpoint {
+            meta: CheckpointMeta { last: now, seen },
+            gaps: gaps.clone(),
+        };
+        Checkpoint::set_pending(&replica, overlay, &pending, &self.store)?;
+        debug_println!("REPLICATION GAPS {}", gaps.len());
+        Ok((r, gaps))
+    }
+
+    /// Advances the checkpoint of a replica once it acknowledged it received
+    /// the stream of its last `replicate_from_checkpoint`.
+    /// Returns the gaps of that replication, or NotFound if there is no replication to acknowledge
+    pub fn ack_replication(
+        &self,
+        replica: PubKey,
+        overlay: &OverlayId,
+    ) -> Result<Vec<BlockId>, ProtocolError> {
+        self.check_overlay_access(replica, overlay)?;
+        let pending = Checkpoint::acknowledge(&replica, overlay, &self.store)?;
+        Ok(pending.gaps)
+    }
+
+    /// Lists one page of at most `limit` IDs of the blocks stored in an overlay,
+    /// starting right after the `after` cursor of the previous page.
+    /// The limit is capped at MAX_LIST_BLOCKS, and a limit of 0 is an InvalidValue.
+    /// Only users that have joined the overlay can list it.
+    pub fn list_blocks(
+        &self,
+        user: PubKey,
+        overlay: &OverlayId,
+        limit: u32,
+        after: Option<&Cursor>,
+    ) -> Result<BlockListResp, ProtocolError> {
+        self.check_overlay_access(user, overlay)?;
+        if limit == 0 {
+            return Err(ProtocolError::InvalidValue);
+        }
+        let limit = min(limit, MAX_LIST_BLOCKS) as usize;
+        self.get_repostore_from_overlay_id(overlay, |store| {
+            let (ids, next) = store.list_blocks_page(limit, after.cloned())?;
+            Ok(BlockListResp::V0(BlockListRespV0 { ids, next }))
+        })
+    }
+
+    /// Checks that the user has an account on this broker
+    pub fn check_account(&self, user: PubKey) -> Result<(), ProtocolError> {
+        match Account::open(&user, &self.store) {
+            Ok(_) => Ok(()),
+            Err(StorageError::NotFound) => Err(ProtocolError::NoAccount),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Finds the adverts of the peers known to host an overlay,
+    /// from the adverts relayed in the overlay and the peers it was joined with.
+    /// A public overlay can thus be discovered with only the ID derived from its repo pubkey
+    pub fn find_overlay_peers(&self, overlay_id: OverlayId) -> Vec<PeerAdvert> {
+        let peers = match Overlay::open(&overlay_id, &self.store).and_then(|o| o.peers()) {
+            Ok(peers) => peers,
+            Err(_) => return vec![],
+        };
+        peers
+            .iter()
+            .filter_map(|p| Peer::open(p, &self.store).and_then(|p| p.advert()).ok())
+            .collect()
+    }
+
+    /// Status of an overlay: whether the user joined it, and the peers hosting it
+    pub fn overlay_status(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+    ) -> Result<OverlayStatusResp, ProtocolError> {
+        let account =
+            Account::open(&user, &self.store).map_err(|_e| ProtocolError::AccessDenied)?;
+        Ok(OverlayStatusResp::V0(OverlayStatusRespV0 {
+            joined: account.has_overlay(&overlay_id).is_ok(),
+            peers: self.find_overlay_peers(overlay_id),
+        }))
+    }
+
+    /// Checks that the user has joined the overlay
+    fn check_overlay_access(&self, user: PubKey, overlay: &OverlayId) -> Result<(), ProtocolError> {
+        let account =
+            Account::open(&user, &self.store).map_err(|_e| ProtocolError::AccessDenied)?;
+        account
+            .has_overlay(overlay)
+            .map_err(|_e| ProtocolError::AccessDenied)
+    }
+
+    fn compute_repostore_id(&self, overlay: OverlayId, repo_id: Option<PubKey>) -> RepoStoreId {
+        match self.mode {
+            ConfigMode::Core => RepoStoreId::Overlay(overlay),
+            ConfigMode::Local => RepoStoreId::Repo(repo_id.unwrap()),
+        }
+    }
+
+    pub fn join_overlay(
+        &self,
+        user: PubKey,
+        overlay_id: OverlayId,
+        repo_id: Option<PubKey>,
+        secret: SymKey,
+        peers: &Vec<PeerAdvert>,
+    ) -> Result<(), ProtocolError> {
+        self.check_account(user)?;
+        self.check_overlay_allowed(&overlay_id)?;
+        let account = Account::open(&user, &self.store)?;
+        if account.has_overlay(&overlay_id).is_err()
+            && account.overlays()?.len() >= self.limits_of(&account)?.max_overlays as usize
+        {
+            return Err(ProtocolError::LimitExceeded);
+        }
+        // check if this overlay already exists
+        //debug_println!("SEARCHING OVERLAY");
+        let overlay_res = Overlay::open(&overlay_id, &self.store);
+        let mut created = false;
+        let overlay = match overlay_res {
+            Err(StorageError::NotFound) => {
+                // we have to add it
+                if self.mode == ConfigMode::Local && repo_id.is_none() {
+                    return Err(ProtocolError::RepoIdRequired);
+                }
+                let over = Overlay::create(
+                    &overlay_id,
+                    &secret,
+                    if self.mode == ConfigMode::Local {
+                        repo_id
+                    } else {
+                        None
+                    },
+                    &self.store,
+                )?;
+                // we need to add an encryption key for the repostore.
+                let mut random_buf = [0u8; 32];
+                getrandom::getrandom(&mut random_buf).unwrap();
+                let key = SymKey::ChaCha20Key(random_buf);
+
+                let _ = RepoStoreInfo::create(
+                    &self.compute_repostore_id(overlay_id, repo_id),
+                    &key,
+                    &self.store,
+                )?; // TODO in case of error, delete the previously created Overlay
+                    //debug_println!("KEY ADDED");
+                created = true;
+                over
+            }
+            Err(e) => return Err(e.into()),
+            Ok(overlay) => overlay,
+        };
+        //debug_println!("OVERLAY FOUND");
+        // add the peers to the overlay
+        for advert in peers {
+            Peer::update_or_create(advert, &self.store)?;
+            overlay.add_peer(&advert.peer())?;
+        }
+        // and ourselves
+        if let Some((priv_key, listen)) = &self.self_peer {
+            let advert = self.self_advert(listen, *priv_key);
+            Peer::update_or_create(&advert, &self.store)?;
+            overlay.add_peer(&advert.peer())?;
+        }
+        //debug_println!("PEERS ADDED");
+
+        // now adding the overlay_id to the account
+        if account.has_overlay(&overlay_id).is_err() {
+            account.add_overlay(&overlay_id)?; // TODO in case of error, delete the previously created Overlay
+            // the overlay is created with its first user
+            if !created {
+                let mut meta = overlay.metadata()?;
+                meta.users += 1;
+                overlay.set_metadata(&meta)?;
+            }
+        }
+        //debug_println!("USER <-> OVERLAY");
+
+        //TODO: connect to peers
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use lofire::types::*;
+    use lofire::utils::*;
+    use lofire_net::errors::*;
+    use lofire_net::types::*;
+    use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::Builder;
+
+    use crate::config::ConfigMode;
+    use crate::server::*;
+    use lofire::store::HashMapRepoStore;
+
+    fn open_broker(root: &Path) -> BrokerServer {
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root).unwrap();
+        println!("{}", root.to_str().unwrap());
+        let store = LmdbBrokerStore::open(root, key);
+        BrokerServer::new(store, ConfigMode::Local).expect("starting broker")
+    }
+
+    fn add_user(server: &BrokerServer, user: PubKey) {
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+    }
+
+    fn count_blocks(r: async_channel::Receiver<Block>) -> usize {
+        let mut i = 0;
+        while let Ok(_b) = r.try_recv() {
+            i += 1;
+        }
+        i
+    }
+
+    fn replicated_blocks(r: async_channel::Receiver<ReplicationStreamItem>) -> Vec<Block> {
+        let mut blocks = vec![];
+        while let Ok(item) = r.try_recv() {
+            if let ReplicationStreamItem::Block(b) = item {
+                blocks.push(b);
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    pub fn test_replicate() {
+        let root_src = Builder::new().prefix("test-env").tempdir().unwrap();
+        let root_dst = Builder::new().prefix("test-env").tempdir().unwrap();
+        let src = open_broker(root_src.path());
+        let dst = open_broker(root_dst.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&src, user);
+        add_user(&dst, user);
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        src.join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        dst.join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        for i in 0..10u8 {
+            let block = Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                None,
+                vec![i; 100],
+                None,
+            );
+            src.put_block(user, overlay, &block).unwrap();
+        }
+
+        // an unknown user cannot replicate
+        let stranger = PubKey::Ed25519PubKey([9; 32]);
+        assert_eq!(
+            src.replicate(stranger, &overlay, None).err().unwrap(),
+            ProtocolError::AccessDenied
+        );
+
+        let r = src.replicate(user, &overlay, None).unwrap();
+        for block in replicated_blocks(r) {
+            dst.put_block(user, overlay, &block).unwrap();
+        }
+
+        let src_count = replicated_blocks(src.replicate(user, &overlay, None).unwrap()).len();
+        let dst_count = replicated_blocks(dst.replicate(user, &overlay, None).unwrap()).len();
+        assert_eq!(src_count, 10);
+        assert_eq!(src_count, dst_count);
+
+        // nothing was stored in the future
+        let since = now_timestamp() + RelTime::Minutes(10);
+        let r = src.replicate(user, &overlay, Some(since)).unwrap();
+        assert!(replicated_blocks(r).is_empty());
+    }
+
+    #[test]
+    pub fn test_replicate_from_checkpoint() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let src = open_broker(root.path());
+
+        let replica = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&src, replica);
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        src.join_overlay(replica, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        fn put_blocks(
+            src: &BrokerServer,
+            user: PubKey,
+            overlay: OverlayId,
+            range: std::ops::Range<u8>,
+        ) -> Vec<BlockId> {
+            range
+                .map(|i| {
+                    let block = Block::new(
+                        vec![],
+                        ObjectDeps::ObjectIdList(vec![]),
+                        None,
+                        vec![i; 100],
+                        None,
+                    );
+                    src.put_block(user, overlay, &block).unwrap();
+                    block.id()
+                })
+                .collect()
+        }
+
+        put_blocks(&src, replica, overlay, 0..5);
+
+        // nothing to acknowledge yet
+        assert_eq!(
+            src.ack_replication(replica, &overlay).err().unwrap(),
+            ProtocolError::NotFound
+        );
+
+        // first replication, everything is sent
+        let (r, gaps) = src.replicate_from_checkpoint(replica, &overlay).unwrap();
+        assert_eq!(replicated_blocks(r).len(), 5);
+        assert!(gaps.is_empty());
+
+        // the replica didn't acknowledge, everything is sent again
+        let (r, gaps) = src.replicate_from_checkpoint(replica, &overlay).unwrap();
+        assert_eq!(replicated_blocks(r).len(), 5);
+        assert!(gaps.is_empty());
+        assert!(src.ack_replication(replica, &overlay).unwrap().is_empty());
+
+        // new blocks, one of them is removed before the replica comes back
+        put_blocks(&src, replica, overlay, 5..8);
+        let removed = put_blocks(&src, replica, overlay, 8..9);
+        src.del_object(replica, overlay, removed[0]).unwrap();
+
+        let (r, gaps) = src.replicate_from_checkpoint(replica, &overlay).unwrap();
+        assert_eq!(replicated_blocks(r).len(), 3);
+        assert_eq!(gaps, removed);
+        assert_eq!(src.ack_replication(replica, &overlay).unwrap(), removed);
+        // acknowledged only once
+        assert_eq!(
+            src.ack_replication(replica, &overlay).err().unwrap(),
+            ProtocolError::NotFound
+        );
+
+        // nothing new
+        let (r, gaps) = src.replicate_from_checkpoint(replica, &overlay).unwrap();
+        assert_eq!(replicated_blocks(r).len(), 0);
+        assert!(gaps.is_empty());
+    }
+
+    #[test]
+    pub fn test_sync_too_many_heads() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_max_sync_heads(4);
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        let heads: Vec<ObjectId> = (0..5u8).map(|i| Digest::Blake3Digest32([i; 32])).collect();
+        let known_commits = BloomFilter { k: 0, f: vec![] };
+
+        let res = server.sync_branch(user, &overlay, &heads, &vec![], &known_commits, None, None);
+        assert_eq!(res.err().unwrap(), ProtocolError::TooManyHeads);
+
+        let res = server.sync_branch(user, &overlay, &vec![], &heads, &known_commits, None, None);
+        assert_eq!(res.err().unwrap(), ProtocolError::TooManyHeads);
+    }
+
+    #[test]
+    pub fn test_get_block_truncated() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_max_get_blocks(25);
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..200000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let obj = Object::new(content, vec![], None, 4000, repo, secret);
+        for block in obj.blocks() {
+            server.put_block(user, overlay, block).unwrap();
+        }
+        let unique: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+        println!("object has {} blocks", unique.len());
+        assert!(unique.len() > 25);
+
+        // the first page starts with the root
+        let (r, continuation) = server
+            .get_block(user, overlay, obj.id(), true, None, Some(10), None, None)
+            .unwrap();
+        assert_eq!(r.try_recv().unwrap().id(), obj.id());
+        assert_eq!(continuation, Some(10));
+
+        // fetch the rest with the continuation token
+        let mut received = HashSet::new();
+        let mut continuation = None;
+        let mut pages = 0;
+        loop {
+            let (r, next) = server
+                .get_block(
+                    user,
+                    overlay,
+                    obj.id(),
+                    true,
+                    None,
+                    Some(10),
+                    continuation,
+                    None,
+                )
+                .unwrap();
+            let mut count = 0;
+            while let Ok(block) = r.try_recv() {
+                assert!(received.insert(block.id()));
+                count += 1;
+            }
+            assert!(count <= 10);
+            pages += 1;
+            match next {
+                Some(n) => continuation = Some(n),
+                None => break,
+            }
+        }
+        assert_eq!(received, unique);
+        assert_eq!(pages, (unique.len() + 9) / 10);
+
+        // the broker limit applies when the client asks for more
+        let (r, continuation) = server
+            .get_block(user, overlay, obj.id(), true, None, None, None, None)
+            .unwrap();
+        assert_eq!(count_blocks(r), 25);
+        assert_eq!(continuation, Some(25));
+    }
+
+    #[test]
+    pub fn test_overlay_allowlist() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let allowed = Digest::Blake3Digest32([2; 32]);
+        let other = Digest::Blake3Digest32([5; 32]);
+        server.set_overlay_allowlist(vec![allowed]);
+
+        server
+            .join_overlay(user, allowed, Some(repo), secret, &vec![])
+            .unwrap();
+        assert_eq!(
+            server
+                .join_overlay(user, other, Some(repo), secret, &vec![])
+                .err()
+                .unwrap(),
+            ProtocolError::OverlayNotAllowed
+        );
+        assert_eq!(
+            server.connect_overlay(user, other).err().unwrap(),
+            ProtocolError::OverlayNotAllowed
+        );
+
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        server.put_block(user, allowed, &block).unwrap();
+        assert_eq!(
+            server.put_block(user, other, &block).err().unwrap(),
+            ProtocolError::OverlayNotAllowed
+        );
+
+        // an empty allowlist allows all overlays
+        server.set_overlay_allowlist(vec![]);
+        server
+            .join_overlay(user, other, Some(repo), secret, &vec![])
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_self_advert() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+
+        let (priv_key, pub_key) = generate_keypair();
+        let listen = vec![
+            IPTransportAddr {
+                ip: IP::IPv4([127, 0, 0, 1]),
+                port: 3012,
+                protocol: IPTransportProtocol::TLS,
+            },
+            IPTransportAddr {
+                ip: IP::IPv6([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+                port: 3013,
+                protocol: IPTransportProtocol::QUIC,
+            },
+        ];
+
+        let advert = server.self_advert(&listen, priv_key);
+        println!("advert: {:?}", advert);
+        assert_eq!(*advert.peer(), pub_key);
+        assert_eq!(advert.ttl(), DEFAULT_ADVERT_TTL);
+        assert_eq!(
+            *advert.address(),
+            listen
+                .iter()
+                .map(|a| NetAddr::IPTransport(*a))
+                .collect::<Vec<NetAddr>>()
+        );
+        verify(
+            &serde_bare::to_vec(advert.content()).unwrap(),
+            advert.sig(),
+            pub_key,
+        )
+        .expect("advert signature should verify");
+
+        // the advert is added to joined overlays
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        server.set_self_peer(priv_key, listen.clone());
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        let stored = Peer::open(&pub_key, &server.store)
+            .unwrap()
+            .advert()
+            .unwrap();
+        assert_eq!(*stored.address(), *advert.address());
+    }
+
+    #[test]
+    pub fn test_advert_relay_rate() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let mut server = BrokerServer::new(store, ConfigMode::Core).expect("starting broker");
+        let (s, r) = async_channel::unbounded();
+        server.set_upstream(s);
+        server.set_advert_relay_rate(Some(5));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, None, SymKey::ChaCha20Key([4; 32]), &vec![])
+            .unwrap();
+
+        let advert = |(priv_key, pub_key): (PrivKey, PubKey), version: u32| {
+            let content = PeerAdvertContentV0 {
+                peer: pub_key,
+                subs: [[0; 32]; 4],
+                address: vec![],
+                version,
+                metadata: vec![],
+            };
+            let sig = sign(priv_key, pub_key, &serde_bare::to_vec(&content).unwrap()).unwrap();
+            PeerAdvert::V0(PeerAdvertV0 {
+                content,
+                sig,
+                ttl: DEFAULT_ADVERT_TTL,
+            })
+        };
+        let relayed = || {
+            let mut count = 0;
+            while let Ok((o, msg)) = r.try_recv() {
+                assert_eq!(o, overlay);
+                match msg {
+                    OverlayMessageContentV0::PeerAdvert(a) => {
+                        assert_eq!(a.ttl(), DEFAULT_ADVERT_TTL - 1)
+                    }
+                    _ => panic!("unexpected upstream message {:?}", msg),
+                }
+                count += 1;
+            }
+            count
+        };
+
+        // a burst of adverts is cut at the rate
+        let clock = MockClock::new(Timestamp::from_minutes(100));
+        let peers: Vec<(PrivKey, PubKey)> = (0..20).map(|_| generate_keypair()).collect();
+        for peer in peers.iter() {
+            server
+                .relay_peer_advert_at(overlay, &advert(*peer, 1), &clock)
+                .unwrap();
+        }
+        assert_eq!(relayed(), 5);
+
+        // adverts already seen are not relayed again
+        clock.advance(1);
+        for peer in peers.iter() {
+            assert!(!server
+                .relay_peer_advert_at(overlay, &advert(*peer, 1), &clock)
+                .unwrap());
+        }
+        assert_eq!(relayed(), 0);
+
+        // dropped adverts are still stored, the most recent one of each peer is kept
+        for version in 2..4 {
+            for peer in peers.iter() {
+                server
+                    .relay_peer_advert_at(overlay, &advert(*peer, version), &clock)
+                    .unwrap();
+            }
+        }
+        assert_eq!(relayed(), 5);
+        for (_, pub_key) in peers.iter() {
+            let stored = Peer::open(pub_key, &server.store)
+                .unwrap()
+                .advert()
+                .unwrap();
+            assert_eq!(stored.version(), 3);
+        }
+
+        // adverts with a bad signature are rejected
+        let mut forged = advert(peers[0], 4);
+        if let PeerAdvert::V0(a) = &mut forged {
+            a.content.peer = peers[1].1;
+        }
+        assert_eq!(
+            server.relay_peer_advert_at(overlay, &forged, &clock),
+            Err(ProtocolError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    pub fn test_not_found_cache() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_not_found_cache(Some(RelTime::Minutes(1)));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        let hits = || server.not_found_cache.as_ref().unwrap().hits();
+
+        // the first miss hits the store, the second one is answered by the cache
+        for include_children in [false, true] {
+            assert_eq!(
+                server
+                    .get_block(
+                        user,
+                        overlay,
+                        block.id(),
+                        include_children,
+                        None,
+                        None,
+                        None,
+                        None
+                    )
+                    .err()
+                    .unwrap(),
+                ProtocolError::NotFound
+            );
+        }
+        assert_eq!(hits(), 1);
+
+        // put invalidates the cached miss
+        server.put_block(user, overlay, &block).unwrap();
+        let (r, _) = server
+            .get_block(user, overlay, block.id(), false, None, None, None, None)
+            .unwrap();
+        assert_eq!(r.try_recv().unwrap().id(), block.id());
+        assert_eq!(hits(), 1);
+    }
+
+    #[test]
+    pub fn test_overlay_requests_total() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = Arc::new(open_broker(root.path()));
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        let (s, _r) = async_channel::unbounded();
+        let handler = BrokerProtocolHandler {
+            broker: Arc::clone(&server),
+            user,
+            async_frames_sender: s,
+            topic_streams: RwLock::new(HashMap::new()),
+        };
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        let status_req = BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(BrokerOverlayMessage::V0(
+                BrokerOverlayMessageV0 {
+                    overlay,
+                    content: BrokerOverlayMessageContentV0::BrokerOverlayRequest(
+                        BrokerOverlayRequest::V0(BrokerOverlayRequestV0 {
+                            id: 1,
+                            content: BrokerOverlayRequestContentV0::OverlayStatusReq(
+                                OverlayStatusReq::V0(),
+                            ),
+                        }),
+                    ),
+                },
+            )),
+        });
+
+        // the user has no account yet
+        let _ = runtime::block_on(handler.handle_incoming(status_req.clone()));
+        add_user(&server, user);
+        let _ = runtime::block_on(handler.handle_incoming(status_req.clone()));
+        let _ = runtime::block_on(handler.handle_incoming(status_req));
+
+        let metrics = server.metrics();
+        assert_eq!(metrics.overlay_requests_total("no_account"), 1);
+        assert_eq!(metrics.overlay_requests_total("success"), 2);
+        assert!(metrics
+            .render()
+            .contains("overlay_requests_total{result=\"no_account\"} 1\n"));
+    }
+
+    #[test]
+    pub fn test_not_found_cache_skips_store() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_not_found_cache(Some(RelTime::Minutes(1)));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        let get = || server.get_block(user, overlay, block.id(), false, None, None, None, None);
+        assert_eq!(get().err().unwrap(), ProtocolError::NotFound);
+
+        // the block is stored behind the back of the broker, without invalidating the cached miss
+        server
+            .get_repostore_from_overlay_id(&overlay, |store| Ok(store.put(&block)?))
+            .unwrap();
+
+        // the second lookup doesn't reach the store, which has the block now
+        assert_eq!(get().err().unwrap(), ProtocolError::NotFound);
+        assert_eq!(server.not_found_cache.as_ref().unwrap().hits(), 1);
+
+        // without the cache, the lookup reaches the store
+        server.set_not_found_cache(None);
+        let (r, _) = server
+            .get_block(user, overlay, block.id(), false, None, None, None, None)
+            .unwrap();
+        assert_eq!(r.try_recv().unwrap().id(), block.id());
+    }
+
+    #[test]
+    pub fn test_del_user_unsubscribes() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        let (s, r) = async_channel::unbounded();
+        server.set_upstream(s);
+
+        let user1 = PubKey::Ed25519PubKey([1; 32]);
+        let user2 = PubKey::Ed25519PubKey([2; 32]);
+        add_user(&server, user1);
+        add_user(&server, user2);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        for user in [user1, user2] {
+            server
+                .join_overlay(user, overlay, Some(repo), secret, &vec![])
+                .unwrap();
+        }
+        // joining again doesn't count the user twice
+        server
+            .join_overlay(user1, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        let overlay_users = || {
+            Overlay::open(&overlay, &server.store)
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .users
+        };
+        assert_eq!(overlay_users(), 2);
+
+        let shared = PubKey::Ed25519PubKey([5; 32]);
+        let own = PubKey::Ed25519PubKey([6; 32]);
+        server.subscribe_topic(user1, overlay, shared).unwrap();
+        server.subscribe_topic(user1, overlay, own).unwrap();
+        server.subscribe_topic(user2, overlay, shared).unwrap();
+        assert_eq!(
+            Topic::open(&shared, &server.store)
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .users,
+            2
+        );
+
+        let client = PubKey::Ed25519PubKey([7; 32]);
+        let (frames1, frames1_r) = async_channel::unbounded();
+        let (frames2, frames2_r) = async_channel::unbounded();
+        server.open_session(user1, client, frames1);
+        server.open_session(user2, client, frames2);
+
+        let del_user = |user: PubKey| {
+            let (admin_privkey, admin_pubkey) = generate_keypair();
+            server.add_admin(admin_pubkey).unwrap();
+            let op_content = DelUserContentV0 {
+                user,
+                timestamp: now_timestamp(),
+                nonce: 0,
+            };
+            let sig = sign(
+                admin_privkey,
+                admin_pubkey,
+                &serde_bare::to_vec(&op_content).unwrap(),
+            )
+            .unwrap();
+            server.del_user(op_content, sig).unwrap();
+        };
+        let unsubscribed = || match r.try_recv() {
+            Ok((o, OverlayMessageContentV0::UnsubReq(UnsubReq::V0(u)))) => {
+                assert_eq!(o, overlay);
+                Some(u.topic)
+            }
+            Ok(msg) => panic!("unexpected upstream message {:?}", msg),
+            Err(_) => None,
+        };
+
+        // the topic only user1 subscribed to is torn down
+        del_user(user1);
+        assert_eq!(server.check_account(user1), Err(ProtocolError::NoAccount));
+        // and only the sessions of user1 are closed
+        assert!(frames1_r.is_closed());
+        assert!(!frames2_r.is_closed());
+        assert_eq!(unsubscribed(), Some(own));
+        assert_eq!(unsubscribed(), None);
+        assert_eq!(overlay_users(), 1);
+        assert!(Topic::open(&own, &server.store).is_err());
+        assert_eq!(
+            Topic::open(&shared, &server.store)
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .users,
+            1
+        );
+
+        // deleting the last subscriber tears down the upstream subscription
+        del_user(user2);
+        assert_eq!(unsubscribed(), Some(shared));
+        assert!(Topic::open(&shared, &server.store).is_err());
+    }
+
+    /// Peer broker searched as a fallback
+    struct PeerBroker {
+        broker: Arc<BrokerServer>,
+        user: PubKey,
+        searches: Arc<RwLock<usize>>,
+    }
+
+    impl BlockSource for PeerBroker {
+        fn search_block(
+            &self,
+            overlay: &OverlayId,
+            id: &BlockId,
+            include_children: bool,
+        ) -> async_channel::Receiver<Block> {
+            *self.searches.write().unwrap() += 1;
+            match self.broker.get_block(
+                self.user,
+                *overlay,
+                *id,
+                include_children,
+                None,
+                None,
+                None,
+                None,
+            ) {
+                Ok((r, _)) => r,
+                Err(_) => async_channel::unbounded::<Block>().1,
+            }
+        }
+    }
+
+    /// Source that answers after a delay
+    struct SlowSource {
+        inner: PeerBroker,
+        delay: Duration,
+    }
+
+    impl BlockSource for SlowSource {
+        fn search_block(
+            &self,
+            overlay: &OverlayId,
+            id: &BlockId,
+            include_children: bool,
+        ) -> async_channel::Receiver<Block> {
+            let found = self.inner.search_block(overlay, id, include_children);
+            let (s, r) = async_channel::unbounded::<Block>();
+            let delay = self.delay;
+            std::thread::spawn(move || {
+                std::thread::sleep(delay);
+                while let Ok(block) = found.recv_blocking() {
+                    let _ = s.send_blocking(block);
+                }
+            });
+            r
+        }
+    }
+
+    /// Source that also sends a block unrelated to the search
+    struct NoisySource {
+        inner: PeerBroker,
+        noise: Block,
+    }
+
+    impl BlockSource for NoisySource {
+        fn search_block(
+            &self,
+            overlay: &OverlayId,
+            id: &BlockId,
+            include_children: bool,
+        ) -> async_channel::Receiver<Block> {
+            let found = self.inner.search_block(overlay, id, include_children);
+            let (s, r) = async_channel::unbounded::<Block>();
+            s.send_blocking(self.noise.clone()).unwrap();
+            while let Ok(block) = found.recv_blocking() {
+                s.send_blocking(block).unwrap();
+            }
+            r
+        }
+    }
+
+    /// Source that panics
+    struct PanickingSource;
+
+    impl BlockSource for PanickingSource {
+        fn search_block(
+            &self,
+            _overlay: &OverlayId,
+            _id: &BlockId,
+            _include_children: bool,
+        ) -> async_channel::Receiver<Block> {
+            panic!("search failed");
+        }
+    }
+
+    /// Source that never answers
+    struct StalledSource;
+
+    impl BlockSource for StalledSource {
+        fn search_block(
+            &self,
+            _overlay: &OverlayId,
+            _id: &BlockId,
+            _include_children: bool,
+        ) -> async_channel::Receiver<Block> {
+            let (s, r) = async_channel::unbounded::<Block>();
+            std::mem::forget(s);
+            r
+        }
+    }
+
+    #[test]
+    pub fn test_block_fallback() {
+        let root_local = Builder::new().prefix("test-env").tempdir().unwrap();
+        let root_peer = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut local = open_broker(root_local.path());
+        let peer = Arc::new(open_broker(root_peer.path()));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&local, user);
+        add_user(&peer, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        local
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        peer.join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        // an object of several blocks, only held by the peer
+        let obj = Object::new(
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: vec![],
+                metadata: vec![],
+                content: (0..10000).map(|i| (i % 251) as u8).collect(),
+            })),
+            vec![],
+            None,
+            1000,
+            repo,
+            secret,
+        );
+        let unique: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+        assert!(unique.len() > 1);
+        for block in obj.blocks() {
+            peer.put_block(user, overlay, block).unwrap();
+        }
+
+        let searches = Arc::new(RwLock::new(0));
+        local.set_block_fallback(
+            Box::new(PeerBroker {
+                broker: Arc::clone(&peer),
+                user,
+                searches: Arc::clone(&searches),
+            }),
+            Duration::from_secs(5),
+        );
+
+        // get_block only looks up the local store
+        assert_eq!(
+            local
+                .get_block(user, overlay, obj.id(), true, None, None, None, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+        assert_eq!(*searches.read().unwrap(), 0);
+
+        let (r, _) = runtime::block_on(local.get_block_with_fallback(
+            user,
+            overlay,
+            obj.id(),
+            true,
+            None,
+            None,
+            None,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(count_blocks(r), unique.len());
+        assert_eq!(*searches.read().unwrap(), 1);
+
+        // the blocks are now stored locally, no more search
+        let (r, _) = runtime::block_on(local.get_block_with_fallback(
+            user,
+            overlay,
+            obj.id(),
+            true,
+            None,
+            None,
+            None,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(count_blocks(r), unique.len());
+        assert_eq!(*searches.read().unwrap(), 1);
+
+        // blocks the peer doesn't have either are not found
+        let missing = Digest::Blake3Digest32([9; 32]);
+        assert_eq!(
+            runtime::block_on(
+                local
+                    .get_block_with_fallback(user, overlay, missing, false, None, None, None, None)
+            )
+            .err()
+            .unwrap(),
+            ProtocolError::NotFound
+        );
+        assert_eq!(*searches.read().unwrap(), 2);
+
+        // the search is bounded by the timeout
+        local.set_block_fallback(Box::new(StalledSource), Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        assert_eq!(
+            runtime::block_on(
+                local
+                    .get_block_with_fallback(user, overlay, missing, false, None, None, None, None)
+            )
+            .err()
+            .unwrap(),
+            ProtocolError::NotFound
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // only the requested block and its children are stored
+        let noise = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![2; 100],
+            None,
+        );
+        let wanted = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![3; 100],
+            None,
+        );
+        peer.put_block(user, overlay, &wanted).unwrap();
+        local.set_block_fallback(
+            Box::new(NoisySource {
+                inner: PeerBroker {
+                    broker: Arc::clone(&peer),
+                    user,
+                    searches: Arc::clone(&searches),
+                },
+                noise: noise.clone(),
+            }),
+            Duration::from_secs(5),
+        );
+        let (r, _) = runtime::block_on(local.get_block_with_fallback(
+            user,
+            overlay,
+            wanted.id(),
+            false,
+            None,
+            None,
+            None,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(count_blocks(r), 1);
+        assert_eq!(
+            local
+                .get_block(user, overlay, noise.id(), false, None, None, None, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+    }
+    #[test]
+    pub fn test_block_fallback_single_flight() {
+        let root_local = Builder::new().prefix("test-env").tempdir().unwrap();
+        let root_peer = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut local = open_broker(root_local.path());
+        let peer = Arc::new(open_broker(root_peer.path()));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&local, user);
+        add_user(&peer, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        local
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        peer.join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        peer.put_block(user, overlay, &block).unwrap();
+
+        let searches = Arc::new(RwLock::new(0));
+        local.set_block_fallback(
+            Box::new(SlowSource {
+                inner: PeerBroker {
+                    broker: Arc::clone(&peer),
+                    user,
+                    searches: Arc::clone(&searches),
+                },
+                delay: Duration::from_millis(500),
+            }),
+            Duration::from_secs(5),
+        );
+        let local = Arc::new(local);
+
+        let n = 8;
+        let barrier = Arc::new(std::sync::Barrier::new(n));
+        let threads: Vec<_> = (0..n)
+            .map(|_| {
+                let local = Arc::clone(&local);
+                let barrier = Arc::clone(&barrier);
+                let id = block.id();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let fetched = local
+                        .get_block_with_fallback(user, overlay, id, false, None, None, None, None);
+                    runtime::block_on(fetched).map(|(r, _)| count_blocks(r))
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), Ok(1));
+        }
+        assert_eq!(*searches.read().unwrap(), 1);
+        assert!(local.in_flight.read().unwrap().is_empty());
+    }
+    #[test]
+    pub fn test_block_fallback_leader_panic() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut local = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&local, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        local
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        local.set_block_fallback(Box::new(PanickingSource), Duration::from_secs(5));
+
+        let missing = Digest::Blake3Digest32([9; 32]);
+        for _ in 0..2 {
+            let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let fetched = local
+                    .get_block_with_fallback(user, overlay, missing, false, None, None, None, None);
+                runtime::block_on(fetched)
+            }));
+            assert!(res.is_err());
+            // the search led by the panicking caller doesn't stay in progress
+            assert!(local.in_flight.read().unwrap().is_empty());
+        }
+    }
+    #[test]
+    pub fn test_put_invalid_block() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        // two children but no room for their keys
+        let block = Block::new(
+            vec![Digest::Blake3Digest32([5; 32]); 2],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        assert_eq!(
+            server.put_block(user, overlay, &block).err().unwrap(),
+            ProtocolError::InvalidBlock
+        );
+        assert_eq!(
+            server
+                .get_block(user, overlay, block.id(), false, None, None, None, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+    }
+    #[test]
+    pub fn test_put_block_duplicate() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = Arc::new(open_broker(root.path()));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+
+        // the same block put concurrently, and put again by each task
+        let n = 2;
+        let barrier = Arc::new(std::sync::Barrier::new(n));
+        let threads: Vec<_> = (0..n)
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let barrier = Arc::clone(&barrier);
+                let block = block.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    (0..10)
+                        .map(|_| server.put_block(user, overlay, &block))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for t in threads {
+            for res in t.join().unwrap() {
+                assert_eq!(res, Ok(()));
+            }
+        }
+        assert_eq!(
+            replicated_blocks(server.replicate(user, &overlay, None).unwrap()).len(),
+            1
+        );
+
+        // a duplicate in a batch, and a block already stored
+        let results = server
+            .put_blocks(user, overlay, &[block.clone(), block.clone()])
+            .unwrap();
+        assert_eq!(results, vec![Ok(()), Ok(())]);
+        assert_eq!(
+            replicated_blocks(server.replicate(user, &overlay, None).unwrap()).len(),
+            1
+        );
+
+        let (r, _) = server
+            .get_block(user, overlay, block.id(), false, None, None, None, None)
+            .unwrap();
+        assert_eq!(r.try_recv().unwrap().id(), block.id());
+    }
+    #[test]
+    pub fn test_overlay_default_expiry() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        server
+            .set_overlay_default_expiry(overlay, Some(RelTime::Minutes(10)))
+            .unwrap();
+
+        let new_block = |i| {
+            Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                None,
+                vec![i; 100],
+                None,
+            )
+        };
+        // the overlay default applies, unless overridden for the put
+        let expiring = new_block(1);
+        server.put_block(user, overlay, &expiring).unwrap();
+        let kept = new_block(2);
+        server
+            .put_block_with_expiry(user, overlay, &kept, Some(RelTime::Days(1)))
+            .unwrap();
+
+        let clock = MockClock::new(now_timestamp() + RelTime::Minutes(11));
+        server.remove_expired_at(&clock).unwrap();
+        assert_eq!(
+            server
+                .get_block(user, overlay, expiring.id(), false, None, None, None, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+        let (r, _) = server
+            .get_block(user, overlay, kept.id(), false, None, None, None, None)
+            .unwrap();
+        assert_eq!(r.try_recv().unwrap().id(), kept.id());
+    }
+
+    #[test]
+    pub fn test_sync_commit_types() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![MemberV0::new(
+                member_pubkey,
+                vec![CommitType::Transaction, CommitType::Ack],
+                vec![],
+            )],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+
+        let put_object = |content: ObjectContent, deps: Vec<ObjectId>| {
+            let obj = Object::new(content, deps, None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        let put_commit = |seq, deps: Vec<ObjectRef>, body| {
+            let body_ref = put_object(ObjectContent::CommitBody(body), vec![]);
+            let dep_ids = deps.iter().map(|d| d.id).collect();
+            let commit = Commit::new(
+                member_privkey,
+                member_pubkey,
+                seq,
+                branch_ref,
+                deps,
+                vec![],
+                vec![],
+                vec![],
+                body_ref,
+                None,
+            )
+            .unwrap();
+            let commit_ref = put_object(ObjectContent::Commit(commit), dep_ids);
+            server
+                .publish_commit(user, overlay, &branch, commit_ref)
+                .unwrap();
+            (commit_ref, body_ref)
+        };
+
+        // the root commit is not sent by the sync
+        let (first, _) = put_commit(1, vec![], CommitBody::Transaction(Transaction::V0(vec![0])));
+        let (t1, t1_body) = put_commit(
+            2,
+            vec![first],
+            CommitBody::Transaction(Transaction::V0(vec![1])),
+        );
+        let (a1, a1_body) = put_commit(3, vec![t1], CommitBody::Ack(Ack::V0()));
+        let (t2, t2_body) = put_commit(
+            4,
+            vec![a1],
+            CommitBody::Transaction(Transaction::V0(vec![2])),
+        );
+
+        // empty filter
+        let known_commits = BloomFilter {
+            k: 1,
+            f: vec![0; 8],
+        };
+        let r = server
+            .sync_branch(
+                user,
+                &overlay,
+                &vec![t2.id],
+                &vec![],
+                &known_commits,
+                Some(&vec![CommitType::Transaction]),
+                None,
+            )
+            .unwrap();
+        let client = HashMapRepoStore::new();
+        while let Ok(block) = r.try_recv() {
+            client.put(&block).unwrap();
+        }
+
+        // all the commits, but only the bodies of the transactions
+        for commit_ref in [t1, a1, t2] {
+            let commit = Commit::load(commit_ref, &client).unwrap();
+            commit.verify_sig().unwrap();
+        }
+        for body_ref in [t1_body, t2_body] {
+            assert!(client.get(&body_ref.id).is_ok());
+        }
+        assert!(client.get(&a1_body.id).is_err());
+        assert!(Commit::load(t2, &client)
+            .unwrap()
+            .load_body(&client)
+            .is_ok());
+        assert!(matches!(
+            Commit::load(a1, &client).unwrap().load_body(&client),
+            Err(CommitLoadError::MissingBlocks(_))
+        ));
+
+        // without filter, only the commits are sent
+        let r = server
+            .sync_branch(
+                user,
+                &overlay,
+                &vec![t2.id],
+                &vec![],
+                &known_commits,
+                None,
+                None,
+            )
+            .unwrap();
+        let client = HashMapRepoStore::new();
+        while let Ok(block) = r.try_recv() {
+            client.put(&block).unwrap();
+        }
+        assert!(Commit::load(t2, &client).is_ok());
+        assert!(client.get(&t2_body.id).is_err());
+
+        // a sync interrupted halfway is resumed from a checkpoint
+        let types = vec![CommitType::Transaction];
+        let sync = |checkpoint: Option<&Vec<u8>>| {
+            let r = server
+                .sync_branch(
+                    user,
+                    &overlay,
+                    &vec![t2.id],
+                    &vec![],
+                    &known_commits,
+                    Some(&types),
+                    checkpoint,
+                )
+                .unwrap();
+            let mut blocks = vec![];
+            while let Ok(block) = r.try_recv() {
+                blocks.push(block.id());
+            }
+            blocks
+        };
+        let all = sync(None);
+        let half = all.len() / 2;
+        let received: Vec<BlockId> = sync(None).into_iter().take(half).collect();
+        let token = SyncCheckpoint::token(
+            &vec![t2.id],
+            &vec![],
+            &known_commits,
+            Some(&types),
+            half as u32,
+        );
+        let rest = sync(Some(&token));
+        assert_eq!(rest.len(), all.len() - half);
+        let resumed: HashSet<BlockId> = received.into_iter().chain(rest).collect();
+        assert_eq!(resumed, all.into_iter().collect::<HashSet<BlockId>>());
+
+        // a checkpoint of another request is refused
+        let token = SyncCheckpoint::token(&vec![a1.id], &vec![], &known_commits, None, 1);
+        assert!(matches!(
+            server.sync_branch(
+                user,
+                &overlay,
+                &vec![t2.id],
+                &vec![],
+                &known_commits,
+                Some(&types),
+                Some(&token),
+            ),
+            Err(ProtocolError::InvalidValue)
+        ));
+    }
+
+    #[test]
+    pub fn test_sync_progress() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![MemberV0::new(
+                member_pubkey,
+                vec![CommitType::Transaction],
+                vec![],
+            )],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+
+        let put_object = |content: ObjectContent, deps: Vec<ObjectId>| {
+            let obj = Object::new(content, deps, None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        // bodies of several blocks, so that the total counts blocks rather than objects
+        let put_commit = |seq: u32, deps: Vec<ObjectRef>| {
+            let body = CommitBody::Transaction(Transaction::V0(vec![seq as u8; 10000]));
+            let body_ref = put_object(ObjectContent::CommitBody(body), vec![]);
+            let dep_ids = deps.iter().map(|d| d.id).collect();
+            let commit = Commit::new(
+                member_privkey,
+                member_pubkey,
+                seq,
+                branch_ref,
+                deps,
+                vec![],
+                vec![],
+                vec![],
+                body_ref,
+                None,
+            )
+            .unwrap();
+            let commit_ref = put_object(ObjectContent::Commit(commit), dep_ids);
+            server
+                .publish_commit(user, overlay, &branch, commit_ref)
+                .unwrap();
+            commit_ref
+        };
+        let c1 = put_commit(1, vec![]);
+        let c2 = put_commit(2, vec![c1]);
+        let c3 = put_commit(3, vec![c2]);
+        let c4 = put_commit(4, vec![c3]);
+
+        let known_commits = BloomFilter {
+            k: 1,
+            f: vec![0; 8],
+        };
+        let types = vec![CommitType::Transaction];
+        let r = server
+            .sync_branch_with_progress(
+                user,
+                &overlay,
+                &vec![c4.id],
+                &vec![],
+                &known_commits,
+                Some(&types),
+                None,
+            )
+            .unwrap();
+        let mut blocks = 0;
+        let mut progress = vec![];
+        while let Ok(item) = r.try_recv() {
+            match item {
+                SyncStreamItem::Block(_) => {
+                    assert!(!progress.is_empty());
+                    blocks += 1;
+                }
+                SyncStreamItem::Progress(p) => {
+                    assert_eq!(p.sent(), blocks);
+                    assert!(p.sent() <= p.total());
+                    progress.push(p);
+                }
+            }
+        }
+
+        // the total is counted in blocks from the first progress on
+        let first = progress.first().unwrap();
+        let last = progress.last().unwrap();
+        assert_eq!(*first, SyncProgress::new(blocks, 0));
+        assert!(progress.iter().all(|p| p.total() == blocks));
+        assert_eq!(*last, SyncProgress::new(blocks, blocks));
+
+        // the same blocks as without progress
+        let r = server
+            .sync_branch(
+                user,
+                &overlay,
+                &vec![c4.id],
+                &vec![],
+                &known_commits,
+                Some(&types),
+                None,
+            )
+            .unwrap();
+        assert_eq!(r.len() as u32, blocks);
+
+        // the last commit is the only head of the branch
+        let topic = branch.topic();
+        assert_eq!(
+            server.branch_heads(user, &overlay, &topic, &vec![]),
+            Ok(vec![c4.id])
+        );
+        assert_eq!(
+            server.branch_heads(user, &overlay, &topic, &vec![c4.id]),
+            Ok(vec![])
+        );
+
+        // a sync cut at the block limit is marked as truncated, and resumed from a checkpoint
+        server.set_max_sync_blocks(blocks as usize - 2);
+        let sync = |checkpoint: Option<&Vec<u8>>| {
+            let r = server
+                .sync_branch_with_progress(
+                    user,
+                    &overlay,
+                    &vec![c4.id],
+                    &vec![],
+                    &known_commits,
+                    Some(&types),
+                    checkpoint,
+                )
+                .unwrap();
+            let mut progress = vec![];
+            while let Ok(item) = r.try_recv() {
+                if let SyncStreamItem::Progress(p) = item {
+                    progress.push(p);
+                }
+            }
+            (*progress.first().unwrap(), *progress.last().unwrap())
+        };
+        let (first, last) = sync(None);
+        assert_eq!(first.total(), blocks - 2);
+        assert!(last.truncated());
+        assert_eq!(last.sent(), blocks - 2);
+        let checkpoint = SyncCheckpoint::token(
+            &vec![c4.id],
+            &vec![],
+            &known_commits,
+            Some(&types),
+            last.sent(),
+        );
+        // the blocks skipped by the checkpoint are not counted in the total
+        assert_eq!(
+            sync(Some(&checkpoint)),
+            (SyncProgress::new(2, 0), SyncProgress::new(2, 2))
+        );
+    }
+
+    #[test]
+    pub fn test_seal_branch() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (owner_privkey, owner_pubkey) = generate_keypair();
+        let (writer_privkey, writer_pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![
+                MemberV0::new(
+                    owner_pubkey,
+                    vec![CommitType::Transaction, CommitType::SealBranch],
+                    vec![],
+                ),
+                MemberV0::new(writer_pubkey, vec![CommitType::Transaction], vec![]),
+            ],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+
+        let put_object = |content: ObjectContent| {
+            let obj = Object::new(content, vec![], None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        let put_commit = |privkey, pubkey, seq, deps: Vec<ObjectRef>, body| {
+            let body_ref = put_object(ObjectContent::CommitBody(body));
+            let commit = Commit::new(
+                privkey,
+                pubkey,
+                seq,
+                branch_ref,
+                deps,
+                vec![],
+                vec![],
+                vec![],
+                body_ref,
+                None,
+            )
+            .unwrap();
+            put_object(ObjectContent::Commit(commit))
+        };
+        let transaction = |i| CommitBody::Transaction(Transaction::V0(vec![i]));
+
+        let t1 = put_commit(writer_privkey, writer_pubkey, 1, vec![], transaction(1));
+        server.publish_commit(user, overlay, &branch, t1).unwrap();
+
+        // only members with the permission can seal
+        let bad_seal = put_commit(
+            writer_privkey,
+            writer_pubkey,
+            2,
+            vec![t1],
+            CommitBody::SealBranch(SealBranch::V0()),
+        );
+        assert_eq!(
+            server
+                .publish_commit(user, overlay, &branch, bad_seal)
+                .err()
+                .unwrap(),
+            ProtocolError::AccessDenied
+        );
+
+        let seal = put_commit(
+            owner_privkey,
+            owner_pubkey,
+            1,
+            vec![t1],
+            CommitBody::SealBranch(SealBranch::V0()),
+        );
+        server.publish_commit(user, overlay, &branch, seal).unwrap();
+        let topic = Topic::open(&branch.topic(), &server.store).unwrap();
+        assert_eq!(topic.sealed().unwrap(), Some(seal.id));
+        assert!(topic.has_head(&seal.id).is_ok());
+
+        // no more commits
+        let t2 = put_commit(writer_privkey, writer_pubkey, 2, vec![seal], transaction(2));
+        assert_eq!(
+            server
+                .publish_commit(user, overlay, &branch, t2)
+                .err()
+                .unwrap(),
+            ProtocolError::BranchSealed
+        );
+
+        // reads still work
+        let (r, _) = server
+            .get_block(user, overlay, t1.id, true, None, None, None, None)
+            .unwrap();
+        assert!(count_blocks(r) > 0);
+    }
+
+    #[test]
+    pub fn test_metadata_limits() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_metadata_limits(MetadataLimits {
+            commit: 100,
+            file: 100,
+        });
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (privkey, pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![MemberV0::new(pubkey, vec![CommitType::Transaction], vec![])],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+
+        let put_object = |content: ObjectContent| {
+            let obj = Object::new(content, vec![], None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        let put_commit = |seq, metadata: Vec<u8>| {
+            let body_ref = put_object(ObjectContent::CommitBody(CommitBody::Transaction(
+                Transaction::V0(vec![seq as u8]),
+            )));
+            let commit = Commit::new(
+                privkey,
+                pubkey,
+                seq,
+                branch_ref,
+                vec![],
+                vec![],
+                vec![],
+                metadata,
+                body_ref,
+                None,
+            )
+            .unwrap();
+            put_object(ObjectContent::Commit(commit))
+        };
+
+        let small = put_commit(1, vec![1; 100]);
+        server
+            .publish_commit(user, overlay, &branch, small)
+            .unwrap();
+
+        let large = put_commit(2, vec![1; 101]);
+        assert_eq!(
+            server
+                .publish_commit(user, overlay, &branch, large)
+                .err()
+                .unwrap(),
+            ProtocolError::MetadataTooLarge
+        );
+    }
+
+    #[test]
+    pub fn test_objects_containing() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        // same content, different deps: the leaves are shared, the roots are not
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: vec![7; 20000],
+        }));
+        let obj1 = Object::new(content.clone(), vec![], None, 4000, repo, secret);
+        let obj2 = Object::new(
+            content,
+            vec![Digest::Blake3Digest32([9; 32])],
+            None,
+            4000,
+            repo,
+            secret,
+        );
+        assert_ne!(obj1.id(), obj2.id());
+        let id1 = server.put_object(user, overlay, &obj1).unwrap();
+        let id2 = server.put_object(user, overlay, &obj2).unwrap();
+
+        let ids2: HashSet<BlockId> = obj2.blocks().iter().map(|b| b.id()).collect();
+        let shared = obj1
+            .blocks()
+            .iter()
+            .map(|b| b.id())
+            .find(|id| ids2.contains(id))
+            .unwrap();
+        let objects = server.objects_containing(&overlay, &shared).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert!(objects.contains(&id1));
+        assert!(objects.contains(&id2));
+        assert_eq!(
+            server.objects_containing(&overlay, &obj1.id()).unwrap(),
+            vec![id1]
+        );
+
+        // deleting one object keeps the blocks the other still contains
+        server.del_object(user, overlay, id1).unwrap();
+        assert_eq!(
+            server.objects_containing(&overlay, &shared).unwrap(),
+            vec![id2]
+        );
+        assert!(server
+            .get_block(user, overlay, shared, false, None, None, None, None)
+            .is_ok());
+        assert_eq!(
+            server
+                .get_block(user, overlay, id1, false, None, None, None, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+    }
+
+    #[test]
+    pub fn test_max_object_size() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_max_object_size(10_000);
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let file = |len: usize| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content: vec![7; len],
+            }))
+        };
+        let small = Object::new(file(9000), vec![], None, 4000, repo, secret);
+        assert!(server.put_object(user, overlay, &small).is_ok());
+
+        let large = Object::new(file(10_000), vec![], None, 4000, repo, secret);
+        assert_eq!(
+            server.put_object(user, overlay, &large).err().unwrap(),
+            ProtocolError::ObjectTooLarge
+        );
+        // nothing was stored
+        assert_eq!(
+            server
+                .get_block(user, overlay, large.id(), false, None, None, None, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+    }
+
+    #[test]
+    pub fn test_tombstone() {
+        let root_a = Builder::new().prefix("test-env").tempdir().unwrap();
+        let root_b = Builder::new().prefix("test-env").tempdir().unwrap();
+        let a = open_broker(root_a.path());
+        let b = open_broker(root_b.path());
+
+        let (user_privkey, user) = generate_keypair();
+        let (_, outsider) = generate_keypair();
+        add_user(&a, user);
+        add_user(&b, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        a.join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        b.join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: vec![7; 10_000],
+        }));
+        let object = Object::new(content, vec![], None, 4000, repo, secret);
+        let id = a.put_object(user, overlay, &object).unwrap();
+        b.put_object(user, overlay, &object).unwrap();
+        let get = |server: &BrokerServer| {
+            server.get_block(user, overlay, id, true, None, None, None, None)
+        };
+
+        // the tombstone must be signed by a user of the overlay
+        let forged = Tombstone::new(&overlay, id, user_privkey, outsider).unwrap();
+        assert_eq!(
+            a.add_tombstone(user, overlay, &forged).err().unwrap(),
+            ProtocolError::AccessDenied
+        );
+        let other_overlay = Digest::Blake3Digest32([5; 32]);
+        let wrong = Tombstone::new(&other_overlay, id, user_privkey, user).unwrap();
+        assert_eq!(
+            a.add_tombstone(user, overlay, &wrong).err().unwrap(),
+            ProtocolError::InvalidSignature
+        );
+
+        let tombstone = Tombstone::new(&overlay, id, user_privkey, user).unwrap();
+        a.add_tombstone(user, overlay, &tombstone).unwrap();
+        assert_eq!(get(&a).err().unwrap(), ProtocolError::NotFound);
+
+        // B still has the object and replicates it to A, which refuses the deleted root
+        let r = b.replicate(user, &overlay, None).unwrap();
+        let mut refused = 0;
+        for block in replicated_blocks(r) {
+            if a.put_block(user, overlay, &block).err() == Some(ProtocolError::ObjectDeleted) {
+                refused += 1;
+            }
+        }
+        assert_eq!(refused, 1);
+        assert_eq!(get(&a).err().unwrap(), ProtocolError::NotFound);
+        let results = a.put_blocks(user, overlay, object.blocks()).unwrap();
+        assert_eq!(results.iter().filter(|res| res.is_err()).count(), 1);
+
+        // the tombstones of A travel with its replication, ahead of the blocks,
+        // and delete the object on B
+        assert_eq!(a.tombstones(user, &overlay).unwrap(), vec![tombstone]);
+        let r = a.replicate(user, &overlay, None).unwrap();
+        match r.try_recv().unwrap() {
+            ReplicationStreamItem::Tombstone(t) => b.add_tombstone(user, overlay, &t).unwrap(),
+            item => panic!("expected a tombstone, got {:?}", item),
+        }
+        assert_eq!(get(&b).err().unwrap(), ProtocolError::NotFound);
+        // adding it again is harmless
+        b.add_tombstone(user, overlay, &tombstone).unwrap();
+
+        // expired tombstones are removed, and the object can be put again
+        let clock = MockClock::new(
+            tombstone.deleted_at() + DEFAULT_TOMBSTONE_RETENTION + RelTime::Minutes(1),
+        );
+        a.remove_expired_tombstones_at(&overlay, &clock).unwrap();
+        assert!(a.tombstones(user, &overlay).unwrap().is_empty());
+        a.put_object(user, overlay, &object).unwrap();
+        assert!(get(&a).is_ok());
+
+        // and an expired tombstone isn't added
+        let old = Tombstone::new_at(&overlay, id, Timestamp::from_minutes(1), user_privkey, user)
+            .unwrap();
+        a.add_tombstone(user, overlay, &old).unwrap();
+        assert!(a.tombstones(user, &overlay).unwrap().is_empty());
+        assert!(get(&a).is_ok());
+
+        // nor a tombstone from the future, that would outlive the retention
+        let now = now_timestamp();
+        let future =
+            Tombstone::new_at(&overlay, id, now + RelTime::Days(30), user_privkey, user).unwrap();
+        assert_eq!(
+            a.add_tombstone(user, overlay, &future).err().unwrap(),
+            ProtocolError::InvalidValue
+        );
+        assert!(get(&a).is_ok());
+        // within the allowed clock skew, it is added
+        let skewed =
+            Tombstone::new_at(&overlay, id, now + RelTime::Minutes(5), user_privkey, user).unwrap();
+        a.add_tombstone(user, overlay, &skewed).unwrap();
+        assert_eq!(a.tombstones(user, &overlay).unwrap(), vec![skewed]);
+        assert_eq!(get(&a).err().unwrap(), ProtocolError::NotFound);
+    }
+
+    #[test]
+    pub fn test_copy_object() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let file = |byte: u8| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content: vec![byte; 10_000],
+            }))
+        };
+        let now = now_timestamp();
+        let soon = Object::new(
+            file(7),
+            vec![],
+            Some(now + RelTime::Minutes(5)),
+            4000,
+            repo,
+            secret,
+        );
+        let other = Object::new(
+            file(8),
+            vec![],
+            Some(now + RelTime::Minutes(5)),
+            4000,
+            repo,
+            secret,
+        );
+        let id = server.put_object(user, overlay, &soon).unwrap();
+        let other_id = server.put_object(user, overlay, &other).unwrap();
+        let get = |id: ObjectId| {
+            server
+                .get_block(user, overlay, id, true, None, None, None, None)
+                .map(|(r, _)| r)
+        };
+
+        // the object keeps its ID and all its blocks get the new expiry
+        assert_eq!(
+            server
+                .copy_object(user, overlay, id, Some(now + RelTime::Minutes(60)))
+                .unwrap(),
+            id
+        );
+        // putting the blocks again doesn't bring back their own expiry
+        server.put_object(user, overlay, &soon).unwrap();
+
+        server
+            .remove_expired_at(&MockClock::new(now + RelTime::Minutes(10)))
+            .unwrap();
+        assert!(get(id).is_ok());
+        assert_eq!(get(other_id).err().unwrap(), ProtocolError::NotFound);
+
+        server
+            .remove_expired_at(&MockClock::new(now + RelTime::Minutes(61)))
+            .unwrap();
+        assert_eq!(get(id).err().unwrap(), ProtocolError::NotFound);
+
+        // without expiry, the object never expires
+        let id = server.put_object(user, overlay, &soon).unwrap();
+        server.copy_object(user, overlay, id, None).unwrap();
+        server
+            .remove_expired_at(&MockClock::new(now + RelTime::Days(1)))
+            .unwrap();
+        assert!(get(id).is_ok());
+
+        let missing = Digest::Blake3Digest32([9; 32]);
+        assert_eq!(
+            server
+                .copy_object(user, overlay, missing, None)
+                .err()
+                .unwrap(),
+            ProtocolError::NotFound
+        );
+    }
+
+    #[test]
+    pub fn test_clamp_expiry() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_max_expiry(RelTime::Days(1));
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let new_block = |i, expiry| {
+            Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                Some(expiry),
+                vec![i; 100],
+                None,
+            )
+        };
+        let now = now_timestamp();
+        // from a client clock behind the broker
+        let past = new_block(1, now - RelTime::Hours(1));
+        server.put_block(user, overlay, &past).unwrap();
+        // far after the maximum expiry
+        let far = new_block(2, Timestamp::MAX);
+        server.put_block(user, overlay, &far).unwrap();
+        let valid = new_block(3, now + RelTime::Hours(1));
+        server.put_block(user, overlay, &valid).unwrap();
+
+        let get = |block: &Block| {
+            server
+                .get_block(user, overlay, block.id(), false, None, None, None, None)
+                .is_ok()
+        };
+        assert!(get(&past) && get(&far) && get(&valid));
+
+        // the past expiry is moved to the next minute
+        server
+            .remove_expired_at(&MockClock::new(now + RelTime::Minutes(2)))
+            .unwrap();
+        assert!(!get(&past) && get(&far) && get(&valid));
+
+        // the far expiry is moved back to the maximum expiry
+        server
+            .remove_expired_at(&MockClock::new(now + RelTime::Days(2)))
+            .unwrap();
+        assert!(!get(&far) && !get(&valid));
+
+        // a maximum expiry under a minute moves the expiries to the next minute
+        server.set_max_expiry(RelTime::Seconds(10));
+        let get = |block: &Block| {
+            server
+                .get_block(user, overlay, block.id(), false, None, None, None, None)
+                .is_ok()
+        };
+        let far = new_block(4, Timestamp::MAX);
+        server.put_block(user, overlay, &far).unwrap();
+        assert!(get(&far));
+        server
+            .remove_expired_at(&MockClock::new(now + RelTime::Minutes(2)))
+            .unwrap();
+        assert!(!get(&far));
+    }
+
+    #[test]
+    pub fn test_peer_events() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let mut events = Box::pin(server.peer_events(overlay));
+        let dropped = server.peer_events(overlay);
+        drop(dropped);
+
+        let peer = PubKey::Ed25519PubKey([5; 32]);
+        server.set_peer_connected(overlay, &peer, true).unwrap();
+        // the flag does not flip
+        server.set_peer_connected(overlay, &peer, true).unwrap();
+        server.set_peer_connected(overlay, &peer, false).unwrap();
+        server.set_peer_connected(overlay, &peer, false).unwrap();
+
+        let mut next = || events.next().now_or_never().flatten();
+        assert_eq!(next(), Some(PeerEvent::Connected(peer)));
+        assert_eq!(next(), Some(PeerEvent::Disconnected(peer)));
+        assert_eq!(next(), None);
+    }
+
+    #[test]
+    pub fn test_account_limits() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let mut server = open_broker(root.path());
+        server.set_account_limits(AccountLimits {
+            max_overlays: 2,
+            max_topics: 1,
+        });
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let join = |i: u8| {
+            server.join_overlay(
+                user,
+                Digest::Blake3Digest32([i; 32]),
+                Some(repo),
+                secret,
+                &vec![],
+            )
+        };
+
+        join(1).unwrap();
+        join(2).unwrap();
+        // the user is at the cap
+        assert_eq!(join(3), Err(ProtocolError::LimitExceeded));
+
+        // the overlays already joined keep working
+        join(2).unwrap();
+        let overlay = Digest::Blake3Digest32([1; 32]);
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![1; 100],
+            None,
+        );
+        server.put_block(user, overlay, &block).unwrap();
+        assert!(server
+            .get_block(user, overlay, block.id(), false, None, None, None, None)
+            .is_ok());
+
+        let topic = |i: u8| PubKey::Ed25519PubKey([i; 32]);
+        server.subscribe_topic(user, overlay, topic(5)).unwrap();
+        assert_eq!(
+            server.subscribe_topic(user, overlay, topic(6)),
+            Err(ProtocolError::LimitExceeded)
+        );
+
+        // the limits of the user override the defaults
+        server
+            .set_user_limits(
+                user,
+                AccountLimits {
+                    max_overlays: 3,
+                    max_topics: 2,
+                },
+            )
+            .unwrap();
+        join(3).unwrap();
+        assert_eq!(join(4), Err(ProtocolError::LimitExceeded));
+        server.subscribe_topic(user, overlay, topic(6)).unwrap();
+    }
+
+    #[test]
+    pub fn test_get_commit_access() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        let outsider = PubKey::Ed25519PubKey([5; 32]);
+        add_user(&server, user);
+        add_user(&server, outsider);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (privkey, pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![MemberV0::new(pubkey, vec![CommitType::Transaction], vec![])],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+        let put_object = |content: ObjectContent| {
+            let obj = Object::new(content, vec![], None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        let body_ref = put_object(ObjectContent::CommitBody(CommitBody::Transaction(
+            Transaction::V0(vec![1]),
+        )));
+        let commit = Commit::new(
+            privkey,
+            pubkey,
+            1,
+            branch_ref,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            body_ref,
+            None,
+        )
+        .unwrap();
+        let commit_ref = put_object(ObjectContent::Commit(commit));
+        server
+            .publish_commit(user, overlay, &branch, commit_ref)
+            .unwrap();
+
+        assert!(server.get_commit(user, &overlay, commit_ref.id).is_ok());
+        // a user who hasn't joined the overlay can't fetch its commits
+        assert_eq!(
+            server
+                .get_commit(outsider, &overlay, commit_ref.id)
+                .err()
+                .unwrap(),
+            ProtocolError::AccessDenied
+        );
+    }
+
+    #[test]
+    pub fn test_get_block_range_access() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        let outsider = PubKey::Ed25519PubKey([5; 32]);
+        add_user(&server, user);
+        add_user(&server, outsider);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..20000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let obj = Object::new(content, vec![], None, 4000, repo, secret);
+        let id = server.put_object(user, overlay, &obj).unwrap();
+
+        assert!(server.get_block_range(user, &overlay, id, 0, 1000).is_ok());
+        // a user who hasn't joined the overlay can't fetch its blocks
+        assert_eq!(
+            server
+                .get_block_range(outsider, &overlay, id, 0, 1000)
+                .err()
+                .unwrap(),
+            ProtocolError::AccessDenied
+        );
+    }
+
+    #[test]
+    pub fn test_publish_future_commit() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        add_user(&server, user);
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let overlay = Digest::Blake3Digest32([2; 32]);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+
+        let (privkey, pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([6; 32]),
+            PubKey::Ed25519PubKey([7; 32]),
+            SymKey::ChaCha20Key([8; 32]),
+            vec![MemberV0::new(pubkey, vec![CommitType::Transaction], vec![])],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+        let branch_ref = ObjectRef {
+            id: Digest::Blake3Digest32([9; 32]),
+            key: SymKey::ChaCha20Key([9; 32]),
+        };
+        let put_object = |content: ObjectContent| {
+            let obj = Object::new(content, vec![], None, 4096, repo, secret);
+            for block in obj.blocks() {
+                server.put_block(user, overlay, block).unwrap();
+            }
+            ObjectRef {
+                id: obj.id(),
+                key: obj.key().unwrap(),
+            }
+        };
+        let body_ref = put_object(ObjectContent::CommitBody(CommitBody::Transaction(
+            Transaction::V0(vec![1]),
+        )));
+        let commit = Commit::new(
+            privkey,
+            pubkey,
+            1,
+            branch_ref,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            body_ref,
+            None,
+        )
+        .unwrap();
+
+        // a commit created a day ahead of the broker clock, properly signed by the author
+        let mut content = commit.content().clone();
+        content.created_at = now_timestamp() + RelTime::Days(1);
+        let sig = sign(privkey, pubkey, &serde_bare::to_vec(&content).unwrap()).unwrap();
+        let future = put_object(ObjectContent::Commit(Commit::V0(CommitV0 {
+            content,
+            sig,
+            device: None,
+            id: None,
+            key: None,
+        })));
+        assert_eq!(
+            server
+                .publish_commit(user, overlay, &branch, future)
+                .err()
+                .unwrap(),
+            ProtocolError::InvalidTimestamp
+        );
+    }
+
+    #[test]
+    pub fn test_client_request_freshness() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let server = open_broker(root.path());
+
+        let (user_privkey, user) = generate_keypair();
+        add_user(&server, user);
+        let client = PubKey::Ed25519PubKey([5; 32]);
+        let sign_content = |content: &Vec<u8>| sign(user_privkey, user, content).unwrap();
+
+        // a stale request is refused
+        let add = AddClientContentV0 {
+            client,
+            timestamp: now_timestamp() - RelTime::Minutes(11),
+            nonce: 0,
+        };
+        let sig = sign_content(&serde_bare::to_vec(&add).unwrap());
+        assert_eq!(
+            server.add_client(user, add, sig),
+            Err(ProtocolError::InvalidSignature)
+        );
+
+        let add = AddClientContentV0 {
+            client,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign_content(&serde_bare::to_vec(&add).unwrap());
+        server.add_client(user, add, sig).unwrap();
+
+        let del = DelClientContentV0 {
+            client,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let del_sig = sign_content(&serde_bare::to_vec(&del).unwrap());
+        server.del_client(user, del, del_sig).unwrap();
+
+        // the captured requests can't be replayed: the client comes back with a new request only,
+        // and the replayed deletion doesn't remove it again
+        assert_eq!(
+            server.add_client(user, add, sig),
+            Err(ProtocolError::InvalidSignature)
+        );
+        let add = AddClientContentV0 { nonce: 1, ..add };
+        let sig = sign_content(&serde_bare::to_vec(&add).unwrap());
+        server.add_client(user, add, sig).unwrap();
+        assert_eq!(
+            server.del_client(user, del, del_sig),
+            Err(ProtocolError::InvalidSignature)
+        );
+        let account = Account::open(&user, &server.store).unwrap();
+        assert!(account.has_client(&client).is_ok());
+    }
 }
@@ -0,0 +1,41 @@
+//! Bound on the store-bound operations a broker runs concurrently
+
+use async_channel::{bounded, Receiver, Sender};
+
+/// Counting semaphore over a bounded channel, holding one message per permit taken
+pub struct StoreLimiter {
+    s: Sender<()>,
+    r: Receiver<()>,
+}
+
+/// Permit to run a store-bound operation, given back when dropped
+pub struct StorePermit<'a> {
+    r: &'a Receiver<()>,
+}
+
+impl StoreLimiter {
+    pub fn new(max: usize) -> StoreLimiter {
+        assert!(max > 0, "the store concurrency must be at least 1");
+        let (s, r) = bounded(max);
+        StoreLimiter { s, r }
+    }
+
+    /// Maximum number of permits taken at the same time
+    pub fn max(&self) -> usize {
+        self.s.capacity().unwrap()
+    }
+
+    /// Wait until fewer than `max` permits are taken, and take one.
+    /// Waiting callers get their permit in the order they asked for it
+    pub async fn acquire(&self) -> StorePermit<'_> {
+        // never closed, we hold the receiver
+        let _ = self.s.send(()).await;
+        StorePermit { r: &self.r }
+    }
+}
+
+impl Drop for StorePermit<'_> {
+    fn drop(&mut self) {
+        let _ = self.r.try_recv();
+    }
+}
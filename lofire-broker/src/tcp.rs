@@ -0,0 +1,1010 @@
+//! Raw TCP transport
+//!
+//! Each frame is sent as its length (u32 big-endian) followed by the BARE payload.
+//! Meant for server-to-server links, without the overhead of WebSocket framing.
+//! An empty frame closes the connection.
+//! Keepalive pings (`RAW_PING`) are answered here, and not passed on.
+//!
+//! Works on any `futures` byte stream: an async-std `TcpStream` as is,
+//! or a tokio `TcpStream` through `tokio_util::compat`.
+
+use crate::codec::{RAW_PING, RAW_PONG};
+use crate::runtime;
+use debug_print::*;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{Sink, SinkExt, StreamExt};
+use lofire_net::errors::*;
+
+/// Default maximum size of a frame, larger frames close the connection
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Split a TCP stream into a sink and a stream of frames,
+/// as used by `ConnectionRemote::open_broker_connection` and `connection_loop`.
+///
+/// Frames above `max_frame_size` are not sent, and close the connection when received.
+/// The stream ends with an empty frame when the connection is closed
+pub fn split<S>(
+    tcp: S,
+    max_frame_size: usize,
+) -> (
+    impl Sink<Vec<u8>, Error = ProtocolError> + Send + Unpin + 'static,
+    async_channel::Receiver<Vec<u8>>,
+)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut read_half, mut write_half) = tcp.split();
+    let (writer_sender, mut writer_receiver) = mpsc::unbounded::<Vec<u8>>();
+    runtime::spawn(async move {
+        while let Some(frame) = writer_receiver.next().await {
+            if frame.is_empty() {
+                break;
+            }
+            if frame.len() > max_frame_size {
+                debug_println!("frame too large to send: {}", frame.len());
+                break;
+            }
+            let len = (frame.len() as u32).to_be_bytes();
+            if write_half.write_all(&len).await.is_err()
+                || write_half.write_all(&frame).await.is_err()
+            {
+                break;
+            }
+        }
+        let _ = write_half.close().await;
+    });
+
+    let pong_sender = writer_sender.clone();
+    let (reader_sender, reader_receiver) = async_channel::unbounded::<Vec<u8>>();
+    runtime::spawn(async move {
+        loop {
+            let mut len = [0u8; 4];
+            if read_half.read_exact(&mut len).await.is_err() {
+                break;
+            }
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                break;
+            }
+            if len > max_frame_size {
+                debug_println!("received frame too large: {}", len);
+                break;
+            }
+            let mut frame = vec![0u8; len];
+            if read_half.read_exact(&mut frame).await.is_err() {
+                break;
+            }
+            if frame == RAW_PING {
+                let _ = pong_sender.unbounded_send(RAW_PONG.to_vec());
+                continue;
+            }
+            if reader_sender.send(frame).await.is_err() {
+                break;
+            }
+        }
+        let _ = reader_sender.send(vec![]).await;
+    });
+
+    (
+        writer_sender.sink_map_err(|_e| ProtocolError::WriteError),
+        reader_receiver,
+    )
+}
+
+#[cfg(all(test, feature = "async-std-runtime"))]
+mod test {
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use futures::io::AsyncReadExt;
+    use futures::{SinkExt, StreamExt};
+    use lofire::object::*;
+    use lofire::store::*;
+    use lofire::types::*;
+    use lofire::utils::*;
+    use lofire_net::errors::*;
+    use lofire_net::types::*;
+    use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::Builder;
+
+    use crate::blocksource::*;
+    use crate::codec::*;
+    use crate::config::ConfigMode;
+    use crate::connection::*;
+    use crate::ipfilter::*;
+    use crate::server::*;
+    use crate::tcp::*;
+
+    #[async_std::test]
+    pub async fn test_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = task::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let (mut w, r) = split(tcp, 1000);
+            // echo
+            while let Ok(frame) = r.recv().await {
+                if frame.is_empty() {
+                    break;
+                }
+                w.send(frame).await.unwrap();
+            }
+        });
+
+        let (mut w, r) = split(TcpStream::connect(addr).await.unwrap(), 1000);
+        w.send(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(r.recv().await.unwrap(), vec![1, 2, 3]);
+        w.send(vec![4; 1000]).await.unwrap();
+        assert_eq!(r.recv().await.unwrap(), vec![4; 1000]);
+
+        // too large, the connection is closed
+        w.send(vec![5; 1001]).await.unwrap();
+        assert_eq!(r.recv().await.unwrap(), vec![]);
+        server.await;
+    }
+
+    #[async_std::test]
+    pub async fn test_broker_over_tcp() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server =
+            Arc::new(BrokerServer::new(store, ConfigMode::Local).expect("starting broker"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_in_task = Arc::clone(&server);
+        task::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let (w, r) = split(tcp, DEFAULT_MAX_FRAME_SIZE);
+            let handler = server_in_task.protocol_handler();
+            let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+            let _ = connection_loop(RawFrameCodec, w, frames, handler).await;
+        });
+
+        let (priv_key, pub_key) = generate_keypair();
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut cnx = ConnectionRemote::open_broker_connection(
+            w,
+            r,
+            pub_key,
+            priv_key,
+            PubKey::Ed25519PubKey([1; 32]),
+            // the broker ignores the padding
+            PaddingPolicy::powers_of_two(64, DEFAULT_MAX_FRAME_SIZE),
+        )
+        .await
+        .expect("broker handshake");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        cnx.add_user(pub_key, admin_privkey).await.unwrap();
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: PubKey::Ed25519PubKey([3; 32]),
+            secret: SymKey::ChaCha20Key([4; 32]),
+            peers: vec![],
+        });
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+
+        let block = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![27; 150],
+            None,
+        );
+        let block_id = overlay_cnx.put_block(&block).await.unwrap();
+        assert_eq!(block_id, block.id());
+
+        let mut blocks = overlay_cnx.get_block(block_id, false, None).await.unwrap();
+        assert_eq!(blocks.next().await.unwrap().id(), block_id);
+
+        // an object fetched by pages, resumed with the continuation token sent by the broker
+        let obj = Object::new(
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: vec![],
+                metadata: vec![],
+                content: (0..10000).map(|i| (i % 251) as u8).collect(),
+            })),
+            vec![],
+            None,
+            1000,
+            PubKey::Ed25519PubKey([3; 32]),
+            SymKey::ChaCha20Key([4; 32]),
+        );
+        for block in obj.blocks() {
+            overlay_cnx.put_block(block).await.unwrap();
+        }
+        let mut received = vec![];
+        let mut continuation = None;
+        loop {
+            let (page, next) = overlay_cnx
+                .get_block_page(obj.id(), None, 3, continuation)
+                .await
+                .unwrap();
+            assert!(page.len() <= 3);
+            received.extend(page);
+            match next {
+                Some(next) => continuation = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(received[0].id(), obj.id());
+        let received: std::collections::HashSet<BlockId> =
+            received.iter().map(|b| b.id()).collect();
+        let expected: std::collections::HashSet<BlockId> =
+            obj.blocks().iter().map(|b| b.id()).collect();
+        assert_eq!(received, expected);
+
+        cnx.close().await;
+    }
+
+    /// Serve a broker filtering the peer IPs like the node's accept loop, and return its address
+    async fn listen_filtered(root: &std::path::Path, filter: IpFilter) -> std::net::SocketAddr {
+        let store = LmdbBrokerStore::open(root, [0; 32]);
+        let mut server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+        server.set_ip_filter(filter);
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            while let Ok((tcp, peer)) = listener.accept().await {
+                if !server.is_peer_allowed(&peer.ip()) {
+                    continue;
+                }
+                let (w, r) = split(tcp, DEFAULT_MAX_FRAME_SIZE);
+                let handler = Arc::clone(&server).protocol_handler();
+                let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+                task::spawn(connection_loop(RawFrameCodec, w, frames, handler));
+            }
+        });
+        addr
+    }
+
+    #[async_std::test]
+    pub async fn test_ip_filter() {
+        // loopback is in the allowlist
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let filter = IpFilter::parse(&["127.0.0.0/8", "::1"], &[]).unwrap();
+        let addr = listen_filtered(root.path(), filter).await;
+        let (priv_key, pub_key) = generate_keypair();
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut cnx = ConnectionRemote::open_broker_connection(
+            w,
+            r,
+            pub_key,
+            priv_key,
+            PubKey::Ed25519PubKey([1; 32]),
+            PaddingPolicy::default(),
+        )
+        .await
+        .expect("broker handshake");
+        cnx.close().await;
+
+        // loopback out of the allowlist stands for a peer out of range,
+        // its connection is closed before the handshake
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let filter = IpFilter::parse(&["10.0.0.0/8"], &[]).unwrap();
+        let addr = listen_filtered(root.path(), filter).await;
+        let (_w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        assert_eq!(r.recv().await.unwrap(), vec![]);
+    }
+
+    #[async_std::test]
+    pub async fn test_ext_request() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let user = PubKey::Ed25519PubKey([1; 32]);
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        // a member shares an object of the repository
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let overlay = OverlayConnectionClient::<BrokerConnectionLocal>::overlay(&repo_link, true);
+        server
+            .join_overlay(user, overlay, Some(repo), secret, &vec![])
+            .unwrap();
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..20000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let obj = Object::new(content, vec![], None, 4000, repo, secret);
+        let other_content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: vec![42; 9000],
+        }));
+        let other = Object::new(other_content, vec![], None, 4000, repo, secret);
+        for block in obj.blocks().iter().chain(other.blocks()) {
+            server.put_block(user, overlay, block).unwrap();
+        }
+        let link =
+            ObjectLink::new(repo, secret, vec![obj.reference().unwrap()], true, None).unwrap();
+        let forged = ObjectLink::new(
+            repo,
+            SymKey::ChaCha20Key([5; 32]),
+            vec![obj.reference().unwrap()],
+            true,
+            None,
+        )
+        .unwrap();
+
+        // one connection per request
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_in_task = Arc::clone(&server);
+        task::spawn(async move {
+            for _ in 0..3 {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let (w, r) = split(tcp, DEFAULT_MAX_FRAME_SIZE);
+                let handler = Arc::clone(&server_in_task).protocol_handler();
+                let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+                let _ = connection_loop(RawFrameCodec, w, frames, handler).await;
+            }
+        });
+
+        // the non-member fetches the object with the link only
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let responses = ConnectionRemote::ext_request(w, r, link.req().clone())
+            .await
+            .unwrap();
+        let fetched = HashMapRepoStore::new();
+        for response in responses.iter() {
+            fetched.put(response.block().unwrap()).unwrap();
+        }
+        let object_ref = link.keys()[0];
+        let received = Object::load(object_ref.id, Some(object_ref.key), &fetched).unwrap();
+        assert_eq!(received.content().unwrap(), obj.content().unwrap());
+
+        // two objects in one response stream are told apart
+        let both = ObjectLink::new(
+            repo,
+            secret,
+            vec![obj.reference().unwrap(), other.reference().unwrap()],
+            true,
+            None,
+        )
+        .unwrap();
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let objects = ConnectionRemote::ext_object_get(w, r, both.req().clone())
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 2);
+        for (sent, object_ref) in [&obj, &other].iter().zip(both.keys()) {
+            let fetched = HashMapRepoStore::new();
+            for block in objects[&object_ref.id].iter() {
+                fetched.put(block).unwrap();
+            }
+            let received = Object::load(object_ref.id, Some(object_ref.key), &fetched).unwrap();
+            assert_eq!(received.content().unwrap(), sent.content().unwrap());
+        }
+
+        // a link made without the repository secret is refused
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        assert_eq!(
+            ConnectionRemote::ext_request(w, r, forged.req().clone())
+                .await
+                .err(),
+            Some(ProtocolError::AccessDenied)
+        );
+    }
+
+    #[async_std::test]
+    pub async fn test_keepalive() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let mut server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+        server.set_keepalive(Some((Duration::from_millis(200), 2)));
+        let server = Arc::new(server);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_in_task = Arc::clone(&server);
+        task::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let (w, r) = split(tcp, DEFAULT_MAX_FRAME_SIZE);
+                let handler = Arc::clone(&server_in_task).protocol_handler();
+                let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+                task::spawn(connection_loop(RawFrameCodec, w, frames, handler));
+            }
+        });
+
+        // a client answering the pings stays connected while idle
+        let (priv_key, pub_key) = generate_keypair();
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut cnx = ConnectionRemote::open_broker_connection(
+            w,
+            r,
+            pub_key,
+            priv_key,
+            PubKey::Ed25519PubKey([1; 32]),
+            PaddingPolicy::default(),
+        )
+        .await
+        .expect("broker handshake");
+        task::sleep(Duration::from_millis(1000)).await;
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        cnx.add_user(pub_key, admin_privkey).await.unwrap();
+        cnx.close().await;
+
+        // a client that doesn't answer is closed after the missed pings
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let mut pings = 0;
+        loop {
+            let mut len = [0u8; 4];
+            if tcp.read_exact(&mut len).await.is_err() {
+                break;
+            }
+            let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+            if frame.is_empty() || tcp.read_exact(&mut frame).await.is_err() {
+                break;
+            }
+            assert_eq!(frame, RAW_PING);
+            pings += 1;
+        }
+        assert_eq!(pings, 2);
+    }
+
+    /// Malformed frames are errors closing the connection, not panics
+    #[async_std::test]
+    pub async fn test_malformed_frames() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let server =
+            Arc::new(BrokerServer::new(store, ConfigMode::Local).expect("starting broker"));
+        let (priv_key, pub_key) = generate_keypair();
+
+        // truncated, empty and structurally invalid start frames
+        let hello = serde_bare::to_vec(&StartProtocol::Auth(ClientHello::V0())).unwrap();
+        for frame in [hello[..1].to_vec(), vec![], vec![0x7f, 1, 2, 3]] {
+            let mut handler = Arc::clone(&server).protocol_handler();
+            assert_eq!(
+                handler.handle_incoming(frame).await.0.err(),
+                Some(ProtocolError::SerializationError)
+            );
+        }
+
+        // a truncated ClientAuth is refused, and closes the connection
+        let mut handler = Arc::clone(&server).protocol_handler();
+        handler.handle_incoming(hello.clone()).await.0.unwrap();
+        let (reply, close) = handler.handle_incoming(vec![0, 1]).await;
+        let result = serde_bare::from_slice::<AuthResult>(&reply.unwrap()).unwrap();
+        assert_eq!(
+            ProtocolError::from(result.result()),
+            ProtocolError::SerializationError
+        );
+        assert!(close.await.is_some());
+
+        // truncated, invalid and Close messages once authenticated
+        for frame in [vec![0], vec![0x7f, 1, 2, 3]] {
+            let mut handler = authenticated_handler(&server, priv_key, pub_key).await;
+            assert_eq!(
+                handler.handle_incoming(frame).await.0.err(),
+                Some(ProtocolError::SerializationError)
+            );
+        }
+        let mut handler = authenticated_handler(&server, priv_key, pub_key).await;
+        let close = serde_bare::to_vec(&BrokerMessage::Close).unwrap();
+        assert_eq!(
+            handler.handle_incoming(close).await.0.err(),
+            Some(ProtocolError::InvalidState)
+        );
+
+        // over TCP, an invalid or oversized frame closes the connection
+        let addr = serve(Arc::clone(&server)).await;
+        let (mut w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        w.send(vec![0x7f, 1, 2, 3]).await.unwrap();
+        assert_eq!(r.recv().await.unwrap(), vec![]);
+        let (mut w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE + 1,
+        );
+        w.send(vec![0; DEFAULT_MAX_FRAME_SIZE + 1]).await.unwrap();
+        assert_eq!(r.recv().await.unwrap(), vec![]);
+
+        // the broker still serves new connections
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        assert!(ConnectionRemote::open_broker_connection(
+            w,
+            r,
+            pub_key,
+            priv_key,
+            pub_key,
+            PaddingPolicy::default()
+        )
+        .await
+        .is_ok());
+
+        // a client gets an error from a malformed ServerHello
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let (mut w, r) = split(tcp, DEFAULT_MAX_FRAME_SIZE);
+            let _ = r.recv().await;
+            let _ = w.send(vec![0x7f, 1, 2, 3]).await;
+            let _ = r.recv().await;
+        });
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        assert_eq!(
+            ConnectionRemote::open_broker_connection(
+                w,
+                r,
+                pub_key,
+                priv_key,
+                pub_key,
+                PaddingPolicy::default()
+            )
+            .await
+            .err(),
+            Some(ProtocolError::SerializationError)
+        );
+    }
+
+    /// A client clock an hour behind is corrected by the skew measured in the handshake
+    #[async_std::test]
+    pub async fn test_clock_skew() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let server =
+            Arc::new(BrokerServer::new(store, ConfigMode::Local).expect("starting broker"));
+        let addr = serve(Arc::clone(&server)).await;
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let (user_privkey, user) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+
+        let (w, r) = split(
+            TcpStream::connect(addr).await.unwrap(),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut cnx = ConnectionRemote::open_broker_connection_with_clock(
+            w,
+            r,
+            user,
+            user_privkey,
+            user,
+            PaddingPolicy::default(),
+            OffsetClock::new(SystemClock, -60),
+        )
+        .await
+        .expect("broker handshake");
+        let now = now_timestamp();
+        assert!((cnx.now().as_minutes() as i64 - now.as_minutes() as i64).abs() <= 1);
+
+        let repo = PubKey::Ed25519PubKey([3; 32]);
+        let secret = SymKey::ChaCha20Key([4; 32]);
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: repo,
+            secret,
+            peers: vec![],
+        });
+        let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+        let content = |byte: u8| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: vec![],
+                metadata: vec![],
+                content: vec![byte; 1000],
+            }))
+        };
+        // expiring in 30 minutes at the broker
+        let expiry = overlay_cnx.now() + RelTime::Minutes(30);
+        let kept = overlay_cnx
+            .put_object(content(1), vec![], Some(expiry), 4000, repo, secret)
+            .await
+            .unwrap();
+        // from the uncorrected clock, the expiry is already past at the broker
+        let expiry = OffsetClock::new(SystemClock, -60).now() + RelTime::Minutes(30);
+        let skewed = overlay_cnx
+            .put_object(content(2), vec![], Some(expiry), 4000, repo, secret)
+            .await
+            .unwrap();
+
+        server.garbage_collect(now + RelTime::Minutes(20)).unwrap();
+        assert!(overlay_cnx.get_object(kept, None).await.is_ok());
+        assert_eq!(
+            overlay_cnx.get_object(skewed, None).await.err(),
+            Some(GetObjectError::Protocol(ProtocolError::NotFound))
+        );
+    }
+
+    /// Protocol handler of a connection authenticated as `user`, as after the handshake
+    async fn authenticated_handler(
+        server: &Arc<BrokerServer>,
+        priv_key: PrivKey,
+        user: PubKey,
+    ) -> ProtocolHandler {
+        let mut handler = Arc::clone(server).protocol_handler();
+        let hello = serde_bare::to_vec(&StartProtocol::Auth(ClientHello::V0())).unwrap();
+        let reply = handler.handle_incoming(hello).await.0.unwrap();
+        let server_hello = serde_bare::from_slice::<ServerHello>(&reply).unwrap();
+        let content = ClientAuthContentV0 {
+            user,
+            client: user,
+            nonce: server_hello.nonce().clone(),
+        };
+        let sig = sign(priv_key, user, &serde_bare::to_vec(&content).unwrap()).unwrap();
+        let auth = ClientAuth::V0(ClientAuthV0 { content, sig });
+        let (reply, close) = handler
+            .handle_incoming(serde_bare::to_vec(&auth).unwrap())
+            .await;
+        let result = serde_bare::from_slice::<AuthResult>(&reply.unwrap()).unwrap();
+        assert_eq!(ProtocolError::from(result.result()), ProtocolError::Success);
+        assert!(close.await.is_none());
+        handler
+    }
+
+    /// Serve a broker like the node's accept loop, and return its address
+    async fn serve(server: Arc<BrokerServer>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let (w, r) = split(tcp, DEFAULT_MAX_FRAME_SIZE);
+                let handler = Arc::clone(&server).protocol_handler();
+                let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+                task::spawn(connection_loop(RawFrameCodec, w, frames, handler));
+            }
+        });
+        addr
+    }
+
+    #[async_std::test]
+    pub async fn test_rotate_client() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let server =
+            Arc::new(BrokerServer::new(store, ConfigMode::Local).expect("starting broker"));
+        let addr = serve(Arc::clone(&server)).await;
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        let (user_privkey, user) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        let op_content = AddUserContentV0 {
+            user,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            admin_privkey,
+            admin_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_user(op_content, sig).unwrap();
+        let old_client = PubKey::Ed25519PubKey([5; 32]);
+        let new_client = PubKey::Ed25519PubKey([6; 32]);
+        let op_content = AddClientContentV0 {
+            client: old_client,
+            timestamp: now_timestamp(),
+            nonce: 0,
+        };
+        let sig = sign(
+            user_privkey,
+            user,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        server.add_client(user, op_content, sig).unwrap();
+
+        // the reader signals when the broker closes the connection
+        let connect = |client, closed: Option<async_channel::Sender<()>>| async move {
+            let (w, r) = split(
+                TcpStream::connect(addr).await.unwrap(),
+                DEFAULT_MAX_FRAME_SIZE,
+            );
+            let r = r.inspect(move |frame| {
+                if frame.is_empty() {
+                    if let Some(closed) = &closed {
+                        let _ = closed.try_send(());
+                    }
+                }
+            });
+            ConnectionRemote::open_broker_connection(
+                w,
+                r,
+                user,
+                user_privkey,
+                client,
+                PaddingPolicy::default(),
+            )
+            .await
+        };
+        let (closed_s, closed_r) = async_channel::bounded::<()>(1);
+        let _old_cnx = connect(old_client, Some(closed_s))
+            .await
+            .expect("old client handshake");
+        // unregistered clients are refused
+        assert!(matches!(
+            connect(new_client, None).await,
+            Err(ProtocolError::AccessDenied)
+        ));
+
+        let rotation = |timestamp: Timestamp, nonce: u64| {
+            let op_content = RotateClientContentV0 {
+                old_client,
+                new_client,
+                timestamp,
+                nonce,
+            };
+            let sig = sign(
+                user_privkey,
+                user,
+                &serde_bare::to_vec(&op_content).unwrap(),
+            )
+            .unwrap();
+            (op_content, sig)
+        };
+        let (op_content, sig) = rotation(now_timestamp(), 0);
+        // only the user can rotate its clients
+        let (other_privkey, other_pubkey) = generate_keypair();
+        let other_sig = sign(
+            other_privkey,
+            other_pubkey,
+            &serde_bare::to_vec(&op_content).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            server.rotate_client(user, op_content, other_sig),
+            Err(ProtocolError::InvalidSignature)
+        );
+        // a stale rotation is refused
+        let (stale_content, stale_sig) = rotation(now_timestamp() - RelTime::Minutes(11), 0);
+        assert_eq!(
+            server.rotate_client(user, stale_content, stale_sig),
+            Err(ProtocolError::InvalidSignature)
+        );
+        server.rotate_client(user, op_content, sig).unwrap();
+
+        // the session of the old client is dropped
+        assert!(runtime::timeout(Duration::from_secs(5), closed_r.recv())
+            .await
+            .is_some());
+        // the new client authenticates, the old one doesn't anymore
+        let mut new_cnx = connect(new_client, None)
+            .await
+            .expect("new client handshake");
+        new_cnx.close().await;
+        assert!(matches!(
+            connect(old_client, None).await,
+            Err(ProtocolError::AccessDenied)
+        ));
+        // the same rotation can't be replayed, and a new one finds no old client
+        assert_eq!(
+            server.rotate_client(user, op_content, sig),
+            Err(ProtocolError::InvalidSignature)
+        );
+        let (op_content, sig) = rotation(now_timestamp(), 1);
+        assert_eq!(
+            server.rotate_client(user, op_content, sig),
+            Err(ProtocolError::NotFound)
+        );
+    }
+
+    /// Source that finds no block, recording how many searches run at the same time
+    struct CountingSource {
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    impl BlockSource for CountingSource {
+        fn search_block(
+            &self,
+            _overlay: &OverlayId,
+            _id: &BlockId,
+            _include_children: bool,
+        ) -> async_channel::Receiver<Block> {
+            let (s, r) = async_channel::unbounded::<Block>();
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            let running = Arc::clone(&self.running);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(200));
+                running.fetch_sub(1, Ordering::SeqCst);
+                s.close();
+            });
+            r
+        }
+    }
+
+    #[async_std::test]
+    pub async fn test_store_concurrency() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let mut server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+        let max = 2;
+        server.set_store_concurrency(Some(max));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        server.set_block_fallback(
+            Box::new(CountingSource {
+                running: Arc::new(AtomicUsize::new(0)),
+                max_running: Arc::clone(&max_running),
+            }),
+            Duration::from_secs(5),
+        );
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        let addr = serve(Arc::new(server)).await;
+
+        let (priv_key, pub_key) = generate_keypair();
+        let repo_link = RepoLink::V0(RepoLinkV0 {
+            id: PubKey::Ed25519PubKey([3; 32]),
+            secret: SymKey::ChaCha20Key([4; 32]),
+            peers: vec![],
+        });
+        let connect = move || async move {
+            let (w, r) = split(
+                TcpStream::connect(addr).await.unwrap(),
+                DEFAULT_MAX_FRAME_SIZE,
+            );
+            ConnectionRemote::open_broker_connection(
+                w,
+                r,
+                pub_key,
+                priv_key,
+                PubKey::Ed25519PubKey([1; 32]),
+                PaddingPolicy::default(),
+            )
+            .await
+            .expect("broker handshake")
+        };
+        let mut cnx = connect().await;
+        cnx.add_user(pub_key, admin_privkey).await.unwrap();
+        cnx.close().await;
+
+        // each client asks for a different missing block, searched in the fallback
+        let clients: Vec<_> = (0..6u8)
+            .map(|i| {
+                let repo_link = repo_link.clone();
+                task::spawn(async move {
+                    let mut cnx = connect().await;
+                    let mut overlay_cnx = cnx.overlay_connect(&repo_link, true).await.unwrap();
+                    let _ = overlay_cnx
+                        .get_block(Digest::Blake3Digest32([i; 32]), false, None)
+                        .await;
+                    cnx.close().await;
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await;
+        }
+        let max_running = max_running.load(Ordering::SeqCst);
+        assert!(max_running > 0);
+        assert!(max_running <= max);
+    }
+}
+
+#[cfg(all(test, feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+mod test_tokio {
+    use futures::StreamExt;
+    use lofire::types::*;
+    use lofire::utils::*;
+    use lofire_net::errors::*;
+    use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
+    use std::fs;
+    use std::sync::Arc;
+    use tempfile::Builder;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use crate::codec::RawFrameCodec;
+    use crate::config::ConfigMode;
+    use crate::connection::*;
+    use crate::server::*;
+    use crate::tcp::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn test_handshake() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        let server =
+            Arc::new(BrokerServer::new(store, ConfigMode::Local).expect("starting broker"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_in_task = Arc::clone(&server);
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let (w, r) = split(tcp.compat(), DEFAULT_MAX_FRAME_SIZE);
+            let handler = server_in_task.protocol_handler();
+            let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+            let _ = connection_loop(RawFrameCodec, w, frames, handler).await;
+        });
+
+        let (priv_key, pub_key) = generate_keypair();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (w, r) = split(tcp.compat(), DEFAULT_MAX_FRAME_SIZE);
+        let mut cnx = ConnectionRemote::open_broker_connection(
+            w,
+            r,
+            pub_key,
+            priv_key,
+            PubKey::Ed25519PubKey([1; 32]),
+            PaddingPolicy::default(),
+        )
+        .await
+        .expect("broker handshake");
+
+        let (admin_privkey, admin_pubkey) = generate_keypair();
+        server.add_admin(admin_pubkey).unwrap();
+        cnx.add_user(pub_key, admin_privkey).await.unwrap();
+        cnx.close().await;
+    }
+}
@@ -0,0 +1,97 @@
+//! Tombstones of the objects deleted in an overlay
+
+use lofire::brokerstore::BrokerStore;
+use lofire::store::*;
+use lofire::types::*;
+use lofire_net::types::*;
+use serde_bare::{from_slice, to_vec};
+
+pub struct Tombstones<'a> {
+    /// Overlay ID
+    overlay: OverlayId,
+    store: &'a dyn BrokerStore,
+}
+
+impl<'a> Tombstones<'a> {
+    const PREFIX: u8 = b"d"[0];
+
+    // propertie's suffixes
+    /// Tombstone of an object, on the (overlay, object) key
+    const TOMBSTONE: u8 = b"t"[0];
+    /// Objects having a tombstone, on the overlay key
+    const OBJECT: u8 = b"o"[0];
+
+    /// Tombstones of an overlay, that doesn't need to have any yet
+    pub fn new(overlay: &OverlayId, store: &'a dyn BrokerStore) -> Tombstones<'a> {
+        Tombstones {
+            overlay: overlay.clone(),
+            store,
+        }
+    }
+    fn key(&self) -> Result<Vec<u8>, StorageError> {
+        Ok(to_vec(&self.overlay)?)
+    }
+    fn object_key(&self, object: &ObjectId) -> Result<Vec<u8>, StorageError> {
+        Ok(to_vec(&(self.overlay, object))?)
+    }
+    pub fn overlay(&self) -> OverlayId {
+        self.overlay
+    }
+
+    /// Add the tombstone of an object, replacing the previous one if any
+    pub fn add(&self, tombstone: &Tombstone) -> Result<(), StorageError> {
+        let object = tombstone.object();
+        let key = self.object_key(&object)?;
+        if self.get(&object).is_ok() {
+            return self.store.replace(
+                Self::PREFIX,
+                &key,
+                Some(Self::TOMBSTONE),
+                to_vec(tombstone)?,
+            );
+        }
+        self.store.put(
+            Self::PREFIX,
+            &key,
+            Some(Self::TOMBSTONE),
+            to_vec(tombstone)?,
+        )?;
+        self.store.put(
+            Self::PREFIX,
+            &self.key()?,
+            Some(Self::OBJECT),
+            to_vec(&object)?,
+        )
+    }
+    pub fn get(&self, object: &ObjectId) -> Result<Tombstone, StorageError> {
+        let tombstone = self.store.get(
+            Self::PREFIX,
+            &self.object_key(object)?,
+            Some(Self::TOMBSTONE),
+        )?;
+        Ok(from_slice::<Tombstone>(&tombstone)?)
+    }
+
+    /// All the tombstones of the overlay
+    pub fn list(&self) -> Result<Vec<Tombstone>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &self.key()?, Some(Self::OBJECT))?
+            .iter()
+            .map(|o| self.get(&from_slice::<ObjectId>(o)?))
+            .collect()
+    }
+
+    pub fn remove(&self, object: &ObjectId) -> Result<(), StorageError> {
+        self.store.del(
+            Self::PREFIX,
+            &self.object_key(object)?,
+            Some(Self::TOMBSTONE),
+        )?;
+        self.store.del_property_value(
+            Self::PREFIX,
+            &self.key()?,
+            Some(Self::OBJECT),
+            to_vec(object)?,
+        )
+    }
+}
@@ -1,6 +1,6 @@
 //! Topic
 
-use lofire::brokerstore::BrokerStore;
+use lofire::brokerstore::{BrokerStore, BrokerStoreTransaction};
 use lofire::store::*;
 use lofire::types::*;
 use lofire_net::types::*;
@@ -26,8 +26,9 @@ impl<'a> Topic<'a> {
     const ADVERT: u8 = b"a"[0];
     const HEAD: u8 = b"h"[0];
     const META: u8 = b"m"[0];
+    const SEALED: u8 = b"s"[0];
 
-    const ALL_PROPERTIES: [u8; 3] = [Self::ADVERT, Self::HEAD, Self::META];
+    const ALL_PROPERTIES: [u8; 4] = [Self::ADVERT, Self::HEAD, Self::META, Self::SEALED];
 
     const SUFFIX_FOR_EXIST_CHECK: u8 = Self::META;
 
@@ -90,6 +91,15 @@ impl<'a> Topic<'a> {
         )
     }
 
+    /// Heads of the branch: the published commits that no other published commit depends on
+    pub fn heads(&self) -> Result<Vec<ObjectId>, StorageError> {
+        self.store
+            .get_all(Self::PREFIX, &to_vec(&self.id)?, Some(Self::HEAD))?
+            .iter()
+            .map(|h| Ok(from_slice::<ObjectId>(h)?))
+            .collect()
+    }
+
     pub fn has_head(&self, head: &ObjectId) -> Result<(), StorageError> {
         self.store.has_property_value(
             Self::PREFIX,
@@ -119,9 +129,49 @@ impl<'a> Topic<'a> {
             to_vec(meta)?,
         )
     }
+    pub fn set_metadata_in(
+        &self,
+        tx: &mut dyn BrokerStoreTransaction,
+        meta: &TopicMeta,
+    ) -> Result<(), StorageError> {
+        tx.replace(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::META),
+            to_vec(meta)?,
+        )
+    }
+
+    /// Seal the topic with the SealBranch commit
+    pub fn seal(&self, commit: &ObjectId) -> Result<(), StorageError> {
+        if !self.exists() {
+            return Err(StorageError::BackendError);
+        }
+        self.store.replace(
+            Self::PREFIX,
+            &to_vec(&self.id)?,
+            Some(Self::SEALED),
+            to_vec(commit)?,
+        )
+    }
+
+    /// Get the SealBranch commit, if the topic is sealed
+    pub fn sealed(&self) -> Result<Option<ObjectId>, StorageError> {
+        match self
+            .store
+            .get(Self::PREFIX, &to_vec(&self.id)?, Some(Self::SEALED))
+        {
+            Ok(commit) => Ok(Some(from_slice::<ObjectId>(&commit)?)),
+            Err(StorageError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
     pub fn del(&self) -> Result<(), StorageError> {
         self.store
             .del_all(Self::PREFIX, &to_vec(&self.id)?, &Self::ALL_PROPERTIES)
     }
+    pub fn del_in(&self, tx: &mut dyn BrokerStoreTransaction) -> Result<(), StorageError> {
+        tx.del_all(Self::PREFIX, &to_vec(&self.id)?, &Self::ALL_PROPERTIES)
+    }
 }
@@ -7,6 +7,7 @@ use fastbloom_rs::{BloomFilter as Filter, FilterBuilder, Membership};
 use futures::{future, pin_mut, stream, SinkExt, StreamExt};
 use lofire::object::Object;
 use lofire::store::{store_max_value_size, store_valid_value_size, HashMapRepoStore, RepoStore};
+use lofire_broker::codec::*;
 use lofire_broker::config::ConfigMode;
 use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
 use lofire_store_lmdb::repostore::LmdbRepoStore;
@@ -358,7 +359,7 @@ async fn test_sync(cnx: &mut impl BrokerConnection, user_pub_key: PubKey, userpr
         .expect("overlay_connect failed");
 
     // Sending everything to the broker
-    for (v) in store.get_all() {
+    for (_, v) in store.iter() {
         //debug_println!("SENDING {}", k);
         let _ = public_overlay_cnx
             .put_block(&v)
@@ -396,7 +397,7 @@ async fn test_sync(cnx: &mut impl BrokerConnection, user_pub_key: PubKey, userpr
         &mut store,
     );
 
-    debug_println!("LOCAL STORE HAS {} BLOCKS", store.get_len());
+    debug_println!("LOCAL STORE HAS {} BLOCKS", store.len().unwrap());
 
     // Let's pretend that we know that the head of the branch in the broker is at commits a6 and a7.
     // normally it would be the pub/sub that notifies us of those heads.
@@ -420,7 +421,13 @@ async fn test_sync(cnx: &mut impl BrokerConnection, user_pub_key: PubKey, userpr
     let remote_heads = [a6.id, a7.id];
 
     let mut synced_blocks_stream = public_overlay_cnx
-        .sync_branch(remote_heads.to_vec(), known_heads.to_vec(), known_commits)
+        .sync_branch(
+            remote_heads.to_vec(),
+            known_heads.to_vec(),
+            known_commits,
+            None,
+            None,
+        )
         .await
         .expect("sync_branch failed");
 
@@ -433,20 +440,29 @@ async fn test_sync(cnx: &mut impl BrokerConnection, user_pub_key: PubKey, userpr
 
     debug_println!("SYNCED {} BLOCKS", i);
 
-    debug_println!("LOCAL STORE HAS {} BLOCKS", store.get_len());
+    debug_println!("LOCAL STORE HAS {} BLOCKS", store.len().unwrap());
 
-    // now the client can verify the DAG and each commit. Then update its list of heads.
+    // now the client can verify the DAG and each commit with `verify_branch`, once it has fetched their bodies.
+    // Then update its list of heads.
 }
 
-async fn test(cnx: &mut impl BrokerConnection, pub_key: PubKey, priv_key: PrivKey) -> Result<(), ProtocolError>{
-    
-    cnx.add_user(PubKey::Ed25519PubKey([1; 32]), priv_key).await?;
+async fn test(
+    cnx: &mut impl BrokerConnection,
+    pub_key: PubKey,
+    priv_key: PrivKey,
+    admin_priv_key: PrivKey,
+) -> Result<(), ProtocolError> {
+    cnx.add_user(PubKey::Ed25519PubKey([1; 32]), admin_priv_key)
+        .await?;
 
-    cnx.add_user(pub_key, priv_key).await?;
+    cnx.add_user(pub_key, admin_priv_key).await?;
     //.expect("add_user 2 (myself) failed");
 
     assert_eq!(
-        cnx.add_user(PubKey::Ed25519PubKey([1; 32]), priv_key).await.err().unwrap(),
+        cnx.add_user(PubKey::Ed25519PubKey([1; 32]), admin_priv_key)
+            .await
+            .err()
+            .unwrap(),
         ProtocolError::UserAlreadyExists
     );
 
@@ -513,12 +529,14 @@ async fn test(cnx: &mut impl BrokerConnection, pub_key: PubKey, priv_key: PrivKe
 
     debug_println!("GOT OBJECT with ID {}", object.id());
 
-    // let object_id = public_overlay_cnx
-    //     .copy_object(object_id, Some(now_timestamp() + 60))
-    //     .await
-    //     .expect("copy_object failed");
+    let object_id = public_overlay_cnx
+        .copy_object(
+            object_id,
+            Some(public_overlay_cnx.now() + RelTime::Minutes(60)),
+        )
+        .await?;
 
-    // debug_println!("COPIED OBJECT to OBJECT ID {}", object_id);
+    debug_println!("COPIED OBJECT to OBJECT ID {}", object_id);
 
     public_overlay_cnx
         .delete_object(object_id)
@@ -528,12 +546,17 @@ async fn test(cnx: &mut impl BrokerConnection, pub_key: PubKey, priv_key: PrivKe
     let res = public_overlay_cnx
         .get_object(object_id, None)
         .await
+        .map_err(ProtocolError::from)
         .unwrap_err();
     
     debug_println!("result from get object after delete: {}", res);
     assert_eq!(res, ProtocolError::NotFound);
     
-    //TODO test pin/unpin
+    public_overlay_cnx.pin_object(my_block_id).await?;
+    debug_println!("PINNED OBJECT {}", my_block_id);
+
+    public_overlay_cnx.unpin_object(my_block_id).await?;
+    debug_println!("UNPINNED OBJECT {}", my_block_id);
 
     // TEST BRANCH SYNC
 
@@ -554,13 +577,16 @@ async fn test_local_connection() {
     println!("{}", root.path().to_str().unwrap());
     let store = LmdbBrokerStore::open(root.path(), master_key);
 
-    let mut server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+    let server = BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+
+    let (admin_priv_key, admin_pub_key) = generate_keypair();
+    server.add_admin(admin_pub_key).expect("add_admin failed");
 
     let (priv_key, pub_key) = generate_keypair();
 
     let mut cnx = server.local_connection(pub_key);
 
-    test(&mut cnx, pub_key, priv_key).await;
+    test(&mut cnx, pub_key, priv_key, admin_priv_key).await;
 }
 
 async fn test_remote_connection() {
@@ -573,30 +599,26 @@ async fn test_remote_connection() {
             debug_println!("WebSocket handshake completed");
 
             let (write, read) = ws.split();
-            let mut frames_stream_read = read.map(|msg_res| match msg_res {
-                Err(e) => {
-                    debug_println!("ERROR {:?}", e);
-                    vec![]
-                }
-                Ok(message) => {
-                    if message.is_close() {
-                        debug_println!("CLOSE FROM SERVER");
-                        vec![]
-                    } else {
-                        message.into_data()
+            let codec = WebSocketCodec;
+            // an empty frame signals the end of the connection
+            let mut frames_stream_read = read.filter_map(move |msg_res| {
+                future::ready(match msg_res {
+                    Err(e) => {
+                        debug_println!("ERROR {:?}", e);
+                        Some(vec![])
                     }
-                }
+                    Ok(message) => {
+                        if codec.is_close(&message) {
+                            debug_println!("CLOSE FROM SERVER");
+                            Some(vec![])
+                        } else {
+                            codec.decode(message)
+                        }
+                    }
+                })
             });
-            async fn transform(message: Vec<u8>) -> Result<Message, Error> {
-                if message.len() == 0 {
-                    debug_println!("sending CLOSE message to SERVER");
-                    Ok(Message::Close(None))
-                } else {
-                    Ok(Message::binary(message))
-                }
-            }
             let frames_stream_write = write
-                .with(|message| transform(message))
+                .with(move |frame: Vec<u8>| future::ok::<Message, Error>(codec.encode(frame)))
                 .sink_map_err(|e| ProtocolError::WriteError);
 
             let (priv_key, pub_key) = generate_keypair();
@@ -607,12 +629,14 @@ async fn test_remote_connection() {
                 pub_key,
                 priv_key,
                 PubKey::Ed25519PubKey([1; 32]),
+                PaddingPolicy::default(),
             )
             .await;
 
             match cnx_res {
                 Ok(mut cnx) => {
-                    if let Err(e) = test(&mut cnx, pub_key, priv_key).await {
+                    // the users are only added if the node lists this key in its admins
+                    if let Err(e) = test(&mut cnx, pub_key, priv_key, priv_key).await {
                         debug_println!("error: {:?}", e)
                     }
                     else {
@@ -801,4 +825,62 @@ mod test {
 
         Ok(())
     }
+
+    use lofire_broker::codec::RawFrameCodec;
+    use lofire_broker::connection::*;
+    use lofire_broker::{quic, tcp};
+    use lofire_net::errors::ProtocolError;
+
+    #[async_std::test]
+    pub async fn test_quic_cnx() {
+        let root = Builder::new().prefix("node-daemon").tempdir().unwrap();
+        let store = LmdbBrokerStore::open(root.path(), [0; 32]);
+        let server =
+            Arc::new(BrokerServer::new(store, ConfigMode::Local).expect("starting broker"));
+        let (admin_priv_key, admin_pub_key) = generate_keypair();
+        server.add_admin(admin_pub_key).unwrap();
+
+        let (config, cert) = quic::self_signed(vec!["localhost".into()]).unwrap();
+        let endpoint = quic::listen("127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        task::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let handler = Arc::clone(&server).protocol_handler();
+                task::spawn(async move {
+                    let (w, r) = quic::accept(connecting, tcp::DEFAULT_MAX_FRAME_SIZE)
+                        .await
+                        .unwrap();
+                    let frames = Box::pin(r.map(|frame| Ok::<_, ProtocolError>(frame)));
+                    let _ = connection_loop(RawFrameCodec, w, frames, handler).await;
+                });
+            }
+        });
+
+        let client = quic::client_endpoint().unwrap();
+        let (w, r) = quic::connect(
+            &client,
+            quic::client_config(&cert).unwrap(),
+            addr,
+            "localhost",
+            tcp::DEFAULT_MAX_FRAME_SIZE,
+        )
+        .await
+        .unwrap();
+        let (priv_key, pub_key) = generate_keypair();
+        let mut cnx = ConnectionRemote::open_broker_connection(
+            w,
+            r,
+            pub_key,
+            priv_key,
+            PubKey::Ed25519PubKey([1; 32]),
+            PaddingPolicy::default(),
+        )
+        .await
+        .expect("broker handshake");
+
+        crate::test(&mut cnx, pub_key, priv_key, admin_priv_key)
+            .await
+            .unwrap();
+        cnx.close().await;
+    }
 }
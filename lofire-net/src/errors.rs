@@ -1,18 +1,26 @@
+use crate::types::BlockListResp;
 use crate::types::BrokerMessage;
+use crate::types::OverlayStatusResp;
 use core::fmt;
 use lofire::object::ObjectParseError;
 use lofire::types::Block;
+use lofire::types::BlockId;
 use lofire::types::ObjectId;
+use num_enum::FromPrimitive;
 use num_enum::IntoPrimitive;
-use num_enum::TryFromPrimitive;
 use std::convert::From;
-use std::convert::TryFrom;
 use std::error::Error;
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive, IntoPrimitive, Clone)]
+/// Result codes of the broker protocol.
+///
+/// `u16::from` gives the code sent in responses, and `ProtocolError::from` maps a received code back,
+/// with unknown codes mapped to InvalidValue.
+/// `Success` is the code of successful responses and is never returned as an error.
+#[derive(Debug, Eq, PartialEq, FromPrimitive, IntoPrimitive, Clone)]
 #[repr(u16)]
 pub enum ProtocolError {
-    WriteError = 1,
+    Success = 0,
+    WriteError,
     ActorError,
     InvalidState,
     SignatureError,
@@ -28,15 +36,91 @@ pub enum ProtocolError {
     StoreError,
     MissingBlocks,
     ObjectParseError,
+    #[num_enum(default)]
     InvalidValue,
     UserAlreadyExists,
     RepoIdRequired,
     Closing,
+    TooManyHeads,
+    Truncated,
+    OverlayNotAllowed,
+    NoAccount,
+    InvalidBlock,
+    BranchSealed,
+    ConnectionClosed,
+    ReorderBufferFull,
+    InvalidResponse,
+    MetadataTooLarge,
+    ObjectTooLarge,
+    ObjectDeleted,
+    Cancelled,
+    ForeignBlock,
+    LimitExceeded,
+    InvalidTimestamp,
 }
 
 impl ProtocolError {
     pub fn is_stream(&self) -> bool {
-        *self == ProtocolError::PartialContent || *self == ProtocolError::EndOfStream
+        *self == ProtocolError::PartialContent
+            || *self == ProtocolError::EndOfStream
+            || *self == ProtocolError::Truncated
+    }
+
+    /// Errors that may not happen again if the request is retried
+    pub fn is_transient(&self) -> bool {
+        *self == ProtocolError::WriteError
+            || *self == ProtocolError::ActorError
+            || *self == ProtocolError::BrokerError
+            || *self == ProtocolError::StoreError
+    }
+
+    /// Stable name of the error, used for logging and metrics labels
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolError::Success => "success",
+            ProtocolError::WriteError => "write_error",
+            ProtocolError::ActorError => "actor_error",
+            ProtocolError::InvalidState => "invalid_state",
+            ProtocolError::SignatureError => "signature_error",
+            ProtocolError::InvalidSignature => "invalid_signature",
+            ProtocolError::SerializationError => "serialization_error",
+            ProtocolError::PartialContent => "partial_content",
+            ProtocolError::AccessDenied => "access_denied",
+            ProtocolError::OverlayNotJoined => "overlay_not_joined",
+            ProtocolError::OverlayNotFound => "overlay_not_found",
+            ProtocolError::BrokerError => "broker_error",
+            ProtocolError::NotFound => "not_found",
+            ProtocolError::EndOfStream => "end_of_stream",
+            ProtocolError::StoreError => "store_error",
+            ProtocolError::MissingBlocks => "missing_blocks",
+            ProtocolError::ObjectParseError => "object_parse_error",
+            ProtocolError::InvalidValue => "invalid_value",
+            ProtocolError::UserAlreadyExists => "user_already_exists",
+            ProtocolError::RepoIdRequired => "repo_id_required",
+            ProtocolError::Closing => "closing",
+            ProtocolError::TooManyHeads => "too_many_heads",
+            ProtocolError::Truncated => "truncated",
+            ProtocolError::OverlayNotAllowed => "overlay_not_allowed",
+            ProtocolError::NoAccount => "no_account",
+            ProtocolError::InvalidBlock => "invalid_block",
+            ProtocolError::BranchSealed => "branch_sealed",
+            ProtocolError::ConnectionClosed => "connection_closed",
+            ProtocolError::ReorderBufferFull => "reorder_buffer_full",
+            ProtocolError::InvalidResponse => "invalid_response",
+            ProtocolError::MetadataTooLarge => "metadata_too_large",
+            ProtocolError::ObjectTooLarge => "object_too_large",
+            ProtocolError::ObjectDeleted => "object_deleted",
+            ProtocolError::Cancelled => "cancelled",
+            ProtocolError::ForeignBlock => "foreign_block",
+            ProtocolError::LimitExceeded => "limit_exceeded",
+            ProtocolError::InvalidTimestamp => "invalid_timestamp",
+        }
+    }
+
+    /// Get the ProtocolError of a result code received from a broker,
+    /// like `ProtocolError::from`. Unknown codes are mapped to InvalidValue
+    pub fn from_code(code: u16) -> ProtocolError {
+        ProtocolError::from(code)
     }
 }
 
@@ -53,13 +137,28 @@ impl From<lofire::errors::LofireError> for ProtocolError {
         match e {
             lofire::errors::LofireError::InvalidSignature => ProtocolError::InvalidSignature,
             lofire::errors::LofireError::SerializationError => ProtocolError::SerializationError,
+            lofire::errors::LofireError::InvalidTimestamp => ProtocolError::InvalidValue,
+            lofire::errors::LofireError::InvalidKey => ProtocolError::InvalidValue,
+            lofire::errors::LofireError::InvalidBlock => ProtocolError::InvalidBlock,
+            lofire::errors::LofireError::MetadataTooLarge => ProtocolError::MetadataTooLarge,
+            lofire::errors::LofireError::ObjectTooLarge => ProtocolError::ObjectTooLarge,
+            lofire::errors::LofireError::MissingBody => ProtocolError::MissingBlocks,
+            lofire::errors::LofireError::MissingDependency => ProtocolError::MissingBlocks,
+            lofire::errors::LofireError::InvalidCommit(_) => ProtocolError::InvalidValue,
+            lofire::errors::LofireError::NotMember => ProtocolError::AccessDenied,
+            lofire::errors::LofireError::PermissionDenied => ProtocolError::AccessDenied,
+            lofire::errors::LofireError::QuorumNotReached => ProtocolError::InvalidValue,
         }
     }
 }
 
 impl From<ObjectParseError> for ProtocolError {
     fn from(e: ObjectParseError) -> Self {
-        ProtocolError::ObjectParseError
+        match e {
+            ObjectParseError::ReorderBufferFull => ProtocolError::ReorderBufferFull,
+            ObjectParseError::StorageError => ProtocolError::StoreError,
+            _ => ProtocolError::ObjectParseError,
+        }
     }
 }
 
@@ -84,9 +183,9 @@ impl From<BrokerMessage> for Result<(), ProtocolError> {
         if !msg.is_response() {
             panic!("BrokerMessage is not a response");
         }
-        match msg.result() {
-            0 => Ok(()),
-            err => Err(ProtocolError::try_from(err).unwrap()),
+        match ProtocolError::from(msg.result()) {
+            ProtocolError::Success => Ok(()),
+            err => Err(err),
         }
     }
 }
@@ -96,9 +195,57 @@ impl From<BrokerMessage> for Result<ObjectId, ProtocolError> {
         if !msg.is_response() {
             panic!("BrokerMessage is not a response");
         }
-        match msg.result() {
-            0 => Ok(msg.response_object_id()),
-            err => Err(ProtocolError::try_from(err).unwrap()),
+        match ProtocolError::from(msg.result()) {
+            ProtocolError::Success => Ok(msg.response_object_id()),
+            err => Err(err),
+        }
+    }
+}
+
+impl From<BrokerMessage> for Result<Vec<u16>, ProtocolError> {
+    fn from(msg: BrokerMessage) -> Self {
+        if !msg.is_response() {
+            panic!("BrokerMessage is not a response");
+        }
+        match ProtocolError::from(msg.result()) {
+            ProtocolError::Success => msg.try_response_block_results(),
+            err => Err(err),
+        }
+    }
+}
+
+impl From<BrokerMessage> for Result<Vec<BlockId>, ProtocolError> {
+    fn from(msg: BrokerMessage) -> Self {
+        if !msg.is_response() {
+            panic!("BrokerMessage is not a response");
+        }
+        match ProtocolError::from(msg.result()) {
+            ProtocolError::Success => msg.try_response_block_ids(),
+            err => Err(err),
+        }
+    }
+}
+
+impl From<BrokerMessage> for Result<BlockListResp, ProtocolError> {
+    fn from(msg: BrokerMessage) -> Self {
+        if !msg.is_response() {
+            panic!("BrokerMessage is not a response");
+        }
+        match ProtocolError::from(msg.result()) {
+            ProtocolError::Success => msg.try_response_block_list(),
+            err => Err(err),
+        }
+    }
+}
+
+impl From<BrokerMessage> for Result<OverlayStatusResp, ProtocolError> {
+    fn from(msg: BrokerMessage) -> Self {
+        if !msg.is_response() {
+            panic!("BrokerMessage is not a response");
+        }
+        match ProtocolError::from(msg.result()) {
+            ProtocolError::Success => msg.try_response_overlay_status(),
+            err => Err(err),
         }
     }
 }
@@ -109,9 +256,9 @@ impl From<BrokerMessage> for Result<Option<u16>, ProtocolError> {
         if !msg.is_response() {
             panic!("BrokerMessage is not a response");
         }
-        //let partial: u16 = ProtocolError::PartialContent.into();
         let res = msg.result();
-        if res == 0 || ProtocolError::try_from(res).unwrap().is_stream() {
+        let err = ProtocolError::from(res);
+        if err == ProtocolError::Success || err.is_stream() {
             if msg.is_overlay() {
                 match msg.response_block() {
                     Some(_) => Ok(Some(res)),
@@ -121,7 +268,7 @@ impl From<BrokerMessage> for Result<Option<u16>, ProtocolError> {
                 Ok(None)
             }
         } else {
-            Err(ProtocolError::try_from(res).unwrap())
+            Err(err)
         }
     }
 }
@@ -132,9 +279,8 @@ impl From<BrokerMessage> for Result<Option<Block>, ProtocolError> {
         if !msg.is_response() {
             panic!("BrokerMessage is not a response");
         }
-        //let partial: u16 = ProtocolError::PartialContent.into();
-        let res = msg.result();
-        if res == 0 || ProtocolError::try_from(res).unwrap().is_stream() {
+        let err = ProtocolError::from(msg.result());
+        if err == ProtocolError::Success || err.is_stream() {
             if msg.is_overlay() {
                 match msg.response_block() {
                     Some(b) => Ok(Some(b.clone())),
@@ -144,7 +290,64 @@ impl From<BrokerMessage> for Result<Option<Block>, ProtocolError> {
                 Ok(None)
             }
         } else {
-            Err(ProtocolError::try_from(res).unwrap())
+            Err(err)
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::errors::*;
+    use std::collections::HashSet;
+
+    /// All the errors, in the order of their codes
+    fn all_errors() -> Vec<ProtocolError> {
+        (0..=u16::MAX)
+            .map_while(|code| {
+                let e = ProtocolError::from(code);
+                (u16::from(e.clone()) == code).then_some(e)
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_error_names() {
+        let all = all_errors();
+        println!("{} error codes", all.len());
+        assert_eq!(all[0], ProtocolError::Success);
+
+        let mut names = HashSet::new();
+        for e in all.iter() {
+            assert!(!e.as_str().is_empty());
+            assert!(names.insert(e.as_str()), "duplicate name {}", e.as_str());
+        }
+    }
+
+    #[test]
+    pub fn test_error_codes() {
+        let all = all_errors();
+        // every variant survives u16 -> ProtocolError -> u16
+        assert!(all.contains(&ProtocolError::MetadataTooLarge));
+        assert!(all.contains(&ProtocolError::ObjectTooLarge));
+        assert!(all.contains(&ProtocolError::ObjectDeleted));
+        assert!(all.contains(&ProtocolError::Cancelled));
+        assert!(all.contains(&ProtocolError::ForeignBlock));
+        assert!(all.contains(&ProtocolError::LimitExceeded));
+        assert!(all.contains(&ProtocolError::InvalidTimestamp));
+        for (code, e) in all.iter().enumerate() {
+            let code = code as u16;
+            assert_eq!(u16::from(ProtocolError::from(code)), code);
+            assert_eq!(ProtocolError::from(u16::from(e.clone())), *e);
+            assert_eq!(ProtocolError::from_code(code), *e);
+        }
+
+        // unknown codes
+        let unknown = all.len() as u16;
+        assert_eq!(ProtocolError::from(unknown), ProtocolError::InvalidValue);
+        assert_eq!(ProtocolError::from(u16::MAX), ProtocolError::InvalidValue);
+        assert_eq!(
+            ProtocolError::from_code(unknown),
+            ProtocolError::InvalidValue
+        );
+    }
+}
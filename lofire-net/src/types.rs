@@ -2,7 +2,11 @@
 //!
 //! Corresponds to the BARE schema
 
+use crate::errors::ProtocolError;
+use lofire::errors::LofireError;
+use lofire::store::Cursor;
 use lofire::types::*;
+use lofire::utils::{check_pubkey, sign, verify, Clock, SystemClock};
 use serde::{Deserialize, Serialize};
 
 //
@@ -210,11 +214,12 @@ pub struct EventContentV0 {
     /// Pub/sub topic
     pub topic: TopicId,
 
-    /// Publisher pubkey encrypted with ChaCha20:
-    /// - key: BLAKE3 derive_key ("LoFiRe Event Publisher ChaCha20 key",
+    /// Publisher pubkey hash
+    /// BLAKE3 keyed hash over branch member pubkey
+    /// - key: BLAKE3 derive_key ("LoFiRe Event Publisher BLAKE3 key",
     ///                           repo_pubkey + repo_secret +
     ///                           branch_pubkey + branch_secret)
-    pub publisher: [u8; 32], // PubKey
+    pub publisher: [u8; 32], // Digest
 
     /// Commit sequence number of publisher
     pub seq: u32,
@@ -240,6 +245,36 @@ pub enum Event {
     V0(EventV0),
 }
 
+impl Event {
+    /// Event signed by the topic key
+    pub fn new(content: EventContentV0, topic_privkey: PrivKey) -> Result<Event, LofireError> {
+        let sig = sign(topic_privkey, content.topic, &serde_bare::to_vec(&content)?)?;
+        Ok(Event::V0(EventV0 { content, sig }))
+    }
+    /// Verify the signature of the topic key
+    pub fn verify(&self) -> Result<(), LofireError> {
+        match self {
+            Event::V0(e) => verify(&serde_bare::to_vec(&e.content)?, e.sig, e.content.topic),
+        }
+    }
+    pub fn topic(&self) -> &TopicId {
+        match self {
+            Event::V0(e) => &e.content.topic,
+        }
+    }
+    /// Publisher hash, see `lofire::branch::compute_publisher_hash`
+    pub fn publisher(&self) -> Digest {
+        match self {
+            Event::V0(e) => Digest::Blake3Digest32(e.content.publisher),
+        }
+    }
+    pub fn seq(&self) -> u32 {
+        match self {
+            Event::V0(e) => e.content.seq,
+        }
+    }
+}
+
 /// Object search in a pub/sub topic
 ///
 /// Sent along the reverse path of a pub/sub topic
@@ -308,7 +343,8 @@ pub enum BlockResult {
 /// Request latest events corresponding to the branch heads in a pub/sub topic
 ///
 /// In response an Event is sent for each commit chunk that belong to branch heads
-/// that are not present in the requestor's known heads
+/// that are not present in the requestor's known heads.
+/// A broker responds with the IDs of these heads in `BrokerOverlayResponseContentV0::BlockIds`
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BranchHeadsReqV0 {
     /// Topic public key of the branch
@@ -324,6 +360,19 @@ pub enum BranchHeadsReq {
     V0(BranchHeadsReqV0),
 }
 
+impl BranchHeadsReq {
+    pub fn topic(&self) -> &TopicId {
+        match self {
+            BranchHeadsReq::V0(o) => &o.topic,
+        }
+    }
+    pub fn known_heads(&self) -> &Vec<ObjectId> {
+        match self {
+            BranchHeadsReq::V0(o) => &o.known_heads,
+        }
+    }
+}
+
 /// Branch synchronization request
 ///
 /// In response a stream of `Block`s of the requested Objects are sent
@@ -338,6 +387,14 @@ pub struct BranchSyncReqV0 {
 
     /// Known commit IDs since known_heads
     pub known_commits: BloomFilter,
+
+    /// Only send the bodies of the commits of these types.
+    /// The commits themselves are always sent, to keep the DAG verifiable.
+    /// None sends the commits only, without their bodies
+    pub commit_types: Option<Vec<CommitType>>,
+
+    /// Resume an interrupted sync of the same request, see `SyncCheckpoint`
+    pub checkpoint: Option<Vec<u8>>,
 }
 
 /// Branch synchronization request
@@ -362,6 +419,153 @@ impl BranchSyncReq {
             BranchSyncReq::V0(o) => &o.known_commits,
         }
     }
+    pub fn commit_types(&self) -> Option<&Vec<CommitType>> {
+        match self {
+            BranchSyncReq::V0(o) => o.commit_types.as_ref(),
+        }
+    }
+    pub fn checkpoint(&self) -> Option<&Vec<u8>> {
+        match self {
+            BranchSyncReq::V0(o) => o.checkpoint.as_ref(),
+        }
+    }
+    /// Checkpoint to resume this sync after `received` blocks of its stream
+    pub fn checkpoint_after(&self, received: u32) -> Vec<u8> {
+        SyncCheckpoint::token(
+            self.heads(),
+            self.known_heads(),
+            self.known_commits(),
+            self.commit_types(),
+            received,
+        )
+    }
+}
+
+/// Checkpoint of an interrupted branch sync
+///
+/// The broker streams the blocks of a sync in the same order for the same request,
+/// so the sync can resume after the blocks already received.
+/// The checkpoint is tied to the request it was made for,
+/// and is refused for any other, so that it can't cause blocks to be skipped
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncCheckpointV0 {
+    /// Hash of the heads, known heads, known commits and commit types of the request
+    pub request: Digest,
+
+    /// Number of blocks of the stream already received
+    pub received: u32,
+}
+
+/// Checkpoint of an interrupted branch sync
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SyncCheckpoint {
+    V0(SyncCheckpointV0),
+}
+
+impl SyncCheckpoint {
+    fn request_hash(
+        heads: &Vec<ObjectId>,
+        known_heads: &Vec<ObjectId>,
+        known_commits: &BloomFilter,
+        commit_types: Option<&Vec<CommitType>>,
+    ) -> Digest {
+        let request =
+            serde_bare::to_vec(&(heads, known_heads, known_commits, commit_types)).unwrap();
+        Digest::Blake3Digest32(*blake3::hash(&request).as_bytes())
+    }
+
+    /// Opaque token to put in `BranchSyncReqV0::checkpoint`
+    pub fn token(
+        heads: &Vec<ObjectId>,
+        known_heads: &Vec<ObjectId>,
+        known_commits: &BloomFilter,
+        commit_types: Option<&Vec<CommitType>>,
+        received: u32,
+    ) -> Vec<u8> {
+        let checkpoint = SyncCheckpoint::V0(SyncCheckpointV0 {
+            request: Self::request_hash(heads, known_heads, known_commits, commit_types),
+            received,
+        });
+        serde_bare::to_vec(&checkpoint).unwrap()
+    }
+
+    /// Number of blocks of the stream to skip when resuming with the token.
+    /// Fails with InvalidValue if the token was made for another request
+    pub fn resume_index(
+        token: &[u8],
+        heads: &Vec<ObjectId>,
+        known_heads: &Vec<ObjectId>,
+        known_commits: &BloomFilter,
+        commit_types: Option<&Vec<CommitType>>,
+    ) -> Result<u32, ProtocolError> {
+        let checkpoint = serde_bare::from_slice::<SyncCheckpoint>(token)
+            .map_err(|_e| ProtocolError::InvalidValue)?;
+        match checkpoint {
+            SyncCheckpoint::V0(c) => {
+                if c.request != Self::request_hash(heads, known_heads, known_commits, commit_types)
+                {
+                    return Err(ProtocolError::InvalidValue);
+                }
+                Ok(c.received)
+            }
+        }
+    }
+}
+
+/// Progress of a branch sync, interleaved with the blocks of its stream
+///
+/// The first one is sent before the blocks, with the total in blocks,
+/// and the last one is sent after all the blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncProgressV0 {
+    /// Number of blocks the stream sends
+    pub total: u32,
+
+    /// Number of blocks sent so far
+    pub sent: u32,
+
+    /// Set in the last progress of a stream cut at the block limit of the broker.
+    /// The sync is resumed with a checkpoint after the blocks received, see `BranchSyncReq::checkpoint_after`
+    pub truncated: bool,
+}
+
+/// Progress of a branch sync
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncProgress {
+    V0(SyncProgressV0),
+}
+
+impl SyncProgress {
+    pub fn new(total: u32, sent: u32) -> SyncProgress {
+        SyncProgress::V0(SyncProgressV0 {
+            total,
+            sent,
+            truncated: false,
+        })
+    }
+    /// Last progress of a stream truncated after `sent` blocks
+    pub fn new_truncated(sent: u32) -> SyncProgress {
+        SyncProgress::V0(SyncProgressV0 {
+            total: sent,
+            sent,
+            truncated: true,
+        })
+    }
+    pub fn total(&self) -> u32 {
+        match self {
+            SyncProgress::V0(p) => p.total,
+        }
+    }
+    pub fn sent(&self) -> u32 {
+        match self {
+            SyncProgress::V0(p) => p.sent,
+        }
+    }
+    pub fn truncated(&self) -> bool {
+        match self {
+            SyncProgress::V0(p) => p.truncated,
+        }
+    }
 }
 
 /// Events the requestor needs, see EventReqV0
@@ -525,6 +729,26 @@ impl PeerAdvert {
             PeerAdvert::V0(o) => &o.content.peer,
         }
     }
+    pub fn address(&self) -> &Vec<NetAddr> {
+        match self {
+            PeerAdvert::V0(o) => &o.content.address,
+        }
+    }
+    pub fn content(&self) -> &PeerAdvertContentV0 {
+        match self {
+            PeerAdvert::V0(o) => &o.content,
+        }
+    }
+    pub fn sig(&self) -> Sig {
+        match self {
+            PeerAdvert::V0(o) => o.sig,
+        }
+    }
+    pub fn ttl(&self) -> u8 {
+        match self {
+            PeerAdvert::V0(o) => o.ttl,
+        }
+    }
 }
 
 /// Content of OverlayMessagePaddedV0
@@ -595,6 +819,12 @@ pub enum OverlayMessage {
 pub struct AddUserContentV0 {
     /// User pub key
     pub user: PubKey,
+
+    /// Time of the request, so that the broker refuses it once it is stale
+    pub timestamp: Timestamp,
+
+    /// Random value, so that repeating a request within a minute gives another signature
+    pub nonce: u64,
 }
 
 /// Add user account
@@ -635,6 +865,12 @@ impl AddUser {
 pub struct DelUserContentV0 {
     /// User pub key
     pub user: PubKey,
+
+    /// Time of the request, so that the broker refuses it once it is stale
+    pub timestamp: Timestamp,
+
+    /// Random value, so that repeating a request within a minute gives another signature
+    pub nonce: u64,
 }
 
 /// Delete user account
@@ -675,6 +911,12 @@ impl DelUser {
 pub struct AddClientContentV0 {
     /// Client pub key
     pub client: PubKey,
+
+    /// Time of the request, so that the broker refuses it once it is stale
+    pub timestamp: Timestamp,
+
+    /// Random value, so that repeating a request within a minute gives another signature
+    pub nonce: u64,
 }
 /// Add a client
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -714,6 +956,12 @@ impl AddClient {
 pub struct DelClientContentV0 {
     /// Client pub key
     pub client: PubKey,
+
+    /// Time of the request, so that the broker refuses it once it is stale
+    pub timestamp: Timestamp,
+
+    /// Random value, so that repeating a request within a minute gives another signature
+    pub nonce: u64,
 }
 
 /// Remove a client
@@ -749,6 +997,23 @@ impl DelClient {
     }
 }
 
+/// Rotation of a client key, signed by the user key.
+/// The new client replaces the old one in a single operation
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RotateClientContentV0 {
+    /// Client pub key to remove
+    pub old_client: PubKey,
+
+    /// Client pub key to add
+    pub new_client: PubKey,
+
+    /// Time of the request, so that the broker refuses it once it is stale
+    pub timestamp: Timestamp,
+
+    /// Random value, so that repeating a request within a minute gives another signature
+    pub nonce: u64,
+}
+
 /// Content of `BrokerRequestV0`
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum BrokerRequestContentV0 {
@@ -881,6 +1146,19 @@ pub enum OverlayStatusResp {
     V0(OverlayStatusRespV0),
 }
 
+impl OverlayStatusResp {
+    pub fn joined(&self) -> bool {
+        match self {
+            OverlayStatusResp::V0(o) => o.joined,
+        }
+    }
+    pub fn peers(&self) -> &Vec<PeerAdvert> {
+        match self {
+            OverlayStatusResp::V0(o) => &o.peers,
+        }
+    }
+}
+
 /// Request a Block by ID
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BlockGetV0 {
@@ -892,6 +1170,23 @@ pub struct BlockGetV0 {
 
     /// Topic the object is referenced from
     pub topic: Option<PubKey>,
+
+    /// Maximum number of blocks to send when including children.
+    /// The broker also applies its own limit
+    pub max_blocks: Option<u32>,
+
+    /// Continuation token of a previous truncated response,
+    /// to request the remaining blocks
+    pub continuation: Option<u32>,
+
+    /// Blocks the client already has, not sent when including children.
+    /// Blocks skipped because of a false positive have to be requested again
+    pub known_blocks: Option<BloomFilter>,
+
+    /// Byte range (offset, length) in the serialized content of the object:
+    /// only the blocks covering it are sent, with the blocks on their path from the root.
+    /// Overrides include_children
+    pub range: Option<(u64, u64)>,
 }
 
 /// Request an object by ID
@@ -916,6 +1211,64 @@ impl BlockGet {
             BlockGet::V0(o) => o.topic,
         }
     }
+    pub fn max_blocks(&self) -> Option<u32> {
+        match self {
+            BlockGet::V0(o) => o.max_blocks,
+        }
+    }
+    pub fn continuation(&self) -> Option<u32> {
+        match self {
+            BlockGet::V0(o) => o.continuation,
+        }
+    }
+    pub fn known_blocks(&self) -> Option<&BloomFilter> {
+        match self {
+            BlockGet::V0(o) => o.known_blocks.as_ref(),
+        }
+    }
+    pub fn range(&self) -> Option<(u64, u64)> {
+        match self {
+            BlockGet::V0(o) => o.range,
+        }
+    }
+}
+
+/// Request the blocks of a File object covering a byte range of its serialized content
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockRangeGetV0 {
+    /// Object ID to request
+    pub id: ObjectId,
+
+    /// Offset of the range in the serialized content of the object
+    pub offset: u64,
+
+    /// Length of the range
+    pub len: u64,
+}
+
+/// Request the blocks of an object covering a byte range,
+/// with the blocks on their path from the root needed to verify them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BlockRangeGet {
+    V0(BlockRangeGetV0),
+}
+
+impl BlockRangeGet {
+    pub fn id(&self) -> ObjectId {
+        match self {
+            BlockRangeGet::V0(o) => o.id,
+        }
+    }
+    pub fn offset(&self) -> u64 {
+        match self {
+            BlockRangeGet::V0(o) => o.offset,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        match self {
+            BlockRangeGet::V0(o) => o.len,
+        }
+    }
 }
 
 /// Request to store an object
@@ -932,6 +1285,29 @@ impl BlockPut {
     }
 }
 
+/// Request to store several blocks at once
+///
+/// The valid blocks are stored together, in a single transaction.
+/// The response carries one result code per block, in the same order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlocksPutV0 {
+    pub blocks: Vec<Block>,
+}
+
+/// Request to store several blocks at once
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BlocksPut {
+    V0(BlocksPutV0),
+}
+
+impl BlocksPut {
+    pub fn blocks(&self) -> &Vec<Block> {
+        match self {
+            BlocksPut::V0(o) => &o.blocks,
+        }
+    }
+}
+
 /// Request to pin an object
 ///
 /// Brokers maintain an LRU cache of objects,
@@ -1012,6 +1388,10 @@ impl ObjectCopy {
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct ObjectDelV0 {
     pub id: ObjectId,
+
+    /// Tombstone of the object, kept by the broker so that the object isn't added back
+    /// by a replication from a broker that still has it
+    pub tombstone: Option<Tombstone>,
 }
 
 /// Request to delete an object
@@ -1026,6 +1406,95 @@ impl ObjectDel {
             ObjectDel::V0(o) => o.id,
         }
     }
+    pub fn tombstone(&self) -> Option<&Tombstone> {
+        match self {
+            ObjectDel::V0(o) => o.tombstone.as_ref(),
+        }
+    }
+}
+
+/// Tombstone of a deleted object
+///
+/// Brokers keep it for a retention period and exchange it when replicating,
+/// so that a broker still having the object deletes it too,
+/// and doesn't send it back to the brokers that deleted it
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TombstoneV0 {
+    /// ID of the deleted object
+    pub object: ObjectId,
+
+    /// Time of the deletion
+    pub deleted_at: Timestamp,
+
+    /// User that deleted the object, who must have joined the overlay
+    pub author: PubKey,
+
+    /// Signature by the author over the overlay ID, the object ID and the time of the deletion
+    pub sig: Sig,
+}
+
+/// Tombstone of a deleted object
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Tombstone {
+    V0(TombstoneV0),
+}
+
+impl Tombstone {
+    /// Tombstone of an object of the overlay, deleted now
+    pub fn new(
+        overlay: &OverlayId,
+        object: ObjectId,
+        author_privkey: PrivKey,
+        author_pubkey: PubKey,
+    ) -> Result<Tombstone, LofireError> {
+        Self::new_at(
+            overlay,
+            object,
+            SystemClock.now(),
+            author_privkey,
+            author_pubkey,
+        )
+    }
+    pub fn new_at(
+        overlay: &OverlayId,
+        object: ObjectId,
+        deleted_at: Timestamp,
+        author_privkey: PrivKey,
+        author_pubkey: PubKey,
+    ) -> Result<Tombstone, LofireError> {
+        let content = serde_bare::to_vec(&(overlay, object, deleted_at))?;
+        let sig = sign(author_privkey, author_pubkey, &content)?;
+        Ok(Tombstone::V0(TombstoneV0 {
+            object,
+            deleted_at,
+            author: author_pubkey,
+            sig,
+        }))
+    }
+    pub fn object(&self) -> ObjectId {
+        match self {
+            Tombstone::V0(t) => t.object,
+        }
+    }
+    pub fn deleted_at(&self) -> Timestamp {
+        match self {
+            Tombstone::V0(t) => t.deleted_at,
+        }
+    }
+    pub fn author(&self) -> PubKey {
+        match self {
+            Tombstone::V0(t) => t.author,
+        }
+    }
+    /// Verify the signature of the author, for the given overlay
+    pub fn verify(&self, overlay: &OverlayId) -> Result<(), LofireError> {
+        match self {
+            Tombstone::V0(t) => {
+                let content = serde_bare::to_vec(&(overlay, t.object, t.deleted_at))?;
+                verify(&content, t.sig, t.author)
+            }
+        }
+    }
 }
 
 /// Request subscription to a `Topic`
@@ -1044,6 +1513,14 @@ pub enum TopicSub {
     V0(TopicSubV0),
 }
 
+impl TopicSub {
+    pub fn topic(&self) -> TopicId {
+        match self {
+            TopicSub::V0(o) => o.topic,
+        }
+    }
+}
+
 /// Request unsubscription from a `Topic`
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct TopicUnsubV0 {
@@ -1057,6 +1534,14 @@ pub enum TopicUnsub {
     V0(TopicUnsubV0),
 }
 
+impl TopicUnsub {
+    pub fn topic(&self) -> TopicId {
+        match self {
+            TopicUnsub::V0(o) => o.topic,
+        }
+    }
+}
+
 /// Connect to an already subscribed `Topic`, and start receiving its `Event`s
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct TopicConnectV0 {
@@ -1070,6 +1555,14 @@ pub enum TopicConnect {
     V0(TopicConnectV0),
 }
 
+impl TopicConnect {
+    pub fn topic(&self) -> TopicId {
+        match self {
+            TopicConnect::V0(o) => o.topic,
+        }
+    }
+}
+
 /// Disconnect from a Topic, and stop receiving its `Event`s
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct TopicDisconnectV0 {
@@ -1083,6 +1576,146 @@ pub enum TopicDisconnect {
     V0(TopicDisconnectV0),
 }
 
+impl TopicDisconnect {
+    pub fn topic(&self) -> TopicId {
+        match self {
+            TopicDisconnect::V0(o) => o.topic,
+        }
+    }
+}
+
+/// Request all the blocks of an overlay, for replication by another broker
+///
+/// In response a stream of `Block`s is sent
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct OverlayReplicateV0 {
+    /// Only send the blocks stored after this timestamp (incremental replication)
+    pub since: Option<Timestamp>,
+
+    /// Resume from the checkpoint the broker keeps for the requesting replica, instead of `since`.
+    /// The checkpoint only advances once the replica sends a `ReplicationAck`
+    pub checkpoint: bool,
+}
+
+/// Request all the blocks of an overlay, for replication by another broker
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum OverlayReplicate {
+    V0(OverlayReplicateV0),
+}
+
+impl OverlayReplicate {
+    pub fn since(&self) -> Option<Timestamp> {
+        match self {
+            OverlayReplicate::V0(o) => o.since,
+        }
+    }
+    pub fn checkpoint(&self) -> bool {
+        match self {
+            OverlayReplicate::V0(o) => o.checkpoint,
+        }
+    }
+}
+
+/// Acknowledge that all the blocks of the last `OverlayReplicate` with `checkpoint` were received,
+/// so that the broker advances the checkpoint of the replica
+///
+/// In response the gaps of that replication are sent:
+/// the blocks stored after the previous checkpoint that have been removed since
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ReplicationAckV0 {}
+
+/// Acknowledge the last replication from checkpoint
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ReplicationAck {
+    V0(ReplicationAckV0),
+}
+
+/// List the IDs of the blocks stored in the overlay, one page at a time
+///
+/// Blocks are listed in a stable order, so that the cursor is well-defined.
+/// In response a `BlockListResp` is sent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockListV0 {
+    /// Maximum number of block IDs in the page.
+    /// The broker also applies its own limit
+    pub limit: u32,
+
+    /// Cursor returned with the previous page, None for the first page
+    pub after: Option<Cursor>,
+}
+
+/// List the IDs of the blocks stored in the overlay, one page at a time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BlockList {
+    V0(BlockListV0),
+}
+
+impl BlockList {
+    pub fn limit(&self) -> u32 {
+        match self {
+            BlockList::V0(o) => o.limit,
+        }
+    }
+    pub fn after(&self) -> Option<&Cursor> {
+        match self {
+            BlockList::V0(o) => o.after.as_ref(),
+        }
+    }
+}
+
+/// Page of block IDs in response to a `BlockList`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BlockListRespV0 {
+    /// Block IDs of the page
+    pub ids: Vec<BlockId>,
+
+    /// Cursor to request the next page with, None if this was the last page
+    pub next: Option<Cursor>,
+}
+
+/// Page of block IDs in response to a `BlockList`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum BlockListResp {
+    V0(BlockListRespV0),
+}
+
+impl BlockListResp {
+    pub fn ids(&self) -> &Vec<BlockId> {
+        match self {
+            BlockListResp::V0(o) => &o.ids,
+        }
+    }
+    pub fn next(&self) -> Option<&Cursor> {
+        match self {
+            BlockListResp::V0(o) => o.next.as_ref(),
+        }
+    }
+}
+
+/// Request a commit with its body
+///
+/// In response a stream of `Block`s is sent:
+/// the blocks of the commit object, then the blocks of its body object
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CommitGetV0 {
+    /// Commit ID
+    pub id: ObjectId,
+}
+
+/// Request a commit with its body
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum CommitGet {
+    V0(CommitGetV0),
+}
+
+impl CommitGet {
+    pub fn id(&self) -> ObjectId {
+        match self {
+            CommitGet::V0(o) => o.id,
+        }
+    }
+}
+
 /// Content of `BrokerOverlayRequestV0`
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum BrokerOverlayRequestContentV0 {
@@ -1103,6 +1736,12 @@ pub enum BrokerOverlayRequestContentV0 {
     ObjectDel(ObjectDel),
     BranchHeadsReq(BranchHeadsReq),
     BranchSyncReq(BranchSyncReq),
+    OverlayReplicate(OverlayReplicate),
+    CommitGet(CommitGet),
+    BlocksPut(BlocksPut),
+    BlockRangeGet(BlockRangeGet),
+    ReplicationAck(ReplicationAck),
+    BlockList(BlockList),
 }
 /// Broker overlay request
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -1139,6 +1778,32 @@ pub enum BrokerOverlayResponseContentV0 {
     Block(Block),
     ObjectId(ObjectId),
     OverlayStatusResp(OverlayStatusResp),
+
+    /// Result codes of the blocks of a `BlocksPut`, 0 for success
+    BlockResults(Vec<u16>),
+
+    /// Progress of a `BranchSyncReq`, streamed with PartialContent between its blocks
+    SyncProgress(SyncProgress),
+
+    /// Block IDs, such as the gaps of a replication in response to a `ReplicationAck`,
+    /// or the branch heads in response to a `BranchHeadsReq`
+    BlockIds(Vec<BlockId>),
+
+    /// Continuation token of a `BlockGet` cut at the block limit,
+    /// sent with the Truncated result that ends the stream
+    Continuation(u32),
+
+    /// Tombstone of a deleted object, streamed with PartialContent before the blocks of an `OverlayReplicate`
+    Tombstone(Tombstone),
+
+    /// Page of block IDs in response to a `BlockList`
+    BlockListResp(BlockListResp),
+}
+
+impl From<Block> for BrokerOverlayResponseContentV0 {
+    fn from(block: Block) -> Self {
+        BrokerOverlayResponseContentV0::Block(block)
+    }
 }
 
 /// Response to a `BrokerOverlayRequest`
@@ -1193,6 +1858,73 @@ impl BrokerOverlayResponse {
             },
         }
     }
+    /// Progress of a branch sync, None if the response is not a progress
+    pub fn sync_progress(&self) -> Option<&SyncProgress> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::SyncProgress(p)) => Some(p),
+                _ => None,
+            },
+        }
+    }
+    /// Result codes of the blocks of a `BlocksPut`,
+    /// InvalidResponse if the response doesn't have them
+    pub fn block_results(&self) -> Result<Vec<u16>, ProtocolError> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::BlockResults(r)) => Ok(r.clone()),
+                _ => Err(ProtocolError::InvalidResponse),
+            },
+        }
+    }
+    /// Continuation token of a truncated stream, None if the response is not its end
+    pub fn continuation(&self) -> Option<u32> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::Continuation(c)) => Some(*c),
+                _ => None,
+            },
+        }
+    }
+    /// Tombstone streamed by a replication, None if the response is not a tombstone
+    pub fn tombstone(&self) -> Option<&Tombstone> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::Tombstone(t)) => Some(t),
+                _ => None,
+            },
+        }
+    }
+    /// Block IDs of a `ReplicationAck` or `BranchHeadsReq` response,
+    /// InvalidResponse if the response doesn't have them
+    pub fn block_ids(&self) -> Result<Vec<BlockId>, ProtocolError> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::BlockIds(ids)) => Ok(ids.clone()),
+                _ => Err(ProtocolError::InvalidResponse),
+            },
+        }
+    }
+    /// Page of block IDs in response to a `BlockList`,
+    /// InvalidResponse if the response doesn't have it
+    pub fn block_list(&self) -> Result<BlockListResp, ProtocolError> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::BlockListResp(page)) => Ok(page.clone()),
+                _ => Err(ProtocolError::InvalidResponse),
+            },
+        }
+    }
+    /// Status of the overlay in response to an `OverlayStatusReq`,
+    /// InvalidResponse if the response doesn't have it
+    pub fn overlay_status(&self) -> Result<OverlayStatusResp, ProtocolError> {
+        match self {
+            BrokerOverlayResponse::V0(o) => match &o.content {
+                Some(BrokerOverlayResponseContentV0::OverlayStatusResp(s)) => Ok(s.clone()),
+                _ => Err(ProtocolError::InvalidResponse),
+            },
+        }
+    }
 }
 
 /// Content of `BrokerOverlayMessageV0`
@@ -1250,6 +1982,15 @@ impl BrokerOverlayMessage {
             ),
         }
     }
+    /// Event of a topic the client is connected to
+    pub fn event(&self) -> Option<&Event> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::Event(e) => Some(e),
+                _ => None,
+            },
+        }
+    }
     pub fn id(&self) -> u64 {
         match self {
             BrokerOverlayMessage::V0(o) => match &o.content {
@@ -1300,6 +2041,108 @@ impl BrokerOverlayMessage {
             },
         }
     }
+    /// Same as `overlay_request()`, but returns InvalidState instead of panicking
+    pub fn try_overlay_request(&self) -> Result<&BrokerOverlayRequest, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayRequest(r) => Ok(&r),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    /// Same as `id()`, but returns InvalidState instead of panicking
+    pub fn try_id(&self) -> Result<u64, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => Ok(r.id()),
+                BrokerOverlayMessageContentV0::BrokerOverlayRequest(r) => Ok(r.id()),
+                BrokerOverlayMessageContentV0::Event(_) => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    /// Same as `result()`, but returns InvalidState instead of panicking
+    pub fn try_result(&self) -> Result<u16, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => Ok(r.result()),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    /// Same as `block()`, but returns InvalidState instead of panicking
+    pub fn try_block(&self) -> Result<Option<&Block>, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => Ok(r.block()),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    /// Same as `object_id()`, but returns InvalidState instead of panicking
+    pub fn try_object_id(&self) -> Result<ObjectId, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => Ok(r.object_id()),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    pub fn try_block_results(&self) -> Result<Vec<u16>, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.block_results(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    pub fn try_block_ids(&self) -> Result<Vec<BlockId>, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.block_ids(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    pub fn try_block_list(&self) -> Result<BlockListResp, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.block_list(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    pub fn try_overlay_status(&self) -> Result<OverlayStatusResp, ProtocolError> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.overlay_status(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+        }
+    }
+    pub fn sync_progress(&self) -> Option<&SyncProgress> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.sync_progress(),
+                _ => None,
+            },
+        }
+    }
+    pub fn continuation(&self) -> Option<u32> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.continuation(),
+                _ => None,
+            },
+        }
+    }
+    pub fn tombstone(&self) -> Option<&Tombstone> {
+        match self {
+            BrokerOverlayMessage::V0(o) => match &o.content {
+                BrokerOverlayMessageContentV0::BrokerOverlayResponse(r) => r.tombstone(),
+                _ => None,
+            },
+        }
+    }
 }
 
 /// Content of BrokerMessageV0
@@ -1362,6 +2205,16 @@ impl BrokerMessage {
             BrokerMessage::Close => panic!("Close not implemented"),
         }
     }
+    /// Event of a topic the client is connected to, None for any other message
+    pub fn event(&self) -> Option<&Event> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.event(),
+                _ => None,
+            },
+            BrokerMessage::Close => None,
+        }
+    }
     pub fn id(&self) -> u64 {
         match self {
             BrokerMessage::V0(o) => match &o.content {
@@ -1423,6 +2276,119 @@ impl BrokerMessage {
             BrokerMessage::Close => panic!("Close not implemented"),
         }
     }
+
+    /// Same as `id()`, but returns an error instead of panicking
+    pub fn try_id(&self) -> Result<u64, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_id(),
+                BrokerMessageContentV0::BrokerResponse(r) => Ok(r.id()),
+                BrokerMessageContentV0::BrokerRequest(r) => Ok(r.id()),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Same as `result()`, but returns an error instead of panicking
+    pub fn try_result(&self) -> Result<u16, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_result(),
+                BrokerMessageContentV0::BrokerResponse(r) => Ok(r.result()),
+                BrokerMessageContentV0::BrokerRequest(_) => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Same as `response_block()`, but returns an error instead of panicking
+    pub fn try_response_block(&self) -> Result<Option<&Block>, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_block(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Same as `response_object_id()`, but returns an error instead of panicking
+    pub fn try_response_object_id(&self) -> Result<ObjectId, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_object_id(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Result codes of the blocks of a `BlocksPut` response
+    pub fn try_response_block_results(&self) -> Result<Vec<u16>, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_block_results(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Block IDs of a `ReplicationAck` response
+    pub fn try_response_block_ids(&self) -> Result<Vec<BlockId>, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_block_ids(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Page of block IDs in a `BlockList` response
+    pub fn try_response_block_list(&self) -> Result<BlockListResp, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_block_list(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Status of the overlay in an `OverlayStatusReq` response
+    pub fn try_response_overlay_status(&self) -> Result<OverlayStatusResp, ProtocolError> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.try_overlay_status(),
+                _ => Err(ProtocolError::InvalidState),
+            },
+            BrokerMessage::Close => Err(ProtocolError::Closing),
+        }
+    }
+    /// Progress of a branch sync streamed in an overlay response, if it is one
+    pub fn response_sync_progress(&self) -> Option<&SyncProgress> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.sync_progress(),
+                _ => None,
+            },
+            BrokerMessage::Close => None,
+        }
+    }
+    /// Continuation token ending a truncated stream in an overlay response, if it is one
+    pub fn response_continuation(&self) -> Option<u32> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.continuation(),
+                _ => None,
+            },
+            BrokerMessage::Close => None,
+        }
+    }
+    /// Tombstone streamed by a replication in an overlay response, if it is one
+    pub fn response_tombstone(&self) -> Option<&Tombstone> {
+        match self {
+            BrokerMessage::V0(o) => match &o.content {
+                BrokerMessageContentV0::BrokerOverlayMessage(p) => p.tombstone(),
+                _ => None,
+            },
+            BrokerMessage::Close => None,
+        }
+    }
 }
 
 //
@@ -1493,12 +2459,70 @@ pub enum ExtRequest {
     V0(ExtRequestV0),
 }
 
+impl ExtRequest {
+    /// Create a request authenticated with a MAC derived from the repository keys
+    pub fn new(
+        id: u64,
+        content: ExtRequestContentV0,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<ExtRequest, LofireError> {
+        let mac = Self::mac(&content, repo_pubkey, repo_secret)?;
+        Ok(ExtRequest::V0(ExtRequestV0 { id, content, mac }))
+    }
+    pub fn id(&self) -> u64 {
+        match self {
+            ExtRequest::V0(o) => o.id,
+        }
+    }
+    pub fn content(&self) -> &ExtRequestContentV0 {
+        match self {
+            ExtRequest::V0(o) => &o.content,
+        }
+    }
+
+    /// Check the MAC of the request with the repository keys
+    pub fn verify_mac(&self, repo_pubkey: PubKey, repo_secret: SymKey) -> Result<(), LofireError> {
+        match self {
+            ExtRequest::V0(o) => {
+                if Self::mac(&o.content, repo_pubkey, repo_secret)? == o.mac {
+                    Ok(())
+                } else {
+                    Err(LofireError::InvalidSignature)
+                }
+            }
+        }
+    }
+
+    fn mac(
+        content: &ExtRequestContentV0,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<Digest, LofireError> {
+        let key_material = [*repo_pubkey.slice(), *repo_secret.slice()].concat();
+        let key = blake3::derive_key("LoFiRe ExtRequest BLAKE3 key", key_material.as_slice());
+        let mac = blake3::keyed_hash(&key, &serde_bare::to_vec(content)?);
+        Ok(Digest::Blake3Digest32(*mac.as_bytes()))
+    }
+}
+
+/// Block of one of the objects requested by an `ExtObjectGet`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExtObjectBlockV0 {
+    /// ID of the requested object the block belongs to
+    pub object: ObjectId,
+
+    /// The block
+    pub block: Block,
+}
+
 /// Content of ExtResponseV0
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ExtResponseContentV0 {
     Block(Block),
     EventResp(EventResp),
     Event(Event),
+    ObjectBlock(ExtObjectBlockV0),
 }
 
 /// Response to an ExtRequest
@@ -1520,6 +2544,38 @@ pub enum ExtResponse {
     V0(ExtResponseV0),
 }
 
+impl ExtResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            ExtResponse::V0(o) => o.id,
+        }
+    }
+    pub fn result(&self) -> u16 {
+        match self {
+            ExtResponse::V0(o) => o.result,
+        }
+    }
+    pub fn content(&self) -> Option<&ExtResponseContentV0> {
+        match self {
+            ExtResponse::V0(o) => o.content.as_ref(),
+        }
+    }
+    pub fn block(&self) -> Option<&Block> {
+        match self.content() {
+            Some(ExtResponseContentV0::Block(b)) => Some(b),
+            Some(ExtResponseContentV0::ObjectBlock(ob)) => Some(&ob.block),
+            _ => None,
+        }
+    }
+    /// ID of the requested object a streamed block belongs to
+    pub fn object(&self) -> Option<ObjectId> {
+        match self.content() {
+            Some(ExtResponseContentV0::ObjectBlock(ob)) => Some(ob.object),
+            _ => None,
+        }
+    }
+}
+
 ///
 /// AUTHENTICATION MESSAGES
 ///
@@ -1544,6 +2600,9 @@ pub struct ServerHelloV0 {
     /// Nonce for ClientAuth
     #[serde(with = "serde_bytes")]
     pub nonce: Vec<u8>,
+
+    /// Time of the broker, for the client to correct the skew of its clock
+    pub now: Timestamp,
 }
 
 /// Server hello sent upon a client connection
@@ -1558,6 +2617,11 @@ impl ServerHello {
             ServerHello::V0(o) => &o.nonce,
         }
     }
+    pub fn now(&self) -> Timestamp {
+        match self {
+            ServerHello::V0(o) => o.now,
+        }
+    }
 }
 
 /// Content of ClientAuthV0
@@ -1684,6 +2748,73 @@ impl RepoLink {
             RepoLink::V0(o) => o.peers.clone(),
         }
     }
+
+    /// Deserialize and validate a link.
+    /// Unknown versions and truncated keys or secrets fail with SerializationError
+    pub fn from_bytes(bytes: &[u8]) -> Result<RepoLink, LofireError> {
+        let link = serde_bare::from_slice::<RepoLink>(bytes)?;
+        link.validate()?;
+        Ok(link)
+    }
+
+    /// Check that the repository key is well-formed,
+    /// and that the advert of each peer is signed by the peer
+    pub fn validate(&self) -> Result<(), LofireError> {
+        match self {
+            RepoLink::V0(o) => {
+                check_pubkey(o.id)?;
+                for peer in o.peers.iter() {
+                    let content = serde_bare::to_vec(peer.content())?;
+                    verify(&content, peer.sig(), *peer.peer())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Start building a link to the repository, with the peers added to the builder
+    pub fn builder(id: PubKey, secret: SymKey) -> RepoLinkBuilder {
+        RepoLinkBuilder {
+            id,
+            secret,
+            peers: vec![],
+        }
+    }
+}
+
+/// Builder of a `RepoLink`, keeping one verified advert per peer
+pub struct RepoLinkBuilder {
+    id: PubKey,
+    secret: SymKey,
+    peers: Vec<PeerAdvert>,
+}
+
+impl RepoLinkBuilder {
+    /// Add the advert of a peer, once checked that it is signed by the peer.
+    /// Of the adverts of a same peer, only the one with the highest version is kept
+    pub fn add_peer(&mut self, advert: PeerAdvert) -> Result<(), LofireError> {
+        let content = serde_bare::to_vec(advert.content())?;
+        verify(&content, advert.sig(), *advert.peer())?;
+        match self.peers.iter_mut().find(|p| p.peer() == advert.peer()) {
+            Some(p) => {
+                if advert.version() > p.version() {
+                    *p = advert;
+                }
+            }
+            None => self.peers.push(advert),
+        }
+        Ok(())
+    }
+
+    /// Build the link, fails with InvalidKey if the repository key is not a curve point
+    pub fn build(self) -> Result<RepoLink, LofireError> {
+        check_pubkey(self.id)?;
+        Ok(RepoLink::V0(RepoLinkV0 {
+            id: self.id,
+            secret: self.secret,
+            peers: self.peers,
+        }))
+    }
 }
 
 /// Link to object(s) or to a branch from a repository
@@ -1704,6 +2835,78 @@ pub enum ObjectLink {
     V0(ObjectLinkV0),
 }
 
+impl ObjectLink {
+    /// Create a link to the given objects of a repository,
+    /// with an `ExtObjectGet` request authenticated with the repository keys
+    pub fn new(
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        objects: Vec<ObjectRef>,
+        include_children: bool,
+        expiry: Option<Timestamp>,
+    ) -> Result<ObjectLink, LofireError> {
+        let content = ExtRequestContentV0::ExtObjectGet(ExtObjectGet::V0(ExtObjectGetV0 {
+            repo: repo_pubkey,
+            ids: objects.iter().map(|r| r.id).collect(),
+            include_children,
+            expiry,
+        }));
+        Ok(ObjectLink::V0(ObjectLinkV0 {
+            req: ExtRequest::new(0, content, repo_pubkey, repo_secret)?,
+            keys: objects,
+        }))
+    }
+    pub fn req(&self) -> &ExtRequest {
+        match self {
+            ObjectLink::V0(o) => &o.req,
+        }
+    }
+    pub fn keys(&self) -> &Vec<ObjectRef> {
+        match self {
+            ObjectLink::V0(o) => &o.keys,
+        }
+    }
+
+    /// Check the structure of the link without fetching the objects.
+    /// See `validate_at`
+    pub fn validate(&self) -> Result<(), LofireError> {
+        self.validate_at(&SystemClock)
+    }
+
+    /// Check that the IDs requested by an `ExtObjectGet` correspond to the keys,
+    /// that the request has a MAC, and that the link has not expired at the time of the clock.
+    /// The MAC itself can only be verified with the repository secret.
+    pub fn validate_at(&self, clock: &impl Clock) -> Result<(), LofireError> {
+        match self {
+            ObjectLink::V0(o) => match &o.req {
+                ExtRequest::V0(req) => {
+                    if req.mac == Digest::Blake3Digest32([0; 32]) {
+                        return Err(LofireError::InvalidSignature);
+                    }
+                    match &req.content {
+                        ExtRequestContentV0::ExtObjectGet(ExtObjectGet::V0(get)) => {
+                            if get.ids.is_empty()
+                                || get.ids.len() != o.keys.len()
+                                || get.ids.iter().zip(o.keys.iter()).any(|(id, r)| *id != r.id)
+                            {
+                                return Err(LofireError::InvalidKey);
+                            }
+                            match get.expiry {
+                                Some(expiry) if expiry.is_past(clock.now()) => {
+                                    Err(LofireError::InvalidTimestamp)
+                                }
+                                _ => Ok(()),
+                            }
+                        }
+                        ExtRequestContentV0::ExtBranchHeadsReq(_)
+                        | ExtRequestContentV0::ExtBranchSyncReq(_) => Ok(()),
+                    }
+                }
+            },
+        }
+    }
+}
+
 /// Owned repository with private key
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RepoKeysV0 {
@@ -1722,3 +2925,297 @@ pub struct RepoKeysV0 {
 pub enum RepoKeys {
     V0(RepoKeysV0),
 }
+
+#[cfg(test)]
+mod test {
+    use crate::errors::*;
+    use crate::types::*;
+    use lofire::errors::LofireError;
+    use lofire::utils::*;
+
+    #[test]
+    pub fn test_try_accessors() {
+        let event = Event::V0(EventV0 {
+            content: EventContentV0 {
+                topic: PubKey::Ed25519PubKey([1; 32]),
+                publisher: [2; 32],
+                seq: 1,
+                body: EventBodyV0::Change,
+            },
+            sig: Sig::Ed25519Sig([[0; 32], [0; 32]]),
+        });
+        let omsg = BrokerOverlayMessage::V0(BrokerOverlayMessageV0 {
+            overlay: Digest::Blake3Digest32([3; 32]),
+            content: BrokerOverlayMessageContentV0::Event(event),
+        });
+
+        assert_eq!(omsg.try_block().err(), Some(ProtocolError::InvalidState));
+        assert_eq!(omsg.try_id().err(), Some(ProtocolError::InvalidState));
+        assert_eq!(omsg.try_result().err(), Some(ProtocolError::InvalidState));
+        assert_eq!(
+            omsg.try_object_id().err(),
+            Some(ProtocolError::InvalidState)
+        );
+        assert!(omsg.try_overlay_request().is_err());
+
+        let msg = BrokerMessage::V0(BrokerMessageV0 {
+            padding: vec![],
+            content: BrokerMessageContentV0::BrokerOverlayMessage(omsg),
+        });
+        assert_eq!(msg.try_id().err(), Some(ProtocolError::InvalidState));
+        assert_eq!(
+            msg.try_response_block().err(),
+            Some(ProtocolError::InvalidState)
+        );
+        assert_eq!(
+            BrokerMessage::Close.try_id().err(),
+            Some(ProtocolError::Closing)
+        );
+    }
+
+    #[test]
+    pub fn test_object_link() {
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let repo_secret = SymKey::ChaCha20Key([2; 32]);
+        let obj = ObjectRef {
+            id: Digest::Blake3Digest32([3; 32]),
+            key: SymKey::ChaCha20Key([4; 32]),
+        };
+        let link = ObjectLink::new(repo_pubkey, repo_secret, vec![obj], true, None).unwrap();
+        assert_eq!(link.keys().len(), 1);
+        let other = ObjectLink::new(
+            repo_pubkey,
+            SymKey::ChaCha20Key([5; 32]),
+            vec![obj],
+            true,
+            None,
+        )
+        .unwrap();
+        match (link.req(), other.req()) {
+            (ExtRequest::V0(req), ExtRequest::V0(other_req)) => {
+                match &req.content {
+                    ExtRequestContentV0::ExtObjectGet(ExtObjectGet::V0(get)) => {
+                        assert_eq!(get.repo, repo_pubkey);
+                        assert_eq!(get.ids, vec![obj.id]);
+                    }
+                    _ => panic!("unexpected request"),
+                }
+                // the MAC depends on the repository secret
+                assert_ne!(req.mac, other_req.mac);
+            }
+        }
+        assert!(link.req().verify_mac(repo_pubkey, repo_secret).is_ok());
+        assert!(other.req().verify_mac(repo_pubkey, repo_secret).is_err());
+    }
+
+    #[test]
+    pub fn test_object_link_validate() {
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let repo_secret = SymKey::ChaCha20Key([2; 32]);
+        let obj = ObjectRef {
+            id: Digest::Blake3Digest32([3; 32]),
+            key: SymKey::ChaCha20Key([4; 32]),
+        };
+        let other_obj = ObjectRef {
+            id: Digest::Blake3Digest32([5; 32]),
+            key: SymKey::ChaCha20Key([6; 32]),
+        };
+        let clock = MockClock::new(Timestamp::from_minutes(100));
+        let expiry = Timestamp::from_minutes(200);
+
+        let link =
+            ObjectLink::new(repo_pubkey, repo_secret, vec![obj], true, Some(expiry)).unwrap();
+        assert!(link.validate_at(&clock).is_ok());
+        assert!(
+            ObjectLink::new(repo_pubkey, repo_secret, vec![obj], true, None)
+                .unwrap()
+                .validate()
+                .is_ok()
+        );
+
+        // keys do not correspond to the requested ids
+        let mismatched = match link.clone() {
+            ObjectLink::V0(o) => ObjectLink::V0(ObjectLinkV0 {
+                req: o.req,
+                keys: vec![other_obj],
+            }),
+        };
+        assert!(matches!(
+            mismatched.validate_at(&clock),
+            Err(LofireError::InvalidKey)
+        ));
+        let missing = match link.clone() {
+            ObjectLink::V0(o) => ObjectLink::V0(ObjectLinkV0 {
+                req: o.req,
+                keys: vec![obj, other_obj],
+            }),
+        };
+        assert!(matches!(
+            missing.validate_at(&clock),
+            Err(LofireError::InvalidKey)
+        ));
+
+        // expired link
+        clock.set(expiry);
+        assert!(matches!(
+            link.validate_at(&clock),
+            Err(LofireError::InvalidTimestamp)
+        ));
+    }
+
+    #[test]
+    pub fn test_repo_link_validate() {
+        let (_, repo_pubkey) = generate_keypair();
+        let (peer_privkey, peer_pubkey) = generate_keypair();
+        let content = PeerAdvertContentV0 {
+            peer: peer_pubkey,
+            subs: [[0; 32]; 4],
+            address: vec![],
+            version: 1,
+            metadata: vec![],
+        };
+        let sig = sign(
+            peer_privkey,
+            peer_pubkey,
+            &serde_bare::to_vec(&content).unwrap(),
+        )
+        .unwrap();
+        let advert = PeerAdvert::V0(PeerAdvertV0 {
+            content: content.clone(),
+            sig,
+            ttl: 1,
+        });
+        let link = |id: PubKey, peers: Vec<PeerAdvert>| {
+            RepoLink::V0(RepoLinkV0 {
+                id,
+                secret: SymKey::ChaCha20Key([4; 32]),
+                peers,
+            })
+        };
+
+        // valid link
+        let valid = link(repo_pubkey, vec![advert.clone()]);
+        assert!(valid.validate().is_ok());
+        let bytes = serde_bare::to_vec(&valid).unwrap();
+        assert!(RepoLink::from_bytes(&bytes).is_ok());
+
+        // repository key is not a curve point
+        let mut bad_key = [0u8; 32];
+        bad_key[0] = 2;
+        assert!(matches!(
+            link(PubKey::Ed25519PubKey(bad_key), vec![]).validate(),
+            Err(LofireError::InvalidKey)
+        ));
+
+        // advert not signed by the advertised peer
+        let (_, other_pubkey) = generate_keypair();
+        let forged = PeerAdvert::V0(PeerAdvertV0 {
+            content: PeerAdvertContentV0 {
+                peer: other_pubkey,
+                ..content.clone()
+            },
+            sig,
+            ttl: 1,
+        });
+        assert!(matches!(
+            link(repo_pubkey, vec![advert.clone(), forged]).validate(),
+            Err(LofireError::InvalidSignature)
+        ));
+
+        // tampered advert
+        let tampered = PeerAdvert::V0(PeerAdvertV0 {
+            content: PeerAdvertContentV0 {
+                version: 2,
+                ..content.clone()
+            },
+            sig,
+            ttl: 1,
+        });
+        assert!(matches!(
+            link(repo_pubkey, vec![tampered]).validate(),
+            Err(LofireError::InvalidSignature)
+        ));
+
+        // unknown version
+        let mut unknown = bytes.clone();
+        unknown[0] = 1;
+        assert!(matches!(
+            RepoLink::from_bytes(&unknown),
+            Err(LofireError::SerializationError)
+        ));
+
+        // truncated secret: version, key, then half of the secret
+        let truncated = &bytes[..1 + 33 + 17];
+        assert!(matches!(
+            RepoLink::from_bytes(truncated),
+            Err(LofireError::SerializationError)
+        ));
+    }
+
+    #[test]
+    pub fn test_repo_link_builder() {
+        let (_, repo_pubkey) = generate_keypair();
+        let (peer1_privkey, peer1_pubkey) = generate_keypair();
+        let (peer2_privkey, peer2_pubkey) = generate_keypair();
+        let advert = |privkey, pubkey, version| {
+            let content = PeerAdvertContentV0 {
+                peer: pubkey,
+                subs: [[0; 32]; 4],
+                address: vec![],
+                version,
+                metadata: vec![],
+            };
+            let sig = sign(privkey, pubkey, &serde_bare::to_vec(&content).unwrap()).unwrap();
+            PeerAdvert::V0(PeerAdvertV0 {
+                content,
+                sig,
+                ttl: 1,
+            })
+        };
+
+        let mut builder = RepoLink::builder(repo_pubkey, SymKey::ChaCha20Key([4; 32]));
+        builder
+            .add_peer(advert(peer1_privkey, peer1_pubkey, 1))
+            .ok()
+            .unwrap();
+        builder
+            .add_peer(advert(peer1_privkey, peer1_pubkey, 3))
+            .ok()
+            .unwrap();
+        // older and duplicate adverts are dropped
+        builder
+            .add_peer(advert(peer1_privkey, peer1_pubkey, 2))
+            .ok()
+            .unwrap();
+        builder
+            .add_peer(advert(peer1_privkey, peer1_pubkey, 3))
+            .ok()
+            .unwrap();
+        builder
+            .add_peer(advert(peer2_privkey, peer2_pubkey, 1))
+            .ok()
+            .unwrap();
+        // advert of peer2 signed by peer1
+        assert!(matches!(
+            builder.add_peer(advert(peer1_privkey, peer2_pubkey, 5)),
+            Err(LofireError::InvalidSignature)
+        ));
+
+        let link = builder.build().ok().unwrap();
+        assert!(link.validate().is_ok());
+        let peers: Vec<(PeerId, u32)> = link
+            .peers()
+            .iter()
+            .map(|p| (*p.peer(), p.version()))
+            .collect();
+        assert_eq!(peers, vec![(peer1_pubkey, 3), (peer2_pubkey, 1)]);
+
+        // repository key is not a curve point
+        let mut bad_key = [0u8; 32];
+        bad_key[0] = 2;
+        assert!(matches!(
+            RepoLink::builder(PubKey::Ed25519PubKey(bad_key), SymKey::ChaCha20Key([4; 32])).build(),
+            Err(LofireError::InvalidKey)
+        ));
+    }
+}
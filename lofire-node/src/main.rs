@@ -1,12 +1,18 @@
 use async_std::net::{TcpListener, TcpStream};
 use async_std::sync::Mutex;
 use async_std::task;
-use async_tungstenite::accept_async;
-use async_tungstenite::tungstenite::protocol::Message;
+use async_tungstenite::accept_async_with_config;
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
 use debug_print::*;
 use futures::{SinkExt, StreamExt};
+use lofire::types::PubKey;
+use lofire_broker::codec::*;
 use lofire_broker::config::ConfigMode;
+use lofire_broker::ipfilter::IpFilter;
+use lofire_broker::quic;
 use lofire_broker::server::*;
+use lofire_broker::tcp;
+use lofire_net::errors::ProtocolError;
 use lofire_store_lmdb::brokerstore::LmdbBrokerStore;
 use lofire_store_lmdb::repostore::LmdbRepoStore;
 use std::fs;
@@ -14,93 +20,127 @@ use std::sync::Arc;
 use tempfile::Builder;
 use std::{thread, time};
 
+/// Peer IP ranges allowed to connect, in CIDR notation. Empty allows all the peers not denied
+const IP_ALLOWLIST: &[&str] = &[];
 
-async fn connection_loop(tcp: TcpStream, mut handler: ProtocolHandler) -> std::io::Result<()> {
-    let mut ws = accept_async(tcp).await.unwrap();
-    let (mut tx, mut rx) = ws.split();
+/// Peer IP ranges refused, even when in the allowlist
+const IP_DENYLIST: &[&str] = &[];
 
-    let mut tx_mutex = Arc::new(Mutex::new(tx));
+/// Ed25519 public keys of the admins, allowed to sign AddUser and DelUser.
+/// Without any, the broker refuses to add or delete users
+const ADMINS: &[[u8; 32]] = &[];
 
-    // setup the async frames task
-    let receiver = handler.async_frames_receiver();
-    let ws_in_task = Arc::clone(&tx_mutex);
-    task::spawn(async move {
-        while let Ok(frame) = receiver.recv().await {
-            let mut sink = ws_in_task
-            .lock()
-            .await;
-            if sink.send(Message::binary(frame))
-                .await
-                .is_err()
-            {
-                break;
-            }
-        }
-        debug_println!("end of async frames loop");
+/// Maximum number of requests accessing the stores at the same time, over all the connections
+const STORE_CONCURRENCY: usize = DEFAULT_STORE_CONCURRENCY;
 
-        let mut sink = ws_in_task.lock().await;
-        let _ = sink.send(Message::Close(None)).await;
-        let _ = sink.close().await;
-    });
+/// Transport of the connections accepted by a listener
+#[derive(Clone, Copy, Debug)]
+enum ListenerType {
+    /// WebSocket, for clients
+    WebSocket,
+    /// Length-prefixed frames over raw TCP, for other brokers
+    RawTcp,
+}
 
-    while let Some(msg) = rx.next().await {
-        //debug_println!("RCV: {:?}", msg);
-        let msg = match msg {
-            Err(e) => {
-                debug_println!("Error on server stream: {:?}", e);
-                // Errors returned directly through the AsyncRead/Write API are fatal, generally an error on the underlying
-                // transport. closing connection
-                break;
-            }
-            Ok(m) => m,
-        };
-        //TODO implement PING messages
-        if msg.is_close() {
-            debug_println!("CLOSE from CLIENT");
-            break;
-        } else if msg.is_binary() {
-            //debug_println!("server received binary: {:?}", msg);
+async fn websocket_connection(tcp: TcpStream, handler: ProtocolHandler) -> std::io::Result<()> {
+    // messages are bounded like raw TCP frames, before they are buffered
+    let config = WebSocketConfig {
+        max_message_size: Some(tcp::DEFAULT_MAX_FRAME_SIZE),
+        max_frame_size: Some(tcp::DEFAULT_MAX_FRAME_SIZE),
+        ..Default::default()
+    };
+    let ws = accept_async_with_config(tcp, Some(config))
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let (tx, rx) = ws.split();
+    connection_loop(WebSocketCodec, tx, rx, handler).await
+}
 
-            let replies = handler.handle_incoming(msg.into_data()).await;
+async fn raw_tcp_connection(tcp: TcpStream, handler: ProtocolHandler) -> std::io::Result<()> {
+    let (tx, rx) = tcp::split(tcp, tcp::DEFAULT_MAX_FRAME_SIZE);
+    let rx = Box::pin(rx.map(|frame| Ok::<_, ProtocolError>(frame)));
+    connection_loop(RawFrameCodec, tx, rx, handler).await
+}
 
-            match replies.0 {
-                Err(e) => {
-                    debug_println!("Protocol Error: {:?}", e);
-                    // dealing with ProtocolErrors (closing the connection)
-                    break;
-                }
-                Ok(r) => {
-                    if tx_mutex
-                        .lock()
-                        .await
-                        .send(Message::binary(r))
-                        .await
-                        .is_err()
-                    {
-                        //dealing with sending errors (closing the connection)
-                        break;
-                    }
-                }
+async fn listen(
+    server: Arc<BrokerServer>,
+    addr: &str,
+    listener_type: ListenerType,
+) -> std::io::Result<()> {
+    let socket = TcpListener::bind(addr).await?;
+    println!("Listening on {} ({:?})", addr, listener_type);
+    let mut connections = socket.incoming();
+    while let Some(tcp) = connections.next().await {
+        let tcp = tcp?;
+        // refused peers are disconnected right away, by dropping the stream
+        match tcp.peer_addr() {
+            Ok(peer) if server.is_peer_allowed(&peer.ip()) => {}
+            Ok(peer) => {
+                debug_println!("refused connection from {}", peer);
+                continue;
             }
-            match replies.1.await {
-                Some(errcode) => {
-                    if errcode > 0 {
-                        debug_println!("Close due to error code : {:?}", errcode);
-                        //closing connection
-                        break;
-                    }
+            Err(_) => continue,
+        }
+        let proto_handler = Arc::clone(&server).protocol_handler();
+        let _handle = match listener_type {
+            ListenerType::WebSocket => task::spawn(websocket_connection(tcp, proto_handler)),
+            ListenerType::RawTcp => task::spawn(raw_tcp_connection(tcp, proto_handler)),
+        };
+    }
+    Ok(())
+}
+
+/// Accept QUIC connections on `addr`, with a new self-signed certificate for localhost.
+/// The certificate is written to `cert_path`, for the clients to trust
+async fn listen_quic(
+    server: Arc<BrokerServer>,
+    addr: std::net::SocketAddr,
+    cert_path: std::path::PathBuf,
+) -> std::io::Result<()> {
+    let (config, cert) = quic::self_signed(vec!["localhost".into()])
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.as_str()))?;
+    fs::write(&cert_path, cert)?;
+    let endpoint = quic::listen(addr, config)?;
+    println!(
+        "Listening on {} (QUIC), certificate in {}",
+        addr,
+        cert_path.to_str().unwrap()
+    );
+    while let Some(connecting) = endpoint.accept().await {
+        if !server.is_peer_allowed(&connecting.remote_address().ip()) {
+            debug_println!("refused connection from {}", connecting.remote_address());
+            continue;
+        }
+        let proto_handler = Arc::clone(&server).protocol_handler();
+        task::spawn(async move {
+            match quic::accept(connecting, tcp::DEFAULT_MAX_FRAME_SIZE).await {
+                Ok((tx, rx)) => {
+                    let rx = Box::pin(rx.map(|frame| Ok::<_, ProtocolError>(frame)));
+                    let _ = connection_loop(RawFrameCodec, tx, rx, proto_handler).await;
                 }
-                None => {}
+                Err(e) => debug_println!("QUIC connection failed: {}", e.as_str()),
             }
-        }
+        });
     }
-    let mut sink = tx_mutex.lock().await;
-    let _ = sink.send(Message::Close(None)).await;
-    let _ = sink.close().await;
-    debug_println!("end of sync read+write loop");
     Ok(())
 }
 
+/// Address of the QUIC listener, given with `--quic <addr>`. Without it, only TCP is served
+fn quic_addr() -> Option<std::net::SocketAddr> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--quic" {
+            return Some(
+                args.next()
+                    .expect("missing address after --quic")
+                    .parse()
+                    .expect("invalid QUIC address"),
+            );
+        }
+    }
+    None
+}
+
 async fn run_server() -> std::io::Result<()> {
     let root = tempfile::Builder::new()
         .prefix("node-daemon")
@@ -111,17 +151,40 @@ async fn run_server() -> std::io::Result<()> {
     println!("{}", root.path().to_str().unwrap());
     let store = LmdbBrokerStore::open(root.path(), master_key);
 
-    let server: BrokerServer =
+    let mut server: BrokerServer =
         BrokerServer::new(store, ConfigMode::Local).expect("starting broker");
+    server.set_ip_filter(IpFilter::parse(IP_ALLOWLIST, IP_DENYLIST).expect("invalid IP range"));
+    server.set_store_concurrency(Some(STORE_CONCURRENCY));
+    for admin in ADMINS {
+        server
+            .add_admin(PubKey::Ed25519PubKey(*admin))
+            .expect("adding admin");
+    }
 
-    let socket = TcpListener::bind("127.0.0.1:3012").await?;
-    let mut connections = socket.incoming();
     let server_arc = Arc::new(server);
-    while let Some(tcp) = connections.next().await {
-        let proto_handler = Arc::clone(&server_arc).protocol_handler();
-        let _handle = task::spawn(connection_loop(tcp.unwrap(), proto_handler));
+    if let Some(addr) = quic_addr() {
+        task::spawn(listen_quic(
+            Arc::clone(&server_arc),
+            addr,
+            root.path().join("quic-cert.der"),
+        ));
     }
-    Ok(())
+    task::spawn(listen(
+        Arc::clone(&server_arc),
+        "127.0.0.1:3013",
+        ListenerType::RawTcp,
+    ));
+    let res = listen(
+        Arc::clone(&server_arc),
+        "127.0.0.1:3012",
+        ListenerType::WebSocket,
+    )
+    .await;
+    // the listener is gone, make sure everything written so far is on disk
+    if let Err(e) = server_arc.flush() {
+        println!("flushing the stores failed: {}", e.as_str());
+    }
+    res
 }
 
 #[async_std::main]
@@ -3,7 +3,13 @@ use lofire::store::*;
 use lofire::types::*;
 use lofire::utils::*;
 
+use crate::durability::*;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
 use debug_print::*;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
@@ -19,6 +25,16 @@ use rkv::{
 use serde::{Deserialize, Serialize};
 use serde_bare::error::Error;
 
+/// Size of the random nonce stored before each encrypted value
+const VALUE_NONCE_SIZE: usize = 12;
+/// Size of the MAC stored after each encrypted value
+const VALUE_MAC_SIZE: usize = blake3::OUT_LEN;
+/// Version of the format of the values, stored before each of them
+/// and in the format store when the store is created
+const VALUE_FORMAT_VERSION: u8 = 1;
+/// Key of the format version in the format store
+const FORMAT_KEY: &str = "values";
+
 pub struct LmdbBrokerStore {
     /// the main store where all the properties of keys are stored
     main_store: MultiStore<LmdbDatabase>,
@@ -26,6 +42,10 @@ pub struct LmdbBrokerStore {
     environment: Arc<RwLock<Rkv<LmdbEnvironment>>>,
     /// path for the storage backend data
     path: String,
+    /// key encrypting the values, derived from the master key
+    value_key: [u8; 32],
+    /// key authenticating the encrypted values
+    mac_key: [u8; 32],
 }
 
 impl BrokerStore for LmdbBrokerStore {
@@ -39,7 +59,7 @@ impl BrokerStore for LmdbBrokerStore {
             .get(&reader, property)
             .map_err(|e| StorageError::BackendError)?;
         match iter.next() {
-            Some(Ok(val)) => Ok(val.1.to_bytes().unwrap()),
+            Some(Ok(val)) => self.decrypt_value(&property, &val.1.to_bytes().unwrap()),
             Some(Err(_e)) => Err(StorageError::BackendError),
             None => Err(StorageError::NotFound),
         }
@@ -57,12 +77,12 @@ impl BrokerStore for LmdbBrokerStore {
         let reader = lock.read().unwrap();
         let mut iter = self
             .main_store
-            .get(&reader, property)
+            .get(&reader, property.clone())
             .map_err(|e| StorageError::BackendError)?;
         let mut vector: Vec<Vec<u8>> = vec![];
         while let res = iter.next() {
             vector.push(match res {
-                Some(Ok(val)) => val.1.to_bytes().unwrap(),
+                Some(Ok(val)) => self.decrypt_value(&property, &val.1.to_bytes().unwrap())?,
                 Some(Err(_e)) => return Err(StorageError::BackendError),
                 None => {
                     break;
@@ -83,14 +103,13 @@ impl BrokerStore for LmdbBrokerStore {
         let property = Self::compute_property(prefix, key, suffix);
         let lock = self.environment.read().unwrap();
         let reader = lock.read().unwrap();
-        let exists = self
+        let iter = self
             .main_store
-            .get_key_value(&reader, property, &Value::Blob(value.as_slice()))
+            .get(&reader, property.clone())
             .map_err(|e| StorageError::BackendError)?;
-        if exists {
-            Ok(())
-        } else {
-            Err(StorageError::NotFound)
+        match self.find_encrypted_value(&property, iter, &value)? {
+            Some(_) => Ok(()),
+            None => Err(StorageError::NotFound),
         }
     }
 
@@ -102,12 +121,9 @@ impl BrokerStore for LmdbBrokerStore {
         suffix: Option<u8>,
         value: Vec<u8>,
     ) -> Result<(), StorageError> {
-        let property = Self::compute_property(prefix, key, suffix);
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
-        self.main_store
-            .put(&mut writer, property, &Value::Blob(value.as_slice()))
-            .map_err(|e| StorageError::BackendError)?;
+        self.put_in(&mut writer, prefix, key, suffix, value)?;
 
         writer.commit().unwrap();
 
@@ -122,16 +138,9 @@ impl BrokerStore for LmdbBrokerStore {
         suffix: Option<u8>,
         value: Vec<u8>,
     ) -> Result<(), StorageError> {
-        let property = Self::compute_property(prefix, key, suffix);
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
-        self.main_store
-            .delete_all(&mut writer, property.clone())
-            .map_err(|e| StorageError::BackendError)?;
-
-        self.main_store
-            .put(&mut writer, property, &Value::Blob(value.as_slice()))
-            .map_err(|e| StorageError::BackendError)?;
+        self.replace_in(&mut writer, prefix, key, suffix, value)?;
 
         writer.commit().unwrap();
 
@@ -140,12 +149,9 @@ impl BrokerStore for LmdbBrokerStore {
 
     /// Delete a property from the store.
     fn del(&self, prefix: u8, key: &Vec<u8>, suffix: Option<u8>) -> Result<(), StorageError> {
-        let property = Self::compute_property(prefix, key, suffix);
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
-        self.main_store
-            .delete_all(&mut writer, property)
-            .map_err(|e| StorageError::BackendError)?;
+        self.del_in(&mut writer, prefix, key, suffix)?;
 
         writer.commit().unwrap();
 
@@ -160,12 +166,9 @@ impl BrokerStore for LmdbBrokerStore {
         suffix: Option<u8>,
         value: Vec<u8>,
     ) -> Result<(), StorageError> {
-        let property = Self::compute_property(prefix, key, suffix);
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
-        self.main_store
-            .delete(&mut writer, property, &Value::Blob(value.as_slice()))
-            .map_err(|e| StorageError::BackendError)?;
+        self.del_property_value_in(&mut writer, prefix, key, suffix, value)?;
 
         writer.commit().unwrap();
 
@@ -182,6 +185,152 @@ impl BrokerStore for LmdbBrokerStore {
         }
         Ok(())
     }
+
+    /// Run several writes in a single LMDB write transaction, aborted if the closure fails
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&mut dyn BrokerStoreTransaction) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        let lock = self.environment.read().unwrap();
+        let writer = lock.write().map_err(|_e| StorageError::BackendError)?;
+        let mut tx = LmdbBrokerStoreTransaction {
+            store: self,
+            writer,
+        };
+        f(&mut tx)?;
+        tx.writer.commit().map_err(|_e| StorageError::BackendError)
+    }
+}
+
+/// Write transaction of a `LmdbBrokerStore`, dropped without commit if it fails
+struct LmdbBrokerStoreTransaction<'s, 'e> {
+    store: &'s LmdbBrokerStore,
+    writer: Writer<LmdbRwTransaction<'e>>,
+}
+
+impl<'s, 'e> BrokerStoreTransaction for LmdbBrokerStoreTransaction<'s, 'e> {
+    fn put(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        self.store
+            .put_in(&mut self.writer, prefix, key, suffix, value)
+    }
+
+    fn replace(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        self.store
+            .replace_in(&mut self.writer, prefix, key, suffix, value)
+    }
+
+    fn del(&mut self, prefix: u8, key: &Vec<u8>, suffix: Option<u8>) -> Result<(), StorageError> {
+        self.store.del_in(&mut self.writer, prefix, key, suffix)
+    }
+
+    fn del_property_value(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        self.store
+            .del_property_value_in(&mut self.writer, prefix, key, suffix, value)
+    }
+}
+
+impl LmdbBrokerStore {
+    /// Save a property value in a write transaction.
+    /// The same value is only stored once, even though its encryptions differ
+    fn put_in(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        let property = Self::compute_property(prefix, key, suffix);
+        let iter = self
+            .main_store
+            .get(&*writer, property.clone())
+            .map_err(|e| StorageError::BackendError)?;
+        if self
+            .find_encrypted_value(&property, iter, &value)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let encrypted = self.encrypt_value(&property, &value);
+        self.main_store
+            .put(writer, property, &Value::Blob(encrypted.as_slice()))
+            .map_err(|e| StorageError::BackendError)
+    }
+
+    /// Replace the property of a key (single value) in a write transaction
+    fn replace_in(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        let property = Self::compute_property(prefix, key, suffix);
+        self.main_store
+            .delete_all(writer, property.clone())
+            .map_err(|e| StorageError::BackendError)?;
+
+        let encrypted = self.encrypt_value(&property, &value);
+        self.main_store
+            .put(writer, property, &Value::Blob(encrypted.as_slice()))
+            .map_err(|e| StorageError::BackendError)
+    }
+
+    /// Delete a property in a write transaction
+    fn del_in(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+    ) -> Result<(), StorageError> {
+        let property = Self::compute_property(prefix, key, suffix);
+        self.main_store
+            .delete_all(writer, property)
+            .map_err(|e| StorageError::BackendError)
+    }
+
+    /// Delete a specific value for a property in a write transaction
+    fn del_property_value_in(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        let property = Self::compute_property(prefix, key, suffix);
+        let iter = self
+            .main_store
+            .get(&*writer, property.clone())
+            .map_err(|e| StorageError::BackendError)?;
+        let encrypted = match self.find_encrypted_value(&property, iter, &value)? {
+            Some(encrypted) => encrypted,
+            None => return Err(StorageError::NotFound),
+        };
+        self.main_store
+            .delete(writer, property, &Value::Blob(encrypted.as_slice()))
+            .map_err(|e| StorageError::BackendError)
+    }
 }
 
 impl LmdbBrokerStore {
@@ -189,6 +338,15 @@ impl LmdbBrokerStore {
         PathBuf::from(&self.path)
     }
 
+    /// Forces the writes committed so far to disk, whatever the durability of the store
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.environment
+            .read()
+            .unwrap()
+            .sync(true)
+            .map_err(|_e| StorageError::BackendError)
+    }
+
     fn compute_property(prefix: u8, key: &Vec<u8>, suffix: Option<u8>) -> Vec<u8> {
         let mut new: Vec<u8> = Vec::with_capacity(key.len() + 2);
         new.push(prefix);
@@ -199,26 +357,295 @@ impl LmdbBrokerStore {
         new
     }
 
+    /// MAC of an encrypted value, bound to the property it is stored in,
+    /// so that a value moved to another property fails its integrity check
+    fn value_mac(&self, property: &[u8], content: &[u8]) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.mac_key);
+        hasher.update(&(property.len() as u64).to_le_bytes());
+        hasher.update(property);
+        hasher.update(content);
+        hasher.finalize()
+    }
+
+    /// Encrypts the value of a property with a random nonce.
+    /// The version of the format and the nonce are stored before the ciphertext,
+    /// followed by the MAC of the property, the version, the nonce and the ciphertext
+    fn encrypt_value(&self, property: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; VALUE_NONCE_SIZE];
+        OsRng {}.fill_bytes(&mut nonce);
+        let mut encrypted = Vec::with_capacity(1 + VALUE_NONCE_SIZE + value.len() + VALUE_MAC_SIZE);
+        encrypted.push(VALUE_FORMAT_VERSION);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(value);
+        let mut cipher = ChaCha20::new((&self.value_key).into(), &nonce.into());
+        cipher.apply_keystream(&mut encrypted[1 + VALUE_NONCE_SIZE..]);
+        let mac = self.value_mac(property, &encrypted);
+        encrypted.extend_from_slice(mac.as_bytes());
+        encrypted
+    }
+
+    /// Checks the version and the MAC of the encrypted value of a property, and decrypts it
+    fn decrypt_value(&self, property: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, StorageError> {
+        if encrypted.len() < 1 + VALUE_NONCE_SIZE + VALUE_MAC_SIZE
+            || encrypted[0] != VALUE_FORMAT_VERSION
+        {
+            return Err(StorageError::DecryptionError);
+        }
+        let (content, mac) = encrypted.split_at(encrypted.len() - VALUE_MAC_SIZE);
+        // constant time comparison
+        if self.value_mac(property, content) != *mac {
+            return Err(StorageError::DecryptionError);
+        }
+        let (nonce, ciphertext) = content[1..].split_at(VALUE_NONCE_SIZE);
+        let nonce: [u8; VALUE_NONCE_SIZE] = nonce.try_into().unwrap();
+        let mut value = ciphertext.to_vec();
+        let mut cipher = ChaCha20::new((&self.value_key).into(), &nonce.into());
+        cipher.apply_keystream(&mut value);
+        Ok(value)
+    }
+
+    /// Finds the encryption of a value among the values of a property, as stored
+    fn find_encrypted_value<'i>(
+        &self,
+        property: &[u8],
+        mut iter: impl Iterator<Item = Result<(&'i [u8], Value<'i>), StoreError>>,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        while let Some(res) = iter.next() {
+            let encrypted = res
+                .map_err(|_e| StorageError::BackendError)?
+                .1
+                .to_bytes()
+                .map_err(|_e| StorageError::BackendError)?;
+            if self.decrypt_value(property, &encrypted)? == value {
+                return Ok(Some(encrypted));
+            }
+        }
+        Ok(None)
+    }
+
     /// Opens the store and returns a BrokerStore object that should be kept and used to manipulate Accounts, Overlays, Topics and options
     /// The key is the encryption key for the data at rest.
+    /// Each value is encrypted with a key derived from it, so that reading with another key fails with a DecryptionError
     pub fn open<'a>(path: &Path, key: [u8; 32]) -> LmdbBrokerStore {
+        Self::open_with_durability(path, key, Durability::SyncOnCommit)
+    }
+
+    /// Opens the store with the given durability of the writes.
+    /// An environment already opened in the process keeps the durability it was first opened with.
+    /// Panics if the store was written in an incompatible format, see `try_open_with_durability`
+    pub fn open_with_durability(
+        path: &Path,
+        key: [u8; 32],
+        durability: Durability,
+    ) -> LmdbBrokerStore {
+        Self::try_open_with_durability(path, key, durability).unwrap_or_else(|e| {
+            panic!(
+                "cannot open the broker store at {}: {:?}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    /// Opens the store with the given durability of the writes.
+    /// Fails with IncompatibleFormat if the store was written before its values were versioned:
+    /// their format can't be told apart from plaintext, so they are refused instead of migrated
+    pub fn try_open_with_durability(
+        path: &Path,
+        key: [u8; 32],
+        durability: Durability,
+    ) -> Result<LmdbBrokerStore, StorageError> {
         let mut manager = Manager::<LmdbEnvironment>::singleton().write().unwrap();
         let shared_rkv = manager
-            .get_or_create(path, |path| {
-                //Rkv::new::<Lmdb>(path) // use this instead to disable encryption
-                Rkv::with_encryption_key_and_mapsize::<Lmdb>(path, key, 2 * 1024 * 1024 * 1024)
-            })
+            .get_or_create(path, |path| create_environment(path, key, durability))
             .unwrap();
         let env = shared_rkv.read().unwrap();
 
         println!("created env with LMDB Version: {}", env.version());
 
         let main_store = env.open_multi("main", StoreOptions::create()).unwrap();
+        let format_store = env.open_single("format", StoreOptions::create()).unwrap();
+        Self::check_format(&env, &main_store, &format_store)?;
 
-        LmdbBrokerStore {
+        let value_key = blake3::derive_key("LoFiRe LmdbBrokerStore value key", &key);
+        let mac_key = blake3::derive_key("LoFiRe LmdbBrokerStore value MAC key", &value_key);
+
+        Ok(LmdbBrokerStore {
             environment: shared_rkv.clone(),
             main_store,
             path: path.to_str().unwrap().to_string(),
+            value_key,
+            mac_key,
+        })
+    }
+
+    /// Checks the format version of the store, and records it if the store is new.
+    /// A store with values but without a format version was written by an older version
+    fn check_format(
+        env: &Rkv<LmdbEnvironment>,
+        main_store: &MultiStore<LmdbDatabase>,
+        format_store: &SingleStore<LmdbDatabase>,
+    ) -> Result<(), StorageError> {
+        let mut writer = env.write().map_err(|_e| StorageError::BackendError)?;
+        match format_store
+            .get(&writer, FORMAT_KEY)
+            .map_err(|_e| StorageError::BackendError)?
+        {
+            Some(Value::Blob(version)) if version == [VALUE_FORMAT_VERSION] => return Ok(()),
+            Some(_) => return Err(StorageError::IncompatibleFormat),
+            None => {}
+        }
+        let is_empty = main_store
+            .iter_start(&writer)
+            .map_err(|_e| StorageError::BackendError)?
+            .next()
+            .is_none();
+        if !is_empty {
+            return Err(StorageError::IncompatibleFormat);
+        }
+        format_store
+            .put(
+                &mut writer,
+                FORMAT_KEY,
+                &Value::Blob(&[VALUE_FORMAT_VERSION]),
+            )
+            .map_err(|_e| StorageError::BackendError)?;
+        writer.commit().map_err(|_e| StorageError::BackendError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::brokerstore::LmdbBrokerStore;
+    use crate::durability::*;
+    use lofire::brokerstore::BrokerStore;
+    use lofire::store::*;
+    use rkv::backend::LmdbEnvironment;
+    use rkv::{Manager, StoreOptions, Value};
+    use std::fs;
+    use tempfile::Builder;
+
+    #[test]
+    pub fn test_encrypted_values() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        fs::create_dir_all(root.path()).unwrap();
+        let key: [u8; 32] = [0; 32];
+        let store = LmdbBrokerStore::open(root.path(), key);
+
+        let k = vec![1, 2, 3];
+        let secret = vec![42; 32];
+        store
+            .put(b"o"[0], &k, Some(b"s"[0]), secret.clone())
+            .unwrap();
+        assert_eq!(store.get(b"o"[0], &k, Some(b"s"[0])).unwrap(), secret);
+
+        // values of a multi-valued property are still found and removed by value
+        store.put(b"o"[0], &k, Some(b"m"[0]), vec![1]).unwrap();
+        store.put(b"o"[0], &k, Some(b"m"[0]), vec![2]).unwrap();
+        store.put(b"o"[0], &k, Some(b"m"[0]), vec![1]).unwrap();
+        assert_eq!(store.get_all(b"o"[0], &k, Some(b"m"[0])).unwrap().len(), 2);
+        assert!(store
+            .has_property_value(b"o"[0], &k, Some(b"m"[0]), vec![2])
+            .is_ok());
+        store
+            .del_property_value(b"o"[0], &k, Some(b"m"[0]), vec![2])
+            .unwrap();
+        assert_eq!(
+            store.has_property_value(b"o"[0], &k, Some(b"m"[0]), vec![2]),
+            Err(StorageError::NotFound)
+        );
+        assert_eq!(
+            store.get_all(b"o"[0], &k, Some(b"m"[0])).unwrap(),
+            vec![vec![1]]
+        );
+
+        // with the wrong master key, reads fail instead of returning garbage
+        let wrong = LmdbBrokerStore::open(root.path(), [1; 32]);
+        assert_eq!(
+            wrong.get(b"o"[0], &k, Some(b"s"[0])),
+            Err(StorageError::DecryptionError)
+        );
+        assert_eq!(
+            wrong.get_all(b"o"[0], &k, Some(b"m"[0])),
+            Err(StorageError::DecryptionError)
+        );
+        assert_eq!(
+            wrong.get(b"o"[0], &vec![4], None),
+            Err(StorageError::NotFound)
+        );
+
+        // a value copied to another property fails its integrity check
+        let encrypted = {
+            let lock = store.environment.read().unwrap();
+            let reader = lock.read().unwrap();
+            let mut iter = store
+                .main_store
+                .get(
+                    &reader,
+                    LmdbBrokerStore::compute_property(b"o"[0], &k, Some(b"s"[0])),
+                )
+                .unwrap();
+            iter.next().unwrap().unwrap().1.to_bytes().unwrap()
+        };
+        {
+            let lock = store.environment.read().unwrap();
+            let mut writer = lock.write().unwrap();
+            store
+                .main_store
+                .put(
+                    &mut writer,
+                    LmdbBrokerStore::compute_property(b"o"[0], &k, Some(b"c"[0])),
+                    &Value::Blob(encrypted.as_slice()),
+                )
+                .unwrap();
+            writer.commit().unwrap();
         }
+        assert_eq!(
+            store.get(b"o"[0], &k, Some(b"c"[0])),
+            Err(StorageError::DecryptionError)
+        );
+    }
+
+    #[test]
+    pub fn test_refuse_unversioned_store() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        fs::create_dir_all(root.path()).unwrap();
+        let key: [u8; 32] = [0; 32];
+
+        // a store written before the values were versioned has values but no format
+        {
+            let mut manager = Manager::<LmdbEnvironment>::singleton().write().unwrap();
+            let shared_rkv = manager
+                .get_or_create(root.path(), |path| {
+                    create_environment(path, key, Durability::SyncOnCommit)
+                })
+                .unwrap();
+            let env = shared_rkv.read().unwrap();
+            let main_store = env.open_multi("main", StoreOptions::create()).unwrap();
+            let mut writer = env.write().unwrap();
+            main_store
+                .put(&mut writer, vec![b"o"[0], 1], &Value::Blob(&[42; 32]))
+                .unwrap();
+            writer.commit().unwrap();
+        }
+        assert_eq!(
+            LmdbBrokerStore::try_open_with_durability(root.path(), key, Durability::SyncOnCommit)
+                .err(),
+            Some(StorageError::IncompatibleFormat)
+        );
+
+        // a new store records its format, and opens again
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbBrokerStore::open(root.path(), key);
+        store.put(b"o"[0], &vec![1], None, vec![42]).unwrap();
+        assert!(LmdbBrokerStore::try_open_with_durability(
+            root.path(),
+            key,
+            Durability::SyncOnCommit
+        )
+        .is_ok());
     }
 }
@@ -0,0 +1,57 @@
+//! Durability of the writes to an LMDB environment
+
+use std::path::Path;
+
+use rkv::backend::{BackendEnvironmentBuilder, Lmdb, LmdbEnvironment};
+use rkv::{EnvironmentFlags, Rkv, StoreError};
+
+/// Size of the memory map of an environment
+const MAP_SIZE: usize = 2 * 1024 * 1024 * 1024;
+
+/// Maximum number of named stores in an environment
+const MAX_DBS: u32 = 16;
+
+/// Durability of the writes to a store
+///
+/// With `SyncOnCommit`, every write transaction is fsynced when committed:
+/// a committed write survives a crash of the process or of the system,
+/// at the cost of one fsync per write.
+///
+/// With `NoSync`, commits are not fsynced, the OS writes the pages back when it sees fit,
+/// or when the store is explicitly flushed. Ingest is much faster,
+/// but a system crash (not a process crash) may lose the writes since the last flush.
+/// The database stays consistent, the lost writes are rolled back as a whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    SyncOnCommit,
+    NoSync,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::SyncOnCommit
+    }
+}
+
+/// Create an encrypted environment with the given durability
+pub(crate) fn create_environment(
+    path: &Path,
+    key: [u8; 32],
+    durability: Durability,
+) -> Result<Rkv<LmdbEnvironment>, StoreError> {
+    match durability {
+        //Rkv::new::<Lmdb>(path) // use this instead to disable encryption
+        Durability::SyncOnCommit => {
+            Rkv::with_encryption_key_and_mapsize::<Lmdb>(path, key, MAP_SIZE)
+        }
+        Durability::NoSync => {
+            let mut builder = Rkv::environment_builder::<Lmdb>();
+            builder
+                .set_map_size(MAP_SIZE)
+                .set_max_dbs(MAX_DBS)
+                .set_flags(EnvironmentFlags::NO_SYNC)
+                .set_encryption_key(key);
+            Rkv::from_builder(path, builder)
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod repostore;
 
 pub mod brokerstore;
+
+pub mod durability;
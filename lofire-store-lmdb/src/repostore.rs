@@ -2,9 +2,14 @@ use lofire::store::*;
 use lofire::types::*;
 use lofire::utils::*;
 
+use crate::durability::*;
+
 use debug_print::*;
+use std::collections::HashSet;
 use std::path::Path;
+use std::sync::mpsc::sync_channel;
 use std::sync::{Arc, RwLock};
+use std::thread;
 
 use rkv::backend::{
     BackendDatabaseFlags, BackendFlags, BackendIter, BackendWriteFlags, DatabaseFlags, Lmdb,
@@ -18,6 +23,9 @@ use rkv::{
 use serde::{Deserialize, Serialize};
 use serde_bare::error::Error;
 
+/// Number of blocks read ahead by the cursor of `LmdbRepoStore::iter`
+const ITER_READ_AHEAD: usize = 64;
+
 pub struct LmdbRepoStore {
     /// the main store where all the repo blocks are stored
     main_store: SingleStore<LmdbDatabase>,
@@ -27,6 +35,15 @@ pub struct LmdbRepoStore {
     expiry_store: MultiIntegerStore<LmdbDatabase, u32>,
     /// store for the LRU list
     recently_used_store: MultiIntegerStore<LmdbDatabase, u32>,
+    /// store for the timestamp at which each block was stored, used for replication
+    stored_at_store: SingleStore<LmdbDatabase>,
+    /// store for the removal records of deleted blocks, used for replication
+    removed_store: SingleStore<LmdbDatabase>,
+    /// store for the default expiry applied to blocks without an expiry of their own
+    default_expiry_store: SingleStore<LmdbDatabase>,
+    /// store for the expiry set with `set_expiry`, replacing the expiry of the block.
+    /// None means that the block doesn't expire anymore
+    expiry_override_store: SingleStore<LmdbDatabase>,
     /// the opened environment so we can create new transactions
     environment: Arc<RwLock<Rkv<LmdbEnvironment>>>,
 }
@@ -39,6 +56,22 @@ struct BlockMeta {
     pub synced: bool,
 }
 
+// TODO: versioning V0
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct RemovedMeta {
+    pub stored_at: Timestamp,
+    pub removed_at: Timestamp,
+}
+
+/// Result of a garbage collection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Number of blocks deleted
+    pub blocks: usize,
+    /// Approximate size of the storage space reclaimed, in bytes
+    pub bytes: u64,
+}
+
 impl RepoStore for LmdbRepoStore {
     /// Retrieves a block from the storage backend.
     fn get(&self, block_id: &BlockId) -> Result<Block, StorageError> {
@@ -108,125 +141,638 @@ impl RepoStore for LmdbRepoStore {
     /// The block is persisted to disk.
     /// Returns the BlockId of the Block.
     fn put(&self, block: &Block) -> Result<BlockId, StorageError> {
-        let block_ser = serde_bare::to_vec(&block).unwrap();
+        self.put_with_default_expiry(block, None)
+    }
 
-        let block_id = block.id();
-        let block_id_ser = serde_bare::to_vec(&block_id).unwrap();
+    /// Adds several blocks in a single write transaction,
+    /// so a failure leaves none of them in the storage backend
+    fn put_all(&self, blocks: &[Block]) -> Result<Vec<BlockId>, StorageError> {
+        self.put_all_with_default_expiry(blocks, None)
+    }
 
+    /// Removes the block from the storage backend.
+    /// The removed block is returned, so it can be inspected.
+    /// Also returned is the approximate size of of free space that was reclaimed.
+    fn del(&self, block_id: &BlockId) -> Result<(Block, usize), StorageError> {
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
+        let removed = self.delete_block(&mut writer, block_id)?;
+        writer.commit().unwrap();
+        Ok(removed)
+    }
 
-        // TODO: check if the block is already in store? if yes, don't put it again.
-        // I didnt do it yet because it is extra cost. surely a get on the store is lighter than a put
-        // but doing a get in additing to a put for every call, is probably even costlier. better to deal with that at the higher level
+    /// Iterates over the blocks with a cursor, in the order of their serialized ID.
+    /// Unlike `get`, it doesn't update the recently used list.
+    ///
+    /// The cursor holds a read transaction until the iterator is dropped,
+    /// so the iteration sees the store as it was when it started, whatever is written meanwhile.
+    /// LMDB transactions are bound to their thread, so the cursor runs in a thread of its own
+    /// and reads at most `ITER_READ_AHEAD` blocks ahead of the iterator.
+    /// An iterator kept open keeps LMDB from reusing the pages freed since it started
+    fn iter(&self) -> Box<dyn Iterator<Item = (BlockId, Block)> + Send + '_> {
+        let environment = Arc::clone(&self.environment);
+        let main_store = self.main_store;
+        let (sender, receiver) = sync_channel(ITER_READ_AHEAD);
+        let (ready, started) = sync_channel(1);
+        thread::spawn(move || {
+            let lock = environment.read().unwrap();
+            let reader = match lock.read() {
+                Ok(reader) => reader,
+                Err(_e) => return,
+            };
+            let _ = ready.send(());
+            let mut iter = match main_store.iter_start(&reader) {
+                Ok(iter) => iter,
+                Err(_e) => return,
+            };
+            while let Some(Ok((key, value))) = iter.next() {
+                let id = match serde_bare::from_slice::<BlockId>(key) {
+                    Ok(id) => id,
+                    Err(_e) => continue,
+                };
+                let block = match value
+                    .to_bytes()
+                    .ok()
+                    .and_then(|value| serde_bare::from_slice::<Block>(&value).ok())
+                {
+                    Some(block) => block,
+                    None => continue,
+                };
+                // the iterator was dropped
+                if sender.send((id, block)).is_err() {
+                    break;
+                }
+            }
+        });
+        // the snapshot is taken before returning
+        let _ = started.recv();
+        Box::new(receiver.into_iter())
+    }
 
+    /// Counts the entries with a cursor, without deserializing the blocks
+    fn len(&self) -> Result<usize, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let reader = lock.read().unwrap();
+        let mut iter = self
+            .main_store
+            .iter_start(&reader)
+            .map_err(|_e| StorageError::BackendError)?;
+        let mut len = 0;
+        while let Some(res) = iter.next() {
+            res.map_err(|_e| StorageError::BackendError)?;
+            len += 1;
+        }
+        Ok(len)
+    }
+}
+
+impl LmdbRepoStore {
+    /// Opens the store and returns a RepoStore object that should be kept and used to call put/get/delete/pin
+    /// The key is the encryption key for the data at rest.
+    pub fn open<'a>(path: &Path, key: [u8; 32]) -> LmdbRepoStore {
+        Self::open_with_durability(path, key, Durability::SyncOnCommit)
+    }
+
+    /// Opens the store with the given durability of the writes.
+    /// An environment already opened in the process keeps the durability it was first opened with
+    pub fn open_with_durability(
+        path: &Path,
+        key: [u8; 32],
+        durability: Durability,
+    ) -> LmdbRepoStore {
+        let mut manager = Manager::<LmdbEnvironment>::singleton().write().unwrap();
+        let shared_rkv = manager
+            .get_or_create(path, |path| create_environment(path, key, durability))
+            .unwrap();
+        let env = shared_rkv.read().unwrap();
+
+        println!(
+            "created env with LMDB Version: {} key: {}",
+            env.version(),
+            hex::encode(&key)
+        );
+
+        let main_store = env.open_single("main", StoreOptions::create()).unwrap();
+        let meta_store = env.open_single("meta", StoreOptions::create()).unwrap();
+        let mut opts = StoreOptions::<LmdbDatabaseFlags>::create();
+        opts.flags.set(DatabaseFlags::DUP_FIXED, true);
+        let expiry_store = env.open_multi_integer("expiry", opts).unwrap();
+        let recently_used_store = env.open_multi_integer("recently_used", opts).unwrap();
+        let stored_at_store = env
+            .open_single("stored_at", StoreOptions::create())
+            .unwrap();
+        let removed_store = env.open_single("removed", StoreOptions::create()).unwrap();
+        let default_expiry_store = env
+            .open_single("default_expiry", StoreOptions::create())
+            .unwrap();
+        let expiry_override_store = env
+            .open_single("expiry_override", StoreOptions::create())
+            .unwrap();
+
+        LmdbRepoStore {
+            environment: shared_rkv.clone(),
+            main_store,
+            meta_store,
+            expiry_store,
+            recently_used_store,
+            stored_at_store,
+            removed_store,
+            default_expiry_store,
+            expiry_override_store,
+        }
+    }
+
+    /// Adds a block in the storage backend, like `put`.
+    /// A block without an expiry of its own expires at `default_expiry`, if given.
+    /// The block itself is not modified, as its expiry is part of its ID.
+    ///
+    /// Putting a block that is already stored is a no-op that returns its ID,
+    /// so concurrent puts of the same block all succeed and only the first one is stored
+    pub fn put_with_default_expiry(
+        &self,
+        block: &Block,
+        default_expiry: Option<Timestamp>,
+    ) -> Result<BlockId, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let mut writer = lock.write().unwrap();
+        let block_id = self.write_block(&mut writer, block, default_expiry)?;
+        writer.commit().unwrap();
+
+        Ok(block_id)
+    }
+
+    /// Adds several blocks in the storage backend, like `put_with_default_expiry`,
+    /// in a single write transaction: either all the blocks are stored, or none of them
+    pub fn put_all_with_default_expiry(
+        &self,
+        blocks: &[Block],
+        default_expiry: Option<Timestamp>,
+    ) -> Result<Vec<BlockId>, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let mut writer = lock.write().unwrap();
+        let mut ids = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            // on error, the transaction is aborted when the writer is dropped
+            ids.push(self.write_block(&mut writer, block, default_expiry)?);
+        }
+        writer.commit().map_err(|_e| StorageError::BackendError)?;
+        Ok(ids)
+    }
+
+    /// Writes a block and its metadata in a write transaction
+    fn write_block(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        block: &Block,
+        default_expiry: Option<Timestamp>,
+    ) -> Result<BlockId, StorageError> {
+        let block_id = block.id();
+        let block_id_ser = serde_bare::to_vec(&block_id)?;
+
+        // the write transactions are serialized, so the block can't be stored meanwhile
+        if self
+            .main_store
+            .get(writer, &block_id_ser)
+            .map_err(|_e| StorageError::BackendError)?
+            .is_some()
+        {
+            return Ok(block_id);
+        }
+
+        let block_ser = serde_bare::to_vec(&block)?;
         self.main_store
+            .put(writer, &block_id_ser, &Value::Blob(block_ser.as_slice()))
+            .map_err(|_e| StorageError::BackendError)?;
+
+        let stored_at_ser = serde_bare::to_vec(&now_timestamp())?;
+        self.stored_at_store
             .put(
-                &mut writer,
+                writer,
                 &block_id_ser,
-                &Value::Blob(block_ser.as_slice()),
+                &Value::Blob(stored_at_ser.as_slice()),
             )
-            .unwrap();
+            .map_err(|_e| StorageError::BackendError)?;
+        // the block is back, it is not removed anymore
+        let _ = self.removed_store.delete(writer, &block_id_ser);
+
+        // an expiry set with `set_expiry` replaces the expiry of the block
+        if self.expiry_override(writer, &block_id_ser)?.is_some() {
+            return Ok(block_id);
+        }
 
         // if it has an expiry, adding the BlockId to the expiry_store
         match block.expiry() {
             Some(expiry) => {
                 self.expiry_store
-                    .put(&mut writer, expiry, &Value::Blob(block_id_ser.as_slice()))
-                    .unwrap();
+                    .put(
+                        writer,
+                        expiry.as_minutes(),
+                        &Value::Blob(block_id_ser.as_slice()),
+                    )
+                    .map_err(|_e| StorageError::BackendError)?;
+            }
+            None => {
+                if let Some(expiry) = default_expiry {
+                    self.set_default_expiry(writer, &block_id_ser, expiry)?;
+                }
             }
-            _ => {}
         }
-        writer.commit().unwrap();
-
         Ok(block_id)
     }
 
-    /// Removes the block from the storage backend.
-    /// The removed block is returned, so it can be inspected.
-    /// Also returned is the approximate size of of free space that was reclaimed.
-    fn del(&self, block_id: &BlockId) -> Result<(Block, usize), StorageError> {
+    /// Replaces the default expiry of a block, if any
+    fn set_default_expiry(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        block_id_ser: &Vec<u8>,
+        expiry: Timestamp,
+    ) -> Result<(), StorageError> {
+        self.remove_default_expiry(writer, block_id_ser)?;
+        self.expiry_store
+            .put(
+                writer,
+                expiry.as_minutes(),
+                &Value::Blob(block_id_ser.as_slice()),
+            )
+            .map_err(|_e| StorageError::BackendError)?;
+        let expiry_ser = serde_bare::to_vec(&expiry)?;
+        self.default_expiry_store
+            .put(writer, block_id_ser, &Value::Blob(expiry_ser.as_slice()))
+            .map_err(|_e| StorageError::BackendError)
+    }
+
+    /// Removes the default expiry of a block, if any
+    fn remove_default_expiry(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        block_id_ser: &Vec<u8>,
+    ) -> Result<(), StorageError> {
+        let expiry = match self
+            .default_expiry_store
+            .get(writer, block_id_ser)
+            .map_err(|_e| StorageError::BackendError)?
+        {
+            Some(value) => serde_bare::from_slice::<Timestamp>(&value.to_bytes().unwrap())?,
+            None => return Ok(()),
+        };
+        self.expiry_store
+            .delete(
+                writer,
+                expiry.as_minutes(),
+                &Value::Blob(block_id_ser.as_slice()),
+            )
+            .map_err(|_e| StorageError::BackendError)?;
+        self.default_expiry_store
+            .delete(writer, block_id_ser)
+            .map_err(|_e| StorageError::BackendError)
+    }
+
+    /// Sets the expiry of blocks already stored, replacing their own expiry or their default expiry.
+    /// With None, the blocks don't expire anymore.
+    /// The blocks themselves are not modified, so their IDs don't change.
+    /// Runs in a single write transaction: if a block is missing, it fails with NotFound and nothing is changed
+    pub fn set_expiry(
+        &self,
+        block_ids: &[BlockId],
+        expiry: Option<Timestamp>,
+    ) -> Result<(), StorageError> {
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
+        for block_id in block_ids {
+            let block_id_ser = serde_bare::to_vec(block_id)?;
+            let block_ser = self
+                .main_store
+                .get(&writer, &block_id_ser)
+                .map_err(|_e| StorageError::BackendError)?
+                .ok_or(StorageError::NotFound)?;
+            let block = serde_bare::from_slice::<Block>(
+                &block_ser
+                    .to_bytes()
+                    .map_err(|_e| StorageError::BackendError)?,
+            )?;
+            match self.expiry_override(&writer, &block_id_ser)? {
+                Some(previous) => {
+                    self.remove_expiry_override(&mut writer, &block_id_ser, previous)?
+                }
+                None => match block.expiry() {
+                    Some(own) => self
+                        .expiry_store
+                        .delete(
+                            &mut writer,
+                            own.as_minutes(),
+                            &Value::Blob(block_id_ser.as_slice()),
+                        )
+                        .map_err(|_e| StorageError::BackendError)?,
+                    None => self.remove_default_expiry(&mut writer, &block_id_ser)?,
+                },
+            }
+            if let Some(expiry) = expiry {
+                self.expiry_store
+                    .put(
+                        &mut writer,
+                        expiry.as_minutes(),
+                        &Value::Blob(block_id_ser.as_slice()),
+                    )
+                    .map_err(|_e| StorageError::BackendError)?;
+            }
+            let expiry_ser = serde_bare::to_vec(&expiry)?;
+            self.expiry_override_store
+                .put(
+                    &mut writer,
+                    &block_id_ser,
+                    &Value::Blob(expiry_ser.as_slice()),
+                )
+                .map_err(|_e| StorageError::BackendError)?;
+        }
+        writer.commit().map_err(|_e| StorageError::BackendError)?;
+        Ok(())
+    }
+
+    /// Expiry set with `set_expiry` for a block, if any
+    fn expiry_override(
+        &self,
+        writer: &Writer<LmdbRwTransaction>,
+        block_id_ser: &Vec<u8>,
+    ) -> Result<Option<Option<Timestamp>>, StorageError> {
+        match self
+            .expiry_override_store
+            .get(writer, block_id_ser)
+            .map_err(|_e| StorageError::BackendError)?
+        {
+            Some(value) => Ok(Some(serde_bare::from_slice::<Option<Timestamp>>(
+                &value.to_bytes().map_err(|_e| StorageError::BackendError)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the expiry set with `set_expiry` for a block
+    fn remove_expiry_override(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        block_id_ser: &Vec<u8>,
+        expiry: Option<Timestamp>,
+    ) -> Result<(), StorageError> {
+        if let Some(expiry) = expiry {
+            self.expiry_store
+                .delete(
+                    writer,
+                    expiry.as_minutes(),
+                    &Value::Blob(block_id_ser.as_slice()),
+                )
+                .map_err(|_e| StorageError::BackendError)?;
+        }
+        self.expiry_override_store
+            .delete(writer, block_id_ser)
+            .map_err(|_e| StorageError::BackendError)
+    }
+
+    /// Deletes a block and its metadata in a write transaction, keeping a removal record
+    fn delete_block(
+        &self,
+        writer: &mut Writer<LmdbRwTransaction>,
+        block_id: &BlockId,
+    ) -> Result<(Block, usize), StorageError> {
         let block_id_ser = serde_bare::to_vec(&block_id).unwrap();
         // retrieving the block itself (we need the expiry)
         let block_ser = self
             .main_store
-            .get(&writer, block_id_ser.clone())
+            .get(writer, block_id_ser.clone())
             .unwrap()
             .ok_or(StorageError::NotFound)?;
         let slice = block_ser.to_bytes().unwrap();
         let block = serde_bare::from_slice::<Block>(&slice).unwrap(); //FIXME propagate error?
-        let meta_res = self.meta_store.get(&writer, block_id_ser.clone()).unwrap();
+        let meta_res = self.meta_store.get(writer, block_id_ser.clone()).unwrap();
         if meta_res.is_some() {
             let meta = serde_bare::from_slice::<BlockMeta>(&meta_res.unwrap().to_bytes().unwrap())
                 .unwrap();
-            if meta.last_used != 0 {
-                self.remove_from_lru(&mut writer, &block_id_ser.clone(), &meta.last_used)
+            if meta.last_used != Timestamp::MIN {
+                self.remove_from_lru(writer, &block_id_ser.clone(), &meta.last_used)
                     .unwrap();
             }
             // removing the meta
             self.meta_store
-                .delete(&mut writer, block_id_ser.clone())
+                .delete(writer, block_id_ser.clone())
                 .unwrap();
         }
         // delete block from main_store
         self.main_store
-            .delete(&mut writer, block_id_ser.clone())
+            .delete(writer, block_id_ser.clone())
             .unwrap();
+        // keeping a removal record, so replicas can find out about blocks they will never get
+        let stored_at = match self
+            .stored_at_store
+            .get(writer, block_id_ser.clone())
+            .unwrap()
+        {
+            Some(value) => serde_bare::from_slice::<Timestamp>(&value.to_bytes().unwrap()).unwrap(),
+            None => Timestamp::MIN,
+        };
+        let removed = RemovedMeta {
+            stored_at,
+            removed_at: now_timestamp(),
+        };
+        let removed_ser = serde_bare::to_vec(&removed).unwrap();
+        self.removed_store
+            .put(
+                writer,
+                block_id_ser.clone(),
+                &Value::Blob(removed_ser.as_slice()),
+            )
+            .unwrap();
+        // blocks stored before stored_at_store existed have no entry there
+        let _ = self.stored_at_store.delete(writer, block_id_ser.clone());
         // remove BlockId from expiry_store, if any expiry
-        match block.expiry() {
-            Some(expiry) => {
-                self.expiry_store
-                    .delete(
-                        &mut writer,
-                        expiry,
-                        &Value::Blob(block_id_ser.clone().as_slice()),
-                    )
-                    .unwrap();
-            }
-            _ => {}
+        match self.expiry_override(writer, &block_id_ser)? {
+            Some(expiry) => self.remove_expiry_override(writer, &block_id_ser, expiry)?,
+            None => match block.expiry() {
+                Some(expiry) => {
+                    self.expiry_store
+                        .delete(
+                            writer,
+                            expiry.as_minutes(),
+                            &Value::Blob(block_id_ser.clone().as_slice()),
+                        )
+                        .unwrap();
+                }
+                None => self.remove_default_expiry(writer, &block_id_ser)?,
+            },
         }
 
-        writer.commit().unwrap();
         Ok((block, slice.len()))
     }
 
-}
+    /// Forces the writes committed so far to disk, whatever the durability of the store
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.environment
+            .read()
+            .unwrap()
+            .sync(true)
+            .map_err(|_e| StorageError::BackendError)
+    }
 
-impl LmdbRepoStore {
-    /// Opens the store and returns a RepoStore object that should be kept and used to call put/get/delete/pin
-    /// The key is the encryption key for the data at rest.
-    pub fn open<'a>(path: &Path, key: [u8; 32]) -> LmdbRepoStore {
-        let mut manager = Manager::<LmdbEnvironment>::singleton().write().unwrap();
-        let shared_rkv = manager
-            .get_or_create(path, |path| {
-                //Rkv::new::<Lmdb>(path) // use this instead to disable encryption
-                Rkv::with_encryption_key_and_mapsize::<Lmdb>(path, key, 2 * 1024 * 1024 * 1024)
-            })
-            .unwrap();
-        let env = shared_rkv.read().unwrap();
+    /// Lists the IDs of all the blocks in the store.
+    /// If since is given, only the blocks stored at or after that timestamp are listed.
+    pub fn list_blocks(&self, since: Option<Timestamp>) -> Result<Vec<BlockId>, StorageError> {
+        Ok(self
+            .list_blocks_stored_at(since)?
+            .into_iter()
+            .map(|(id, _stored_at)| id)
+            .collect())
+    }
 
-        println!(
-            "created env with LMDB Version: {} key: {}",
-            env.version(),
-            hex::encode(&key)
-        );
+    /// Lists the IDs of the blocks in the store, together with the timestamp at which they were stored.
+    /// If since is given, only the blocks stored at or after that timestamp are listed.
+    pub fn list_blocks_stored_at(
+        &self,
+        since: Option<Timestamp>,
+    ) -> Result<Vec<(BlockId, Timestamp)>, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let reader = lock.read().unwrap();
+        let mut blocks: Vec<(BlockId, Timestamp)> = vec![];
 
-        let main_store = env.open_single("main", StoreOptions::create()).unwrap();
-        let meta_store = env.open_single("meta", StoreOptions::create()).unwrap();
-        let mut opts = StoreOptions::<LmdbDatabaseFlags>::create();
-        opts.flags.set(DatabaseFlags::DUP_FIXED, true);
-        let expiry_store = env.open_multi_integer("expiry", opts).unwrap();
-        let recently_used_store = env.open_multi_integer("recently_used", opts).unwrap();
+        let mut iter = self
+            .main_store
+            .iter_start(&reader)
+            .map_err(|_e| StorageError::BackendError)?;
+        while let Some(res) = iter.next() {
+            let entry = res.map_err(|_e| StorageError::BackendError)?;
+            let stored_at = match self
+                .stored_at_store
+                .get(&reader, entry.0)
+                .map_err(|_e| StorageError::BackendError)?
+            {
+                Some(value) => serde_bare::from_slice::<Timestamp>(&value.to_bytes().unwrap())?,
+                None => Timestamp::MIN,
+            };
+            if since.is_some() && stored_at < since.unwrap() {
+                continue;
+            }
+            blocks.push((serde_bare::from_slice::<BlockId>(entry.0)?, stored_at));
+        }
+        Ok(blocks)
+    }
 
-        LmdbRepoStore {
-            environment: shared_rkv.clone(),
-            main_store,
-            meta_store,
-            expiry_store,
-            recently_used_store,
+    /// Lists at most `limit` block IDs, starting right after the `after` cursor.
+    /// Blocks are listed in the order of their serialized ID, which is stable across calls.
+    /// Also returns the cursor of the next page, or None if this was the last page.
+    pub fn list_blocks_page(
+        &self,
+        limit: usize,
+        after: Option<Cursor>,
+    ) -> Result<(Vec<BlockId>, Option<Cursor>), StorageError> {
+        let lock = self.environment.read().unwrap();
+        let reader = lock.read().unwrap();
+        let mut blocks: Vec<BlockId> = vec![];
+
+        let mut iter = match &after {
+            Some(cursor) => self.main_store.iter_from(&reader, cursor),
+            None => self.main_store.iter_start(&reader),
+        }
+        .map_err(|_e| StorageError::BackendError)?;
+        let mut last: Option<Cursor> = None;
+        while let Some(res) = iter.next() {
+            let entry = res.map_err(|_e| StorageError::BackendError)?;
+            if after.as_deref() == Some(entry.0) {
+                continue;
+            }
+            if blocks.len() == limit {
+                return Ok((blocks, last));
+            }
+            blocks.push(serde_bare::from_slice::<BlockId>(entry.0)?);
+            last = Some(entry.0.to_vec());
+        }
+        Ok((blocks, None))
+    }
+
+    /// Lists the blocks removed from the store at or after the given timestamp,
+    /// together with the timestamp at which they had been stored.
+    pub fn list_removed(
+        &self,
+        since: Timestamp,
+    ) -> Result<Vec<(BlockId, Timestamp)>, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let reader = lock.read().unwrap();
+        let mut blocks: Vec<(BlockId, Timestamp)> = vec![];
+
+        let mut iter = self
+            .removed_store
+            .iter_start(&reader)
+            .map_err(|_e| StorageError::BackendError)?;
+        while let Some(res) = iter.next() {
+            let entry = res.map_err(|_e| StorageError::BackendError)?;
+            let removed = serde_bare::from_slice::<RemovedMeta>(&entry.1.to_bytes().unwrap())?;
+            if removed.removed_at >= since {
+                blocks.push((
+                    serde_bare::from_slice::<BlockId>(entry.0)?,
+                    removed.stored_at,
+                ));
+            }
         }
+        Ok(blocks)
+    }
+
+    /// Deletes the removal records of the blocks removed before the given timestamp.
+    /// A replica whose checkpoint is older than that won't be told about these gaps anymore.
+    /// Returns the number of records deleted
+    pub fn prune_removed(&self, before: Timestamp) -> Result<usize, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let mut writer = lock.write().unwrap();
+        let mut pruned: Vec<Vec<u8>> = vec![];
+        {
+            let mut iter = self
+                .removed_store
+                .iter_start(&writer)
+                .map_err(|_e| StorageError::BackendError)?;
+            while let Some(res) = iter.next() {
+                let entry = res.map_err(|_e| StorageError::BackendError)?;
+                let removed = serde_bare::from_slice::<RemovedMeta>(&entry.1.to_bytes().unwrap())?;
+                if removed.removed_at < before {
+                    pruned.push(entry.0.to_vec());
+                }
+            }
+        }
+        for key in pruned.iter() {
+            self.removed_store
+                .delete(&mut writer, key.clone())
+                .map_err(|_e| StorageError::BackendError)?;
+        }
+        writer.commit().map_err(|_e| StorageError::BackendError)?;
+        Ok(pruned.len())
+    }
+
+    /// Returns the number of bytes taken by the blocks and their index entries,
+    /// with the same accounting as `store_block_footprint`
+    pub fn stored_bytes(&self) -> Result<u64, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let reader = lock.read().unwrap();
+        let mut total: u64 = 0;
+        for store in [&self.main_store, &self.stored_at_store] {
+            let mut iter = store
+                .iter_start(&reader)
+                .map_err(|_e| StorageError::BackendError)?;
+            while let Some(res) = iter.next() {
+                let entry = res.map_err(|_e| StorageError::BackendError)?;
+                let value = entry
+                    .1
+                    .to_bytes()
+                    .map_err(|_e| StorageError::BackendError)?;
+                total += (entry.0.len() + value.len()) as u64;
+            }
+        }
+        let mut iter = self
+            .expiry_store
+            .iter_start(&reader)
+            .map_err(|_e| StorageError::BackendError)?;
+        while let Some(res) = iter.next() {
+            let entry = res.map_err(|_e| StorageError::BackendError)?;
+            let value = entry
+                .1
+                .to_bytes()
+                .map_err(|_e| StorageError::BackendError)?;
+            total += (std::mem::size_of::<Timestamp>() + value.len()) as u64;
+        }
+        Ok(total)
     }
 
     //FIXME: use BlockId, not ObjectId. this is a block level operation
@@ -245,6 +791,7 @@ impl LmdbRepoStore {
     /// Sets the pin for that Object. if add is true, will add the pin. if false, will remove the pin.
     /// A pin on an object prevents it from being removed when the store is making some disk space by using the LRU.
     /// A pin does not override the expiry. If expiry is set and is reached, the obejct will be deleted, no matter what.
+    /// Only `garbage_collect` keeps expired objects that are pinned.
     pub fn set_pin(&self, object_id: &ObjectId, add: bool) -> Result<(), StorageError> {
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
@@ -285,7 +832,7 @@ impl LmdbRepoStore {
                     meta = BlockMeta {
                         pin: true,
                         synced: false,
-                        last_used: 0,
+                        last_used: Timestamp::MIN,
                     }
                 } else {
                     // there is no meta, and user wants to unpin, so let's leave everything as it is.
@@ -311,7 +858,11 @@ impl LmdbRepoStore {
     /// the broker calls this method when the block has been retrieved/synced by enough peers and it
     /// can now be included in the LRU for potential garbage collection.
     /// If this method has not been called on a block, it will be kept in the store and will not enter LRU.
-    pub fn has_been_synced(&self, block_id: &BlockId, when: Option<u32>) -> Result<(), Error> {
+    pub fn has_been_synced(
+        &self,
+        block_id: &BlockId,
+        when: Option<Timestamp>,
+    ) -> Result<(), Error> {
         let lock = self.environment.read().unwrap();
         let mut writer = lock.write().unwrap();
         let block_id_ser = serde_bare::to_vec(&block_id).unwrap();
@@ -373,6 +924,11 @@ impl LmdbRepoStore {
     /// Removes all the blocks that have expired.
     /// The broker should call this method periodically.
     pub fn remove_expired(&self) -> Result<(), Error> {
+        self.remove_expired_at(&SystemClock)
+    }
+
+    /// Removes all the blocks that have expired at the time of the clock
+    pub fn remove_expired_at(&self, clock: &impl Clock) -> Result<(), Error> {
         let mut block_ids: Vec<BlockId> = vec![];
 
         {
@@ -381,7 +937,7 @@ impl LmdbRepoStore {
 
             let mut iter = self
                 .expiry_store
-                .iter_prev_dup_from(&reader, now_timestamp())
+                .iter_prev_dup_from(&reader, clock.now().as_minutes())
                 .unwrap();
 
             while let Some(Ok(mut sub_iter)) = iter.next() {
@@ -398,6 +954,85 @@ impl LmdbRepoStore {
         Ok(())
     }
 
+    /// Deletes the blocks that have expired at `now`, unless they are protected by a pin:
+    /// a block is protected when it is pinned, or when a pinned block references it in the Merkle tree of its object.
+    /// Unlike `remove_expired`, pinned objects survive their expiry.
+    /// Runs in a single write transaction, so it is serialized with concurrent puts.
+    /// Returns the number of blocks deleted and the space reclaimed
+    pub fn garbage_collect(&self, now: Timestamp) -> Result<GcReport, StorageError> {
+        let lock = self.environment.read().unwrap();
+        let mut writer = lock.write().unwrap();
+
+        let mut expired: Vec<BlockId> = vec![];
+        {
+            let mut iter = self
+                .expiry_store
+                .iter_prev_dup_from(&writer, now.as_minutes())
+                .map_err(|_e| StorageError::BackendError)?;
+            while let Some(Ok(mut sub_iter)) = iter.next() {
+                while let Some(Ok(k)) = sub_iter.next() {
+                    expired.push(serde_bare::from_slice::<BlockId>(k.1)?);
+                }
+            }
+        }
+        let mut report = GcReport::default();
+        if expired.is_empty() {
+            return Ok(report);
+        }
+
+        let protected = self.pinned_blocks(&writer)?;
+        for block_id in expired.iter().filter(|id| !protected.contains(*id)) {
+            match self.delete_block(&mut writer, block_id) {
+                Ok((_, size)) => {
+                    report.blocks += 1;
+                    report.bytes += size as u64;
+                }
+                // listed under both its own and a default expiry
+                Err(StorageError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        writer.commit().map_err(|_e| StorageError::BackendError)?;
+        Ok(report)
+    }
+
+    /// IDs of the pinned blocks and of all the blocks below them in their Merkle trees
+    fn pinned_blocks(
+        &self,
+        writer: &Writer<LmdbRwTransaction>,
+    ) -> Result<HashSet<BlockId>, StorageError> {
+        let mut stack: Vec<BlockId> = vec![];
+        {
+            let mut iter = self
+                .meta_store
+                .iter_start(writer)
+                .map_err(|_e| StorageError::BackendError)?;
+            while let Some(Ok((key, value))) = iter.next() {
+                let value = value.to_bytes().map_err(|_e| StorageError::BackendError)?;
+                if serde_bare::from_slice::<BlockMeta>(&value)?.pin {
+                    stack.push(serde_bare::from_slice::<BlockId>(key)?);
+                }
+            }
+        }
+        let mut protected = HashSet::new();
+        while let Some(id) = stack.pop() {
+            if !protected.insert(id) {
+                continue;
+            }
+            let id_ser = serde_bare::to_vec(&id)?;
+            if let Some(value) = self
+                .main_store
+                .get(writer, id_ser)
+                .map_err(|_e| StorageError::BackendError)?
+            {
+                let value = value.to_bytes().map_err(|_e| StorageError::BackendError)?;
+                let block = serde_bare::from_slice::<Block>(&value)?;
+                stack.extend(block.children().iter().cloned());
+            }
+        }
+        Ok(protected)
+    }
+
     /// Removes some blocks that haven't been used for a while, reclaiming some space on disk.
     /// The oldest are removed first, until the total amount of data removed is at least equal to size,
     /// or the LRU list became empty. The approximate size of the storage space that was reclaimed is returned.
@@ -435,8 +1070,11 @@ impl LmdbRepoStore {
         block_id_ser: &Vec<u8>,
         time: &Timestamp,
     ) -> Result<(), StoreError> {
-        self.recently_used_store
-            .delete(writer, *time, &Value::Blob(block_id_ser.as_slice()))
+        self.recently_used_store.delete(
+            writer,
+            time.as_minutes(),
+            &Value::Blob(block_id_ser.as_slice()),
+        )
     }
 
     fn add_to_lru(
@@ -449,7 +1087,7 @@ impl LmdbRepoStore {
         flag.set(WriteFlags::APPEND_DUP, true);
         self.recently_used_store.put_with_flags(
             writer,
-            *time,
+            time.as_minutes(),
             &Value::Blob(block_id_ser.as_slice()),
             flag,
         )
@@ -483,12 +1121,15 @@ impl LmdbRepoStore {
 #[cfg(test)]
 mod test {
 
-    use crate::repostore::LmdbRepoStore;
+    use crate::durability::Durability;
+    use crate::repostore::{GcReport, LmdbRepoStore};
+    use lofire::object::*;
     use lofire::store::*;
     use lofire::types::*;
     use lofire::utils::*;
     use rkv::backend::{BackendInfo, BackendStat, Lmdb, LmdbEnvironment};
     use rkv::{Manager, Rkv, StoreOptions, Value};
+    use std::collections::HashSet;
     #[allow(unused_imports)]
     use std::time::Duration;
     #[allow(unused_imports)]
@@ -503,8 +1144,7 @@ mod test {
         fs::create_dir_all(root.path()).unwrap();
         println!("{}", root.path().to_str().unwrap());
         let mut store = LmdbRepoStore::open(root.path(), key);
-        let mut now = now_timestamp();
-        now -= 200;
+        let now = now_timestamp() - RelTime::Minutes(200);
         // TODO: fix the LMDB bug that is triggered with x max set to 86 !!!
         for x in 1..85 {
             let block = Block::new(
@@ -517,7 +1157,7 @@ mod test {
             let block_id = store.put(&block).unwrap();
             println!("#{} -> objId {:?}", x, block_id);
             store
-                .has_been_synced(&block_id, Some(now + x as u32))
+                .has_been_synced(&block_id, Some(now + RelTime::Minutes(x)))
                 .unwrap();
         }
 
@@ -528,6 +1168,178 @@ mod test {
         //store.list_all();
     }
 
+    #[test]
+    pub fn test_storage_footprint() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbRepoStore::open(root.path(), key);
+
+        let obj = Object::new(
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: vec![],
+                metadata: vec![1; 100],
+                // repeated content, so some leaves are identical
+                content: vec![7; 20000],
+            })),
+            vec![],
+            Some(now_timestamp() + RelTime::Days(1)),
+            1000,
+            PubKey::Ed25519PubKey([1; 32]),
+            SymKey::ChaCha20Key([2; 32]),
+        );
+        let unique: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+        assert!(unique.len() < obj.blocks().len());
+
+        let before = store.stored_bytes().unwrap();
+        for block in obj.blocks() {
+            store.put(block).unwrap();
+        }
+        let after = store.stored_bytes().unwrap();
+        assert_eq!(after - before, obj.storage_footprint());
+
+        // putting the object again takes no more space
+        for block in obj.blocks() {
+            store.put(block).unwrap();
+        }
+        assert_eq!(store.stored_bytes().unwrap(), after);
+    }
+
+    #[test]
+    pub fn test_flush() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let block = Block::new(
+            Vec::new(),
+            ObjectDeps::ObjectIdList(Vec::new()),
+            None,
+            b"flushed".to_vec(),
+            None,
+        );
+        {
+            let store = LmdbRepoStore::open_with_durability(root.path(), key, Durability::NoSync);
+            store.put(&block).unwrap();
+            store.flush().unwrap();
+        }
+
+        let store = LmdbRepoStore::open(root.path(), key);
+        assert_eq!(store.get(&block.id()).unwrap().id(), block.id());
+    }
+
+    #[test]
+    pub fn test_put_all() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbRepoStore::open(root.path(), key);
+
+        let blocks: Vec<Block> = (0..100u32)
+            .map(|x| {
+                Block::new(
+                    Vec::new(),
+                    ObjectDeps::ObjectIdList(Vec::new()),
+                    None,
+                    x.to_be_bytes().to_vec(),
+                    None,
+                )
+            })
+            .collect();
+        let ids = store.put_all(&blocks).unwrap();
+        assert_eq!(ids.len(), blocks.len());
+        for (block, id) in blocks.iter().zip(ids) {
+            assert_eq!(block.id(), id);
+            assert_eq!(store.get(&id).unwrap().id(), id);
+        }
+        assert!(store.put_all(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_iter() {
+        let path_str = "test-env";
+        let root = Builder::new().prefix(path_str).tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbRepoStore::open(root.path(), key);
+        assert!(store.is_empty().unwrap());
+
+        let block = |x: u32| {
+            Block::new(
+                Vec::new(),
+                ObjectDeps::ObjectIdList(Vec::new()),
+                None,
+                x.to_be_bytes().to_vec(),
+                None,
+            )
+        };
+        for start in (0..10000u32).step_by(1000) {
+            let blocks: Vec<Block> = (start..start + 1000).map(block).collect();
+            store.put_all(&blocks).unwrap();
+        }
+        assert_eq!(store.len().unwrap(), 10000);
+
+        // a block added during the iteration is not seen
+        let iter = store.iter();
+        let added = store.put(&block(10000)).unwrap();
+        let mut seen = HashSet::new();
+        for (id, block) in iter {
+            assert_eq!(block.id(), id);
+            assert!(seen.insert(id), "block {} yielded twice", id);
+        }
+        assert_eq!(seen.len(), 10000);
+        assert!(!seen.contains(&added));
+        assert_eq!(store.len().unwrap(), 10001);
+
+        // dropping an iterator before its end releases its read transaction
+        let first = store.iter().next().unwrap();
+        assert!(seen.contains(&first.0));
+        store.del(&first.0).unwrap();
+        assert_eq!(store.iter().count(), 10000);
+    }
+
+    #[test]
+    pub fn test_list_blocks_page() {
+        let path_str = "test-env";
+        let root = Builder::new().prefix(path_str).tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        println!("{}", root.path().to_str().unwrap());
+        let store = LmdbRepoStore::open(root.path(), key);
+
+        let mut ids = HashSet::new();
+        for x in 0..1000u32 {
+            let block = Block::new(
+                Vec::new(),
+                ObjectDeps::ObjectIdList(Vec::new()),
+                None,
+                x.to_be_bytes().to_vec(),
+                None,
+            );
+            ids.insert(store.put(&block).unwrap());
+        }
+        assert_eq!(ids.len(), 1000);
+
+        let mut listed = HashSet::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let (page, next) = store.list_blocks_page(100, after).unwrap();
+            pages += 1;
+            assert!(page.len() <= 100);
+            for id in page {
+                // no duplicates
+                assert!(listed.insert(id));
+            }
+            match next {
+                Some(cursor) => after = Some(cursor),
+                None => break,
+            }
+        }
+        // no gaps
+        assert_eq!(listed, ids);
+        assert_eq!(pages, 10);
+    }
+
     #[test]
     pub fn test_set_pin() {
         let path_str = "test-env";
@@ -536,8 +1348,7 @@ mod test {
         fs::create_dir_all(root.path()).unwrap();
         println!("{}", root.path().to_str().unwrap());
         let mut store = LmdbRepoStore::open(root.path(), key);
-        let mut now = now_timestamp();
-        now -= 200;
+        let now = now_timestamp() - RelTime::Minutes(200);
         // TODO: fix the LMDB bug that is triggered with x max set to 86 !!!
         for x in 1..100 {
             let block = Block::new(
@@ -551,7 +1362,7 @@ mod test {
             println!("#{} -> objId {:?}", x, obj_id);
             store.set_pin(&obj_id, true).unwrap();
             store
-                .has_been_synced(&obj_id, Some(now + x as u32))
+                .has_been_synced(&obj_id, Some(now + RelTime::Minutes(x)))
                 .unwrap();
         }
 
@@ -596,20 +1407,20 @@ mod test {
 
         let now = now_timestamp();
         let list = [
-            now - 10,
-            now - 6,
-            now - 6,
-            now - 3,
-            now - 2,
-            now - 1, //#5 should be removed, and above
-            now + 3,
-            now + 4,
-            now + 4,
-            now + 5,
-            now + 10,
+            now - RelTime::Minutes(10),
+            now - RelTime::Minutes(6),
+            now - RelTime::Minutes(6),
+            now - RelTime::Minutes(3),
+            now - RelTime::Minutes(2),
+            now - RelTime::Minutes(1), //#5 should be removed, and above
+            now + RelTime::Minutes(3),
+            now + RelTime::Minutes(4),
+            now + RelTime::Minutes(4),
+            now + RelTime::Minutes(5),
+            now + RelTime::Minutes(10),
         ];
         let mut block_ids: Vec<ObjectId> = Vec::with_capacity(11);
-        println!("now {}", now);
+        println!("now {:?}", now);
 
         let mut i = 0u8;
         for expiry in list {
@@ -639,6 +1450,58 @@ mod test {
         //store.list_all();
     }
 
+    #[test]
+    pub fn test_garbage_collect() {
+        let root = Builder::new().prefix("test-env").tempdir().unwrap();
+        let key: [u8; 32] = [0; 32];
+        fs::create_dir_all(root.path()).unwrap();
+        let store = LmdbRepoStore::open(root.path(), key);
+
+        let now = now_timestamp();
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let repo_secret = SymKey::ChaCha20Key([2; 32]);
+        let new_object = |c: u8| {
+            let content = ObjectContent::File(File::V0(FileV0 {
+                content_type: b"test".to_vec(),
+                metadata: vec![],
+                content: (0..20000u32).map(|i| (i % 239) as u8 ^ c).collect(),
+            }));
+            Object::new(
+                content,
+                vec![],
+                Some(now + RelTime::Minutes(10)),
+                4000,
+                repo_pubkey,
+                repo_secret,
+            )
+        };
+        let a = new_object(1);
+        let b = new_object(2);
+        store.put_all(a.blocks()).unwrap();
+        store.put_all(b.blocks()).unwrap();
+        store.pin(&a.id()).unwrap();
+
+        // nothing has expired yet
+        assert_eq!(store.garbage_collect(now).unwrap(), GcReport::default());
+
+        let report = store.garbage_collect(now + RelTime::Minutes(20)).unwrap();
+        let b_ids: HashSet<BlockId> = b.blocks().iter().map(|block| block.id()).collect();
+        assert_eq!(report.blocks, b_ids.len());
+        assert!(report.bytes > 0);
+
+        // the pinned object survives, with all its blocks
+        for block in a.blocks() {
+            assert!(store.get(&block.id()).is_ok());
+        }
+        for id in b_ids {
+            assert_eq!(store.get(&id).err(), Some(StorageError::NotFound));
+        }
+        assert_eq!(
+            store.garbage_collect(now + RelTime::Minutes(20)).unwrap(),
+            GcReport::default()
+        );
+    }
+
     #[test]
     pub fn test_remove_all_expired() {
         let path_str = "test-env";
@@ -650,15 +1513,15 @@ mod test {
 
         let now = now_timestamp();
         let list = [
-            now - 10,
-            now - 6,
-            now - 6,
-            now - 3,
-            now - 2,
-            now - 2, //#5 should be removed, and above
+            now - RelTime::Minutes(10),
+            now - RelTime::Minutes(6),
+            now - RelTime::Minutes(6),
+            now - RelTime::Minutes(3),
+            now - RelTime::Minutes(2),
+            now - RelTime::Minutes(2), //#5 should be removed, and above
         ];
         let mut block_ids: Vec<ObjectId> = Vec::with_capacity(6);
-        println!("now {}", now);
+        println!("now {:?}", now);
 
         let mut i = 0u8;
         for expiry in list {
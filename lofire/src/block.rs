@@ -1,7 +1,21 @@
 //! Immutable Block
 
+use crate::compression::decompress;
+use crate::errors::*;
+use crate::object::BLOCK_KEY_SIZE;
+use crate::store::store_max_value_size;
 use crate::types::*;
 
+/// Size of a ULEB128 varint
+fn varint_size(mut n: usize) -> usize {
+    let mut size = 1;
+    while n >= 0x80 {
+        n >>= 7;
+        size += 1;
+    }
+    size
+}
+
 impl BlockV0 {
     pub fn new(
         children: Vec<BlockId>,
@@ -24,6 +38,32 @@ impl BlockV0 {
     }
 }
 
+impl BlockV1 {
+    pub fn new(
+        children: Vec<BlockId>,
+        deps: ObjectDeps,
+        expiry: Option<Timestamp>,
+        content: Vec<u8>,
+        key: Option<SymKey>,
+        compression: Option<Compression>,
+        decompressed_size: u32,
+    ) -> BlockV1 {
+        let mut b = BlockV1 {
+            id: None,
+            key,
+            children,
+            deps,
+            expiry,
+            content,
+            compression,
+            decompressed_size,
+        };
+        let block = Block::V1(b.clone());
+        b.id = Some(block.get_id());
+        b
+    }
+}
+
 impl Block {
     pub fn new(
         children: Vec<BlockId>,
@@ -35,6 +75,30 @@ impl Block {
         Block::V0(BlockV0::new(children, deps, expiry, content, key))
     }
 
+    /// New block with a compressed data chunk, or a `BlockV0` if it isn't compressed.
+    /// `compression` is the algorithm with the decompressed size of the data chunk
+    pub fn new_with_compression(
+        children: Vec<BlockId>,
+        deps: ObjectDeps,
+        expiry: Option<Timestamp>,
+        content: Vec<u8>,
+        key: Option<SymKey>,
+        compression: Option<(Compression, usize)>,
+    ) -> Block {
+        match compression {
+            None => Block::new(children, deps, expiry, content, key),
+            Some((compression, decompressed_size)) => Block::V1(BlockV1::new(
+                children,
+                deps,
+                expiry,
+                content,
+                key,
+                Some(compression),
+                decompressed_size as u32,
+            )),
+        }
+    }
+
     /// Compute the ID
     pub fn get_id(&self) -> BlockId {
         let ser = serde_bare::to_vec(self).unwrap();
@@ -49,6 +113,10 @@ impl Block {
                 Some(id) => id,
                 None => self.get_id(),
             },
+            Block::V1(b) => match b.id {
+                Some(id) => id,
+                None => self.get_id(),
+            },
         }
     }
 
@@ -56,13 +124,44 @@ impl Block {
     pub fn content(&self) -> &Vec<u8> {
         match self {
             Block::V0(b) => &b.content,
+            Block::V1(b) => &b.content,
+        }
+    }
+
+    /// Get the length of the content.
+    /// Encryption preserves the length, so this is also the length of the serialized BlockContentV0
+    pub fn content_len(&self) -> usize {
+        self.content().len()
+    }
+
+    /// Check that the content length is consistent with the block structure:
+    /// an internal node holds exactly one key per child,
+    /// a leaf holds at least an empty data chunk,
+    /// and no block is larger than the maximum block size, even decompressed.
+    /// Only leaves can be compressed
+    pub fn validate(&self) -> Result<(), LofireError> {
+        let len = self.content_len();
+        let children = self.children().len();
+        let consistent = if children > 0 {
+            // enum tag, varint length, keys
+            self.compression().is_none()
+                && len == 1 + varint_size(children) + children * BLOCK_KEY_SIZE
+        } else {
+            // enum tag, varint length of an empty chunk
+            len >= 2
+        };
+        let decompressed = self.decompressed_size().unwrap_or(len);
+        if !consistent || len > store_max_value_size() || decompressed > store_max_value_size() {
+            return Err(LofireError::InvalidBlock);
         }
+        Ok(())
     }
 
     /// Get the children
     pub fn children(&self) -> &Vec<BlockId> {
         match self {
             Block::V0(b) => &b.children,
+            Block::V1(b) => &b.children,
         }
     }
 
@@ -70,6 +169,7 @@ impl Block {
     pub fn deps(&self) -> &ObjectDeps {
         match self {
             Block::V0(b) => &b.deps,
+            Block::V1(b) => &b.deps,
         }
     }
 
@@ -77,6 +177,7 @@ impl Block {
     pub fn expiry(&self) -> Option<Timestamp> {
         match self {
             Block::V0(b) => b.expiry,
+            Block::V1(b) => b.expiry,
         }
     }
 
@@ -86,6 +187,10 @@ impl Block {
                 b.id = None;
                 b.expiry = expiry
             }
+            Block::V1(b) => {
+                b.id = None;
+                b.expiry = expiry
+            }
         }
     }
 
@@ -93,6 +198,7 @@ impl Block {
     pub fn key(&self) -> Option<SymKey> {
         match self {
             Block::V0(b) => b.key,
+            Block::V1(b) => b.key,
         }
     }
 
@@ -100,6 +206,34 @@ impl Block {
     pub fn set_key(&mut self, key: Option<SymKey>) {
         match self {
             Block::V0(b) => b.key = key,
+            Block::V1(b) => b.key = key,
+        }
+    }
+
+    /// Get the compression of the data chunk of a leaf, if compressed
+    pub fn compression(&self) -> Option<Compression> {
+        match self {
+            Block::V0(_) => None,
+            Block::V1(b) => b.compression,
+        }
+    }
+
+    /// Get the size of the data chunk of a leaf once decompressed, if compressed
+    pub fn decompressed_size(&self) -> Option<usize> {
+        match self {
+            Block::V0(_) => None,
+            Block::V1(b) => b.compression.map(|_| b.decompressed_size as usize),
+        }
+    }
+
+    /// Decompress the decrypted data chunk of the leaf, if compressed.
+    /// It must decompress to the size recorded in the block
+    pub fn decompress_chunk(&self, chunk: Vec<u8>) -> Option<Vec<u8>> {
+        match (self.compression(), self.decompressed_size()) {
+            (Some(compression), Some(size)) => {
+                decompress(compression, &chunk, size).filter(|data| data.len() == size)
+            }
+            _ => Some(chunk),
         }
     }
 }
@@ -3,11 +3,16 @@
 use debug_print::*;
 use std::collections::{HashMap, HashSet};
 
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
 use fastbloom_rs::{BloomFilter as Filter, Membership};
 
+use crate::commit::*;
+use crate::errors::*;
 use crate::object::*;
 use crate::store::*;
 use crate::types::*;
+use crate::utils::*;
 
 impl MemberV0 {
     /// New member
@@ -15,6 +20,7 @@ impl MemberV0 {
         MemberV0 {
             id,
             commit_types,
+            authorized_keys: vec![],
             metadata,
         }
     }
@@ -23,6 +29,18 @@ impl MemberV0 {
     pub fn has_perm(&self, commit_type: CommitType) -> bool {
         self.commit_types.contains(&commit_type)
     }
+
+    /// Authorize a device key to sign commits on behalf of this member
+    pub fn authorize_key(&mut self, key: PubKey) {
+        if !self.authorized_keys.contains(&key) {
+            self.authorized_keys.push(key);
+        }
+    }
+
+    /// Check whether the given device key is authorized for this member
+    pub fn is_authorized_key(&self, key: &PubKey) -> bool {
+        self.authorized_keys.contains(key)
+    }
 }
 
 impl Member {
@@ -37,6 +55,71 @@ impl Member {
             Member::V0(m) => m.has_perm(commit_type),
         }
     }
+
+    /// Check whether the given device key is authorized for this member
+    pub fn is_authorized_key(&self, key: &PubKey) -> bool {
+        match self {
+            Member::V0(m) => m.is_authorized_key(key),
+        }
+    }
+}
+
+/// Ack status of a commit at the head of a branch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeadAcks {
+    /// Commit ID
+    pub id: ObjectId,
+
+    /// Commit type
+    pub commit_type: CommitType,
+
+    /// Number of acks received for the commit
+    pub acks: u32,
+
+    /// Time the commit was received
+    pub received_at: Timestamp,
+}
+
+/// Error of `Branch::verify_log`
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogVerifyError {
+    /// The signature of the commit is not valid
+    InvalidSignature(ObjectId),
+    /// A dep or ack of the commit is not before it in the log
+    MissingDep(ObjectId),
+    /// A head is not in the log
+    MissingHead(ObjectId),
+    /// The hash chain does not match the heads and the entries
+    InvalidHash,
+}
+
+/// Verify every commit reachable from `heads` with `Commit::verify_integrity`,
+/// down to the root of the branch.
+/// Fails with `LofireError::InvalidCommit` and the ID of the first commit that doesn't verify
+pub fn verify_branch(store: &impl RepoStore, heads: &[ObjectRef]) -> Result<(), LofireError> {
+    let mut verified: HashSet<ObjectId> = HashSet::new();
+    let mut to_verify = heads.to_vec();
+    while let Some(commit_ref) = to_verify.pop() {
+        if !verified.insert(commit_ref.id) {
+            continue;
+        }
+        let commit = Commit::load(commit_ref, store)
+            .map_err(|_e| LofireError::InvalidCommit(commit_ref.id))?;
+        commit
+            .verify_integrity(store)
+            .map_err(|_e| LofireError::InvalidCommit(commit_ref.id))?;
+        to_verify.extend(commit.deps_acks());
+    }
+    Ok(())
+}
+
+/// Hash chain of a `SignedLog`
+fn log_hash(heads: &[ObjectId], entries: &[LogEntryV0]) -> Digest {
+    let mut hash = blake3::hash(&serde_bare::to_vec(heads).unwrap());
+    for entry in entries {
+        hash = blake3::keyed_hash(hash.as_bytes(), &serde_bare::to_vec(entry).unwrap());
+    }
+    Digest::Blake3Digest32(*hash.as_bytes())
 }
 
 impl BranchV0 {
@@ -63,6 +146,78 @@ impl BranchV0 {
     }
 }
 
+/// Encrypt the key of a commit published in the pub/sub topic of a branch
+///
+/// ChaCha20 with the key derived from branch_pubkey + branch_secret + publisher_pubkey,
+/// and the commit sequence number of the publisher as nonce.
+/// Only members that have the branch secret can decrypt it with `decrypt_event_key`
+pub fn encrypt_event_key(
+    key: SymKey,
+    seq: u32,
+    publisher_pubkey: PubKey,
+    branch_pubkey: PubKey,
+    branch_secret: SymKey,
+) -> SymKey {
+    let key_material = match (branch_pubkey, branch_secret, publisher_pubkey) {
+        (
+            PubKey::Ed25519PubKey(branch_pubkey),
+            SymKey::ChaCha20Key(branch_secret),
+            PubKey::Ed25519PubKey(publisher_pubkey),
+        ) => [branch_pubkey, branch_secret, publisher_pubkey].concat(),
+    };
+    let cipher_key = blake3::derive_key(
+        "LoFiRe Event ObjectRef ChaCha20 key",
+        key_material.as_slice(),
+    );
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&seq.to_le_bytes());
+    let mut cipher = ChaCha20::new((&cipher_key).into(), &nonce.into());
+    match key {
+        SymKey::ChaCha20Key(mut k) => {
+            cipher.apply_keystream(&mut k);
+            SymKey::ChaCha20Key(k)
+        }
+    }
+}
+
+/// Decrypt the key of a commit published in the pub/sub topic of a branch
+pub fn decrypt_event_key(
+    key: SymKey,
+    seq: u32,
+    publisher_pubkey: PubKey,
+    branch_pubkey: PubKey,
+    branch_secret: SymKey,
+) -> SymKey {
+    // ChaCha20 decryption is the same operation as encryption
+    encrypt_event_key(key, seq, publisher_pubkey, branch_pubkey, branch_secret)
+}
+
+/// Compute the publisher hash of a branch member, as sent in events
+///
+/// BLAKE3 keyed hash of the member pubkey, with the key derived from
+/// repo_pubkey + repo_secret + branch_pubkey + branch_secret
+pub fn compute_publisher_hash(
+    member_pubkey: PubKey,
+    repo_pubkey: PubKey,
+    repo_secret: SymKey,
+    branch_pubkey: PubKey,
+    branch_secret: SymKey,
+) -> Digest {
+    let key_material = match (repo_pubkey, repo_secret, branch_pubkey, branch_secret) {
+        (
+            PubKey::Ed25519PubKey(repo_pubkey),
+            SymKey::ChaCha20Key(repo_secret),
+            PubKey::Ed25519PubKey(branch_pubkey),
+            SymKey::ChaCha20Key(branch_secret),
+        ) => [repo_pubkey, repo_secret, branch_pubkey, branch_secret].concat(),
+    };
+    let key = blake3::derive_key("LoFiRe Event Publisher BLAKE3 key", key_material.as_slice());
+    let member = match member_pubkey {
+        PubKey::Ed25519PubKey(pk) => pk,
+    };
+    Digest::Blake3Digest32(*blake3::keyed_hash(&key, &member).as_bytes())
+}
+
 impl Branch {
     pub fn new(
         id: PubKey,
@@ -79,6 +234,62 @@ impl Branch {
         ))
     }
 
+    /// Reconstruct a branch from its branch-creation commit, the root of its DAG,
+    /// to apply its members, quorum and ack delay to the other commits of the branch.
+    /// Returns `LofireError::InvalidCommit` if the commit can't be loaded or doesn't create a branch,
+    /// and `LofireError::MissingBody` if its body is not in the store
+    pub fn from_commit(
+        commit_ref: ObjectRef,
+        store: &impl RepoStore,
+    ) -> Result<Branch, LofireError> {
+        let commit = Commit::load(commit_ref, store)
+            .map_err(|_e| LofireError::InvalidCommit(commit_ref.id))?;
+        let body = commit.load_body(store).map_err(|e| match e {
+            CommitLoadError::MissingBlocks(_) => LofireError::MissingBody,
+            _ => LofireError::SerializationError,
+        })?;
+        match body {
+            CommitBody::Branch(branch) => Ok(branch),
+            _ => Err(LofireError::InvalidCommit(commit_ref.id)),
+        }
+    }
+
+    /// Get the branch public key ID
+    pub fn id(&self) -> PubKey {
+        match self {
+            Branch::V0(b) => b.id,
+        }
+    }
+
+    /// Get the branch secret
+    pub fn secret(&self) -> SymKey {
+        match self {
+            Branch::V0(b) => b.secret,
+        }
+    }
+
+    /// Create an object of the branch, such as a commit body
+    ///
+    /// The convergence key is derived from the branch pubkey and secret instead of the repository secret,
+    /// so that members without the branch secret cannot derive the keys of its objects,
+    /// and the same content in two branches results in different objects
+    pub fn new_object(
+        &self,
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        block_size: usize,
+    ) -> Object {
+        Object::new(content, deps, expiry, block_size, self.id(), self.secret())
+    }
+
+    /// Get the pub/sub topic of the branch
+    pub fn topic(&self) -> PubKey {
+        match self {
+            Branch::V0(b) => b.topic,
+        }
+    }
+
     /// Get member by ID
     pub fn get_member(&self, id: &PubKey) -> Option<&MemberV0> {
         match self {
@@ -93,15 +304,207 @@ impl Branch {
         None
     }
 
+    /// Get the commit types a member is allowed to publish in the branch,
+    /// none if it is not a member.
+    /// Lets a client check `has_perm` before sending a commit the broker would reject
+    pub fn commit_types(&self, id: &PubKey) -> Vec<CommitType> {
+        match self.get_member(id) {
+            Some(m) => m.commit_types.clone(),
+            None => vec![],
+        }
+    }
+
+    /// Validate a commit against the access control of the branch:
+    /// the author must be a member, allowed to publish the commit type of `body`,
+    /// and if signed by a device key, the key must be one of the author's `authorized_keys`.
+    /// For the commit types with a quorum, `acks` (the acks received for the commit)
+    /// must reach the quorum.
+    /// The body is not part of the commit, it has to be loaded with `Commit::load_body`
+    pub fn validate_commit(
+        &self,
+        commit: &Commit,
+        body: &CommitBody,
+        acks: u32,
+    ) -> Result<(), LofireError> {
+        let member = self
+            .get_member(&commit.content().author)
+            .ok_or(LofireError::NotMember)?;
+        if let Some(device) = commit.device() {
+            if !member.is_authorized_key(device) {
+                return Err(LofireError::PermissionDenied);
+            }
+        }
+        let commit_type = body.to_type();
+        if !member.has_perm(commit_type) {
+            return Err(LofireError::PermissionDenied);
+        }
+        if acks < self.quorum(commit_type) {
+            return Err(LofireError::QuorumNotReached);
+        }
+        Ok(())
+    }
+
+    /// Get member by the publisher hash of an event
+    ///
+    /// Returns None if the publisher is not a member of the branch
+    pub fn get_member_by_publisher(
+        &self,
+        publisher: &Digest,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Option<&MemberV0> {
+        match self {
+            Branch::V0(b) => b.members.iter().find(|m| {
+                compute_publisher_hash(m.id, repo_pubkey, repo_secret, b.id, b.secret) == *publisher
+            }),
+        }
+    }
+
+    /// Get number of acks required for the given commit type
+    pub fn quorum(&self, commit_type: CommitType) -> u32 {
+        match self {
+            Branch::V0(b) => *b.quorum.get(&commit_type).unwrap_or(&0),
+        }
+    }
+
+    /// Get delay before a commit can be finalized
+    pub fn ack_delay(&self) -> RelTime {
+        match self {
+            Branch::V0(b) => b.ack_delay,
+        }
+    }
+
+    /// Check whether a commit can be considered final
+    ///
+    /// Requires the quorum of acks for the commit type to be reached,
+    /// and at least `ack_delay` to have elapsed since the commit was received,
+    /// to leave time for late dissent.
+    pub fn is_quorum_reached(
+        &self,
+        commit_type: CommitType,
+        acks: u32,
+        received_at: Timestamp,
+        clock: &impl Clock,
+    ) -> bool {
+        acks >= self.quorum(commit_type) && clock.now() >= received_at + self.ack_delay()
+    }
+
+    /// Get the IDs of heads that can be considered final
+    pub fn finalized_heads(&self, heads: &[HeadAcks], clock: &impl Clock) -> Vec<ObjectId> {
+        heads
+            .iter()
+            .filter(|h| self.is_quorum_reached(h.commit_type, h.acks, h.received_at, clock))
+            .map(|h| h.id)
+            .collect()
+    }
+
+    /// Pick the canonical head among concurrent heads, e.g. to name a snapshot or show a default view.
+    ///
+    /// The canonical head is the head with the lowest ID, comparing the digests byte by byte.
+    /// The choice only depends on the set of heads, not on their order or on who computes it.
+    /// Returns None if there are no heads
+    pub fn canonical_head(heads: &[ObjectId]) -> Option<ObjectId> {
+        heads.iter().min().copied()
+    }
+
+    /// Create a snapshot of the branch at the given heads
+    ///
+    /// The snapshot is a commit signed by the author, depending on the heads,
+    /// with a `Snapshot` body listing the heads and the given repository metadata.
+    /// Both objects are saved in the store.
+    /// Returns the reference of the snapshot commit, that can be shared with an `ObjectLink`
+    pub fn snapshot(
+        branch_ref: ObjectRef,
+        author_privkey: PrivKey,
+        author_pubkey: PubKey,
+        seq: u32,
+        heads: Vec<ObjectRef>,
+        metadata: Vec<u8>,
+        max_object_size: usize,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> Result<ObjectRef, StorageError> {
+        let head_ids: Vec<ObjectId> = heads.iter().map(|h| h.id).collect();
+        let body = CommitBody::Snapshot(Snapshot::V0(SnapshotV0 {
+            heads: head_ids.clone(),
+            content: metadata,
+        }));
+        let body_obj = Object::new(
+            ObjectContent::CommitBody(body),
+            vec![],
+            None,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+        );
+        body_obj.save(store)?;
+        let commit = Commit::new(
+            author_privkey,
+            author_pubkey,
+            seq,
+            branch_ref,
+            heads,
+            vec![],
+            vec![],
+            vec![],
+            body_obj.reference().unwrap(),
+            None,
+        )
+        .map_err(|_e| StorageError::InvalidValue)?;
+        let commit_obj = Object::new(
+            ObjectContent::Commit(commit),
+            head_ids,
+            None,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+        );
+        commit_obj.save(store)?;
+        Ok(commit_obj.reference().unwrap())
+    }
+
+    /// Load a snapshot of the branch and return its heads,
+    /// to be used as `our_heads` of a `sync_req` that stops at the snapshot
+    ///
+    /// The snapshot commit must be signed by a member with the permission to publish snapshots
+    pub fn from_snapshot(
+        &self,
+        snapshot_ref: ObjectRef,
+        store: &impl RepoStore,
+    ) -> Result<Vec<WeakObjectRef>, CommitVerifyError> {
+        let commit =
+            Commit::load(snapshot_ref, store).map_err(|e| CommitVerifyError::DepLoadError(e))?;
+        commit
+            .verify_sig()
+            .map_err(|_e| CommitVerifyError::InvalidSignature)?;
+        let body = commit
+            .load_body(store)
+            .map_err(|e| CommitVerifyError::BodyLoadError(e))?;
+        commit.verify_perm(&body, self)?;
+        match body {
+            CommitBody::Snapshot(Snapshot::V0(s)) => {
+                Ok(s.heads.into_iter().map(|id| WeakObjectRef { id }).collect())
+            }
+            _ => Err(CommitVerifyError::BodyLoadError(
+                CommitLoadError::DeserializeError,
+            )),
+        }
+    }
+
     /// Branch sync request from another peer
     ///
-    /// Return ObjectIds to send
+    /// The DAG is traversed using `WeakObjectRef`s only, no keys are needed.
+    /// The commits in `their_filter` are not sent, unless one of their dependencies is sent:
+    /// as the requester has the dependencies of all the commits it has,
+    /// such a commit is a false positive of the filter, and skipping it would break the DAG.
+    /// Return references of the Objects to send
     pub fn sync_req(
-        our_heads: &[ObjectId],
-        their_heads: &[ObjectId],
+        our_heads: &[WeakObjectRef],
+        their_heads: &[WeakObjectRef],
         their_filter: &BloomFilter,
         store: &impl RepoStore,
-    ) -> Result<Vec<ObjectId>, ObjectParseError> {
+    ) -> Result<Vec<WeakObjectRef>, ObjectParseError> {
         //debug_println!(">> sync_req");
         //debug_println!("   our_heads: {:?}", our_heads);
         //debug_println!("   their_heads: {:?}", their_heads);
@@ -111,9 +514,10 @@ impl Branch {
         fn load_branch(
             cobj: &Object,
             store: &impl RepoStore,
-            their_heads: &[ObjectId],
+            their_heads: &[WeakObjectRef],
             visited: &mut HashSet<ObjectId>,
             missing: &mut HashSet<ObjectId>,
+            deps: &mut HashMap<ObjectId, Vec<ObjectId>>,
         ) -> Result<bool, ObjectParseError> {
             //debug_println!(">>> load_branch: {}", cobj.id());
             let id = cobj.id();
@@ -123,16 +527,17 @@ impl Branch {
             //debug_println!("     deps: {:?}", cobj.deps());
 
             // check if this commit object is present in their_heads
-            let mut their_head_found = their_heads.contains(&id);
+            let mut their_head_found = their_heads.contains(&WeakObjectRef { id });
 
             // load deps, stop at the root or if this is a commit object from their_heads
             if !is_root && !their_head_found {
                 visited.insert(id);
+                deps.insert(id, cobj.deps().clone());
                 for id in cobj.deps() {
                     match Object::load(*id, None, store) {
                         Ok(o) => {
                             if !visited.contains(id) {
-                                if load_branch(&o, store, their_heads, visited, missing)? {
+                                if load_branch(&o, store, their_heads, visited, missing, deps)? {
                                     their_head_found = true;
                                 }
                             }
@@ -149,6 +554,8 @@ impl Branch {
 
         // missing commits from our branch
         let mut missing = HashSet::new();
+        // dependencies of the visited commits
+        let mut deps = HashMap::new();
         // our commits
         let mut ours = HashSet::new();
         // their commits
@@ -156,20 +563,27 @@ impl Branch {
 
         // collect all commits reachable from our_heads
         // up to the root or until encountering a commit from their_heads
-        for id in our_heads {
-            let cobj = Object::load(*id, None, store)?;
+        for head in our_heads {
+            let cobj = Object::load(head.id, None, store)?;
             let mut visited = HashSet::new();
-            let their_head_found =
-                load_branch(&cobj, store, their_heads, &mut visited, &mut missing)?;
+            let their_head_found = load_branch(
+                &cobj,
+                store,
+                their_heads,
+                &mut visited,
+                &mut missing,
+                &mut deps,
+            )?;
             //debug_println!("<<< load_branch: {}", their_head_found);
             ours.extend(visited); // add if one of their_heads found
         }
 
         // collect all commits reachable from their_heads
-        for id in their_heads {
-            let cobj = Object::load(*id, None, store)?;
+        for head in their_heads {
+            let cobj = Object::load(head.id, None, store)?;
             let mut visited = HashSet::new();
-            let their_head_found = load_branch(&cobj, store, &[], &mut visited, &mut missing)?;
+            let their_head_found =
+                load_branch(&cobj, store, &[], &mut visited, &mut missing, &mut deps)?;
             //debug_println!("<<< load_branch: {}", their_head_found);
             theirs.extend(visited); // add if one of their_heads found
         }
@@ -180,19 +594,168 @@ impl Branch {
         //debug_println!("!! theirs: {:?}", theirs);
         //debug_println!("!! result: {:?}", result);
 
-        // remove their_commits from result
+        /// Whether the requester has the commit: it is not one of the commits to send,
+        /// or it is in the filter and the requester has all its dependencies
+        fn is_known(
+            id: &ObjectId,
+            result: &HashSet<ObjectId>,
+            deps: &HashMap<ObjectId, Vec<ObjectId>>,
+            filter: &Filter,
+            known: &mut HashMap<ObjectId, bool>,
+        ) -> bool {
+            if !result.contains(id) {
+                return true;
+            }
+            if let Some(k) = known.get(id) {
+                return *k;
+            }
+            let in_filter = match id {
+                Digest::Blake3Digest32(d) => filter.contains(d),
+            };
+            let k = in_filter
+                && deps.get(id).map_or(true, |ids| {
+                    ids.iter()
+                        .all(|dep| is_known(dep, result, deps, filter, known))
+                });
+            known.insert(*id, k);
+            k
+        }
+
+        // remove their_commits from result, keeping the false positives of the filter
         let filter = Filter::from_u8_array(their_filter.f.as_slice(), their_filter.k.into());
-        for id in result.clone() {
-            match id {
-                Digest::Blake3Digest32(d) => {
-                    if filter.contains(&d) {
-                        result.remove(&id);
-                    }
+        let mut known = HashMap::new();
+        let candidates = result.clone();
+        result.retain(|id| !is_known(id, &candidates, &deps, &filter, &mut known));
+        //debug_println!("!! result filtered: {:?}", result);
+        Ok(result.into_iter().map(|id| WeakObjectRef { id }).collect())
+    }
+
+    /// Export the commit log of the branch from the given heads down to the root, for audit
+    ///
+    /// The commits are ordered so that the deps and acks of a commit come before it,
+    /// the traversal following heads, deps and acks in ID order so that the log
+    /// only depends on the heads.
+    /// The log can be checked without the store with `verify_log`
+    pub fn export_log(
+        heads: &[ObjectRef],
+        store: &impl RepoStore,
+    ) -> Result<SignedLog, CommitLoadError> {
+        fn load_log(
+            commit_ref: ObjectRef,
+            store: &impl RepoStore,
+            visited: &mut HashSet<ObjectId>,
+            entries: &mut Vec<LogEntryV0>,
+        ) -> Result<(), CommitLoadError> {
+            if !visited.insert(commit_ref.id) {
+                return Ok(());
+            }
+            let commit = Commit::load(commit_ref, store)?;
+            let mut deps = commit.deps_acks();
+            deps.sort_by_key(|r| r.id);
+            for dep in deps {
+                load_log(dep, store, visited, entries)?;
+            }
+            entries.push(LogEntryV0 {
+                id: commit_ref.id,
+                commit,
+            });
+            Ok(())
+        }
+
+        let mut heads = heads.to_vec();
+        heads.sort_by_key(|r| r.id);
+        heads.dedup_by_key(|r| r.id);
+        let mut visited = HashSet::new();
+        let mut entries = vec![];
+        for head in heads.iter() {
+            load_log(*head, store, &mut visited, &mut entries)?;
+        }
+        let heads: Vec<ObjectId> = heads.iter().map(|r| r.id).collect();
+        let hash = log_hash(&heads, &entries);
+        Ok(SignedLog::V0(SignedLogV0 {
+            heads,
+            entries,
+            hash,
+        }))
+    }
+
+    /// Verify a log exported with `export_log`
+    ///
+    /// Checks the signature of every commit, that the deps and acks of each commit
+    /// come before it, that the heads are in the log, and the hash chain.
+    /// Permissions are not checked, as the commit bodies are not in the log
+    pub fn verify_log(log: &SignedLog) -> Result<(), LogVerifyError> {
+        let log = match log {
+            SignedLog::V0(l) => l,
+        };
+        let mut seen = HashSet::new();
+        for entry in log.entries.iter() {
+            entry
+                .commit
+                .verify_sig()
+                .map_err(|_e| LogVerifyError::InvalidSignature(entry.id))?;
+            if let Some(dep) = entry
+                .commit
+                .deps_acks()
+                .iter()
+                .find(|dep| !seen.contains(&dep.id))
+            {
+                return Err(LogVerifyError::MissingDep(dep.id));
+            }
+            seen.insert(entry.id);
+        }
+        if let Some(head) = log.heads.iter().find(|h| !seen.contains(*h)) {
+            return Err(LogVerifyError::MissingHead(*head));
+        }
+        if log_hash(&log.heads, &log.entries) != log.hash {
+            return Err(LogVerifyError::InvalidHash);
+        }
+        Ok(())
+    }
+
+    /// Make `candidate_heads` the heads of the branch, once all their commits are verified
+    ///
+    /// Every commit reachable from the candidates is verified, down to the root
+    /// or to the current `heads`, which were verified when they were applied:
+    /// signature, creation time, permissions, and presence of its body and dependencies.
+    /// The only Branch commit accepted is `root`, the known branch-creation commit of this branch,
+    /// any other copy of the branch body is rejected.
+    /// On error `heads` is left unchanged, otherwise the replaced heads are returned
+    pub fn apply_sync(
+        &self,
+        root: &ObjectRef,
+        heads: &mut Vec<ObjectRef>,
+        candidate_heads: Vec<ObjectRef>,
+        store: &impl RepoStore,
+    ) -> Result<Vec<ObjectRef>, CommitVerifyError> {
+        let mut verified: HashSet<ObjectId> = heads.iter().map(|h| h.id).collect();
+        let mut to_verify = candidate_heads.clone();
+        while let Some(commit_ref) = to_verify.pop() {
+            if !verified.insert(commit_ref.id) {
+                continue;
+            }
+            let commit =
+                Commit::load(commit_ref, store).map_err(|e| CommitVerifyError::DepLoadError(e))?;
+            commit
+                .verify_sig()
+                .map_err(|_e| CommitVerifyError::InvalidSignature)?;
+            commit
+                .verify_timestamp(&SystemClock)
+                .map_err(|_e| CommitVerifyError::InvalidTimestamp)?;
+            let body = commit
+                .load_body(store)
+                .map_err(|e| CommitVerifyError::BodyLoadError(e))?;
+            match body {
+                CommitBody::Branch(b) if commit_ref.id == root.id && b.id() == self.id() => {
+                    continue
                 }
+                CommitBody::Branch(_) => return Err(CommitVerifyError::PermissionDenied),
+                _ => commit.verify_perm(&body, self)?,
             }
+            to_verify.extend(commit.deps_acks());
         }
-        //debug_println!("!! result filtered: {:?}", result);
-        Ok(Vec::from_iter(result))
+        debug_println!("applying {} verified heads", candidate_heads.len());
+        Ok(std::mem::replace(heads, candidate_heads))
     }
 }
 
@@ -208,192 +771,319 @@ mod test {
     use crate::object::*;
     use crate::repo;
     use crate::store::*;
+    use crate::utils::*;
 
-    #[test]
-    pub fn test_branch() {
-        fn add_obj(
-            content: ObjectContent,
-            deps: Vec<ObjectId>,
-            expiry: Option<Timestamp>,
-            repo_pubkey: PubKey,
-            repo_secret: SymKey,
-            store: &mut impl RepoStore,
-        ) -> ObjectRef {
-            let max_object_size = 4000;
-            let obj = Object::new(
-                content,
-                deps,
-                expiry,
-                max_object_size,
-                repo_pubkey,
-                repo_secret,
-            );
-            println!(">>> add_obj");
-            println!("     id: {:?}", obj.id());
-            println!("     deps: {:?}", obj.deps());
-            obj.save(store).unwrap();
-            obj.reference().unwrap()
-        }
-
-        fn add_commit(
-            branch: ObjectRef,
-            author_privkey: PrivKey,
-            author_pubkey: PubKey,
-            seq: u32,
-            deps: Vec<ObjectRef>,
-            acks: Vec<ObjectRef>,
-            body_ref: ObjectRef,
-            repo_pubkey: PubKey,
-            repo_secret: SymKey,
-            store: &mut impl RepoStore,
-        ) -> ObjectRef {
-            let mut obj_deps: Vec<ObjectId> = vec![];
-            obj_deps.extend(deps.iter().map(|r| r.id));
-            obj_deps.extend(acks.iter().map(|r| r.id));
-
-            let obj_ref = ObjectRef {
-                id: ObjectId::Blake3Digest32([1; 32]),
-                key: SymKey::ChaCha20Key([2; 32]),
-            };
-            let refs = vec![obj_ref];
-            let metadata = vec![5u8; 55];
-            let expiry = None;
+    fn add_obj(
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> ObjectRef {
+        let max_object_size = 4000;
+        let obj = Object::new(
+            content,
+            deps,
+            expiry,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+        );
+        obj.save(store).unwrap();
+        obj.reference().unwrap()
+    }
 
-            let commit = Commit::new(
-                author_privkey,
-                author_pubkey,
-                seq,
-                branch,
-                deps,
-                acks,
-                refs,
-                metadata,
-                body_ref,
-                expiry,
-            )
-            .unwrap();
-            //println!("commit: {:?}", commit);
-            add_obj(
-                ObjectContent::Commit(commit),
-                obj_deps,
-                expiry,
-                repo_pubkey,
-                repo_secret,
-                store,
-            )
-        }
+    /// Save a commit, as an object depending on the objects of its deps and acks
+    fn save_commit(
+        commit: Commit,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> ObjectRef {
+        let mut obj_deps: Vec<ObjectId> = vec![];
+        obj_deps.extend(commit.deps().iter().map(|r| r.id));
+        obj_deps.extend(commit.acks().iter().map(|r| r.id));
+        add_obj(
+            ObjectContent::Commit(commit),
+            obj_deps,
+            None,
+            repo_pubkey,
+            repo_secret,
+            store,
+        )
+    }
 
-        fn add_body_branch(
-            branch: Branch,
-            repo_pubkey: PubKey,
-            repo_secret: SymKey,
-            store: &mut impl RepoStore,
-        ) -> ObjectRef {
-            let deps = vec![];
-            let expiry = None;
-            let body = CommitBody::Branch(branch);
-            //println!("body: {:?}", body);
-            add_obj(
-                ObjectContent::CommitBody(body),
-                deps,
-                expiry,
-                repo_pubkey,
-                repo_secret,
-                store,
-            )
-        }
+    fn add_commit(
+        branch: ObjectRef,
+        author_privkey: PrivKey,
+        author_pubkey: PubKey,
+        seq: u32,
+        deps: Vec<ObjectRef>,
+        acks: Vec<ObjectRef>,
+        body_ref: ObjectRef,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> ObjectRef {
+        let obj_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([1; 32]),
+            key: SymKey::ChaCha20Key([2; 32]),
+        };
+        let refs = vec![obj_ref];
+        let metadata = vec![5u8; 55];
+        let expiry = None;
 
-        fn add_body_trans(
-            deps: Vec<ObjectId>,
-            repo_pubkey: PubKey,
-            repo_secret: SymKey,
-            store: &mut impl RepoStore,
-        ) -> ObjectRef {
-            let expiry = None;
-            let content = [7u8; 777].to_vec();
-            let body = CommitBody::Transaction(Transaction::V0(content));
-            //println!("body: {:?}", body);
-            add_obj(
-                ObjectContent::CommitBody(body),
-                deps,
-                expiry,
-                repo_pubkey,
-                repo_secret,
-                store,
-            )
-        }
+        let commit = Commit::new(
+            author_privkey,
+            author_pubkey,
+            seq,
+            branch,
+            deps,
+            acks,
+            refs,
+            metadata,
+            body_ref,
+            expiry,
+        )
+        .unwrap();
+        //println!("commit: {:?}", commit);
+        save_commit(commit, repo_pubkey, repo_secret, store)
+    }
 
-        fn add_body_ack(
-            deps: Vec<ObjectId>,
-            repo_pubkey: PubKey,
-            repo_secret: SymKey,
-            store: &mut impl RepoStore,
-        ) -> ObjectRef {
-            let expiry = None;
-            let body = CommitBody::Ack(Ack::V0());
-            //println!("body: {:?}", body);
-            add_obj(
-                ObjectContent::CommitBody(body),
-                deps,
-                expiry,
-                repo_pubkey,
-                repo_secret,
-                store,
-            )
+    fn add_body_branch(
+        branch: Branch,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> ObjectRef {
+        let deps = vec![];
+        let expiry = None;
+        let body = CommitBody::Branch(branch);
+        //println!("body: {:?}", body);
+        add_obj(
+            ObjectContent::CommitBody(body),
+            deps,
+            expiry,
+            repo_pubkey,
+            repo_secret,
+            store,
+        )
+    }
+
+    fn add_body_trans(
+        deps: Vec<ObjectId>,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> ObjectRef {
+        let expiry = None;
+        let content = [7u8; 777].to_vec();
+        let body = CommitBody::Transaction(Transaction::V0(content));
+        //println!("body: {:?}", body);
+        add_obj(
+            ObjectContent::CommitBody(body),
+            deps,
+            expiry,
+            repo_pubkey,
+            repo_secret,
+            store,
+        )
+    }
+
+    fn add_body_ack(
+        deps: Vec<ObjectId>,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        store: &mut impl RepoStore,
+    ) -> ObjectRef {
+        let expiry = None;
+        let body = CommitBody::Ack(Ack::V0());
+        //println!("body: {:?}", body);
+        add_obj(
+            ObjectContent::CommitBody(body),
+            deps,
+            expiry,
+            repo_pubkey,
+            repo_secret,
+            store,
+        )
+    }
+
+    /// Bloom filter of the commits
+    fn commit_filter(commits: &[ObjectRef]) -> BloomFilter {
+        let mut filter = Filter::new(FilterBuilder::new(10, 0.01));
+        for commit_ref in commits {
+            match commit_ref.id {
+                ObjectId::Blake3Digest32(d) => filter.add(&d),
+            }
         }
+        let cfg = filter.config();
+        BloomFilter {
+            k: cfg.hashes,
+            f: filter.get_u8_array().to_vec(),
+        }
+    }
 
-        let mut store = HashMapRepoStore::new();
-        let mut rng = OsRng {};
+    /// A branch with one member, and the commits:
+    ///
+    /// ```text
+    ///      br
+    ///     /  \
+    ///   t1   t2
+    ///   / \  / \
+    ///  a3  t4<--t5-->(t1)
+    ///      / \
+    ///    a6   a7
+    /// ```
+    struct TestBranch {
+        store: HashMapRepoStore,
+        branch: Branch,
+        member_privkey: PrivKey,
+        member_pubkey: PubKey,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+        branch_body: ObjectRef,
+        ack_body: ObjectRef,
+        br: ObjectRef,
+        t1: ObjectRef,
+        t2: ObjectRef,
+        a3: ObjectRef,
+        t4: ObjectRef,
+        t5: ObjectRef,
+        a6: ObjectRef,
+        a7: ObjectRef,
+    }
 
-        // repo
+    impl TestBranch {
+        fn new() -> TestBranch {
+            let mut store = HashMapRepoStore::new();
+            let mut rng = OsRng {};
 
-        let repo_keypair: Keypair = Keypair::generate(&mut rng);
-        println!(
-            "repo private key: ({}) {:?}",
-            repo_keypair.secret.as_bytes().len(),
-            repo_keypair.secret.as_bytes()
-        );
-        println!(
-            "repo public key: ({}) {:?}",
-            repo_keypair.public.as_bytes().len(),
-            repo_keypair.public.as_bytes()
-        );
-        let _repo_privkey = PrivKey::Ed25519PrivKey(repo_keypair.secret.to_bytes());
-        let repo_pubkey = PubKey::Ed25519PubKey(repo_keypair.public.to_bytes());
-        let repo_secret = SymKey::ChaCha20Key([9; 32]);
+            // repo
 
-        // branch
+            let repo_keypair: Keypair = Keypair::generate(&mut rng);
+            let repo_pubkey = PubKey::Ed25519PubKey(repo_keypair.public.to_bytes());
+            let repo_secret = SymKey::ChaCha20Key([9; 32]);
 
-        let branch_keypair: Keypair = Keypair::generate(&mut rng);
-        println!("branch public key: {:?}", branch_keypair.public.as_bytes());
-        let branch_pubkey = PubKey::Ed25519PubKey(branch_keypair.public.to_bytes());
+            // branch
 
-        let member_keypair: Keypair = Keypair::generate(&mut rng);
-        println!("member public key: {:?}", member_keypair.public.as_bytes());
-        let member_privkey = PrivKey::Ed25519PrivKey(member_keypair.secret.to_bytes());
-        let member_pubkey = PubKey::Ed25519PubKey(member_keypair.public.to_bytes());
+            let branch_keypair: Keypair = Keypair::generate(&mut rng);
+            let branch_pubkey = PubKey::Ed25519PubKey(branch_keypair.public.to_bytes());
 
-        let metadata = [66u8; 64].to_vec();
-        let commit_types = vec![CommitType::Ack, CommitType::Transaction];
-        let secret = SymKey::ChaCha20Key([0; 32]);
+            let member_keypair: Keypair = Keypair::generate(&mut rng);
+            let member_privkey = PrivKey::Ed25519PrivKey(member_keypair.secret.to_bytes());
+            let member_pubkey = PubKey::Ed25519PubKey(member_keypair.public.to_bytes());
 
-        let member = MemberV0::new(member_pubkey, commit_types, metadata.clone());
-        let members = vec![member];
-        let mut quorum = HashMap::new();
-        quorum.insert(CommitType::Transaction, 3);
-        let ack_delay = RelTime::Minutes(3);
-        let tags = [99u8; 32].to_vec();
-        let branch = Branch::new(
-            branch_pubkey,
-            branch_pubkey,
-            secret,
-            members,
-            quorum,
-            ack_delay,
-            tags,
-            metadata,
-        );
-        //println!("branch: {:?}", branch);
+            let metadata = [66u8; 64].to_vec();
+            let commit_types = vec![CommitType::Ack, CommitType::Transaction];
+            let secret = SymKey::ChaCha20Key([0; 32]);
+
+            let member = MemberV0::new(member_pubkey, commit_types, metadata.clone());
+            let members = vec![member];
+            let mut quorum = HashMap::new();
+            quorum.insert(CommitType::Transaction, 3);
+            let ack_delay = RelTime::Minutes(3);
+            let tags = [99u8; 32].to_vec();
+            let branch = Branch::new(
+                branch_pubkey,
+                branch_pubkey,
+                secret,
+                members,
+                quorum,
+                ack_delay,
+                tags,
+                metadata,
+            );
+
+            // commit bodies
+
+            let branch_body = add_body_branch(
+                branch.clone(),
+                repo_pubkey.clone(),
+                repo_secret.clone(),
+                &mut store,
+            );
+            let ack_body = add_body_ack(vec![], repo_pubkey, repo_secret, &mut store);
+            let trans_body = add_body_trans(vec![], repo_pubkey, repo_secret, &mut store);
+
+            // create & add commits to store
+
+            let mut commit =
+                |seq: u32, deps: Vec<ObjectRef>, acks: Vec<ObjectRef>, body_ref: ObjectRef| {
+                    add_commit(
+                        branch_body,
+                        member_privkey,
+                        member_pubkey,
+                        seq,
+                        deps,
+                        acks,
+                        body_ref,
+                        repo_pubkey,
+                        repo_secret,
+                        &mut store,
+                    )
+                };
+            let br = commit(0, vec![], vec![], branch_body);
+            let t1 = commit(1, vec![br], vec![], trans_body);
+            let t2 = commit(2, vec![br], vec![], trans_body);
+            let a3 = commit(3, vec![t1], vec![], ack_body);
+            let t4 = commit(4, vec![t2], vec![t1], trans_body);
+            let t5 = commit(5, vec![t1, t2], vec![t4], trans_body);
+            let a6 = commit(6, vec![t4], vec![], ack_body);
+            let a7 = commit(7, vec![t4], vec![], ack_body);
+
+            TestBranch {
+                store,
+                branch,
+                member_privkey,
+                member_pubkey,
+                repo_pubkey,
+                repo_secret,
+                branch_body,
+                ack_body,
+                br,
+                t1,
+                t2,
+                a3,
+                t4,
+                t5,
+                a6,
+                a7,
+            }
+        }
+
+        /// Adds an ack commit of the member to the store
+        fn add_ack(&mut self, seq: u32, deps: Vec<ObjectRef>) -> ObjectRef {
+            add_commit(
+                self.branch_body,
+                self.member_privkey,
+                self.member_pubkey,
+                seq,
+                deps,
+                vec![],
+                self.ack_body,
+                self.repo_pubkey,
+                self.repo_secret,
+                &mut self.store,
+            )
+        }
+    }
+
+    #[test]
+    pub fn test_branch() {
+        let TestBranch {
+            store,
+            branch,
+            br,
+            t1,
+            t2,
+            a3,
+            t5,
+            a6,
+            a7,
+            ..
+        } = TestBranch::new();
 
         fn print_branch() {
             println!("branch deps/acks:");
@@ -408,26 +1098,282 @@ mod test {
             println!("");
         }
 
+        let c7 = Commit::load(a7, &store).unwrap();
+        c7.verify(&branch, &store).unwrap();
+
+        let their_commits = commit_filter(&[br, t1, t2, a3, t5, a6]);
+
         print_branch();
+        println!(">> sync_req");
+        println!("   our_heads: [a3, t5, a6, a7]");
+        println!("   their_heads: [a3, t5]");
+        println!("   their_commits: [br, t1, t2, a3, t5, a6]");
 
-        // commit bodies
+        let ids = Branch::sync_req(
+            &[a3.into(), t5.into(), a6.into(), a7.into()],
+            &[a3.into(), t5.into()],
+            &their_commits,
+            &store,
+        )
+        .unwrap();
 
-        let branch_body = add_body_branch(
-            branch.clone(),
-            repo_pubkey.clone(),
-            repo_secret.clone(),
-            &mut store,
+        assert_eq!(ids.len(), 1);
+        assert!(ids.contains(&a7.into()));
+    }
+
+    #[test]
+    pub fn test_sync_req_false_positive() {
+        let TestBranch {
+            store,
+            br,
+            t1,
+            t2,
+            t4,
+            a6,
+            a7,
+            ..
+        } = TestBranch::new();
+
+        // t4 is a false positive of the filter: the requester has t1, but neither t2 nor t4.
+        // t4 depends on t2 that is sent, so t4 is sent as well
+        let colliding = commit_filter(&[t1, t4]);
+        let ids =
+            Branch::sync_req(&[a6.into(), a7.into()], &[br.into()], &colliding, &store).unwrap();
+        assert_eq!(ids.len(), 4);
+        for commit_ref in [t2, t4, a6, a7] {
+            assert!(ids.contains(&commit_ref.into()));
+        }
+        assert!(!ids.contains(&t1.into()));
+    }
+
+    #[test]
+    pub fn test_weak_reference() {
+        let TestBranch { store, a7, .. } = TestBranch::new();
+
+        // a weak reference is enough to load the blocks, but not to decrypt the content
+        let weak_a7: WeakObjectRef = a7.into();
+        let obj = Object::load(weak_a7.id, None, &store).unwrap();
+        assert_eq!(obj.weak_reference(), weak_a7);
+        assert!(obj.reference().is_none());
+        match obj.content() {
+            Err(ObjectParseError::MissingRootKey) => (),
+            _ => panic!("weak reference should not decrypt"),
+        }
+    }
+
+    #[test]
+    pub fn test_canonical_head() {
+        let TestBranch { a6, a7, .. } = TestBranch::new();
+
+        // the canonical head of concurrent heads doesn't depend on their order
+        let head = Branch::canonical_head(&[a6.id, a7.id]).unwrap();
+        assert_eq!(Branch::canonical_head(&[a7.id, a6.id]), Some(head));
+        assert_eq!(Branch::canonical_head(&[a7.id, a6.id, a7.id]), Some(head));
+        assert_eq!(head, a6.id.min(a7.id));
+        assert_eq!(Branch::canonical_head(&[]), None);
+    }
+
+    #[test]
+    pub fn test_export_log() {
+        let TestBranch {
+            store,
+            br,
+            a3,
+            t4,
+            t5,
+            a6,
+            a7,
+            ..
+        } = TestBranch::new();
+
+        // export the log of the branch and verify it
+        let log = Branch::export_log(&[a3, t5, a6, a7], &store).unwrap();
+        Branch::verify_log(&log).unwrap();
+        let log_v0 = match log.clone() {
+            SignedLog::V0(l) => l,
+        };
+        assert_eq!(log_v0.entries.len(), 8);
+        assert_eq!(log_v0.entries[0].id, br.id);
+        // the log only depends on the heads
+        let other = Branch::export_log(&[a7, a6, t5, a3, a7], &store).unwrap();
+        assert_eq!(other, log);
+
+        // tampering with a commit breaks its signature
+        let mut tampered = log_v0.clone();
+        let t4_pos = tampered.entries.iter().position(|e| e.id == t4.id).unwrap();
+        match &mut tampered.entries[t4_pos].commit {
+            Commit::V0(c) => c.content.seq = 44,
+        }
+        assert_eq!(
+            Branch::verify_log(&SignedLog::V0(tampered)).err(),
+            Some(LogVerifyError::InvalidSignature(t4.id))
+        );
+
+        // removing a commit breaks the chain
+        let mut tampered = log_v0.clone();
+        tampered.entries.remove(t4_pos);
+        assert!(Branch::verify_log(&SignedLog::V0(tampered)).is_err());
+
+        // replacing an entry by another validly signed commit breaks the hash
+        let mut tampered = log_v0.clone();
+        let a6_pos = tampered.entries.iter().position(|e| e.id == a6.id).unwrap();
+        let a7_pos = tampered.entries.iter().position(|e| e.id == a7.id).unwrap();
+        tampered.entries[a6_pos].commit = tampered.entries[a7_pos].commit.clone();
+        assert_eq!(
+            Branch::verify_log(&SignedLog::V0(tampered)).err(),
+            Some(LogVerifyError::InvalidHash)
         );
-        let ack_body = add_body_ack(vec![], repo_pubkey, repo_secret, &mut store);
-        let trans_body = add_body_trans(vec![], repo_pubkey, repo_secret, &mut store);
+    }
 
-        // create & add commits to store
+    #[test]
+    pub fn test_apply_sync() {
+        let mut test = TestBranch::new();
+        let TestBranch {
+            br, a3, t5, a6, a7, ..
+        } = test;
 
-        println!(">> br");
+        // the heads only move to a verified frontier
+        let mut heads = vec![a3, t5];
+        let replaced = test
+            .branch
+            .apply_sync(&br, &mut heads, vec![a6, a7], &test.store)
+            .unwrap();
+        assert_eq!(replaced, vec![a3, t5]);
+        assert_eq!(heads, vec![a6, a7]);
+
+        // a sync with a missing dependency leaves the heads unchanged
+        let missing = ObjectRef {
+            id: ObjectId::Blake3Digest32([8; 32]),
+            key: SymKey::ChaCha20Key([8; 32]),
+        };
+        let a8 = test.add_ack(8, vec![a6, missing]);
+        assert!(matches!(
+            test.branch
+                .apply_sync(&br, &mut heads, vec![a8, a7], &test.store),
+            Err(CommitVerifyError::DepLoadError(
+                CommitLoadError::MissingBlocks(_)
+            ))
+        ));
+        assert_eq!(heads, vec![a6, a7]);
+
+        // a copy of the branch body signed by a non-member is not a root of the branch
+        let (outsider_privkey, outsider_pubkey) = generate_keypair();
+        let forged = add_commit(
+            test.branch_body,
+            outsider_privkey,
+            outsider_pubkey,
+            0,
+            vec![],
+            vec![],
+            test.branch_body,
+            test.repo_pubkey,
+            test.repo_secret,
+            &mut test.store,
+        );
+        let a9 = test.add_ack(9, vec![forged]);
+        assert!(matches!(
+            test.branch
+                .apply_sync(&br, &mut heads, vec![a9], &test.store),
+            Err(CommitVerifyError::PermissionDenied)
+        ));
+        assert_eq!(heads, vec![a6, a7]);
+    }
+
+    #[test]
+    pub fn test_from_commit() {
+        let TestBranch {
+            store,
+            branch,
+            member_pubkey,
+            branch_body,
+            br,
+            t1,
+            ..
+        } = TestBranch::new();
+
+        // a client that only synced the branch commit and its body gets the branch back
+        let synced = HashMapRepoStore::new();
+        for id in [br.id, branch_body.id] {
+            for block in Object::load(id, None, &store).unwrap().blocks() {
+                synced.put(block).unwrap();
+            }
+        }
+        let reconstructed = Branch::from_commit(br, &synced).unwrap();
+        assert_eq!(reconstructed, branch);
+        assert_eq!(reconstructed.quorum(CommitType::Transaction), 3);
+        assert_eq!(reconstructed.ack_delay(), RelTime::Minutes(3));
+        assert!(reconstructed
+            .get_member(&member_pubkey)
+            .unwrap()
+            .has_perm(CommitType::Transaction));
+
+        // other commits don't create a branch
+        assert!(matches!(
+            Branch::from_commit(t1, &store),
+            Err(LofireError::InvalidCommit(id)) if id == t1.id
+        ));
+        assert!(matches!(
+            Branch::from_commit(t1, &synced),
+            Err(LofireError::InvalidCommit(id)) if id == t1.id
+        ));
+
+        // the body of the branch commit is needed as well
+        let commit_only = HashMapRepoStore::new();
+        for block in Object::load(br.id, None, &store).unwrap().blocks() {
+            commit_only.put(block).unwrap();
+        }
+        assert!(matches!(
+            Branch::from_commit(br, &commit_only),
+            Err(LofireError::MissingBody)
+        ));
+    }
+
+    #[test]
+    pub fn test_snapshot() {
+        let mut store = HashMapRepoStore::new();
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let repo_secret = SymKey::ChaCha20Key([2; 32]);
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let (other_privkey, other_pubkey) = generate_keypair();
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([3; 32]),
+            PubKey::Ed25519PubKey([4; 32]),
+            SymKey::ChaCha20Key([5; 32]),
+            vec![
+                MemberV0::new(
+                    member_pubkey,
+                    vec![CommitType::Transaction, CommitType::Snapshot],
+                    vec![],
+                ),
+                MemberV0::new(other_pubkey, vec![CommitType::Transaction], vec![]),
+            ],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+
+        let branch_body = add_obj(
+            ObjectContent::CommitBody(CommitBody::Branch(branch.clone())),
+            vec![],
+            None,
+            repo_pubkey,
+            repo_secret,
+            &mut store,
+        );
+        let trans_body = add_obj(
+            ObjectContent::CommitBody(CommitBody::Transaction(Transaction::V0(vec![7]))),
+            vec![],
+            None,
+            repo_pubkey,
+            repo_secret,
+            &mut store,
+        );
+        let (privkey, pubkey) = (member_privkey, member_pubkey);
         let br = add_commit(
             branch_body,
-            member_privkey,
-            member_pubkey,
+            privkey,
+            pubkey,
             0,
             vec![],
             vec![],
@@ -436,12 +1382,10 @@ mod test {
             repo_secret,
             &mut store,
         );
-
-        println!(">> t1");
         let t1 = add_commit(
             branch_body,
-            member_privkey,
-            member_pubkey,
+            privkey,
+            pubkey,
             1,
             vec![br],
             vec![],
@@ -450,12 +1394,10 @@ mod test {
             repo_secret,
             &mut store,
         );
-
-        println!(">> t2");
         let t2 = add_commit(
             branch_body,
-            member_privkey,
-            member_pubkey,
+            privkey,
+            pubkey,
             2,
             vec![br],
             vec![],
@@ -465,106 +1407,482 @@ mod test {
             &mut store,
         );
 
-        println!(">> a3");
-        let a3 = add_commit(
+        let snapshot = Branch::snapshot(
             branch_body,
             member_privkey,
             member_pubkey,
             3,
-            vec![t1],
-            vec![],
-            ack_body,
+            vec![t1, t2],
+            vec![9; 10],
+            4000,
             repo_pubkey,
             repo_secret,
             &mut store,
-        );
+        )
+        .unwrap();
 
-        println!(">> t4");
-        let t4 = add_commit(
+        // the branch advances after the snapshot
+        let t3 = add_commit(
             branch_body,
-            member_privkey,
-            member_pubkey,
+            privkey,
+            pubkey,
             4,
-            vec![t2],
-            vec![t1],
+            vec![t1, t2],
+            vec![],
             trans_body,
             repo_pubkey,
             repo_secret,
             &mut store,
         );
 
-        println!(">> t5");
-        let t5 = add_commit(
+        let filter = Filter::new(FilterBuilder::new(10, 0.01));
+        let cfg = filter.config();
+        let their_commits = BloomFilter {
+            k: cfg.hashes,
+            f: filter.get_u8_array().to_vec(),
+        };
+
+        // syncing to the snapshot yields the frontier at the time of the snapshot
+        let heads = branch.from_snapshot(snapshot, &store).unwrap();
+        assert_eq!(heads.len(), 2);
+        assert!(heads.contains(&t1.into()) && heads.contains(&t2.into()));
+        let ids = Branch::sync_req(&heads, &[], &their_commits, &store).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&t3.into()));
+
+        let ids = Branch::sync_req(&[t3.into()], &[], &their_commits, &store).unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.contains(&t3.into()));
+
+        // only members allowed to publish snapshots can create them
+        let forged = Branch::snapshot(
             branch_body,
-            member_privkey,
-            member_pubkey,
-            5,
-            vec![t1, t2],
-            vec![t4],
-            trans_body,
+            other_privkey,
+            other_pubkey,
+            1,
+            vec![t3],
+            vec![],
+            4000,
             repo_pubkey,
             repo_secret,
             &mut store,
+        )
+        .unwrap();
+        assert!(matches!(
+            branch.from_snapshot(forged, &store),
+            Err(CommitVerifyError::PermissionDenied)
+        ));
+
+        // other commits are not snapshots
+        assert!(branch.from_snapshot(t1, &store).is_err());
+    }
+
+    #[test]
+    pub fn test_validate_commit() {
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let (acker_privkey, acker_pubkey) = generate_keypair();
+        let (other_privkey, other_pubkey) = generate_keypair();
+        let mut quorum = HashMap::new();
+        quorum.insert(CommitType::Transaction, 2);
+        let branch = Branch::new(
+            PubKey::Ed25519PubKey([3; 32]),
+            PubKey::Ed25519PubKey([4; 32]),
+            SymKey::ChaCha20Key([5; 32]),
+            vec![
+                MemberV0::new(
+                    member_pubkey,
+                    vec![CommitType::Transaction, CommitType::Ack],
+                    vec![],
+                ),
+                MemberV0::new(acker_pubkey, vec![CommitType::Ack], vec![]),
+            ],
+            quorum,
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
         );
+        let obj_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([1; 32]),
+            key: SymKey::ChaCha20Key([2; 32]),
+        };
+        let new_commit = |privkey, pubkey| {
+            Commit::new(
+                privkey,
+                pubkey,
+                0,
+                obj_ref,
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                obj_ref,
+                None,
+            )
+            .unwrap()
+        };
+        let trans = CommitBody::Transaction(Transaction::V0(vec![1]));
+        let ack = CommitBody::Ack(Ack::V0());
 
-        println!(">> a6");
-        let a6 = add_commit(
-            branch_body,
-            member_privkey,
-            member_pubkey,
-            6,
-            vec![t4],
+        let commit = new_commit(member_privkey, member_pubkey);
+        branch.validate_commit(&commit, &trans, 2).ok().unwrap();
+        branch.validate_commit(&commit, &ack, 0).ok().unwrap();
+        // under quorum
+        assert!(matches!(
+            branch.validate_commit(&commit, &trans, 1),
+            Err(LofireError::QuorumNotReached)
+        ));
+
+        // commit type not allowed for the member
+        let commit = new_commit(acker_privkey, acker_pubkey);
+        branch.validate_commit(&commit, &ack, 0).ok().unwrap();
+        assert!(matches!(
+            branch.validate_commit(&commit, &trans, 2),
+            Err(LofireError::PermissionDenied)
+        ));
+
+        // not a member
+        let commit = new_commit(other_privkey, other_pubkey);
+        assert!(matches!(
+            branch.validate_commit(&commit, &ack, 0),
+            Err(LofireError::NotMember)
+        ));
+    }
+
+    #[test]
+    pub fn test_verify_branch() {
+        let mut store = HashMapRepoStore::new();
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let repo_secret = SymKey::ChaCha20Key([2; 32]);
+        let (privkey, pubkey) = generate_keypair();
+        let branch_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([3; 32]),
+            key: SymKey::ChaCha20Key([3; 32]),
+        };
+        let trans_body = add_obj(
+            ObjectContent::CommitBody(CommitBody::Transaction(Transaction::V0(vec![7]))),
             vec![],
-            ack_body,
+            None,
             repo_pubkey,
             repo_secret,
             &mut store,
         );
+        let new_commit = |seq, deps: Vec<ObjectRef>| {
+            Commit::new(
+                privkey,
+                pubkey,
+                seq,
+                branch_ref,
+                deps,
+                vec![],
+                vec![],
+                vec![],
+                trans_body,
+                None,
+            )
+            .unwrap()
+        };
 
-        println!(">> a7");
-        let a7 = add_commit(
-            branch_body,
-            member_privkey,
-            member_pubkey,
-            7,
-            vec![t4],
-            vec![],
-            ack_body,
+        let t0 = save_commit(new_commit(0, vec![]), repo_pubkey, repo_secret, &mut store);
+        let t1 = save_commit(
+            new_commit(1, vec![t0]),
             repo_pubkey,
             repo_secret,
             &mut store,
         );
+        verify_branch(&store, &[t1]).ok().unwrap();
 
-        let c7 = Commit::load(a7, &store).unwrap();
-        c7.verify(&branch, &store).unwrap();
-
-        let mut filter = Filter::new(FilterBuilder::new(10, 0.01));
-        for commit_ref in [br, t1, t2, a3, t5, a6] {
-            match commit_ref.id {
-                ObjectId::Blake3Digest32(d) => filter.add(&d),
-            }
+        // the content of a commit changed after it was signed
+        let mut tampered = new_commit(2, vec![t1]);
+        match &mut tampered {
+            Commit::V0(c) => c.content.seq = 3,
         }
-        let cfg = filter.config();
-        let their_commits = BloomFilter {
-            k: cfg.hashes,
-            f: filter.get_u8_array().to_vec(),
+        assert!(matches!(
+            tampered.verify_integrity(&store),
+            Err(LofireError::InvalidSignature)
+        ));
+        let t2 = save_commit(tampered, repo_pubkey, repo_secret, &mut store);
+        let t3 = save_commit(
+            new_commit(4, vec![t2]),
+            repo_pubkey,
+            repo_secret,
+            &mut store,
+        );
+        assert!(matches!(
+            verify_branch(&store, &[t3]),
+            Err(LofireError::InvalidCommit(id)) if id == t2.id
+        ));
+
+        // a dependency missing from the store
+        let missing = ObjectRef {
+            id: ObjectId::Blake3Digest32([8; 32]),
+            key: SymKey::ChaCha20Key([8; 32]),
         };
+        let commit = new_commit(5, vec![t1, missing]);
+        assert!(matches!(
+            commit.verify_integrity(&store),
+            Err(LofireError::MissingDependency)
+        ));
+        let t5 = save_commit(commit, repo_pubkey, repo_secret, &mut store);
+        assert!(matches!(
+            verify_branch(&store, &[t1, t5]),
+            Err(LofireError::InvalidCommit(id)) if id == t5.id
+        ));
+    }
 
-        print_branch();
-        println!(">> sync_req");
-        println!("   our_heads: [a3, t5, a6, a7]");
-        println!("   their_heads: [a3, t5]");
-        println!("   their_commits: [br, t1, t2, a3, t5, a6]");
+    #[test]
+    pub fn test_branch_secret() {
+        let mut store = HashMapRepoStore::new();
+        let (member_privkey, member_pubkey) = generate_keypair();
+        let new_branch = |id: u8, secret: u8| {
+            Branch::new(
+                PubKey::Ed25519PubKey([id; 32]),
+                PubKey::Ed25519PubKey([id; 32]),
+                SymKey::ChaCha20Key([secret; 32]),
+                vec![MemberV0::new(
+                    member_pubkey,
+                    vec![CommitType::Transaction],
+                    vec![],
+                )],
+                HashMap::new(),
+                RelTime::Minutes(0),
+                vec![],
+                vec![],
+            )
+        };
+        let branch_a = new_branch(1, 2);
+        let branch_b = new_branch(3, 4);
 
-        let ids = Branch::sync_req(
-            &[a3.id, t5.id, a6.id, a7.id],
-            &[a3.id, t5.id],
-            &their_commits,
+        // publish the same transaction in both branches, returning the encrypted commit key
+        let body =
+            ObjectContent::CommitBody(CommitBody::Transaction(Transaction::V0(vec![7; 100])));
+        let mut publish = |branch: &Branch| {
+            let branch_obj = branch.new_object(
+                ObjectContent::CommitBody(CommitBody::Branch(branch.clone())),
+                vec![],
+                None,
+                4000,
+            );
+            let body_obj = branch.new_object(body.clone(), vec![], None, 4000);
+            let commit = Commit::new(
+                member_privkey,
+                member_pubkey,
+                1,
+                branch_obj.reference().unwrap(),
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                body_obj.reference().unwrap(),
+                None,
+            )
+            .unwrap();
+            let commit_obj = branch.new_object(ObjectContent::Commit(commit), vec![], None, 4000);
+            branch_obj.save(&mut store).unwrap();
+            body_obj.save(&mut store).unwrap();
+            commit_obj.save(&mut store).unwrap();
+            let key = encrypt_event_key(
+                commit_obj.key().unwrap(),
+                1,
+                member_pubkey,
+                branch.id(),
+                branch.secret(),
+            );
+            (commit_obj, body_obj, key)
+        };
+        let (commit_a, body_a, key_a) = publish(&branch_a);
+        let (commit_b, body_b, key_b) = publish(&branch_b);
+        assert_ne!(body_a.id(), body_b.id());
+
+        // a member with the secret of branch A can read its commits and their bodies
+        let secret_a = branch_a.secret();
+        let key = decrypt_event_key(key_a, 1, member_pubkey, branch_a.id(), secret_a);
+        let commit = Commit::load(
+            ObjectRef {
+                id: commit_a.id(),
+                key,
+            },
             &store,
         )
         .unwrap();
+        assert!(matches!(
+            commit.load_body(&store).unwrap(),
+            CommitBody::Transaction(Transaction::V0(c)) if c == vec![7; 100]
+        ));
 
-        assert_eq!(ids.len(), 1);
-        assert!(ids.contains(&a7.id));
+        // but neither the commits of branch B, nor the keys of content it knows
+        let key = decrypt_event_key(key_b, 1, member_pubkey, branch_b.id(), secret_a);
+        assert_ne!(key, commit_b.key().unwrap());
+        let guess = Object::new(body, vec![], None, 4000, branch_b.id(), secret_a);
+        assert_ne!(guess.id(), body_b.id());
+        assert_ne!(guess.key(), body_b.key());
+    }
+
+    #[test]
+    pub fn test_publisher_hash() {
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let repo_secret = SymKey::ChaCha20Key([2; 32]);
+        let branch_pubkey = PubKey::Ed25519PubKey([3; 32]);
+        let branch_secret = SymKey::ChaCha20Key([4; 32]);
+        let member1_pubkey = PubKey::Ed25519PubKey([5; 32]);
+        let member2_pubkey = PubKey::Ed25519PubKey([6; 32]);
+        let other_pubkey = PubKey::Ed25519PubKey([7; 32]);
+        let branch = Branch::new(
+            branch_pubkey,
+            branch_pubkey,
+            branch_secret,
+            vec![
+                MemberV0::new(member1_pubkey, vec![CommitType::Transaction], vec![]),
+                MemberV0::new(member2_pubkey, vec![CommitType::Ack], vec![]),
+            ],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+
+        let publisher = compute_publisher_hash(
+            member2_pubkey,
+            repo_pubkey,
+            repo_secret,
+            branch_pubkey,
+            branch_secret,
+        );
+        assert_eq!(
+            publisher,
+            compute_publisher_hash(
+                member2_pubkey,
+                repo_pubkey,
+                repo_secret,
+                branch_pubkey,
+                branch_secret,
+            )
+        );
+        let member = branch
+            .get_member_by_publisher(&publisher, repo_pubkey, repo_secret)
+            .unwrap();
+        assert_eq!(member.id, member2_pubkey);
+        assert!(member.has_perm(CommitType::Ack));
+
+        // hash is bound to the repo
+        let other_repo_secret = SymKey::ChaCha20Key([8; 32]);
+        assert!(branch
+            .get_member_by_publisher(&publisher, repo_pubkey, other_repo_secret)
+            .is_none());
+
+        // not a member
+        let publisher = compute_publisher_hash(
+            other_pubkey,
+            repo_pubkey,
+            repo_secret,
+            branch_pubkey,
+            branch_secret,
+        );
+        assert!(branch
+            .get_member_by_publisher(&publisher, repo_pubkey, repo_secret)
+            .is_none());
+    }
+
+    #[test]
+    pub fn test_commit_types() {
+        let branch_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let branch_secret = SymKey::ChaCha20Key([2; 32]);
+        let member_pubkey = PubKey::Ed25519PubKey([3; 32]);
+        let other_pubkey = PubKey::Ed25519PubKey([4; 32]);
+        // as in the demo
+        let commit_types = vec![CommitType::Ack, CommitType::Transaction];
+        let branch = Branch::new(
+            branch_pubkey,
+            branch_pubkey,
+            branch_secret,
+            vec![MemberV0::new(member_pubkey, commit_types.clone(), vec![])],
+            HashMap::new(),
+            RelTime::Minutes(0),
+            vec![],
+            vec![],
+        );
+
+        assert_eq!(branch.commit_types(&member_pubkey), commit_types);
+        let member = branch.get_member(&member_pubkey).unwrap();
+        for commit_type in [
+            CommitType::Repository,
+            CommitType::AddBranch,
+            CommitType::RemoveBranch,
+            CommitType::Branch,
+            CommitType::AddMembers,
+            CommitType::EndOfBranch,
+            CommitType::Transaction,
+            CommitType::Snapshot,
+            CommitType::Ack,
+            CommitType::SealBranch,
+        ] {
+            assert_eq!(
+                member.has_perm(commit_type),
+                commit_types.contains(&commit_type)
+            );
+        }
+
+        assert!(branch.get_member(&other_pubkey).is_none());
+        assert!(branch.commit_types(&other_pubkey).is_empty());
+    }
+
+    #[test]
+    pub fn test_ack_delay() {
+        let branch_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let member_pubkey = PubKey::Ed25519PubKey([2; 32]);
+        let member = MemberV0::new(
+            member_pubkey,
+            vec![CommitType::Ack, CommitType::Transaction],
+            vec![],
+        );
+        let mut quorum = HashMap::new();
+        quorum.insert(CommitType::Transaction, 2);
+        let branch = Branch::new(
+            branch_pubkey,
+            branch_pubkey,
+            SymKey::ChaCha20Key([0; 32]),
+            vec![member],
+            quorum,
+            RelTime::Minutes(5),
+            vec![],
+            vec![],
+        );
+
+        let received_at = Timestamp::from_minutes(100);
+        let clock = MockClock::new(received_at);
+        let heads = vec![
+            HeadAcks {
+                id: ObjectId::Blake3Digest32([1; 32]),
+                commit_type: CommitType::Transaction,
+                acks: 2,
+                received_at,
+            },
+            HeadAcks {
+                id: ObjectId::Blake3Digest32([2; 32]),
+                commit_type: CommitType::Transaction,
+                acks: 1,
+                received_at,
+            },
+        ];
+
+        // quorum reached, but ack_delay not elapsed yet
+        assert!(!branch.is_quorum_reached(CommitType::Transaction, 2, received_at, &clock));
+        assert!(branch.finalized_heads(&heads, &clock).is_empty());
+
+        clock.advance(4);
+        assert!(branch.finalized_heads(&heads, &clock).is_empty());
+
+        clock.advance(1);
+        assert_eq!(
+            branch.finalized_heads(&heads, &clock),
+            vec![ObjectId::Blake3Digest32([1; 32])]
+        );
+
+        // quorum not reached, even after ack_delay elapsed
+        clock.advance(60);
+        assert!(!branch.is_quorum_reached(CommitType::Transaction, 1, received_at, &clock));
+
+        assert_eq!(RelTime::Seconds(30).as_minutes(), 1);
+        assert_eq!(RelTime::Hours(2).as_minutes(), 120);
+        assert_eq!(RelTime::Days(1).as_minutes(), 1440);
     }
 }
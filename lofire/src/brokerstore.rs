@@ -53,4 +53,60 @@ pub trait BrokerStore {
         suffix: Option<u8>,
         value: Vec<u8>,
     ) -> Result<(), StorageError>;
+
+    /// Run several writes in a single transaction.
+    /// The writes are committed together if the closure succeeds, and none of them otherwise.
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&mut dyn BrokerStoreTransaction) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError>;
+}
+
+/// Writes of a `BrokerStore::transaction`
+pub trait BrokerStoreTransaction {
+    /// Save a property value to the store.
+    fn put(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError>;
+
+    /// Replace the property of a key (single value) to the store.
+    fn replace(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError>;
+
+    /// Delete a property from the store.
+    fn del(&mut self, prefix: u8, key: &Vec<u8>, suffix: Option<u8>) -> Result<(), StorageError>;
+
+    /// Delete a specific value for a property from the store.
+    fn del_property_value(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        suffix: Option<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), StorageError>;
+
+    /// Delete all properties of a key from the store.
+    fn del_all(
+        &mut self,
+        prefix: u8,
+        key: &Vec<u8>,
+        all_suffixes: &[u8],
+    ) -> Result<(), StorageError> {
+        for suffix in all_suffixes {
+            self.del(prefix, key, Some(*suffix))?;
+        }
+        if all_suffixes.is_empty() {
+            self.del(prefix, key, None)?;
+        }
+        Ok(())
+    }
 }
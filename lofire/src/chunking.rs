@@ -0,0 +1,144 @@
+//! Splitting of the serialized content of an Object into data chunks
+
+use std::cmp::min;
+
+/// How the content of an Object is split into leaf blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkingStrategy {
+    /// Chunks of the data size of the given block size, rounded up to the nearest valid block size
+    Fixed(usize),
+
+    /// Chunks cut where a rolling hash of the content matches (FastCDC-style),
+    /// between `min` and `max` bytes, `avg` bytes on average (rounded to a power of two).
+    ///
+    /// An insertion or deletion only changes the chunks around it,
+    /// the following chunks are cut at the same places and keep their block IDs.
+    /// `max` is bounded by the maximum data size of a block
+    ContentDefined { min: usize, avg: usize, max: usize },
+}
+
+/// Random values for the gear rolling hash, generated with splitmix64.
+/// They are part of the format: changing them changes the block IDs of content-defined objects
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x4c6f46695265; // "LoFiRe"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Mask of the `bits` highest bits
+fn high_mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        b if b >= 64 => u64::MAX,
+        b => !(u64::MAX >> b),
+    }
+}
+
+/// Length of the first chunk of `data`
+fn cut_point(data: &[u8], min_size: usize, avg_size: usize, max_size: usize) -> usize {
+    if data.len() <= min_size {
+        return data.len();
+    }
+    let max_size = min(max_size, data.len());
+    let normal_size = min(avg_size, max_size);
+    let bits = avg_size.next_power_of_two().trailing_zeros();
+    // normalized chunking: harder to cut before the average size, easier after it
+    let mask_small = high_mask(bits + 1);
+    let mask_large = high_mask(bits.saturating_sub(1));
+
+    let mut hash: u64 = 0;
+    let mut i = min_size;
+    while i < normal_size {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_small == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    while i < max_size {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_large == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    max_size
+}
+
+/// Length of the first content-defined chunk of `data`, cut like in `content_defined_chunks`.
+/// It only depends on the first `max_size` bytes of `data`,
+/// so content can be chunked as it arrives, once `max_size` bytes are buffered
+pub(crate) fn content_defined_cut(
+    data: &[u8],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> usize {
+    let max_size = max_size.max(1);
+    let min_size = min(min_size, max_size);
+    let avg_size = avg_size.clamp(min_size, max_size);
+    cut_point(data, min_size, avg_size, max_size)
+}
+
+/// Split `data` into content-defined chunks of `min_size` to `max_size` bytes,
+/// the last one possibly shorter
+pub(crate) fn content_defined_chunks(
+    data: &[u8],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(content_defined_cut(rest, min_size, avg_size, max_size));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::chunking::*;
+
+    #[test]
+    pub fn test_content_defined_chunks() {
+        let mut x: u32 = 1;
+        let data: Vec<u8> = (0..200000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+
+        let chunks = content_defined_chunks(&data, 1024, 4096, 8192);
+        assert_eq!(chunks.concat(), data);
+        let (last, full) = chunks.split_last().unwrap();
+        assert!(last.len() <= 8192);
+        for chunk in full {
+            assert!(chunk.len() >= 1024 && chunk.len() <= 8192);
+        }
+        let avg = data.len() / chunks.len();
+        println!("{} chunks, {} bytes on average", chunks.len(), avg);
+        assert!(avg > 2048 && avg < 8192);
+
+        // same cut points for the same content
+        assert_eq!(chunks, content_defined_chunks(&data, 1024, 4096, 8192));
+        assert!(content_defined_chunks(&[], 1024, 4096, 8192).is_empty());
+    }
+}
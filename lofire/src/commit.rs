@@ -6,9 +6,22 @@ use ed25519_dalek::*;
 use std::collections::HashSet;
 use std::iter::FromIterator;
 
+use crate::compression::*;
+use crate::errors::*;
 use crate::object::*;
 use crate::store::*;
 use crate::types::*;
+use crate::utils::*;
+
+/// Metadata up to this size is kept uncompressed
+pub const METADATA_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Maximum size of the decompressed metadata of a commit returned by `Commit::metadata`,
+/// as the decompressed size is read from the compressed metadata, set by the author
+pub const MAX_COMMIT_METADATA_SIZE: usize = 1024 * 1024;
+
+/// Maximum clock skew allowed for the creation time of received commits
+pub const MAX_COMMIT_CLOCK_SKEW: RelTime = RelTime::Minutes(10);
 
 #[derive(Debug)]
 pub enum CommitLoadError {
@@ -21,6 +34,8 @@ pub enum CommitLoadError {
 pub enum CommitVerifyError {
     InvalidSignature,
     PermissionDenied,
+    UnauthorizedDevice,
+    InvalidTimestamp,
     BodyLoadError(CommitLoadError),
     DepLoadError(CommitLoadError),
 }
@@ -37,6 +52,7 @@ impl CommitBody {
             CommitBody::Repository(_) => CommitType::Repository,
             CommitBody::Snapshot(_) => CommitType::Snapshot,
             CommitBody::Transaction(_) => CommitType::Transaction,
+            CommitBody::SealBranch(_) => CommitType::SealBranch,
         }
     }
 }
@@ -55,8 +71,8 @@ impl CommitV0 {
         body: ObjectRef,
         expiry: Option<Timestamp>,
     ) -> Result<CommitV0, SignatureError> {
-        let content = CommitContentV0 {
-            author: author_pubkey,
+        let content = Self::new_content(
+            author_pubkey,
             seq,
             branch,
             deps,
@@ -65,11 +81,98 @@ impl CommitV0 {
             metadata,
             body,
             expiry,
+        );
+        let sig = Self::sign(&content, author_privkey, author_pubkey)?;
+        Ok(CommitV0 {
+            content,
+            sig,
+            device: None,
+            id: None,
+            key: None,
+        })
+    }
+
+    /// New commit authored by a member and signed by one of its device keys
+    pub fn new_with_device(
+        device_privkey: PrivKey,
+        device_pubkey: PubKey,
+        author_pubkey: PubKey,
+        seq: u32,
+        branch: ObjectRef,
+        deps: Vec<ObjectRef>,
+        acks: Vec<ObjectRef>,
+        refs: Vec<ObjectRef>,
+        metadata: Vec<u8>,
+        body: ObjectRef,
+        expiry: Option<Timestamp>,
+    ) -> Result<CommitV0, SignatureError> {
+        let content = Self::new_content(
+            author_pubkey,
+            seq,
+            branch,
+            deps,
+            acks,
+            refs,
+            metadata,
+            body,
+            expiry,
+        );
+        let sig = Self::sign(&content, device_privkey, device_pubkey)?;
+        Ok(CommitV0 {
+            content,
+            sig,
+            device: Some(device_pubkey),
+            id: None,
+            key: None,
+        })
+    }
+
+    /// New commit content.
+    /// Metadata above METADATA_COMPRESSION_THRESHOLD is compressed if that makes it smaller
+    fn new_content(
+        author: PubKey,
+        seq: u32,
+        branch: ObjectRef,
+        deps: Vec<ObjectRef>,
+        acks: Vec<ObjectRef>,
+        refs: Vec<ObjectRef>,
+        metadata: Vec<u8>,
+        body: ObjectRef,
+        expiry: Option<Timestamp>,
+    ) -> CommitContentV0 {
+        let (metadata, metadata_compression) = if metadata.len() > METADATA_COMPRESSION_THRESHOLD {
+            let compressed = lz4_flex::compress_prepend_size(&metadata);
+            if compressed.len() < metadata.len() {
+                (compressed, Some(Compression::Lz4))
+            } else {
+                (metadata, None)
+            }
+        } else {
+            (metadata, None)
         };
-        let content_ser = serde_bare::to_vec(&content).unwrap();
+        CommitContentV0 {
+            author,
+            seq,
+            branch,
+            deps,
+            acks,
+            refs,
+            metadata,
+            metadata_compression,
+            body,
+            expiry,
+            created_at: now_timestamp(),
+        }
+    }
 
-        // sign commit
-        let kp = match (author_privkey, author_pubkey) {
+    /// Sign commit content
+    fn sign(
+        content: &CommitContentV0,
+        privkey: PrivKey,
+        pubkey: PubKey,
+    ) -> Result<Sig, SignatureError> {
+        let content_ser = serde_bare::to_vec(content).unwrap();
+        let kp = match (privkey, pubkey) {
             (PrivKey::Ed25519PrivKey(sk), PubKey::Ed25519PubKey(pk)) => [sk, pk].concat(),
         };
         let keypair = Keypair::from_bytes(kp.as_slice())?;
@@ -78,13 +181,7 @@ impl CommitV0 {
         let mut ss: Ed25519Sig = [[0; 32], [0; 32]];
         ss[0].copy_from_slice(it.next().unwrap());
         ss[1].copy_from_slice(it.next().unwrap());
-        let sig = Sig::Ed25519Sig(ss);
-        Ok(CommitV0 {
-            content,
-            sig,
-            id: None,
-            key: None,
-        })
+        Ok(Sig::Ed25519Sig(ss))
     }
 }
 
@@ -117,6 +214,36 @@ impl Commit {
         .map(|c| Commit::V0(c))
     }
 
+    /// New commit authored by a member and signed by one of its device keys
+    pub fn new_with_device(
+        device_privkey: PrivKey,
+        device_pubkey: PubKey,
+        author_pubkey: PubKey,
+        seq: u32,
+        branch: ObjectRef,
+        deps: Vec<ObjectRef>,
+        acks: Vec<ObjectRef>,
+        refs: Vec<ObjectRef>,
+        metadata: Vec<u8>,
+        body: ObjectRef,
+        expiry: Option<Timestamp>,
+    ) -> Result<Commit, SignatureError> {
+        CommitV0::new_with_device(
+            device_privkey,
+            device_pubkey,
+            author_pubkey,
+            seq,
+            branch,
+            deps,
+            acks,
+            refs,
+            metadata,
+            body,
+            expiry,
+        )
+        .map(|c| Commit::V0(c))
+    }
+
     /// Load commit from store
     pub fn load(commit_ref: ObjectRef, store: &impl RepoStore) -> Result<Commit, CommitLoadError> {
         let (id, key) = (commit_ref.id, commit_ref.key);
@@ -192,6 +319,13 @@ impl Commit {
         }
     }
 
+    /// Get device key that signed the commit, if not signed by the author key
+    pub fn device(&self) -> Option<&PubKey> {
+        match self {
+            Commit::V0(c) => c.device.as_ref(),
+        }
+    }
+
     /// Get commit content
     pub fn content(&self) -> &CommitContentV0 {
         match self {
@@ -199,6 +333,23 @@ impl Commit {
         }
     }
 
+    /// Get metadata, decompressed if needed, up to MAX_COMMIT_METADATA_SIZE
+    pub fn metadata(&self) -> Result<Vec<u8>, CommitLoadError> {
+        self.metadata_within(MAX_COMMIT_METADATA_SIZE)
+    }
+
+    /// Get metadata, decompressed if needed.
+    /// Fails if it would decompress to more than `max_size` bytes,
+    /// such as `MetadataLimits::commit` of the repo
+    pub fn metadata_within(&self, max_size: usize) -> Result<Vec<u8>, CommitLoadError> {
+        let content = self.content();
+        match content.metadata_compression {
+            None => Ok(content.metadata.clone()),
+            Some(compression) => decompress(compression, &content.metadata, max_size)
+                .ok_or(CommitLoadError::DeserializeError),
+        }
+    }
+
     /// Get acks
     pub fn acks(&self) -> Vec<ObjectRef> {
         match self {
@@ -227,13 +378,33 @@ impl Commit {
         }
     }
 
+    /// Get commit creation time
+    pub fn created_at(&self) -> Timestamp {
+        match self {
+            Commit::V0(c) => c.content.created_at,
+        }
+    }
+
+    /// Verify that the commit was not created in the future
+    ///
+    /// Allows for MAX_COMMIT_CLOCK_SKEW between the author's clock and ours.
+    pub fn verify_timestamp(&self, clock: &impl Clock) -> Result<(), LofireError> {
+        if self.created_at() > clock.now() + MAX_COMMIT_CLOCK_SKEW {
+            return Err(LofireError::InvalidTimestamp);
+        }
+        Ok(())
+    }
+
     /// Verify commit signature
+    ///
+    /// The signature is verified with the device key if present,
+    /// otherwise with the author key.
     pub fn verify_sig(&self) -> Result<(), SignatureError> {
         let c = match self {
             Commit::V0(c) => c,
         };
         let content_ser = serde_bare::to_vec(&c.content).unwrap();
-        let pubkey = match c.content.author {
+        let pubkey = match c.device.unwrap_or(c.content.author) {
             PubKey::Ed25519PubKey(pk) => pk,
         };
         let pk = PublicKey::from_bytes(&pubkey)?;
@@ -245,10 +416,18 @@ impl Commit {
     }
 
     /// Verify commit permissions
+    ///
+    /// If the commit was signed by a device key,
+    /// it must be one of the author's `authorized_keys`.
     pub fn verify_perm(&self, body: &CommitBody, branch: &Branch) -> Result<(), CommitVerifyError> {
         let content = self.content();
         match branch.get_member(&content.author) {
             Some(m) => {
+                if let Some(device) = self.device() {
+                    if !m.is_authorized_key(device) {
+                        return Err(CommitVerifyError::UnauthorizedDevice);
+                    }
+                }
                 if m.has_perm(body.to_type()) {
                     return Ok(());
                 }
@@ -320,10 +499,12 @@ impl Commit {
         Ok(Vec::from_iter(visited))
     }
 
-    /// Verify signature, permissions, and dependencies
+    /// Verify signature, creation time, permissions, and dependencies
     pub fn verify(&self, branch: &Branch, store: &impl RepoStore) -> Result<(), CommitVerifyError> {
         self.verify_sig()
             .map_err(|_e| CommitVerifyError::InvalidSignature)?;
+        self.verify_timestamp(&SystemClock)
+            .map_err(|_e| CommitVerifyError::InvalidTimestamp)?;
         let body = self
             .load_body(store)
             .map_err(|e| CommitVerifyError::BodyLoadError(e))?;
@@ -332,6 +513,23 @@ impl Commit {
             .map_err(|e| CommitVerifyError::DepLoadError(e))?;
         Ok(())
     }
+
+    /// Verify the integrity of the commit without its branch:
+    /// its signature, and the presence of its body and of its dependencies (`deps` & `acks`) in the `store`
+    pub fn verify_integrity(&self, store: &impl RepoStore) -> Result<(), LofireError> {
+        self.verify_sig()?;
+        self.load_body(store).map_err(|e| match e {
+            CommitLoadError::MissingBlocks(_) => LofireError::MissingBody,
+            _ => LofireError::SerializationError,
+        })?;
+        for dep in self.deps_acks() {
+            Commit::load(dep, store).map_err(|e| match e {
+                CommitLoadError::MissingBlocks(_) => LofireError::MissingDependency,
+                _ => LofireError::SerializationError,
+            })?;
+        }
+        Ok(())
+    }
 }
 
 mod test {
@@ -342,8 +540,12 @@ mod test {
 
     use crate::branch::*;
     use crate::commit::*;
+    use crate::errors::*;
+    use crate::object::*;
+    use crate::repo::*;
     use crate::store::*;
     use crate::types::*;
+    use crate::utils::*;
 
     #[test]
     pub fn test_commit() {
@@ -375,7 +577,7 @@ mod test {
         let refs = obj_refs.clone();
         let metadata = vec![1, 2, 3];
         let body_ref = obj_ref.clone();
-        let expiry = Some(2342);
+        let expiry = Some(Timestamp::from_minutes(2342));
 
         let commit = Commit::new(
             priv_key, pub_key, seq, branch, deps, acks, refs, metadata, body_ref, expiry,
@@ -440,4 +642,301 @@ mod test {
             Err(e) => panic!("Commit verify error: {:?}", e),
         }
     }
+
+    #[test]
+    pub fn test_commit_device_key() {
+        let mut csprng = OsRng {};
+        let author_keypair: Keypair = Keypair::generate(&mut csprng);
+        let author_pub_key = PubKey::Ed25519PubKey(author_keypair.public.to_bytes());
+        let device_keypair: Keypair = Keypair::generate(&mut csprng);
+        let device_priv_key = PrivKey::Ed25519PrivKey(device_keypair.secret.to_bytes());
+        let device_pub_key = PubKey::Ed25519PubKey(device_keypair.public.to_bytes());
+        let other_keypair: Keypair = Keypair::generate(&mut csprng);
+        let other_priv_key = PrivKey::Ed25519PrivKey(other_keypair.secret.to_bytes());
+        let other_pub_key = PubKey::Ed25519PubKey(other_keypair.public.to_bytes());
+
+        let obj_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([1; 32]),
+            key: SymKey::ChaCha20Key([2; 32]),
+        };
+        let obj_refs = vec![obj_ref];
+        let metadata = vec![1, 2, 3];
+
+        let mut member = MemberV0::new(
+            author_pub_key,
+            vec![CommitType::Ack, CommitType::Transaction],
+            metadata.clone(),
+        );
+        member.authorize_key(device_pub_key);
+        let branch = Branch::new(
+            author_pub_key,
+            author_pub_key,
+            SymKey::ChaCha20Key([0; 32]),
+            vec![member],
+            HashMap::new(),
+            RelTime::Minutes(3),
+            vec![],
+            metadata.clone(),
+        );
+        let body = CommitBody::Ack(Ack::V0());
+
+        let commit = Commit::new_with_device(
+            device_priv_key,
+            device_pub_key,
+            author_pub_key,
+            1,
+            obj_ref,
+            obj_refs.clone(),
+            obj_refs.clone(),
+            obj_refs.clone(),
+            metadata.clone(),
+            obj_ref,
+            None,
+        )
+        .unwrap();
+        println!("commit: {:?}", commit);
+        assert_eq!(commit.content().author, author_pub_key);
+        assert_eq!(commit.device(), Some(&device_pub_key));
+
+        commit.verify_sig().expect("Invalid signature");
+        commit
+            .verify_perm(&body, &branch)
+            .expect("Permission denied");
+
+        let commit = Commit::new_with_device(
+            other_priv_key,
+            other_pub_key,
+            author_pub_key,
+            2,
+            obj_ref,
+            obj_refs.clone(),
+            obj_refs.clone(),
+            obj_refs.clone(),
+            metadata.clone(),
+            obj_ref,
+            None,
+        )
+        .unwrap();
+        println!("commit: {:?}", commit);
+
+        commit.verify_sig().expect("Invalid signature");
+        match commit.verify_perm(&body, &branch) {
+            Err(CommitVerifyError::UnauthorizedDevice) => (),
+            r => panic!("Unauthorized device key should be rejected: {:?}", r),
+        }
+    }
+
+    #[test]
+    pub fn test_commit_timestamp() {
+        let mut csprng = OsRng {};
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let priv_key = PrivKey::Ed25519PrivKey(keypair.secret.to_bytes());
+        let pub_key = PubKey::Ed25519PubKey(keypair.public.to_bytes());
+        let obj_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([1; 32]),
+            key: SymKey::ChaCha20Key([2; 32]),
+        };
+        let obj_refs = vec![obj_ref];
+
+        let commit = Commit::new(
+            priv_key,
+            pub_key,
+            1,
+            obj_ref,
+            obj_refs.clone(),
+            obj_refs.clone(),
+            obj_refs.clone(),
+            vec![],
+            obj_ref,
+            None,
+        )
+        .unwrap();
+        let now = now_timestamp();
+        assert!(commit.created_at() <= now);
+        assert!(commit.verify_timestamp(&SystemClock).is_ok());
+        assert!(commit
+            .verify_timestamp(&MockClock::new(now - RelTime::Minutes(5)))
+            .is_ok());
+
+        // far-future commit, properly signed by the author
+        let mut content = commit.content().clone();
+        content.created_at = now + RelTime::Days(1);
+        let sig = CommitV0::sign(&content, priv_key, pub_key).unwrap();
+        let future = Commit::V0(CommitV0 {
+            content,
+            sig,
+            device: None,
+            id: None,
+            key: None,
+        });
+        future.verify_sig().expect("Invalid signature");
+        assert!(matches!(
+            future.verify_timestamp(&SystemClock),
+            Err(LofireError::InvalidTimestamp)
+        ));
+
+        // the creation time is covered by the signature
+        let mut tampered = commit.clone();
+        match &mut tampered {
+            Commit::V0(c) => c.content.created_at = c.content.created_at - RelTime::Minutes(1),
+        }
+        assert!(tampered.verify_sig().is_err());
+    }
+
+    #[test]
+    pub fn test_commit_metadata_compression() {
+        let mut csprng = OsRng {};
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let priv_key = PrivKey::Ed25519PrivKey(keypair.secret.to_bytes());
+        let pub_key = PubKey::Ed25519PubKey(keypair.public.to_bytes());
+        let obj_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([1; 32]),
+            key: SymKey::ChaCha20Key([2; 32]),
+        };
+        let obj_refs = vec![obj_ref];
+        let new_commit = |metadata: Vec<u8>| {
+            Commit::new(
+                priv_key,
+                pub_key,
+                1,
+                obj_ref,
+                obj_refs.clone(),
+                obj_refs.clone(),
+                obj_refs.clone(),
+                metadata,
+                obj_ref,
+                None,
+            )
+            .unwrap()
+        };
+
+        // small metadata is kept uncompressed
+        let small = vec![66u8; 64];
+        let commit = new_commit(small.clone());
+        assert_eq!(commit.content().metadata_compression, None);
+        assert_eq!(commit.content().metadata, small);
+        assert_eq!(commit.metadata().unwrap(), small);
+
+        // large metadata is compressed
+        let large = [b"{\"message\": \"structured commit metadata\"}".as_slice(); 100].concat();
+        let commit = new_commit(large.clone());
+        println!(
+            "metadata: {} bytes, compressed: {} bytes",
+            large.len(),
+            commit.content().metadata.len()
+        );
+        assert_eq!(
+            commit.content().metadata_compression,
+            Some(Compression::Lz4)
+        );
+        assert!(commit.content().metadata.len() < large.len());
+        assert_eq!(commit.metadata().unwrap(), large);
+        commit.verify_sig().expect("Invalid signature");
+        assert!(commit.metadata_within(large.len() - 1).is_err());
+
+        // the decompressed size in the header is not trusted
+        let mut forged = commit.clone();
+        match &mut forged {
+            Commit::V0(c) => c.content.metadata = vec![0xff, 0xff, 0xff, 0xff, 0],
+        }
+        assert!(forged.metadata().is_err());
+
+        // the compressed metadata is deterministic, and so is the commit id for the same content
+        assert_eq!(
+            new_commit(large).content().metadata,
+            commit.content().metadata
+        );
+        let content = commit.content().clone();
+        let sig = CommitV0::sign(&content, priv_key, pub_key).unwrap();
+        let resigned = Commit::V0(CommitV0 {
+            content,
+            sig,
+            device: None,
+            id: None,
+            key: None,
+        });
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let obj1 = Object::new(
+            ObjectContent::Commit(commit),
+            vec![],
+            None,
+            0,
+            repo_pubkey,
+            repo_secret,
+        );
+        let obj2 = Object::new(
+            ObjectContent::Commit(resigned),
+            vec![],
+            None,
+            0,
+            repo_pubkey,
+            repo_secret,
+        );
+        assert_eq!(obj1.id(), obj2.id());
+    }
+
+    #[test]
+    pub fn test_metadata_limits() {
+        let mut csprng = OsRng {};
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let priv_key = PrivKey::Ed25519PrivKey(keypair.secret.to_bytes());
+        let pub_key = PubKey::Ed25519PubKey(keypair.public.to_bytes());
+        let obj_ref = ObjectRef {
+            id: ObjectId::Blake3Digest32([1; 32]),
+            key: SymKey::ChaCha20Key([2; 32]),
+        };
+        let new_commit = |metadata: Vec<u8>| {
+            Commit::new(
+                priv_key,
+                pub_key,
+                1,
+                obj_ref,
+                vec![],
+                vec![],
+                vec![],
+                metadata,
+                obj_ref,
+                None,
+            )
+            .unwrap()
+        };
+        let limits = MetadataLimits::default();
+
+        let small = new_commit(vec![66u8; 64]);
+        assert!(limits.check_commit(&small).is_ok());
+
+        // compressed metadata is checked with its decompressed size
+        let large = new_commit(vec![66u8; DEFAULT_MAX_COMMIT_METADATA_SIZE as usize + 1]);
+        assert!(large.content().metadata_compression.is_some());
+        assert!(matches!(
+            limits.check_commit(&large),
+            Err(LofireError::MetadataTooLarge)
+        ));
+        assert!(matches!(
+            limits.check_content(&ObjectContent::Commit(large.clone())),
+            Err(LofireError::MetadataTooLarge)
+        ));
+
+        let larger_limits = MetadataLimits {
+            commit: DEFAULT_MAX_COMMIT_METADATA_SIZE * 2,
+            file: DEFAULT_MAX_FILE_METADATA_SIZE,
+        };
+        assert!(larger_limits.check_commit(&large).is_ok());
+
+        let file = |size| {
+            File::V0(FileV0 {
+                content_type: b"text/plain".to_vec(),
+                metadata: vec![0u8; size],
+                content: vec![],
+            })
+        };
+        assert!(limits
+            .check_file(&file(DEFAULT_MAX_FILE_METADATA_SIZE as usize))
+            .is_ok());
+        assert!(matches!(
+            limits.check_file(&file(DEFAULT_MAX_FILE_METADATA_SIZE as usize + 1)),
+            Err(LofireError::MetadataTooLarge)
+        ));
+    }
 }
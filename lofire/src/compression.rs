@@ -0,0 +1,73 @@
+//! Compression of the data chunks of an Object and of the metadata of a Commit
+
+use crate::types::*;
+
+/// Size of the compression tag in the header of a compressed block
+const COMPRESSION_TAG_SIZE: usize = 2;
+
+/// How the data chunks of an Object are compressed before they are encrypted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkCompression {
+    /// Chunks are stored uncompressed
+    None,
+
+    /// Zstandard at the given level, from 1 (fastest) to 22 (smallest)
+    Zstd { level: i32 },
+
+    /// LZ4, faster than Zstandard but compressing less
+    Lz4,
+}
+
+impl ChunkCompression {
+    /// Compress a chunk, and return it with the algorithm to record in its block.
+    ///
+    /// Returns None when the chunk doesn't get smaller than it is,
+    /// counting the compression tag added to the block,
+    /// so that incompressible chunks are stored uncompressed instead of expanding
+    pub fn compress(&self, chunk: &[u8]) -> Option<(Compression, Vec<u8>)> {
+        let (compression, compressed) = match self {
+            ChunkCompression::None => return None,
+            ChunkCompression::Zstd { level } => {
+                (Compression::Zstd, zstd::bulk::compress(chunk, *level).ok()?)
+            }
+            ChunkCompression::Lz4 => (Compression::Lz4, lz4_flex::compress_prepend_size(chunk)),
+        };
+        if compressed.len() + COMPRESSION_TAG_SIZE < chunk.len() {
+            Some((compression, compressed))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ChunkCompression {
+    /// Zstandard at its default level, the compression of the leaves of `Object::new`
+    fn default() -> Self {
+        ChunkCompression::Zstd { level: 3 }
+    }
+}
+
+/// Size of the decompressed data, read from the header of the compressed data
+pub fn decompressed_size(compression: Compression, data: &[u8]) -> Option<usize> {
+    match compression {
+        Compression::Lz4 => data
+            .get(0..4)
+            .map(|header| u32::from_le_bytes(header.try_into().unwrap()) as usize),
+        Compression::Zstd => zstd::zstd_safe::get_frame_content_size(data)
+            .ok()
+            .flatten()
+            .map(|size| size as usize),
+    }
+}
+
+/// Decompress data, unless it would decompress to more than `max_size` bytes
+pub fn decompress(compression: Compression, data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let size = decompressed_size(compression, data)?;
+    if size > max_size {
+        return None;
+    }
+    match compression {
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(data).ok(),
+        Compression::Zstd => zstd::bulk::decompress(data, size).ok(),
+    }
+}
@@ -1,8 +1,22 @@
 //! Errors
 
+use crate::types::ObjectId;
+
 pub enum LofireError {
     InvalidSignature,
     SerializationError,
+    InvalidTimestamp,
+    InvalidKey,
+    InvalidBlock,
+    MetadataTooLarge,
+    ObjectTooLarge,
+    MissingBody,
+    MissingDependency,
+    /// First commit that failed verification, see `verify_branch`
+    InvalidCommit(ObjectId),
+    NotMember,
+    PermissionDenied,
+    QuorumNotReached,
 }
 
 impl From<serde_bare::error::Error> for LofireError {
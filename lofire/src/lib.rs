@@ -6,6 +6,12 @@ pub mod block;
 
 pub mod object;
 
+pub mod chunking;
+
+pub mod compression;
+
+pub mod reader;
+
 pub mod commit;
 
 pub mod branch;
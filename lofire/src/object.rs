@@ -7,6 +7,10 @@ use debug_print::*;
 use chacha20::cipher::{KeyIvInit, StreamCipher};
 use chacha20::ChaCha20;
 
+use crate::chunking::*;
+use crate::compression::*;
+use crate::errors::*;
+use crate::reader::*;
 use crate::store::*;
 use crate::types::*;
 
@@ -15,7 +19,7 @@ const EMPTY_BLOCK_SIZE: usize = 12;
 /// Size of a serialized BlockId
 const BLOCK_ID_SIZE: usize = 33;
 /// Size of serialized SymKey
-const BLOCK_KEY_SIZE: usize = 33;
+pub(crate) const BLOCK_KEY_SIZE: usize = 33;
 /// Size of serialized Object with deps reference.
 const EMPTY_ROOT_SIZE_DEPSREF: usize = 77;
 /// Extra size needed if depsRef used instead of deps list.
@@ -27,6 +31,27 @@ const DATA_VARINT_EXTRA: usize = 4;
 /// Max extra space used by the deps list
 const MAX_DEPS_SIZE: usize = 8 * BLOCK_ID_SIZE;
 
+/// Number of bytes of the varint encoding of `n`
+fn varint_len(n: usize) -> usize {
+    let mut len = 1;
+    let mut n = n >> 7;
+    while n > 0 {
+        len += 1;
+        n >>= 7;
+    }
+    len
+}
+
+/// Size of the data of a leaf, from the size of its serialized `BlockContentV0::DataChunk`:
+/// one byte of enum tag, then the data prefixed with its varint length
+pub(crate) fn data_chunk_size(leaf_content_len: usize) -> usize {
+    let len = leaf_content_len.saturating_sub(1);
+    (1..=10)
+        .map(|prefix| len.saturating_sub(prefix))
+        .find(|size| varint_len(*size) + size == len)
+        .unwrap_or(0)
+}
+
 #[derive(Debug)]
 pub struct Object {
     /// Blocks of the Object (nodes of the tree)
@@ -55,6 +80,10 @@ pub enum ObjectParseError {
     BlockDeserializeError,
     /// Error deserializing content of the object
     ObjectDeserializeError,
+    /// Too many blocks arrived before their parent
+    ReorderBufferFull,
+    /// Error saving a block to the store
+    StorageError,
 }
 
 /// Object copy error
@@ -64,6 +93,23 @@ pub enum ObjectCopyError {
     ParseError,
 }
 
+/// Blocks of a new version of an object compared to the blocks of the previous version
+#[derive(Debug)]
+pub struct DiffReport {
+    /// Blocks present in both versions, that don't need to be stored or sent again
+    pub shared_blocks: Vec<BlockId>,
+    /// Blocks only present in the new version
+    pub new_blocks: Vec<BlockId>,
+    /// Blocks only present in the old version
+    pub removed_blocks: Vec<BlockId>,
+}
+
+impl From<ObjectRef> for WeakObjectRef {
+    fn from(r: ObjectRef) -> Self {
+        WeakObjectRef { id: r.id }
+    }
+}
+
 impl Object {
     fn convergence_key(repo_pubkey: PubKey, repo_secret: SymKey) -> [u8; blake3::OUT_LEN] {
         let key_material = match (repo_pubkey, repo_secret) {
@@ -74,12 +120,35 @@ impl Object {
         blake3::derive_key("LoFiRe Data BLAKE3 key", key_material.as_slice())
     }
 
+    /// Check that a root block belongs to the repo:
+    /// its content has to decrypt under `key` to the plaintext `key` was derived from
+    /// with the convergence key of the repo
+    pub fn verify_root_key(
+        root: &Block,
+        key: &SymKey,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> bool {
+        let conv_key = Self::convergence_key(repo_pubkey, repo_secret);
+        match key {
+            SymKey::ChaCha20Key(key) => {
+                let nonce = [0u8; 12];
+                let mut cipher = ChaCha20::new(key.into(), &nonce.into());
+                let mut content_dec = root.content().clone();
+                let mut content_dec_slice = &mut content_dec.as_mut_slice();
+                cipher.apply_keystream(&mut content_dec_slice);
+                blake3::keyed_hash(&conv_key, content_dec.as_slice()).as_bytes() == key
+            }
+        }
+    }
+
     fn make_block(
         content: &[u8],
         conv_key: &[u8; blake3::OUT_LEN],
         children: Vec<ObjectId>,
         deps: ObjectDeps,
         expiry: Option<Timestamp>,
+        compression: Option<(Compression, usize)>,
     ) -> Block {
         let key_hash = blake3::keyed_hash(conv_key, content);
         let nonce = [0u8; 12];
@@ -89,13 +158,44 @@ impl Object {
         let mut content_enc_slice = &mut content_enc.as_mut_slice();
         cipher.apply_keystream(&mut content_enc_slice);
         let key = SymKey::ChaCha20Key(key.clone());
-        let block = Block::new(children, deps, expiry, content_enc, Some(key));
+        let block = Block::new_with_compression(
+            children,
+            deps,
+            expiry,
+            content_enc,
+            Some(key),
+            compression,
+        );
         //debug_println!(">>> make_block:");
         //debug_println!("!! id: {:?}", obj.id());
         //debug_println!("!! children: ({}) {:?}", children.len(), children);
         block
     }
 
+    /// Make a leaf holding a data chunk, compressed if it gets smaller
+    fn make_leaf(
+        chunk: &[u8],
+        conv_key: &[u8; blake3::OUT_LEN],
+        deps: ObjectDeps,
+        expiry: Option<Timestamp>,
+        compression: ChunkCompression,
+    ) -> Block {
+        let (data, compression) = match compression.compress(chunk) {
+            Some((compression, compressed)) => (compressed, Some((compression, chunk.len()))),
+            None => (chunk.to_vec(), None),
+        };
+        let data_chunk = BlockContentV0::DataChunk(data);
+        let content_ser = serde_bare::to_vec(&data_chunk).unwrap();
+        Self::make_block(
+            content_ser.as_slice(),
+            conv_key,
+            vec![],
+            deps,
+            expiry,
+            compression,
+        )
+    }
+
     fn make_deps(
         deps_vec: Vec<ObjectId>,
         object_size: usize,
@@ -150,6 +250,7 @@ impl Object {
                 children,
                 deps,
                 expiry,
+                None,
             ));
         }
         //debug_println!("parents += {}", parents.len());
@@ -167,12 +268,15 @@ impl Object {
     /// The Object is chunked and stored in a Merkle tree
     /// The arity of the Merkle tree is the maximum that fits in the given `max_object_size`
     ///
+    /// Each leaf is compressed with `ChunkCompression::default()` when that makes it smaller,
+    /// use `new_with_chunking` to choose the compression
+    ///
     /// Arguments:
     /// * `content`: Object content
     /// * `deps`: Dependencies of the object
     /// * `block_size`: Desired block size for chunking content, rounded up to nearest valid block size
-    /// * `repo_pubkey`: Repository public key
-    /// * `repo_secret`: Repository secret
+    /// * `repo_pubkey`: Repository public key, or branch public key for the objects of a branch
+    /// * `repo_secret`: Repository secret, or branch secret for the objects of a branch
     pub fn new(
         content: ObjectContent,
         deps: Vec<ObjectId>,
@@ -180,9 +284,100 @@ impl Object {
         block_size: usize,
         repo_pubkey: PubKey,
         repo_secret: SymKey,
+    ) -> Object {
+        Self::new_with_chunking(
+            content,
+            deps,
+            expiry,
+            ChunkingStrategy::Fixed(block_size),
+            ChunkCompression::default(),
+            repo_pubkey,
+            repo_secret,
+        )
+    }
+
+    /// Create new Object from given content, split according to the `chunking` strategy,
+    /// with each data chunk compressed according to `compression` before it is encrypted
+    ///
+    /// With `ChunkingStrategy::ContentDefined`, the blocks are sized for the maximum chunk size.
+    /// Its leaves don't all hold the same number of bytes, so `ObjectReader` can't seek in them.
+    /// Chunks that don't get smaller when compressed are stored uncompressed
+    pub fn new_with_chunking(
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        chunking: ChunkingStrategy,
+        compression: ChunkCompression,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Object {
+        let content_ser = serde_bare::to_vec(&content).unwrap();
+        Self::from_serialized_content(
+            content_ser,
+            deps,
+            expiry,
+            chunking,
+            compression,
+            repo_pubkey,
+            repo_secret,
+        )
+    }
+
+    /// Create new Object from given content, like `new`,
+    /// unless the serialized content is larger than `max_object_size`,
+    /// usually the limit of the repo settings, see `Repository::max_object_size`.
+    /// Larger content is to be split into several objects
+    pub fn new_with_max_size(
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        block_size: usize,
+        max_object_size: u64,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<Object, LofireError> {
+        let content_ser = serde_bare::to_vec(&content)?;
+        if content_ser.len() as u64 > max_object_size {
+            return Err(LofireError::ObjectTooLarge);
+        }
+        Ok(Self::from_serialized_content(
+            content_ser,
+            deps,
+            expiry,
+            ChunkingStrategy::Fixed(block_size),
+            ChunkCompression::default(),
+            repo_pubkey,
+            repo_secret,
+        ))
+    }
+
+    /// Size of the blocks of an object chunked with the given strategy
+    fn valid_block_size(chunking: ChunkingStrategy) -> usize {
+        match chunking {
+            ChunkingStrategy::Fixed(block_size) => store_valid_value_size(block_size),
+            ChunkingStrategy::ContentDefined { max, .. } => {
+                store_valid_value_size(max.saturating_add(EMPTY_BLOCK_SIZE + DATA_VARINT_EXTRA))
+            }
+        }
+    }
+
+    /// Max number of ObjectRefs that fit inside an InternalNode Object within the block size
+    fn arity(valid_block_size: usize) -> usize {
+        (valid_block_size - EMPTY_BLOCK_SIZE - BIG_VARINT_EXTRA * 2 - MAX_DEPS_SIZE)
+            / (BLOCK_ID_SIZE + BLOCK_KEY_SIZE)
+    }
+
+    fn from_serialized_content(
+        content_ser: Vec<u8>,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        chunking: ChunkingStrategy,
+        compression: ChunkCompression,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
     ) -> Object {
         // create blocks by chunking + encrypting content
-        let valid_block_size = store_valid_value_size(block_size);
+        let valid_block_size = Self::valid_block_size(chunking);
         let data_chunk_size = valid_block_size - EMPTY_BLOCK_SIZE - DATA_VARINT_EXTRA;
 
         let mut blocks: Vec<Block> = vec![];
@@ -190,40 +385,40 @@ impl Object {
 
         let obj_deps = Self::make_deps(deps.clone(), valid_block_size, repo_pubkey, repo_secret);
 
-        let content_ser = serde_bare::to_vec(&content).unwrap();
-
         if EMPTY_BLOCK_SIZE + DATA_VARINT_EXTRA + BLOCK_ID_SIZE * deps.len() + content_ser.len()
             <= valid_block_size
         {
             // content fits in root node
-            let data_chunk = BlockContentV0::DataChunk(content_ser.clone());
-            let content_ser = serde_bare::to_vec(&data_chunk).unwrap();
-            blocks.push(Self::make_block(
-                content_ser.as_slice(),
+            blocks.push(Self::make_leaf(
+                &content_ser,
                 &conv_key,
-                vec![],
                 obj_deps,
                 expiry,
+                compression,
             ));
         } else {
             // chunk content and create leaf nodes
-            for chunk in content_ser.chunks(data_chunk_size) {
-                let data_chunk = BlockContentV0::DataChunk(chunk.to_vec());
-                let content_ser = serde_bare::to_vec(&data_chunk).unwrap();
-                blocks.push(Self::make_block(
-                    content_ser.as_slice(),
+            let chunks: Vec<&[u8]> = match chunking {
+                ChunkingStrategy::Fixed(_) => content_ser.chunks(data_chunk_size).collect(),
+                ChunkingStrategy::ContentDefined { min, avg, max } => content_defined_chunks(
+                    &content_ser,
+                    min,
+                    avg,
+                    std::cmp::min(max, data_chunk_size),
+                ),
+            };
+            for chunk in chunks {
+                blocks.push(Self::make_leaf(
+                    chunk,
                     &conv_key,
-                    vec![],
                     ObjectDeps::ObjectIdList(vec![]),
                     expiry,
+                    compression,
                 ));
             }
 
             // internal nodes
-            // arity: max number of ObjectRefs that fit inside an InternalNode Object within the object_size limit
-            let arity: usize =
-                (valid_block_size - EMPTY_BLOCK_SIZE - BIG_VARINT_EXTRA * 2 - MAX_DEPS_SIZE)
-                    / (BLOCK_ID_SIZE + BLOCK_KEY_SIZE);
+            let arity = Self::arity(valid_block_size);
             let mut parents =
                 Self::make_tree(blocks.as_slice(), &conv_key, &obj_deps, expiry, arity);
             blocks.append(&mut parents);
@@ -291,11 +486,7 @@ impl Object {
                 match store.get(&id) {
                     Ok(block) => {
                         blocks.insert(0, block.clone());
-                        match block {
-                            Block::V0(o) => {
-                                children.extend(o.children.iter().rev());
-                            }
-                        }
+                        children.extend(block.children().iter().rev());
                     }
                     Err(_) => missing.push(id.clone()),
                 }
@@ -333,6 +524,43 @@ impl Object {
         Ok(Object { blocks, deps })
     }
 
+    /// Open a streaming reader of the content of an object in the store,
+    /// that only holds one path of the tree in memory
+    pub fn reader<S: RepoStore>(
+        id: ObjectId,
+        key: SymKey,
+        store: &S,
+    ) -> Result<ObjectReader<S>, ObjectParseError> {
+        ObjectReader::open(id, key, store)
+    }
+
+    /// Read `len` bytes of the serialized content of an object from offset `start`,
+    /// verifying the blocks on the path to each leaf of the range against the object ID.
+    /// Returns `ObjectParseError::MissingBlocks` with the next blocks needed from the store
+    pub fn read_range<S: RepoStore>(
+        id: ObjectId,
+        key: SymKey,
+        store: &S,
+        start: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, ObjectParseError> {
+        ObjectReader::open(id, key, store)?.read_range(start, len)
+    }
+
+    /// Blocks needed to read `len` bytes of the serialized content of an object from offset `start`,
+    /// found from the shape of the tree without the key of the object, so the broker can serve them:
+    /// the leftmost and rightmost paths needed to open an `ObjectReader`,
+    /// and the paths to the leaves of the range, root first.
+    /// Objects with compressed leaves are returned whole
+    pub fn range_blocks<S: RepoStore>(
+        id: ObjectId,
+        store: &S,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<Block>, ObjectParseError> {
+        range_blocks(id, store, start, len)
+    }
+
     /// Save blocks of the object in the store
     pub fn save(&self, store: &mut impl RepoStore) -> Result<(), StorageError> {
         let mut deduplicated: HashSet<ObjectId> = HashSet::new();
@@ -346,6 +574,51 @@ impl Object {
         Ok(())
     }
 
+    /// Compare the blocks of an object loaded from the store
+    /// with the blocks of a new object created from `content`
+    ///
+    /// Each block is only reported once, in the order of `blocks()`.
+    /// The remaining arguments are the ones of `Object::new`
+    pub fn diff(
+        store: &impl RepoStore,
+        old_id: ObjectId,
+        content: ObjectContent,
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        block_size: usize,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> Result<(Object, DiffReport), ObjectParseError> {
+        let old = Object::load(old_id, None, store)?;
+        let new = Object::new(content, deps, expiry, block_size, repo_pubkey, repo_secret);
+
+        let old_ids: HashSet<BlockId> = old.blocks.iter().map(|b| b.id()).collect();
+        let new_ids: HashSet<BlockId> = new.blocks.iter().map(|b| b.id()).collect();
+
+        let mut seen: HashSet<BlockId> = HashSet::new();
+        let (shared_blocks, new_blocks): (Vec<BlockId>, Vec<BlockId>) = new
+            .blocks
+            .iter()
+            .map(|b| b.id())
+            .filter(|id| seen.insert(*id))
+            .partition(|id| old_ids.contains(id));
+        let removed_blocks = old
+            .blocks
+            .iter()
+            .map(|b| b.id())
+            .filter(|id| !new_ids.contains(id) && seen.insert(*id))
+            .collect();
+
+        Ok((
+            new,
+            DiffReport {
+                shared_blocks,
+                new_blocks,
+                removed_blocks,
+            },
+        ))
+    }
+
     /// Get the ID of the Object
     pub fn id(&self) -> ObjectId {
         self.blocks.last().unwrap().id()
@@ -368,6 +641,11 @@ impl Object {
         }
     }
 
+    /// Get a `WeakObjectRef` for the root object
+    pub fn weak_reference(&self) -> WeakObjectRef {
+        WeakObjectRef { id: self.id() }
+    }
+
     pub fn root(&self) -> &Block {
         self.blocks.last().unwrap()
     }
@@ -380,10 +658,40 @@ impl Object {
         &self.deps
     }
 
+    /// Get the blocks of the object in a stable order:
+    /// leaves first in content order, then each level of internal nodes, root last.
+    ///
+    /// The same content always yields the same order, whether the object was created or loaded
     pub fn blocks(&self) -> &Vec<Block> {
         &self.blocks
     }
 
+    /// Get the number of bytes the object takes in the store,
+    /// as the sum of `store_block_footprint` of its blocks.
+    /// Blocks that appear several times in the object are only stored and counted once
+    pub fn storage_footprint(&self) -> u64 {
+        let mut seen: HashSet<BlockId> = HashSet::new();
+        self.blocks
+            .iter()
+            .filter(|b| seen.insert(b.id()))
+            .map(|b| store_block_footprint(b))
+            .sum()
+    }
+
+    /// Get the size of the serialized content of the object, from the sizes of its leaves.
+    /// It doesn't need the key of the object, so the broker can check it.
+    /// Compressed leaves count with the decompressed size recorded in their header
+    pub fn content_size(&self) -> u64 {
+        self.blocks
+            .iter()
+            .filter(|b| b.children().is_empty())
+            .map(|b| {
+                b.decompressed_size()
+                    .unwrap_or_else(|| data_chunk_size(b.content_len())) as u64
+            })
+            .sum()
+    }
+
     pub fn to_hashmap(&self) -> HashMap<BlockId, Block> {
         let mut map: HashMap<BlockId, Block> = HashMap::new();
         for block in &self.blocks {
@@ -392,6 +700,21 @@ impl Object {
         map
     }
 
+    /// Load an Object from the blocks of `to_hashmap`
+    pub fn from_hashmap(
+        id: ObjectId,
+        key: Option<SymKey>,
+        blocks: &HashMap<BlockId, Block>,
+    ) -> Result<Object, ObjectParseError> {
+        let store = HashMapRepoStore::new();
+        for block in blocks.values() {
+            store
+                .put(block)
+                .map_err(|_e| ObjectParseError::StorageError)?;
+        }
+        Self::load(id, key, &store)
+    }
+
     /// Collect leaves from the tree
     fn collect_leaves(
         blocks: &Vec<Block>,
@@ -419,59 +742,58 @@ impl Object {
                 return Err(ObjectParseError::InvalidBlockId);
             }
 
-            match block {
-                Block::V0(b) => {
-                    // decrypt content
-                    let mut content_dec = b.content.clone();
-                    match key {
-                        SymKey::ChaCha20Key(key) => {
-                            let nonce = [0u8; 12];
-                            let mut cipher = ChaCha20::new(key.into(), &nonce.into());
-                            let mut content_dec_slice = &mut content_dec.as_mut_slice();
-                            cipher.apply_keystream(&mut content_dec_slice);
-                        }
-                    }
+            // decrypt content
+            let mut content_dec = block.content().clone();
+            match key {
+                SymKey::ChaCha20Key(key) => {
+                    let nonce = [0u8; 12];
+                    let mut cipher = ChaCha20::new(key.into(), &nonce.into());
+                    let mut content_dec_slice = &mut content_dec.as_mut_slice();
+                    cipher.apply_keystream(&mut content_dec_slice);
+                }
+            }
 
-                    // deserialize content
-                    let content: BlockContentV0;
-                    match serde_bare::from_slice(content_dec.as_slice()) {
-                        Ok(c) => content = c,
-                        Err(e) => {
-                            debug_println!("Block deserialize error: {}", e);
-                            return Err(ObjectParseError::BlockDeserializeError);
-                        }
+            // deserialize content
+            let content: BlockContentV0;
+            match serde_bare::from_slice(content_dec.as_slice()) {
+                Ok(c) => content = c,
+                Err(e) => {
+                    debug_println!("Block deserialize error: {}", e);
+                    return Err(ObjectParseError::BlockDeserializeError);
+                }
+            }
+
+            // parse content
+            match content {
+                BlockContentV0::InternalNode(keys) => {
+                    if keys.len() != block.children().len() {
+                        debug_println!(
+                            "Invalid keys length: got {}, expected {}",
+                            keys.len(),
+                            block.children().len()
+                        );
+                        debug_println!("!!! children: {:?}", block.children());
+                        debug_println!("!!! keys: {:?}", keys);
+                        return Err(ObjectParseError::InvalidKeys);
                     }
 
-                    // parse content
-                    match content {
-                        BlockContentV0::InternalNode(keys) => {
-                            if keys.len() != b.children.len() {
-                                debug_println!(
-                                    "Invalid keys length: got {}, expected {}",
-                                    keys.len(),
-                                    b.children.len()
-                                );
-                                debug_println!("!!! children: {:?}", b.children);
-                                debug_println!("!!! keys: {:?}", keys);
-                                return Err(ObjectParseError::InvalidKeys);
-                            }
-
-                            for (id, key) in b.children.iter().zip(keys.iter()) {
-                                children.push((id.clone(), key.clone()));
-                            }
-                        }
-                        BlockContentV0::DataChunk(chunk) => {
-                            if leaves.is_some() {
-                                let mut leaf = block.clone();
-                                leaf.set_key(Some(*key));
-                                let l = &mut **leaves.as_mut().unwrap();
-                                l.push(leaf);
-                            }
-                            if obj_content.is_some() {
-                                let c = &mut **obj_content.as_mut().unwrap();
-                                c.extend_from_slice(chunk.as_slice());
-                            }
-                        }
+                    for (id, key) in block.children().iter().zip(keys.iter()) {
+                        children.push((id.clone(), key.clone()));
+                    }
+                }
+                BlockContentV0::DataChunk(chunk) => {
+                    let chunk = block
+                        .decompress_chunk(chunk)
+                        .ok_or(ObjectParseError::BlockDeserializeError)?;
+                    if leaves.is_some() {
+                        let mut leaf = block.clone();
+                        leaf.set_key(Some(*key));
+                        let l = &mut **leaves.as_mut().unwrap();
+                        l.push(leaf);
+                    }
+                    if obj_content.is_some() {
+                        let c = &mut **obj_content.as_mut().unwrap();
+                        c.extend_from_slice(chunk.as_slice());
                     }
                 }
             }
@@ -539,9 +861,376 @@ impl Object {
     }
 }
 
+/// Incremental construction of an Object from its serialized content, received in pieces
+///
+/// Leaves are made as soon as their chunk is complete, and internal nodes as soon as
+/// it is known they are not the root, so only about one block per level of the tree is buffered.
+/// The blocks and the object ID are the same as with `Object::new_with_chunking` on the whole content.
+pub struct ObjectBuilder {
+    conv_key: ChaCha20Key,
+    deps: ObjectDeps,
+    expiry: Option<Timestamp>,
+    chunking: ChunkingStrategy,
+    compression: ChunkCompression,
+
+    /// Maximum size of the data of a leaf
+    data_chunk_size: usize,
+
+    /// Maximum size of the content that fits in a root leaf, with the deps
+    root_leaf_size: usize,
+
+    /// Arity of the tree
+    arity: usize,
+
+    /// Content received and not chunked yet
+    buffer: Vec<u8>,
+
+    /// Whether the content is too large to fit in a root leaf
+    chunked: bool,
+
+    /// Children waiting for their parent, for each level of the tree, leaves first
+    levels: Vec<Vec<(BlockId, SymKey)>>,
+}
+
+impl ObjectBuilder {
+    /// New builder, see `Object::new_with_chunking` for the arguments
+    pub fn new(
+        deps: Vec<ObjectId>,
+        expiry: Option<Timestamp>,
+        chunking: ChunkingStrategy,
+        compression: ChunkCompression,
+        repo_pubkey: PubKey,
+        repo_secret: SymKey,
+    ) -> ObjectBuilder {
+        let valid_block_size = Object::valid_block_size(chunking);
+        let data_chunk_size = valid_block_size - EMPTY_BLOCK_SIZE - DATA_VARINT_EXTRA;
+        ObjectBuilder {
+            conv_key: Object::convergence_key(repo_pubkey, repo_secret),
+            deps: Object::make_deps(deps.clone(), valid_block_size, repo_pubkey, repo_secret),
+            expiry,
+            chunking,
+            compression,
+            data_chunk_size,
+            root_leaf_size: data_chunk_size.saturating_sub(BLOCK_ID_SIZE * deps.len()),
+            arity: Object::arity(valid_block_size),
+            buffer: vec![],
+            chunked: false,
+            levels: vec![],
+        }
+    }
+
+    /// Size of the blocks of the object, a good size for the pieces of content added
+    pub fn block_size(&self) -> usize {
+        self.data_chunk_size + EMPTY_BLOCK_SIZE + DATA_VARINT_EXTRA
+    }
+
+    /// Add the next piece of the serialized content.
+    /// Returns the blocks completed so far, children before their parent
+    pub fn add(&mut self, content: &[u8]) -> Vec<Block> {
+        self.buffer.extend_from_slice(content);
+        let mut blocks = vec![];
+        if !self.chunked && self.buffer.len() > self.root_leaf_size {
+            self.chunked = true;
+        }
+        if self.chunked {
+            self.make_leaves(false, &mut blocks);
+        }
+        blocks
+    }
+
+    /// Ends the content, and returns the remaining blocks, the root last, with the object ID
+    pub fn finish(mut self) -> (ObjectId, Vec<Block>) {
+        if !self.chunked {
+            let root = Object::make_leaf(
+                &self.buffer,
+                &self.conv_key,
+                self.deps.clone(),
+                self.expiry,
+                self.compression,
+            );
+            return (root.id(), vec![root]);
+        }
+        let mut blocks = vec![];
+        self.make_leaves(true, &mut blocks);
+        let mut level = 0;
+        loop {
+            let children = std::mem::take(&mut self.levels[level]);
+            if self.levels.len() == level + 1 {
+                // all the nodes of the level fit in one parent
+                let root = self.make_node(children, self.deps.clone());
+                let id = root.id();
+                blocks.push(root);
+                return (id, blocks);
+            }
+            let parent = self.make_node(children, ObjectDeps::ObjectIdList(vec![]));
+            self.add_node(level + 1, (parent.id(), parent.key().unwrap()), &mut blocks);
+            blocks.push(parent);
+            level += 1;
+        }
+    }
+
+    /// Makes the leaves of the chunks that can't change anymore, or of all the chunks at the end
+    fn make_leaves(&mut self, end: bool, blocks: &mut Vec<Block>) {
+        let mut start = 0;
+        loop {
+            let rest = &self.buffer[start..];
+            // a chunk is cut within its maximum size, so it is known once that much content is buffered
+            let max = match self.chunking {
+                ChunkingStrategy::Fixed(_) => self.data_chunk_size,
+                ChunkingStrategy::ContentDefined { max, .. } => {
+                    std::cmp::min(max, self.data_chunk_size).max(1)
+                }
+            };
+            let ready = match end {
+                true => !rest.is_empty(),
+                false => rest.len() >= max,
+            };
+            if !ready {
+                break;
+            }
+            let len = match self.chunking {
+                ChunkingStrategy::Fixed(_) => std::cmp::min(rest.len(), max),
+                ChunkingStrategy::ContentDefined { min, avg, .. } => {
+                    content_defined_cut(rest, min, avg, max)
+                }
+            };
+            let leaf = Object::make_leaf(
+                &rest[..len],
+                &self.conv_key,
+                ObjectDeps::ObjectIdList(vec![]),
+                self.expiry,
+                self.compression,
+            );
+            start += len;
+            self.add_node(0, (leaf.id(), leaf.key().unwrap()), blocks);
+            blocks.push(leaf);
+        }
+        self.buffer.drain(..start);
+    }
+
+    /// Adds a node to a level. When the level is full, another parent is needed,
+    /// so the parent of the nodes already there is made: it can't be the root
+    fn add_node(&mut self, level: usize, node: (BlockId, SymKey), blocks: &mut Vec<Block>) {
+        if self.levels.len() == level {
+            self.levels.push(vec![]);
+        }
+        if self.levels[level].len() == self.arity {
+            let children = std::mem::take(&mut self.levels[level]);
+            let parent = self.make_node(children, ObjectDeps::ObjectIdList(vec![]));
+            self.add_node(level + 1, (parent.id(), parent.key().unwrap()), blocks);
+            blocks.push(parent);
+        }
+        self.levels[level].push(node);
+    }
+
+    fn make_node(&self, children: Vec<(BlockId, SymKey)>, deps: ObjectDeps) -> Block {
+        let (children, keys): (Vec<BlockId>, Vec<SymKey>) = children.into_iter().unzip();
+        let content = BlockContentV0::InternalNode(keys);
+        let content_ser = serde_bare::to_vec(&content).unwrap();
+        Object::make_block(
+            content_ser.as_slice(),
+            &self.conv_key,
+            children,
+            deps,
+            self.expiry,
+            None,
+        )
+    }
+}
+
+/// Incremental assembly of an Object from blocks received in tree order
+///
+/// Each block is verified against the block IDs expected next
+/// (the children of the blocks accepted so far, starting with the root),
+/// and when the key of the object is known, against the keys given by its parent:
+/// an internal node must have a key for each child, and a leaf must decrypt to a data chunk.
+/// Accepted blocks are put in a store and released, so the assembler only holds
+/// the IDs and keys of the blocks expected next, and the blocks received before their parent.
+/// Blocks that are not expected are discarded without being retained.
+pub struct ObjectAssembler<'a, S: RepoStore> {
+    /// Object ID (root block ID)
+    id: ObjectId,
+
+    /// Object key
+    key: Option<SymKey>,
+
+    /// Block IDs expected next, with their key when the object key is known
+    frontier: HashMap<BlockId, Option<SymKey>>,
+
+    /// Store of the accepted blocks
+    store: &'a S,
+
+    /// IDs of the blocks accepted by this assembler.
+    /// Other blocks already in the store may belong to an incomplete subtree
+    accepted: HashSet<BlockId>,
+
+    /// Blocks received before their parent, waiting to be verified
+    early: HashMap<BlockId, Block>,
+
+    /// Maximum number of blocks waiting in `early`
+    reorder_capacity: usize,
+}
+
+impl<'a, S: RepoStore> ObjectAssembler<'a, S> {
+    /// New assembler for the object with the given root block ID, putting the accepted blocks in `store`
+    pub fn new(id: ObjectId, key: Option<SymKey>, store: &'a S) -> ObjectAssembler<'a, S> {
+        Self::with_reorder_buffer(id, key, store, 0)
+    }
+
+    /// New assembler that holds up to `capacity` blocks received out of tree order
+    /// until their parent is accepted, see `add_unordered`
+    pub fn with_reorder_buffer(
+        id: ObjectId,
+        key: Option<SymKey>,
+        store: &'a S,
+        capacity: usize,
+    ) -> ObjectAssembler<'a, S> {
+        let mut frontier = HashMap::new();
+        frontier.insert(id, key);
+        ObjectAssembler {
+            id,
+            key,
+            frontier,
+            store,
+            accepted: HashSet::new(),
+            early: HashMap::new(),
+            reorder_capacity: capacity,
+        }
+    }
+
+    /// Keys of the children of a block, decrypted with the key given by its parent.
+    /// Leaves have no children, their content is only checked
+    fn child_keys(block: &Block, key: &SymKey) -> Result<Vec<SymKey>, ObjectParseError> {
+        let mut content_dec = block.content().clone();
+        match key {
+            SymKey::ChaCha20Key(key) => {
+                let nonce = [0u8; 12];
+                let mut cipher = ChaCha20::new(key.into(), &nonce.into());
+                let mut content_dec_slice = &mut content_dec.as_mut_slice();
+                cipher.apply_keystream(&mut content_dec_slice);
+            }
+        }
+        match serde_bare::from_slice(content_dec.as_slice()) {
+            Ok(BlockContentV0::InternalNode(keys)) => {
+                if keys.len() != block.children().len() {
+                    return Err(ObjectParseError::InvalidKeys);
+                }
+                Ok(keys)
+            }
+            Ok(BlockContentV0::DataChunk(_)) => {
+                if !block.children().is_empty() {
+                    return Err(ObjectParseError::InvalidChildren);
+                }
+                Ok(vec![])
+            }
+            Err(e) => {
+                debug_println!("Block deserialize error: {}", e);
+                Err(ObjectParseError::BlockDeserializeError)
+            }
+        }
+    }
+
+    /// Add a received block
+    ///
+    /// Returns false if the block was not expected and has been discarded,
+    /// or an error if it doesn't decrypt with the key given by its parent
+    pub fn add(&mut self, block: Block) -> Result<bool, ObjectParseError> {
+        let id = block.get_id();
+        let key = match self.frontier.get(&id) {
+            None => return Ok(false),
+            Some(key) => *key,
+        };
+        let keys = match key {
+            Some(key) => Self::child_keys(&block, &key)?
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None; block.children().len()],
+        };
+        self.store
+            .put(&block)
+            .map_err(|_e| ObjectParseError::StorageError)?;
+        self.frontier.remove(&id);
+        self.accepted.insert(id);
+        for (child, key) in block.children().iter().zip(keys) {
+            // a child shared with a block accepted before was already expanded
+            if !self.accepted.contains(child) {
+                self.frontier.insert(*child, key);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Add a received block, in any order
+    ///
+    /// A block that is not expected yet is held in the reordering buffer,
+    /// and accepted once its parent is. Blocks of other objects can't be told apart
+    /// from early ones, they stay in the buffer until `finish`.
+    /// Returns false if the block was a duplicate and has been discarded,
+    /// or `ObjectParseError::ReorderBufferFull` if the buffer is full
+    pub fn add_unordered(&mut self, block: Block) -> Result<bool, ObjectParseError> {
+        let id = block.get_id();
+        if self.frontier.contains_key(&id) {
+            let mut ready = vec![block];
+            while let Some(block) = ready.pop() {
+                let children = block.children().clone();
+                self.add(block)?;
+                for child in children {
+                    if let Some(early) = self.early.remove(&child) {
+                        ready.push(early);
+                    }
+                }
+            }
+            return Ok(true);
+        }
+        if self.early.contains_key(&id) || self.accepted.contains(&id) {
+            return Ok(false);
+        }
+        if self.early.len() >= self.reorder_capacity {
+            return Err(ObjectParseError::ReorderBufferFull);
+        }
+        self.early.insert(id, block);
+        Ok(true)
+    }
+
+    /// Block IDs expected but not received yet
+    pub fn missing(&self) -> Vec<BlockId> {
+        self.frontier.keys().cloned().collect()
+    }
+
+    /// Number of blocks held by the assembler, in the reordering buffer.
+    /// The accepted blocks are in the store
+    pub fn retained(&self) -> usize {
+        self.early.len()
+    }
+
+    /// Check whether all blocks of the object have been received
+    pub fn is_complete(&self) -> bool {
+        self.frontier.is_empty()
+    }
+
+    /// Check that all the blocks of the object have been received
+    ///
+    /// Returns `ObjectParseError::MissingBlocks` with the IDs of missing blocks
+    pub fn finish(self) -> Result<ObjectId, ObjectParseError> {
+        if !self.is_complete() {
+            return Err(ObjectParseError::MissingBlocks(self.missing()));
+        }
+        Ok(self.id)
+    }
+
+    /// Load the assembled object from the store
+    pub fn finish_object(self) -> Result<Object, ObjectParseError> {
+        let (key, store) = (self.key, self.store);
+        let id = self.finish()?;
+        Object::load(id, key, store)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
+    use crate::errors::*;
     use crate::object::*;
     use crate::store::*;
     use crate::types::*;
@@ -566,7 +1255,7 @@ mod test {
         let content = ObjectContent::File(file);
 
         let deps: Vec<ObjectId> = vec![Digest::Blake3Digest32([9; 32])];
-        let exp = Some(2u32.pow(31));
+        let exp = Some(Timestamp::from_minutes(2u32.pow(31)));
         let max_object_size = 0;
 
         let repo_secret = SymKey::ChaCha20Key([0; 32]);
@@ -646,7 +1335,7 @@ mod test {
             Ok(_) => panic!("Object3 should not return content"),
         }
 
-        let exp4 = Some(2342);
+        let exp4 = Some(Timestamp::from_minutes(2342));
         let obj4 = obj.copy(exp4, repo_pubkey, repo_secret).unwrap();
         obj4.save(&mut store).unwrap();
 
@@ -690,7 +1379,7 @@ mod test {
         let content_ser = serde_bare::to_vec(&content).unwrap();
         println!("content len: {}", content_ser.len());
 
-        let expiry = Some(2u32.pow(31));
+        let expiry = Some(Timestamp::from_minutes(2u32.pow(31)));
         let max_object_size = store_max_value_size();
 
         let repo_secret = SymKey::ChaCha20Key([0; 32]);
@@ -713,6 +1402,70 @@ mod test {
         assert_eq!(object.blocks.len(), 1);
     }
 
+    #[test]
+    pub fn test_block_validate() {
+        let file = File::V0(FileV0 {
+            content_type: vec![],
+            metadata: vec![],
+            content: (0..10000).map(|i| (i % 251) as u8).collect(),
+        });
+        let obj = Object::new(
+            ObjectContent::File(file),
+            vec![],
+            None,
+            1000,
+            PubKey::Ed25519PubKey([1; 32]),
+            SymKey::ChaCha20Key([2; 32]),
+        );
+        assert!(obj.blocks().len() > 1);
+        for block in obj.blocks() {
+            assert!(block.validate().is_ok());
+        }
+
+        // the root declares one more child than it has keys for
+        let root = obj.blocks().last().unwrap();
+        let mut children = root.children().clone();
+        children.push(children[0]);
+        let bad_root = Block::new(
+            children,
+            root.deps().clone(),
+            root.expiry(),
+            root.content().clone(),
+            None,
+        );
+        assert_eq!(bad_root.content_len(), root.content_len());
+        assert!(matches!(
+            bad_root.validate(),
+            Err(LofireError::InvalidBlock)
+        ));
+
+        // a leaf too short to hold a data chunk
+        let bad_leaf = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![0],
+            None,
+        );
+        assert!(matches!(
+            bad_leaf.validate(),
+            Err(LofireError::InvalidBlock)
+        ));
+
+        // a leaf larger than the maximum block size
+        let big_leaf = Block::new(
+            vec![],
+            ObjectDeps::ObjectIdList(vec![]),
+            None,
+            vec![0; store_max_value_size() + 1],
+            None,
+        );
+        assert!(matches!(
+            big_leaf.validate(),
+            Err(LofireError::InvalidBlock)
+        ));
+    }
+
     #[test]
     pub fn test_block_size() {
         let max_block_size = store_max_value_size();
@@ -739,7 +1492,7 @@ mod test {
         let leaf_empty = Block::new(
             vec![],
             ObjectDeps::ObjectIdList(vec![]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             data_ser.clone(),
             None,
         );
@@ -748,7 +1501,7 @@ mod test {
         let leaf_full_data = Block::new(
             vec![],
             ObjectDeps::ObjectIdList(vec![]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             data_full_ser.clone(),
             None,
         );
@@ -757,7 +1510,7 @@ mod test {
         let root_depsref = Block::new(
             vec![],
             ObjectDeps::DepListRef(ObjectRef { id: id, key: key }),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             data_ser.clone(),
             None,
         );
@@ -767,7 +1520,7 @@ mod test {
         let internal_max = Block::new(
             vec![id; MAX_ARITY_LEAVES],
             ObjectDeps::ObjectIdList(vec![]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             max_keys_ser.clone(),
             None,
         );
@@ -776,7 +1529,7 @@ mod test {
         let internal_one = Block::new(
             vec![id; 1],
             ObjectDeps::ObjectIdList(vec![]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             one_key_ser.clone(),
             None,
         );
@@ -785,7 +1538,7 @@ mod test {
         let internal_two = Block::new(
             vec![id; 2],
             ObjectDeps::ObjectIdList(vec![]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             two_keys_ser.clone(),
             None,
         );
@@ -794,7 +1547,7 @@ mod test {
         let root_one = Block::new(
             vec![id; 1],
             ObjectDeps::ObjectIdList(vec![id; 8]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             one_key_ser.clone(),
             None,
         );
@@ -803,7 +1556,7 @@ mod test {
         let root_two = Block::new(
             vec![id; 2],
             ObjectDeps::ObjectIdList(vec![id; 8]),
-            Some(2342),
+            Some(Timestamp::from_minutes(2342)),
             two_keys_ser.clone(),
             None,
         );
@@ -895,4 +1648,568 @@ mod test {
         // println!("max arity of 1-page object: {}", arity_1);
         // println!("max arity of 512-page object: {}", arity_512);
     }
+
+    /// Checks that incremental assembly only retains blocks of the object
+    #[test]
+    pub fn test_assembler() {
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: [(0..255).collect::<Vec<u8>>().as_slice(); 4000].concat(),
+        }));
+        let deps: Vec<ObjectId> = vec![];
+        let max_object_size = store_valid_value_size(0);
+
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+
+        let obj = Object::new(
+            content.clone(),
+            deps.clone(),
+            None,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+        );
+        let unique: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+        println!("obj.blocks.len: {:?}", obj.blocks().len());
+        println!("unique blocks: {:?}", unique.len());
+
+        let other = Object::new(
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/other"),
+                metadata: vec![],
+                content: vec![7; 10000],
+            })),
+            deps,
+            None,
+            max_object_size,
+            repo_pubkey,
+            repo_secret,
+        );
+
+        // blocks arrive in tree order, interleaved with unrelated and duplicate blocks
+        let store = HashMapRepoStore::new();
+        let mut assembler = ObjectAssembler::new(obj.id(), obj.key(), &store);
+        let mut others = other.blocks().iter();
+        let mut max_frontier = 0;
+        for block in obj.blocks().iter().rev() {
+            assembler.add(block.clone()).unwrap();
+            assert!(!assembler.add(block.clone()).unwrap());
+            if let Some(o) = others.next() {
+                assert!(!assembler.add(o.clone()).unwrap());
+            }
+            // the accepted blocks are released to the store
+            assert_eq!(assembler.retained(), 0);
+            max_frontier = max_frontier.max(assembler.missing().len());
+        }
+        assert!(assembler.is_complete());
+        assert!(max_frontier < unique.len());
+        assert_eq!(store.len().unwrap(), unique.len());
+
+        let obj2 = assembler.finish_object().expect("Object assembly error");
+        assert_eq!(obj2.id(), obj.id());
+        match obj2.content() {
+            Ok(cnt) => {
+                assert_eq!(content, cnt);
+            }
+            Err(e) => panic!("Object2 parse error: {:?}", e),
+        }
+
+        // a block that doesn't decrypt with the key of its parent is refused
+        let store = HashMapRepoStore::new();
+        let wrong_key = SymKey::ChaCha20Key([9; 32]);
+        let mut assembler = ObjectAssembler::new(obj.id(), Some(wrong_key), &store);
+        assert!(assembler.add(obj.root().clone()).is_err());
+        assert_eq!(store.len().unwrap(), 0);
+
+        // a missing leaf is reported by ID
+        let leaf = obj.blocks()[0].id();
+        let store = HashMapRepoStore::new();
+        let mut assembler = ObjectAssembler::new(obj.id(), obj.key(), &store);
+        for block in obj.blocks().iter().rev() {
+            if block.id() != leaf {
+                assembler.add(block.clone()).unwrap();
+            }
+        }
+        match assembler.finish() {
+            Err(ObjectParseError::MissingBlocks(missing)) => {
+                assert_eq!(missing, vec![leaf]);
+            }
+            Err(e) => panic!("Object parse error: {:?}", e),
+            Ok(_) => panic!("Object should not be complete"),
+        }
+    }
+
+    /// Checks that a block already in the store doesn't stand for its subtree
+    #[test]
+    pub fn test_assembler_partial_store() {
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..300000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let obj = Object::new(
+            content.clone(),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        );
+        let unique: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+
+        // an internal node left in the store by an interrupted download, without its children
+        let node = obj
+            .blocks()
+            .iter()
+            .find(|b| !b.children().is_empty() && b.id() != obj.id())
+            .unwrap();
+        let store = HashMapRepoStore::new();
+        store.put(node).unwrap();
+
+        let mut assembler = ObjectAssembler::new(obj.id(), obj.key(), &store);
+        for block in obj.blocks().iter().rev() {
+            assembler.add(block.clone()).unwrap();
+        }
+        assert!(assembler.is_complete());
+        assert_eq!(store.len().unwrap(), unique.len());
+        let obj2 = assembler.finish_object().expect("Object assembly error");
+        assert_eq!(obj2.content().unwrap(), content);
+    }
+
+    /// Checks that blocks arriving in reverse tree order are reassembled within the buffer bound
+    #[test]
+    pub fn test_assembler_reorder() {
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..100000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let obj = Object::new(
+            content.clone(),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        );
+        let unique: HashSet<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+
+        // blocks() is leaves first and root last: all but the root arrive early
+        let store = HashMapRepoStore::new();
+        let mut assembler =
+            ObjectAssembler::with_reorder_buffer(obj.id(), obj.key(), &store, unique.len());
+        for block in obj.blocks() {
+            assembler.add_unordered(block.clone()).unwrap();
+            assert!(assembler.retained() <= unique.len());
+        }
+        assert!(assembler.is_complete());
+        assert_eq!(assembler.retained(), 0);
+        let obj2 = assembler.finish_object().expect("Object assembly error");
+        assert_eq!(obj2.content().unwrap(), content);
+
+        // a duplicate is discarded
+        let store = HashMapRepoStore::new();
+        let mut assembler = ObjectAssembler::with_reorder_buffer(obj.id(), obj.key(), &store, 1);
+        assert!(assembler.add_unordered(obj.blocks()[0].clone()).unwrap());
+        assert!(!assembler.add_unordered(obj.blocks()[0].clone()).unwrap());
+
+        // the buffer is bounded
+        match assembler.add_unordered(obj.blocks()[1].clone()) {
+            Err(ObjectParseError::ReorderBufferFull) => {}
+            _ => panic!("the reordering buffer should be full"),
+        }
+    }
+
+    /// Checks that the order of blocks is identical across constructions and loading
+    #[test]
+    pub fn test_blocks_order() {
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..100000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+
+        let obj1 = Object::new(
+            content.clone(),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        );
+        let obj2 = Object::new(content, vec![], None, 4000, repo_pubkey, repo_secret);
+        let ids1: Vec<BlockId> = obj1.blocks().iter().map(|b| b.id()).collect();
+        let ids2: Vec<BlockId> = obj2.blocks().iter().map(|b| b.id()).collect();
+        println!("obj.blocks.len: {:?}", ids1.len());
+        assert!(ids1.len() > 1);
+        assert_eq!(ids1, ids2);
+        assert_eq!(*ids1.last().unwrap(), obj1.id());
+
+        let mut store = HashMapRepoStore::new();
+        obj1.save(&mut store).unwrap();
+        let obj3 = Object::load(obj1.id(), obj1.key(), &store).unwrap();
+        let ids3: Vec<BlockId> = obj3.blocks().iter().map(|b| b.id()).collect();
+        assert_eq!(ids1, ids3);
+    }
+
+    /// Checks that an insertion near the start of a file only changes a few leaves
+    /// with content-defined chunking, and almost all of them with fixed-size chunks
+    #[test]
+    pub fn test_content_defined_chunking() {
+        let mut x: u32 = 1;
+        let data: Vec<u8> = (0..1000000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let mut inserted = data.clone();
+        inserted.insert(100, 42);
+        let file = |content: Vec<u8>| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content,
+            }))
+        };
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let leaves = |content: Vec<u8>, chunking| -> HashSet<BlockId> {
+            let obj = Object::new_with_chunking(
+                file(content),
+                vec![],
+                None,
+                chunking,
+                ChunkCompression::None,
+                repo_pubkey,
+                repo_secret,
+            );
+            obj.leaves().unwrap().iter().map(|b| b.id()).collect()
+        };
+
+        let cdc = ChunkingStrategy::ContentDefined {
+            min: 1024,
+            avg: 4096,
+            max: 16384,
+        };
+        let old = leaves(data.clone(), cdc);
+        let new = leaves(inserted.clone(), cdc);
+        let changed = new.difference(&old).count();
+        println!("content-defined: {} leaves, {} changed", new.len(), changed);
+        assert!(new.len() > 100);
+        assert!(changed <= 3);
+
+        let fixed = ChunkingStrategy::Fixed(4096);
+        let old = leaves(data, fixed);
+        let new = leaves(inserted, fixed);
+        let changed = new.difference(&old).count();
+        println!("fixed: {} leaves, {} changed", new.len(), changed);
+        assert!(changed > new.len() / 2);
+
+        // the content is read back
+        let obj = Object::new_with_chunking(
+            file(vec![7; 100000]),
+            vec![],
+            None,
+            cdc,
+            ChunkCompression::None,
+            repo_pubkey,
+            repo_secret,
+        );
+        assert_eq!(obj.content().unwrap(), file(vec![7; 100000]));
+    }
+
+    /// Checks that appending to a file only changes a few blocks
+    #[test]
+    pub fn test_diff() {
+        let file = |len: usize| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content: (0..len).map(|i| (i % 251) as u8).collect(),
+            }))
+        };
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+
+        let old = Object::new(file(200000), vec![], None, 4000, repo_pubkey, repo_secret);
+        let mut store = HashMapRepoStore::new();
+        old.save(&mut store).unwrap();
+
+        let (new, report) = Object::diff(
+            &store,
+            old.id(),
+            file(201000),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        )
+        .unwrap();
+        println!(
+            "shared: {} new: {} removed: {}",
+            report.shared_blocks.len(),
+            report.new_blocks.len(),
+            report.removed_blocks.len()
+        );
+        assert_eq!(
+            report.shared_blocks.len() + report.new_blocks.len(),
+            new.to_hashmap().len()
+        );
+        assert_eq!(
+            report.shared_blocks.len() + report.removed_blocks.len(),
+            old.to_hashmap().len()
+        );
+        assert!(report.new_blocks.contains(&new.id()));
+        assert!(report.removed_blocks.contains(&old.id()));
+        assert!(report.shared_blocks.len() > 9 * report.new_blocks.len());
+
+        // the same content shares everything
+        let (_, report) = Object::diff(
+            &store,
+            old.id(),
+            file(200000),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        )
+        .unwrap();
+        assert!(report.new_blocks.is_empty());
+        assert!(report.removed_blocks.is_empty());
+    }
+
+    #[test]
+    pub fn test_max_object_size() {
+        let file = |len: usize| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content: vec![7; len],
+            }))
+        };
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let max_object_size = 100_000;
+
+        // the size seen by the broker is the size checked when creating the object
+        for len in [10, 5000, 99_000] {
+            let content_len = serde_bare::to_vec(&file(len)).unwrap().len() as u64;
+            let obj = Object::new_with_max_size(
+                file(len),
+                vec![],
+                None,
+                4000,
+                max_object_size,
+                repo_pubkey,
+                repo_secret,
+            )
+            .unwrap();
+            assert_eq!(obj.content_size(), content_len);
+            assert_eq!(
+                obj.id(),
+                Object::new(file(len), vec![], None, 4000, repo_pubkey, repo_secret).id()
+            );
+        }
+
+        assert!(matches!(
+            Object::new_with_max_size(
+                file(100_000),
+                vec![],
+                None,
+                4000,
+                max_object_size,
+                repo_pubkey,
+                repo_secret,
+            ),
+            Err(LofireError::ObjectTooLarge)
+        ));
+    }
+
+    #[test]
+    pub fn test_compression() {
+        use std::io::Read;
+
+        let file = |content: Vec<u8>| {
+            ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("text/plain"),
+                metadata: vec![],
+                content,
+            }))
+        };
+        let text: Vec<u8> = "all work and no play makes jack a dull boy\n"
+            .bytes()
+            .cycle()
+            .take(200000)
+            .collect();
+        let content_ser = serde_bare::to_vec(&file(text.clone())).unwrap();
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let new = |content: Vec<u8>, compression| {
+            Object::new_with_chunking(
+                file(content),
+                vec![],
+                None,
+                ChunkingStrategy::Fixed(4000),
+                compression,
+                repo_pubkey,
+                repo_secret,
+            )
+        };
+
+        let uncompressed = new(text.clone(), ChunkCompression::None);
+        assert!(uncompressed
+            .blocks()
+            .iter()
+            .all(|b| b.compression().is_none()));
+        // Object::new compresses the leaves that get smaller, and they round-trip
+        let default = Object::new(
+            file(text.clone()),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        );
+        assert_eq!(
+            default.id(),
+            new(text.clone(), ChunkCompression::default()).id()
+        );
+        assert!(default.storage_footprint() < uncompressed.storage_footprint() / 4);
+        assert!(default
+            .blocks()
+            .iter()
+            .filter(|b| b.children().is_empty())
+            .all(|b| b.compression() == Some(Compression::Zstd)));
+        let loaded =
+            Object::from_hashmap(default.id(), default.key(), &default.to_hashmap()).unwrap();
+        assert_eq!(loaded.content().unwrap(), file(text.clone()));
+
+        for (compression, tag) in [
+            (ChunkCompression::Zstd { level: 3 }, Compression::Zstd),
+            (ChunkCompression::Lz4, Compression::Lz4),
+        ] {
+            let obj = new(text.clone(), compression);
+            println!(
+                "{:?}: {} bytes stored, {} uncompressed",
+                compression,
+                obj.storage_footprint(),
+                uncompressed.storage_footprint()
+            );
+            assert!(obj.storage_footprint() < uncompressed.storage_footprint() / 4);
+            // the broker sees the decompressed size of the content
+            assert_eq!(obj.content_size(), content_ser.len() as u64);
+            // compression is deterministic, so are the IDs of the blocks
+            assert_eq!(obj.id(), new(text.clone(), compression).id());
+            for block in obj.blocks() {
+                assert!(block.validate().is_ok());
+                if block.children().is_empty() {
+                    assert_eq!(block.compression(), Some(tag));
+                } else {
+                    assert_eq!(block.compression(), None);
+                }
+            }
+
+            // the content is decompressed when read back, whole or streamed
+            assert_eq!(obj.content().unwrap(), file(text.clone()));
+            let store = HashMapRepoStore::new();
+            for block in obj.blocks() {
+                store.put(block).unwrap();
+            }
+            let loaded = Object::load(obj.id(), obj.key(), &store).unwrap();
+            assert_eq!(loaded.content().unwrap(), file(text.clone()));
+            let mut read = vec![];
+            Object::reader(obj.id(), obj.key().unwrap(), &store)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(read, content_ser);
+        }
+
+        // incompressible chunks are stored as they are
+        let mut x: u32 = 1;
+        let noise: Vec<u8> = (0..50000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let obj = new(noise.clone(), ChunkCompression::Zstd { level: 3 });
+        assert!(obj.blocks().iter().all(|b| matches!(b, Block::V0(_))));
+        assert_eq!(obj.id(), new(noise.clone(), ChunkCompression::None).id());
+        assert_eq!(obj.content().unwrap(), file(noise));
+    }
+
+    /// Checks that an object built from pieces of its content is the same as the object built at once
+    #[test]
+    pub fn test_object_builder() {
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let cdc = ChunkingStrategy::ContentDefined {
+            min: 1024,
+            avg: 4096,
+            max: 8192,
+        };
+        let deps = vec![Digest::Blake3Digest32([9; 32]); 3];
+        // root leaf, single leaf with deps, one level and two levels of internal nodes
+        for (len, deps, chunking) in [
+            (100, vec![], ChunkingStrategy::Fixed(4000)),
+            (3950, deps.clone(), ChunkingStrategy::Fixed(4000)),
+            (50000, vec![], ChunkingStrategy::Fixed(4000)),
+            (400000, deps.clone(), ChunkingStrategy::Fixed(4000)),
+            (400000, vec![], cdc),
+        ] {
+            let content = ObjectContent::File(File::V0(FileV0 {
+                content_type: Vec::from("file/test"),
+                metadata: vec![],
+                content: (0..len).map(|i| ((i * 7) % 251) as u8).collect(),
+            }));
+            let obj = Object::new_with_chunking(
+                content.clone(),
+                deps.clone(),
+                None,
+                chunking,
+                ChunkCompression::None,
+                repo_pubkey,
+                repo_secret,
+            );
+
+            let mut builder = ObjectBuilder::new(
+                deps,
+                None,
+                chunking,
+                ChunkCompression::None,
+                repo_pubkey,
+                repo_secret,
+            );
+            let mut blocks = vec![];
+            for piece in serde_bare::to_vec(&content).unwrap().chunks(777) {
+                blocks.extend(builder.add(piece));
+            }
+            let (id, last) = builder.finish();
+            blocks.extend(last);
+
+            assert_eq!(id, obj.id());
+            assert_eq!(blocks.last().unwrap().id(), id);
+            let mut ids: Vec<BlockId> = blocks.iter().map(|b| b.id()).collect();
+            let mut expected: Vec<BlockId> = obj.blocks().iter().map(|b| b.id()).collect();
+            ids.sort();
+            expected.sort();
+            assert_eq!(ids, expected);
+        }
+    }
 }
@@ -0,0 +1,609 @@
+//! Streaming reads of the content of an Object, without assembling it in memory
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use debug_print::*;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+
+use crate::object::*;
+use crate::store::*;
+use crate::types::*;
+
+/// Decrypted block of the tree
+enum Node {
+    /// Children IDs with their keys
+    Internal(Vec<(BlockId, SymKey)>),
+    /// Data chunk
+    Leaf(Vec<u8>),
+}
+
+/// Reader of the serialized content of an Object, decrypting one leaf at a time
+///
+/// Only the blocks on the path from the root to the current leaf are kept in memory,
+/// each block is verified against the ID referenced by its parent when it is loaded.
+///
+/// All the leaves but the last hold the same number of bytes,
+/// and each level of the tree groups the nodes of the level below by the same arity,
+/// so the leaf holding an offset is found by descending the tree, without scanning.
+/// Objects with content-defined chunking don't have such leaves,
+/// reading them fails with `ObjectParseError::InvalidChildren`.
+pub struct ObjectReader<'a, S: RepoStore> {
+    store: &'a S,
+
+    /// Internal nodes on the path to the current leaf, root first, with their decrypted children
+    path: Vec<(BlockId, Vec<(BlockId, SymKey)>)>,
+
+    /// Number of levels of internal nodes
+    depth: u32,
+
+    /// Number of children of the internal nodes that are not the last of their level
+    arity: u64,
+
+    /// Size of the data chunks of all the leaves but the last
+    chunk_size: u64,
+
+    /// Index and data of the current leaf
+    leaf: Option<(u64, Vec<u8>)>,
+
+    /// Current position in the serialized content
+    pos: u64,
+
+    /// Start of the readable range in the serialized content
+    start: u64,
+
+    /// End of the readable range in the serialized content
+    end: u64,
+}
+
+impl<'a, S: RepoStore> ObjectReader<'a, S> {
+    /// Open the object with the given root ID and key from the store
+    ///
+    /// Loads the leftmost and rightmost paths of the tree to find its shape and length
+    pub fn open(
+        id: ObjectId,
+        key: SymKey,
+        store: &'a S,
+    ) -> Result<ObjectReader<'a, S>, ObjectParseError> {
+        let mut reader = ObjectReader {
+            store,
+            path: vec![],
+            depth: 0,
+            arity: 1,
+            chunk_size: 0,
+            leaf: None,
+            pos: 0,
+            start: 0,
+            end: 0,
+        };
+
+        // leftmost path: depth, arity and chunk size
+        let mut node = (id, key);
+        let first = loop {
+            match reader.load(&node.0, &node.1)? {
+                Node::Internal(children) => {
+                    let first = *children.first().ok_or(ObjectParseError::InvalidChildren)?;
+                    reader.path.push((node.0, children));
+                    node = first;
+                }
+                Node::Leaf(data) => break data,
+            }
+        };
+        reader.depth = reader.path.len() as u32;
+        if let Some((_, children)) = reader.path.get(1) {
+            reader.arity = children.len() as u64;
+        }
+        reader.chunk_size = first.len() as u64;
+
+        // rightmost path: number of leaves and size of the last one
+        let mut leaves: u64 = 0;
+        let mut level: u32 = 0;
+        let mut node = (id, key);
+        let last = loop {
+            match reader.load(&node.0, &node.1)? {
+                Node::Internal(children) => {
+                    if level == reader.depth {
+                        return Err(ObjectParseError::InvalidChildren);
+                    }
+                    let per_child = leaves_per_child(reader.arity, reader.depth, level);
+                    leaves += (children.len() as u64 - 1) * per_child;
+                    level += 1;
+                    node = *children.last().ok_or(ObjectParseError::InvalidChildren)?;
+                }
+                Node::Leaf(data) => break data,
+            }
+        };
+        if level != reader.depth {
+            return Err(ObjectParseError::InvalidChildren);
+        }
+        reader.end = leaves * reader.chunk_size + last.len() as u64;
+        reader.leaf = Some((0, first));
+
+        Ok(reader)
+    }
+
+    /// Skip the header of a File object, and restrict the reader to the file content.
+    /// Positions and seeks are then relative to the start of the file content.
+    ///
+    /// Returns the content type and metadata of the file
+    pub fn open_file(&mut self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        self.open_file_verified().map_err(|e| match e {
+            ObjectParseError::ObjectDeserializeError => {
+                io::Error::new(io::ErrorKind::InvalidData, "not a File object")
+            }
+            e => io_error(e),
+        })
+    }
+
+    /// Same as `open_file`, with the errors of the tree.
+    /// A content that is not a File fails with `ObjectDeserializeError`.
+    /// On `MissingBlocks` it can be retried once they are fetched
+    pub fn open_file_verified(&mut self) -> Result<(Vec<u8>, Vec<u8>), ObjectParseError> {
+        self.pos = self.start;
+        // ObjectContent::File(File::V0(..))
+        if self.read_varint()? != 2 || self.read_varint()? != 0 {
+            return Err(ObjectParseError::ObjectDeserializeError);
+        }
+        let content_type = self.read_bytes()?;
+        let metadata = self.read_bytes()?;
+        let len = self.read_varint()?;
+        if self.pos + len > self.end {
+            return Err(ObjectParseError::ObjectDeserializeError);
+        }
+        self.start = self.pos;
+        self.end = self.pos + len;
+        Ok((content_type, metadata))
+    }
+
+    /// Length of the readable content
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Load a block from the store, verify its ID and decrypt it
+    fn load(&self, id: &BlockId, key: &SymKey) -> Result<Node, ObjectParseError> {
+        let block = self
+            .store
+            .get(id)
+            .map_err(|_e| ObjectParseError::MissingBlocks(vec![*id]))?;
+        if block.get_id() != *id {
+            debug_println!("Invalid BlockId.\nExp: {:?}\nGot: {:?}", id, block.get_id());
+            return Err(ObjectParseError::InvalidBlockId);
+        }
+
+        let mut content_dec = block.content().clone();
+        match key {
+            SymKey::ChaCha20Key(key) => {
+                let nonce = [0u8; 12];
+                let mut cipher = ChaCha20::new(key.into(), &nonce.into());
+                let mut content_dec_slice = &mut content_dec.as_mut_slice();
+                cipher.apply_keystream(&mut content_dec_slice);
+            }
+        }
+
+        match serde_bare::from_slice(content_dec.as_slice()) {
+            Ok(BlockContentV0::InternalNode(keys)) => {
+                if keys.len() != block.children().len() {
+                    return Err(ObjectParseError::InvalidKeys);
+                }
+                Ok(Node::Internal(
+                    block.children().iter().cloned().zip(keys).collect(),
+                ))
+            }
+            Ok(BlockContentV0::DataChunk(chunk)) => block
+                .decompress_chunk(chunk)
+                .map(Node::Leaf)
+                .ok_or(ObjectParseError::BlockDeserializeError),
+            Err(e) => {
+                debug_println!("Block deserialize error: {}", e);
+                Err(ObjectParseError::BlockDeserializeError)
+            }
+        }
+    }
+
+    /// Load the leaf with the given index, reusing the internal nodes already on the path
+    fn load_leaf(&mut self, index: u64) -> Result<(), ObjectParseError> {
+        if let Some((i, _)) = &self.leaf {
+            if *i == index {
+                return Ok(());
+            }
+        }
+        self.leaf = None;
+        for level in 0..self.depth as usize {
+            let per_child = leaves_per_child(self.arity, self.depth, level as u32);
+            let mut child = index / per_child;
+            if level > 0 {
+                child %= self.arity;
+            }
+            let (id, key) = *self.path[level]
+                .1
+                .get(child as usize)
+                .ok_or(ObjectParseError::InvalidChildren)?;
+            let next = level + 1;
+            if next == self.depth as usize {
+                let data = match self.load(&id, &key)? {
+                    Node::Leaf(data) => data,
+                    Node::Internal(_) => return Err(ObjectParseError::InvalidChildren),
+                };
+                self.leaf = Some((index, data));
+                break;
+            }
+            if self.path.get(next).map(|(n, _)| *n) != Some(id) {
+                self.path.truncate(next);
+                match self.load(&id, &key)? {
+                    Node::Internal(children) => self.path.push((id, children)),
+                    Node::Leaf(_) => return Err(ObjectParseError::InvalidChildren),
+                }
+            }
+        }
+        match &self.leaf {
+            // all the leaves but the last must be full
+            Some((i, data)) if (*i + 1) * self.chunk_size < self.end => {
+                if data.len() as u64 != self.chunk_size {
+                    self.leaf = None;
+                    return Err(ObjectParseError::InvalidChildren);
+                }
+                Ok(())
+            }
+            Some(_) => Ok(()),
+            None => Err(ObjectParseError::InvalidChildren),
+        }
+    }
+
+    /// Read from the current position, like `Read::read`, with the errors of the tree.
+    /// On error the position is left unchanged, so that the read can be retried,
+    /// e.g. after fetching the `MissingBlocks`
+    pub fn read_verified(&mut self, buf: &mut [u8]) -> Result<usize, ObjectParseError> {
+        if self.pos >= self.end || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = if self.depth == 0 {
+            0
+        } else {
+            self.pos / self.chunk_size
+        };
+        self.load_leaf(index)?;
+        let data = &self.leaf.as_ref().unwrap().1;
+        let offset = (self.pos - index * self.chunk_size) as usize;
+        let n = min(
+            buf.len() as u64,
+            min((data.len() - offset) as u64, self.end - self.pos),
+        ) as usize;
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Read `len` bytes from offset `start`, or less at the end of the content.
+    /// Only the blocks on the paths to the leaves of the range are loaded and verified
+    pub fn read_range(&mut self, start: u64, len: usize) -> Result<Vec<u8>, ObjectParseError> {
+        self.pos = self.start.saturating_add(start);
+        let mut bytes = vec![0u8; len];
+        let mut read = 0;
+        while read < len {
+            match self.read_verified(&mut bytes[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        bytes.truncate(read);
+        Ok(bytes)
+    }
+
+    /// Fill `buf` from the current position, the end of the content fails with `ObjectDeserializeError`
+    fn read_exact_verified(&mut self, buf: &mut [u8]) -> Result<(), ObjectParseError> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read_verified(&mut buf[read..])? {
+                0 => return Err(ObjectParseError::ObjectDeserializeError),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_varint(&mut self) -> Result<u64, ObjectParseError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8; 1];
+            self.read_exact_verified(&mut byte)?;
+            value |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        // varint overflow
+        Err(ObjectParseError::ObjectDeserializeError)
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>, ObjectParseError> {
+        let len = self.read_varint()?;
+        if self.pos + len > self.end {
+            return Err(ObjectParseError::ObjectDeserializeError);
+        }
+        let mut bytes = vec![0u8; len as usize];
+        self.read_exact_verified(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Blocks needed to read a range of the content of an object, see `Object::range_blocks`
+pub(crate) fn range_blocks<S: RepoStore>(
+    id: ObjectId,
+    store: &S,
+    start: u64,
+    len: u64,
+) -> Result<Vec<Block>, ObjectParseError> {
+    /// Get a block from the store, keeping the order in which blocks are first needed
+    fn get<S: RepoStore>(
+        store: &S,
+        loaded: &mut HashMap<BlockId, Block>,
+        order: &mut Vec<BlockId>,
+        id: &BlockId,
+    ) -> Result<Block, ObjectParseError> {
+        if let Some(block) = loaded.get(id) {
+            return Ok(block.clone());
+        }
+        let block = store
+            .get(id)
+            .map_err(|_e| ObjectParseError::MissingBlocks(vec![*id]))?;
+        loaded.insert(*id, block.clone());
+        order.push(*id);
+        Ok(block)
+    }
+
+    let mut loaded: HashMap<BlockId, Block> = HashMap::new();
+    let mut order: Vec<BlockId> = vec![];
+
+    // leftmost path: depth, arity and chunk size
+    let mut node = get(store, &mut loaded, &mut order, &id)?;
+    let mut depth: u32 = 0;
+    let mut arity: u64 = 1;
+    while let Some(first) = node.children().first() {
+        if depth == 1 {
+            arity = node.children().len() as u64;
+        }
+        depth += 1;
+        node = get(store, &mut loaded, &mut order, first)?;
+    }
+    if node.compression().is_some() {
+        // the size of the data of compressed leaves is unknown without decrypting them
+        return Object::load(id, None, store).map(|obj| obj.blocks().clone());
+    }
+    let chunk_size = data_chunk_size(node.content_len()) as u64;
+    if depth == 0 || len == 0 {
+        return Ok(order
+            .into_iter()
+            .map(|id| loaded.remove(&id).unwrap())
+            .collect());
+    }
+    if chunk_size == 0 {
+        return Err(ObjectParseError::InvalidChildren);
+    }
+
+    // rightmost path: number of leaves
+    let mut leaves: u64 = 0;
+    let mut node = get(store, &mut loaded, &mut order, &id)?;
+    for level in 0..depth {
+        let per_child = leaves_per_child(arity, depth, level);
+        let last = node
+            .children()
+            .last()
+            .ok_or(ObjectParseError::InvalidChildren)?;
+        leaves += (node.children().len() as u64 - 1) * per_child;
+        node = get(store, &mut loaded, &mut order, last)?;
+    }
+
+    // paths to the leaves of the range
+    let first = start / chunk_size;
+    let last = min(start.saturating_add(len - 1) / chunk_size, leaves);
+    for index in first..=last {
+        let mut node = get(store, &mut loaded, &mut order, &id)?;
+        for level in 0..depth {
+            let per_child = leaves_per_child(arity, depth, level);
+            let mut child = index / per_child;
+            if level > 0 {
+                child %= arity;
+            }
+            let child = node
+                .children()
+                .get(child as usize)
+                .ok_or(ObjectParseError::InvalidChildren)?;
+            node = get(store, &mut loaded, &mut order, child)?;
+        }
+    }
+
+    Ok(order
+        .into_iter()
+        .map(|id| loaded.remove(&id).unwrap())
+        .collect())
+}
+
+/// Number of leaves under each child of a full node at `level` (the root being at level 0)
+fn leaves_per_child(arity: u64, depth: u32, level: u32) -> u64 {
+    arity.pow(depth - 1 - level)
+}
+
+/// Error of the `Read` interface for an error of the tree
+fn io_error(e: ObjectParseError) -> io::Error {
+    match e {
+        ObjectParseError::MissingBlocks(_) => {
+            io::Error::new(io::ErrorKind::NotFound, format!("{:?}", e))
+        }
+        _ => io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)),
+    }
+}
+
+impl<'a, S: RepoStore> Read for ObjectReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_verified(buf).map_err(io_error)
+    }
+}
+
+impl<'a, S: RepoStore> Seek for ObjectReader<'a, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => self.start.checked_add(offset),
+            SeekFrom::End(offset) => checked_add_signed(self.end, offset),
+            SeekFrom::Current(offset) => checked_add_signed(self.pos, offset),
+        };
+        match pos {
+            Some(pos) if pos >= self.start => {
+                self.pos = pos;
+                Ok(pos - self.start)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the content",
+            )),
+        }
+    }
+}
+
+fn checked_add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::object::*;
+    use crate::reader::*;
+    use crate::store::*;
+    use crate::types::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    /// Checks streaming reads and seeks against the assembled content
+    #[test]
+    pub fn test_reader() {
+        let data: Vec<u8> = (0..500000).map(|i| (i % 251) as u8).collect();
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("video/test"),
+            metadata: Vec::from("some meta data here"),
+            content: data.clone(),
+        }));
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let obj = Object::new(
+            content.clone(),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        );
+        let mut store = HashMapRepoStore::new();
+        obj.save(&mut store).unwrap();
+
+        // the whole serialized content
+        let mut reader = Object::reader(obj.id(), obj.key().unwrap(), &store).unwrap();
+        let content_ser = serde_bare::to_vec(&content).unwrap();
+        assert_eq!(reader.len(), content_ser.len() as u64);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, content_ser);
+
+        // the file content only
+        let (content_type, metadata) = reader.open_file().unwrap();
+        assert_eq!(content_type, Vec::from("video/test"));
+        assert_eq!(metadata, Vec::from("some meta data here"));
+        assert_eq!(reader.len(), data.len() as u64);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        // seeks across leaves of both levels of the tree
+        for offset in [0u64, 4079, 123456, 250000, 499000] {
+            assert_eq!(reader.seek(SeekFrom::Start(offset)).unwrap(), offset);
+            let mut buf = vec![0u8; 1000];
+            reader.read_exact(&mut buf).unwrap();
+            let start = offset as usize;
+            assert_eq!(buf, data[start..start + 1000]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 499990);
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[499990..]);
+        assert!(reader.seek(SeekFrom::Current(-500001)).is_err());
+
+        // a missing leaf is only noticed when it is read
+        let leaf = obj.blocks()[60].id();
+        store.del(&leaf).unwrap();
+        let mut reader = Object::reader(obj.id(), obj.key().unwrap(), &store).unwrap();
+        let mut buf = vec![0u8; 1000];
+        reader.read_exact(&mut buf).unwrap();
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+    }
+
+    /// Checks that a range is read from the blocks of its path only, and verified
+    #[test]
+    pub fn test_read_range() {
+        let content = ObjectContent::File(File::V0(FileV0 {
+            content_type: Vec::from("file/test"),
+            metadata: vec![],
+            content: (0..500000).map(|i| (i % 251) as u8).collect(),
+        }));
+        let repo_secret = SymKey::ChaCha20Key([0; 32]);
+        let repo_pubkey = PubKey::Ed25519PubKey([1; 32]);
+        let obj = Object::new(
+            content.clone(),
+            vec![],
+            None,
+            4000,
+            repo_pubkey,
+            repo_secret,
+        );
+        let content_ser = serde_bare::to_vec(&content).unwrap();
+        let (id, key) = (obj.id(), obj.key().unwrap());
+
+        let store = HashMapRepoStore::new();
+        // fetch blocks on demand, as a client would from a broker
+        let full = obj.to_hashmap();
+        let mut fetched = 0;
+        let range = loop {
+            match Object::read_range(id, key, &store, 300000, 5000) {
+                Ok(range) => break range,
+                Err(ObjectParseError::MissingBlocks(missing)) => {
+                    for id in missing {
+                        store.put(&full[&id]).unwrap();
+                        fetched += 1;
+                    }
+                }
+                Err(e) => panic!("read_range error: {:?}", e),
+            }
+        };
+        assert_eq!(range, content_ser[300000..305000]);
+        assert!(fetched < full.len() / 10);
+
+        // a corrupted block on the path is detected
+        let leaf = obj.blocks()[80].clone();
+        let mut corrupted = leaf.clone();
+        match &mut corrupted {
+            Block::V0(b) => {
+                b.id = Some(leaf.id());
+                b.content[10] ^= 1;
+            }
+            Block::V1(b) => {
+                b.id = Some(leaf.id());
+                b.content[10] ^= 1;
+            }
+        }
+        let store = HashMapRepoStore::new();
+        for block in full.values() {
+            store.put(block).unwrap();
+        }
+        store.put(&corrupted).unwrap();
+        assert!(matches!(
+            Object::read_range(id, key, &store, 0, content_ser.len()),
+            Err(ObjectParseError::InvalidBlockId)
+        ));
+        assert!(Object::read_range(id, key, &store, 0, 10000).is_ok());
+    }
+}
@@ -1,19 +1,81 @@
 //! Repository
 
+use crate::commit::*;
+use crate::compression::*;
+use crate::errors::*;
 use crate::types::*;
 
+/// Default maximum size of the metadata of a commit
+pub const DEFAULT_MAX_COMMIT_METADATA_SIZE: u32 = 16 * 1024;
+
+/// Default maximum size of the metadata of a file
+pub const DEFAULT_MAX_FILE_METADATA_SIZE: u32 = 64 * 1024;
+
+/// Default maximum size of the content of an object
+pub const DEFAULT_MAX_OBJECT_SIZE: u64 = 64 * 1024 * 1024;
+
+impl Default for MetadataLimits {
+    fn default() -> Self {
+        MetadataLimits {
+            commit: DEFAULT_MAX_COMMIT_METADATA_SIZE,
+            file: DEFAULT_MAX_FILE_METADATA_SIZE,
+        }
+    }
+}
+
+impl MetadataLimits {
+    /// Check the size of the metadata of a commit, once decompressed.
+    /// The size of compressed metadata is read from its header, without decompressing it
+    pub fn check_commit(&self, commit: &Commit) -> Result<(), LofireError> {
+        let content = commit.content();
+        let size = match content.metadata_compression {
+            None => content.metadata.len(),
+            Some(compression) => decompressed_size(compression, &content.metadata)
+                .ok_or(LofireError::SerializationError)?,
+        };
+        if size > self.commit as usize {
+            return Err(LofireError::MetadataTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Check the size of the metadata of a file
+    pub fn check_file(&self, file: &File) -> Result<(), LofireError> {
+        let size = match file {
+            File::V0(f) => f.metadata.len(),
+        };
+        if size > self.file as usize {
+            return Err(LofireError::MetadataTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Check the size of the metadata of the content of an object, if it has metadata
+    pub fn check_content(&self, content: &ObjectContent) -> Result<(), LofireError> {
+        match content {
+            ObjectContent::Commit(commit) => self.check_commit(commit),
+            ObjectContent::File(file) => self.check_file(file),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl RepositoryV0 {
     pub fn new(
         id: &PubKey,
         branches: &Vec<ObjectRef>,
         allow_ext_requests: bool,
         metadata: &Vec<u8>,
+        metadata_limits: MetadataLimits,
+        max_object_size: u64,
     ) -> RepositoryV0 {
         RepositoryV0 {
             id: id.clone(),
             branches: branches.clone(),
             allow_ext_requests,
             metadata: metadata.clone(),
+            metadata_limits,
+            max_object_size,
         }
     }
 }
@@ -24,12 +86,30 @@ impl Repository {
         branches: &Vec<ObjectRef>,
         allow_ext_requests: bool,
         metadata: &Vec<u8>,
+        metadata_limits: MetadataLimits,
+        max_object_size: u64,
     ) -> Repository {
         Repository::V0(RepositoryV0::new(
             id,
             branches,
             allow_ext_requests,
             metadata,
+            metadata_limits,
+            max_object_size,
         ))
     }
+
+    /// Get the maximum metadata sizes of the commits and files of the repo
+    pub fn metadata_limits(&self) -> &MetadataLimits {
+        match self {
+            Repository::V0(r) => &r.metadata_limits,
+        }
+    }
+
+    /// Get the maximum size of the content of an object of the repo, see `Object::new_with_max_size`
+    pub fn max_object_size(&self) -> u64 {
+        match self {
+            Repository::V0(r) => r.max_object_size,
+        }
+    }
 }
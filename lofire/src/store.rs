@@ -4,7 +4,7 @@ use crate::types::*;
 
 use std::{
     cmp::min,
-    collections::{hash_map::Iter, HashMap},
+    collections::{hash_map::Iter, BTreeMap, HashMap},
     mem::size_of_val,
 };
 use std::sync::{Arc, RwLock};
@@ -14,10 +14,29 @@ pub trait RepoStore {
     fn get(&self, id: &BlockId) -> Result<Block, StorageError>;
 
     /// Save a block to the store.
+    /// Saving a block that is already in the store is a no-op returning its ID.
     fn put(&self, block: &Block) -> Result<BlockId, StorageError>;
 
+    /// Save several blocks to the store, returning their IDs in the same order.
+    /// Stores that support it save them atomically, the default implementation saves them one by one
+    fn put_all(&self, blocks: &[Block]) -> Result<Vec<BlockId>, StorageError> {
+        blocks.iter().map(|block| self.put(block)).collect()
+    }
+
     /// Delete a block from the store.
     fn del(&self, id: &BlockId) -> Result<(Block, usize), StorageError>;
+
+    /// Iterate over all the blocks of the store, without loading them all in memory.
+    /// Blocks that can't be read are skipped
+    fn iter(&self) -> Box<dyn Iterator<Item = (BlockId, Block)> + Send + '_>;
+
+    /// Number of blocks in the store.
+    fn len(&self) -> Result<usize, StorageError>;
+
+    /// Check whether the store has no block.
+    fn is_empty(&self) -> Result<bool, StorageError> {
+        Ok(self.len()? == 0)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -26,6 +45,10 @@ pub enum StorageError {
     InvalidValue,
     BackendError,
     SerializationError,
+    /// A value could not be decrypted, or failed its integrity check
+    DecryptionError,
+    /// The store was written in a format this version can't read
+    IncompatibleFormat,
 }
 
 impl From<serde_bare::error::Error> for StorageError {
@@ -34,6 +57,12 @@ impl From<serde_bare::error::Error> for StorageError {
     }
 }
 
+/// Cursor of a paginated listing
+///
+/// Opaque key of the last item of the previous page,
+/// the listing resumes right after it.
+pub type Cursor = Vec<u8>;
+
 const MIN_SIZE: usize = 4072;
 const PAGE_SIZE: usize = 4096;
 const HEADER: usize = PAGE_SIZE - MIN_SIZE;
@@ -53,6 +82,28 @@ pub const fn store_max_value_size() -> usize {
     MAX_FACTOR * PAGE_SIZE - HEADER
 }
 
+/// Size of a serialized BlockId, the key of the block entries
+const BLOCK_ID_SIZE: u64 = 33;
+/// Size of a serialized Timestamp
+const TIMESTAMP_SIZE: u64 = 4;
+
+/// Returns the number of bytes a block takes in the storage backend, counting keys and values of:
+/// - the serialized block, keyed by its ID
+/// - the time it was stored at, keyed by its ID
+/// - its ID, keyed by its expiry, if it has one
+///
+/// Pin and LRU metadata is only added once a block is pinned or synced, and is not counted.
+/// Neither is the page overhead of the backend, which is amortized over many entries
+pub fn store_block_footprint(block: &Block) -> u64 {
+    let block_size = serde_bare::to_vec(block).unwrap().len() as u64;
+    let stored_at = BLOCK_ID_SIZE + TIMESTAMP_SIZE;
+    let expiry = match block.expiry() {
+        Some(_) => TIMESTAMP_SIZE + BLOCK_ID_SIZE,
+        None => 0,
+    };
+    BLOCK_ID_SIZE + block_size + stored_at + expiry
+}
+
 /// Store with a HashMap backend
 pub struct HashMapRepoStore {
     blocks: RwLock<HashMap<BlockId, Block>>,
@@ -65,10 +116,6 @@ impl HashMapRepoStore {
         }
     }
 
-    pub fn get_len(&self) -> usize {
-        self.blocks.read().unwrap().len()
-    }
-
     pub fn get_all(&self) -> Vec<Block> {
         self.blocks.read().unwrap().values().map(|x| x.clone()).collect()
     }
@@ -90,9 +137,260 @@ impl RepoStore for HashMapRepoStore {
         Ok(id)
     }
 
+    fn put_all(&self, blocks: &[Block]) -> Result<Vec<BlockId>, StorageError> {
+        let mut map = self.blocks.write().unwrap();
+        Ok(blocks
+            .iter()
+            .map(|block| {
+                let id = block.id();
+                let mut b = block.clone();
+                b.set_key(None);
+                map.insert(id, b);
+                id
+            })
+            .collect())
+    }
+
     fn del(&self, id: &BlockId) -> Result<(Block, usize), StorageError> {
         let block = self.blocks.write().unwrap().remove(id).ok_or(StorageError::NotFound)?;
         let size = size_of_val(&block);
         Ok((block, size))
     }
+
+    /// Only the block IDs are collected upfront, the blocks are cloned one at a time.
+    /// Blocks deleted during the iteration are skipped, blocks added are not seen
+    fn iter(&self) -> Box<dyn Iterator<Item = (BlockId, Block)> + Send + '_> {
+        let ids: Vec<BlockId> = self.blocks.read().unwrap().keys().cloned().collect();
+        Box::new(
+            ids.into_iter()
+                .filter_map(move |id| self.get(&id).ok().map(|block| (id, block))),
+        )
+    }
+
+    fn len(&self) -> Result<usize, StorageError> {
+        Ok(self.blocks.read().unwrap().len())
+    }
+}
+
+/// Store keeping the recently accessed blocks of another store in memory,
+/// so that repeated reads of popular objects don't go to the backend.
+///
+/// The cache is bounded by the total size of the serialized blocks it holds,
+/// the least recently used blocks are evicted first.
+/// Blocks larger than the capacity are not cached
+pub struct CachedRepoStore<S: RepoStore> {
+    store: S,
+    capacity: usize,
+    cache: RwLock<BlockCache>,
+}
+
+/// LRU of the blocks of a `CachedRepoStore`
+struct BlockCache {
+    /// Cached blocks, with their size and last access
+    blocks: HashMap<BlockId, (Block, usize, u64)>,
+    /// Cached block IDs by last access
+    recency: BTreeMap<u64, BlockId>,
+    /// Total size of the cached blocks
+    size: usize,
+    /// Counter of the accesses
+    tick: u64,
+}
+
+impl BlockCache {
+    fn get(&mut self, id: &BlockId) -> Option<Block> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (block, _, accessed) = self.blocks.get_mut(id)?;
+        self.recency.remove(accessed);
+        self.recency.insert(tick, *id);
+        *accessed = tick;
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, id: BlockId, mut block: Block, capacity: usize) {
+        self.remove(&id);
+        block.set_key(None);
+        let size = serde_bare::to_vec(&block).unwrap().len();
+        if size > capacity {
+            return;
+        }
+        while self.size + size > capacity {
+            let oldest_access = *self.recency.keys().next().unwrap();
+            let oldest = self.recency.remove(&oldest_access).unwrap();
+            let (_, oldest_size, _) = self.blocks.remove(&oldest).unwrap();
+            self.size -= oldest_size;
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, id);
+        self.blocks.insert(id, (block, size, self.tick));
+        self.size += size;
+    }
+
+    fn remove(&mut self, id: &BlockId) {
+        if let Some((_, size, accessed)) = self.blocks.remove(id) {
+            self.recency.remove(&accessed);
+            self.size -= size;
+        }
+    }
+}
+
+impl<S: RepoStore> CachedRepoStore<S> {
+    /// Cache the blocks of `store`, up to `capacity` bytes of serialized blocks
+    pub fn new(store: S, capacity: usize) -> CachedRepoStore<S> {
+        CachedRepoStore {
+            store,
+            capacity,
+            cache: RwLock::new(BlockCache {
+                blocks: HashMap::new(),
+                recency: BTreeMap::new(),
+                size: 0,
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Get the underlying store
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Total size of the cached blocks
+    pub fn cached_size(&self) -> usize {
+        self.cache.read().unwrap().size
+    }
+
+    fn cache(&self, block: &Block) {
+        self.cache
+            .write()
+            .unwrap()
+            .insert(block.id(), block.clone(), self.capacity);
+    }
+}
+
+impl<S: RepoStore> RepoStore for CachedRepoStore<S> {
+    /// The cache stays locked while a missing block is read from the backend,
+    /// so that a concurrent `del` can't be undone by caching the block again
+    fn get(&self, id: &BlockId) -> Result<Block, StorageError> {
+        let mut cache = self.cache.write().unwrap();
+        if let Some(block) = cache.get(id) {
+            return Ok(block);
+        }
+        let block = self.store.get(id)?;
+        cache.insert(*id, block.clone(), self.capacity);
+        Ok(block)
+    }
+
+    fn put(&self, block: &Block) -> Result<BlockId, StorageError> {
+        let id = self.store.put(block)?;
+        self.cache(block);
+        Ok(id)
+    }
+
+    fn put_all(&self, blocks: &[Block]) -> Result<Vec<BlockId>, StorageError> {
+        let ids = self.store.put_all(blocks)?;
+        for block in blocks {
+            self.cache(block);
+        }
+        Ok(ids)
+    }
+
+    /// The block is deleted from the backend before it is evicted, with the cache locked across both
+    fn del(&self, id: &BlockId) -> Result<(Block, usize), StorageError> {
+        let mut cache = self.cache.write().unwrap();
+        let deleted = self.store.del(id);
+        cache.remove(id);
+        deleted
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (BlockId, Block)> + Send + '_> {
+        self.store.iter()
+    }
+
+    fn len(&self) -> Result<usize, StorageError> {
+        self.store.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::store::*;
+
+    /// Store counting the reads that reach it
+    struct CountingStore {
+        inner: HashMapRepoStore,
+        gets: AtomicUsize,
+    }
+
+    impl RepoStore for CountingStore {
+        fn get(&self, id: &BlockId) -> Result<Block, StorageError> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(id)
+        }
+        fn put(&self, block: &Block) -> Result<BlockId, StorageError> {
+            self.inner.put(block)
+        }
+        fn del(&self, id: &BlockId) -> Result<(Block, usize), StorageError> {
+            self.inner.del(id)
+        }
+        fn iter(&self) -> Box<dyn Iterator<Item = (BlockId, Block)> + Send + '_> {
+            self.inner.iter()
+        }
+        fn len(&self) -> Result<usize, StorageError> {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    pub fn test_cached_store() {
+        let block = |i: u8| {
+            Block::new(
+                vec![],
+                ObjectDeps::ObjectIdList(vec![]),
+                None,
+                vec![i; 1000],
+                None,
+            )
+        };
+        let (b1, b2, b3) = (block(1), block(2), block(3));
+        let size = serde_bare::to_vec(&b1).unwrap().len();
+        let backend = CountingStore {
+            inner: HashMapRepoStore::new(),
+            gets: AtomicUsize::new(0),
+        };
+        for b in [&b1, &b2, &b3] {
+            backend.put(b).unwrap();
+        }
+        // room for 2 blocks
+        let store = CachedRepoStore::new(backend, 2 * size + 10);
+        let gets =
+            |store: &CachedRepoStore<CountingStore>| store.inner().gets.load(Ordering::SeqCst);
+
+        // the second get is served from memory
+        assert_eq!(store.get(&b1.id()).unwrap(), b1);
+        assert_eq!(gets(&store), 1);
+        assert_eq!(store.get(&b1.id()).unwrap(), b1);
+        assert_eq!(gets(&store), 1);
+
+        // b2 is the least recently used once b1 is read again, b3 evicts it
+        store.get(&b2.id()).unwrap();
+        store.get(&b1.id()).unwrap();
+        store.get(&b3.id()).unwrap();
+        assert_eq!(gets(&store), 3);
+        assert_eq!(store.cached_size(), 2 * size);
+        store.get(&b1.id()).unwrap();
+        assert_eq!(gets(&store), 3);
+        store.get(&b2.id()).unwrap();
+        assert_eq!(gets(&store), 4);
+
+        // writes go to the cache, deletes evict
+        let b4 = block(4);
+        store.put(&b4).unwrap();
+        assert_eq!(store.get(&b4.id()).unwrap(), b4);
+        assert_eq!(gets(&store), 4);
+        store.del(&b4.id()).unwrap();
+        assert_eq!(store.get(&b4.id()), Err(StorageError::NotFound));
+        assert_eq!(gets(&store), 5);
+    }
 }
@@ -15,7 +15,9 @@ use std::hash::Hash;
 pub type Blake3Digest32 = [u8; 32];
 
 /// Hash digest
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+///
+/// Digests are ordered lexicographically on their bytes
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Digest {
     Blake3Digest32(Blake3Digest32),
 }
@@ -89,7 +91,12 @@ pub enum Sig {
 }
 
 /// Timestamp: absolute time in minutes since 2022-02-22 22:22 UTC
-pub type Timestamp = u32;
+///
+/// Serialized as the bare number of minutes
+#[derive(
+    Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct Timestamp(pub(crate) u32);
 
 pub const EPOCH_AS_UNIX_TIMESTAMP: u64 = 1645568520;
 
@@ -147,6 +154,16 @@ pub type ObjectId = BlockId;
 /// Object reference
 pub type ObjectRef = BlockRef;
 
+/// Weak Object reference: the Object ID without the key
+///
+/// Allows addressing an Object (in deps lists, indices, DAG traversal)
+/// without granting the ability to decrypt its content
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct WeakObjectRef {
+    /// Object ID
+    pub id: ObjectId,
+}
+
 /// Internal node of a Merkle tree
 pub type InternalNode = Vec<SymKey>;
 
@@ -207,16 +224,56 @@ pub struct BlockV0 {
     /// Encrypted using convergent encryption with ChaCha20:
     /// - convergence_key: BLAKE3 derive_key ("LoFiRe Data BLAKE3 key",
     ///                                        repo_pubkey + repo_secret)
+    ///   or branch_pubkey + branch_secret for the objects of a branch
     /// - key: BLAKE3 keyed hash (convergence_key, plain_object_content)
     /// - nonce: 0
     #[serde(with = "serde_bytes")]
     pub content: Vec<u8>,
 }
 
+/// Immutable block with encrypted content, compressed before encryption
+///
+/// Only used for compressed leaves, the other blocks are `BlockV0`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockV1 {
+    /// Block ID
+    #[serde(skip)]
+    pub id: Option<BlockId>,
+
+    /// Block Key
+    #[serde(skip)]
+    pub key: Option<SymKey>,
+
+    /// Block IDs for child nodes in the Merkle tree
+    pub children: Vec<BlockId>,
+
+    /// Other objects this object depends on (e.g. Commit deps & acks)
+    /// Only set for the root block
+    pub deps: ObjectDeps,
+
+    /// Expiry time of this object and all of its children
+    /// when the object should be deleted by all replicas
+    /// Only set for the root block
+    pub expiry: Option<Timestamp>,
+
+    /// Encrypted ObjectContentV0, see `BlockV0::content`.
+    /// The data chunk it holds is compressed
+    #[serde(with = "serde_bytes")]
+    pub content: Vec<u8>,
+
+    /// Compression of the data chunk, if compressed
+    pub compression: Option<Compression>,
+
+    /// Size of the data chunk once decompressed,
+    /// so that the size of the object is known without its key
+    pub decompressed_size: u32,
+}
+
 /// Immutable object with encrypted content
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Block {
     V0(BlockV0),
+    V1(BlockV1),
 }
 
 /// Repository definition
@@ -239,6 +296,23 @@ pub struct RepositoryV0 {
     /// App-specific metadata
     #[serde(with = "serde_bytes")]
     pub metadata: Vec<u8>,
+
+    /// Maximum metadata sizes of the commits and files of the repo
+    pub metadata_limits: MetadataLimits,
+
+    /// Maximum size of the content of an object of the repo, in bytes.
+    /// Larger content is split into several objects by the apps
+    pub max_object_size: u64,
+}
+
+/// Maximum sizes of app-specific metadata, in bytes, before compression
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MetadataLimits {
+    /// Maximum size of the metadata of a commit
+    pub commit: u32,
+
+    /// Maximum size of the metadata of a file
+    pub file: u32,
 }
 
 /// Repository definition
@@ -271,6 +345,7 @@ pub enum CommitType {
     Transaction,
     Snapshot,
     Ack,
+    SealBranch,
 }
 
 /// Member of a Branch
@@ -282,6 +357,9 @@ pub struct MemberV0 {
     /// Commit types the member is allowed to publish in the branch
     pub commit_types: Vec<CommitType>,
 
+    /// Device keys authorized to sign commits on behalf of the member
+    pub authorized_keys: Vec<PubKey>,
+
     /// App-specific metadata
     /// (role, permissions, cryptographic material, etc)
     #[serde(with = "serde_bytes")]
@@ -419,6 +497,15 @@ pub enum Ack {
     V0(),
 }
 
+/// Seal the branch
+///
+/// No more commits accepted afterwards,
+/// the branch stays readable as a frozen snapshot
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SealBranch {
+    V0(),
+}
+
 /// Commit body, corresponds to CommitType
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum CommitBody {
@@ -431,6 +518,17 @@ pub enum CommitBody {
     Transaction(Transaction),
     Snapshot(Snapshot),
     Ack(Ack),
+    SealBranch(SealBranch),
+}
+
+/// Compression algorithm
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4 block format, prepended with the uncompressed size
+    Lz4,
+
+    /// Zstandard frame, with the uncompressed size in its header
+    Zstd,
 }
 
 /// Content of a Commit
@@ -458,11 +556,17 @@ pub struct CommitContentV0 {
     #[serde(with = "serde_bytes")]
     pub metadata: Vec<u8>,
 
+    /// Compression of the metadata, if compressed
+    pub metadata_compression: Option<Compression>,
+
     /// Object with a CommitBody inside
     pub body: ObjectRef,
 
     /// Expiry time of the body object
     pub expiry: Option<Timestamp>,
+
+    /// Creation time of the commit
+    pub created_at: Timestamp,
 }
 
 /// Commit object
@@ -480,8 +584,12 @@ pub struct CommitV0 {
     /// Commit content
     pub content: CommitContentV0,
 
-    /// Signature over the content by the author
+    /// Signature over the content by the author,
+    /// or by the author's device key if `device` is set
     pub sig: Sig,
+
+    /// Device key of the author that signed the commit
+    pub device: Option<PubKey>,
 }
 
 /// Commit Object
@@ -490,6 +598,39 @@ pub enum Commit {
     V0(CommitV0),
 }
 
+/// Entry of a `SignedLog`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LogEntryV0 {
+    /// ID of the commit Object
+    pub id: ObjectId,
+
+    /// The commit with its signature
+    pub commit: Commit,
+}
+
+/// Exported commit log of a branch, for audit
+///
+/// The commits are in causal order: the deps and acks of a commit come before it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedLogV0 {
+    /// Branch heads the log was exported from
+    pub heads: Vec<ObjectId>,
+
+    /// Commits from the root to the heads
+    pub entries: Vec<LogEntryV0>,
+
+    /// Hash chain over the heads and the entries.
+    /// Starts with the BLAKE3 hash of the serialized heads,
+    /// then each serialized entry is hashed with the previous hash as key
+    pub hash: Digest,
+}
+
+/// Exported commit log of a branch, for audit
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SignedLog {
+    V0(SignedLogV0),
+}
+
 /// File Object
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileV0 {
@@ -2,8 +2,14 @@ use crate::errors::*;
 use crate::types::*;
 
 use ed25519_dalek::*;
+use fastbloom_rs::{BloomFilter as Filter, FilterBuilder, Membership};
 use rand::rngs::OsRng;
-use std::time::{SystemTime, UNIX_EPOCH};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::ops::{Add, Sub};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn sign(
     author_privkey: PrivKey,
@@ -34,6 +40,15 @@ pub fn verify(content: &Vec<u8>, sig: Sig, pub_key: PubKey) -> Result<(), Lofire
     Ok(pk.verify_strict(content, &sig)?)
 }
 
+/// Checks that the public key is a valid curve point
+pub fn check_pubkey(pub_key: PubKey) -> Result<(), LofireError> {
+    let pk = match pub_key {
+        PubKey::Ed25519PubKey(pk) => pk,
+    };
+    PublicKey::from_bytes(&pk).map_err(|_e| LofireError::InvalidKey)?;
+    Ok(())
+}
+
 pub fn generate_keypair() -> (PrivKey, PubKey) {
     let mut csprng = OsRng {};
     let keypair: Keypair = Keypair::generate(&mut csprng);
@@ -47,6 +62,71 @@ pub fn generate_keypair() -> (PrivKey, PubKey) {
     //     keypair.public.as_bytes().len(),
     //     keypair.public.as_bytes()
     // );
+    keypair_to_keys(keypair)
+}
+
+/// Generates a keypair deterministically from `seed`, expanded with ChaCha20.
+///
+/// Use `generate_keypair` for new identities, and `keypair_from_seed` to recover one from its seed.
+pub fn generate_keypair_from_seed(seed: [u8; 32]) -> (PrivKey, PubKey) {
+    let mut csprng = ChaCha20Rng::from_seed(seed);
+    let keypair: Keypair = Keypair::generate(&mut csprng);
+    keypair_to_keys(keypair)
+}
+
+/// Derives a keypair from a 32-byte seed, to recover an identity from a backup of its seed.
+///
+/// The same seed always gives the same keypair, the one `generate_keypair_from_seed` generates,
+/// so key recovery and reproducible vectors use a single derivation
+pub fn keypair_from_seed(seed: &[u8; 32]) -> (PrivKey, PubKey) {
+    generate_keypair_from_seed(*seed)
+}
+
+/// BIP39 English wordlist, sorted
+const BIP39_WORDS: &str = include_str!("bip39_english.txt");
+
+/// Derives a keypair from a BIP39 mnemonic phrase of 12, 15, 18, 21 or 24 words.
+///
+/// Words are separated by whitespace, and compared case-insensitively.
+/// The entropy is decoded from the BIP39 English wordlist and its checksum verified,
+/// then the seed is derived from the entropy with BLAKE3,
+/// so the keypair is not the one a BIP39 wallet would derive from the same phrase.
+/// Returns LofireError::InvalidKey for another number of words, an unknown word or a wrong checksum
+pub fn keypair_from_mnemonic(words: &str) -> Result<(PrivKey, PubKey), LofireError> {
+    let wordlist: Vec<&str> = BIP39_WORDS.lines().collect();
+    let words: Vec<String> = words.split_whitespace().map(|w| w.to_lowercase()).collect();
+    if !(12..=24).contains(&words.len()) || words.len() % 3 != 0 {
+        return Err(LofireError::InvalidKey);
+    }
+
+    // each word is 11 bits: ENT bits of entropy followed by ENT/32 bits of checksum
+    let mut bits: Vec<bool> = Vec::with_capacity(words.len() * 11);
+    for word in &words {
+        let index = wordlist
+            .binary_search(&word.as_str())
+            .map_err(|_| LofireError::InvalidKey)?;
+        bits.extend((0..11).rev().map(|i| (index >> i) & 1 == 1));
+    }
+    let entropy_bits = bits.len() * 32 / 33;
+    let entropy: Vec<u8> = bits[..entropy_bits]
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8))
+        .collect();
+
+    let hash = Sha256::digest(&entropy);
+    let checksum_ok = bits[entropy_bits..]
+        .iter()
+        .enumerate()
+        .all(|(i, &bit)| ((hash[i / 8] >> (7 - i % 8)) & 1 == 1) == bit);
+    if !checksum_ok {
+        return Err(LofireError::InvalidKey);
+    }
+
+    let seed = blake3::derive_key("LoFiRe keypair from mnemonic", &entropy);
+    Ok(keypair_from_seed(&seed))
+}
+
+fn keypair_to_keys(keypair: Keypair) -> (PrivKey, PubKey) {
     let ed_priv_key = keypair.secret.to_bytes();
     let ed_pub_key = keypair.public.to_bytes();
     let priv_key = PrivKey::Ed25519PrivKey(ed_priv_key);
@@ -54,14 +134,436 @@ pub fn generate_keypair() -> (PrivKey, PubKey) {
     (priv_key, pub_key)
 }
 
+/// returns the public key of the given private key.
+pub fn pubkey_from_privkey(privkey: PrivKey) -> PubKey {
+    let sk = match privkey {
+        PrivKey::Ed25519PrivKey(sk) => SecretKey::from_bytes(&sk).unwrap(),
+    };
+    PubKey::Ed25519PubKey(PublicKey::from(&sk).to_bytes())
+}
+
 /// returns the Lofire Timestamp of now.
 pub fn now_timestamp() -> Timestamp {
-    ((SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        - EPOCH_AS_UNIX_TIMESTAMP)
-        / 60)
-        .try_into()
-        .unwrap()
+    Timestamp::from_unix_secs(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    )
+}
+
+/// Source of the current Lofire Timestamp
+pub trait Clock {
+    /// returns the Lofire Timestamp of now.
+    fn now(&self) -> Timestamp;
+}
+
+/// Clock using the system time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        now_timestamp()
+    }
+}
+
+/// Clock running ahead of another clock by a number of minutes, or behind it when negative,
+/// e.g. a local clock corrected by its skew to the clock of the broker
+pub struct OffsetClock<C: Clock> {
+    clock: C,
+    offset: i64,
+}
+
+impl<C: Clock> OffsetClock<C> {
+    pub fn new(clock: C, offset: i64) -> OffsetClock<C> {
+        OffsetClock { clock, offset }
+    }
+
+    /// Clock corrected to show `reference`, the time of another clock read now
+    pub fn synced_to(clock: C, reference: Timestamp) -> OffsetClock<C> {
+        let offset = reference.as_minutes() as i64 - clock.now().as_minutes() as i64;
+        OffsetClock { clock, offset }
+    }
+
+    /// Offset in minutes
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+}
+
+impl<C: Clock> Clock for OffsetClock<C> {
+    fn now(&self) -> Timestamp {
+        let now = self.clock.now().as_minutes() as i64 + self.offset;
+        Timestamp::from_minutes(now.clamp(0, u32::MAX as i64) as u32)
+    }
+}
+
+/// Clock that only moves when told to, for tests
+pub struct MockClock {
+    now: Cell<Timestamp>,
+}
+
+impl MockClock {
+    pub fn new(now: Timestamp) -> MockClock {
+        MockClock {
+            now: Cell::new(now),
+        }
+    }
+
+    /// Set the current time
+    pub fn set(&self, now: Timestamp) {
+        self.now.set(now);
+    }
+
+    /// Move the current time forward by `minutes`
+    pub fn advance(&self, minutes: u32) {
+        let now = self.now.get().as_minutes().saturating_add(minutes);
+        self.now.set(Timestamp::from_minutes(now));
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Timestamp {
+        self.now.get()
+    }
+}
+
+impl RelTime {
+    /// Number of minutes, rounding seconds up to the next minute
+    /// as Timestamps have a granularity of one minute
+    pub fn as_minutes(&self) -> u32 {
+        match self {
+            RelTime::Seconds(s) => (*s as u32 + 59) / 60,
+            RelTime::Minutes(m) => *m as u32,
+            RelTime::Hours(h) => *h as u32 * 60,
+            RelTime::Days(d) => *d as u32 * 24 * 60,
+        }
+    }
+
+    /// Number of seconds
+    pub fn as_secs(&self) -> u64 {
+        match self {
+            RelTime::Seconds(s) => *s as u64,
+            RelTime::Minutes(m) => *m as u64 * 60,
+            RelTime::Hours(h) => *h as u64 * 60 * 60,
+            RelTime::Days(d) => *d as u64 * 24 * 60 * 60,
+        }
+    }
+
+    /// Duration of the relative time
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_secs(self.as_secs())
+    }
+}
+
+impl Timestamp {
+    /// The Lofire epoch
+    pub const MIN: Timestamp = Timestamp::from_minutes(0);
+
+    /// The last Timestamp
+    pub const MAX: Timestamp = Timestamp::from_minutes(u32::MAX);
+
+    /// Timestamp at a number of minutes after the Lofire epoch
+    pub const fn from_minutes(minutes: u32) -> Timestamp {
+        Timestamp(minutes)
+    }
+
+    /// Number of minutes since the Lofire epoch
+    pub const fn as_minutes(&self) -> u32 {
+        self.0
+    }
+
+    /// Timestamp of a Unix time in seconds, rounded down to the minute.
+    /// Times before the Lofire epoch give MIN, and times after the last Timestamp give MAX
+    pub fn from_unix_secs(secs: u64) -> Timestamp {
+        let minutes = secs.saturating_sub(EPOCH_AS_UNIX_TIMESTAMP) / 60;
+        Timestamp(minutes.try_into().unwrap_or(u32::MAX))
+    }
+
+    /// Unix time in seconds at the start of the minute
+    pub fn to_unix_secs(&self) -> u64 {
+        EPOCH_AS_UNIX_TIMESTAMP + self.0 as u64 * 60
+    }
+
+    /// Whether the Timestamp is reached at `now`, e.g. an expiry.
+    /// The minute of the Timestamp itself is past
+    pub fn is_past(&self, now: Timestamp) -> bool {
+        *self <= now
+    }
+
+    /// Timestamp after a relative time, None if it is after the last Timestamp
+    pub fn checked_add(&self, rel: RelTime) -> Option<Timestamp> {
+        self.0.checked_add(rel.as_minutes()).map(Timestamp)
+    }
+
+    /// Number of minutes elapsed since an earlier Timestamp, 0 if it is not earlier
+    pub fn minutes_since(&self, earlier: Timestamp) -> u32 {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+/// Deadline `Timestamp` after a relative time,
+/// saturating at the maximum Timestamp
+impl Add<RelTime> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: RelTime) -> Timestamp {
+        self.checked_add(rhs).unwrap_or(Timestamp::MAX)
+    }
+}
+
+/// `Timestamp` a relative time earlier,
+/// saturating at the Lofire epoch
+impl Sub<RelTime> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, rhs: RelTime) -> Timestamp {
+        Timestamp(self.0.saturating_sub(rhs.as_minutes()))
+    }
+}
+
+impl BloomFilter {
+    /// Bloom filter of the given IDs, with the given false positive rate
+    pub fn from_ids<'a>(ids: impl ExactSizeIterator<Item = &'a Digest>, fp_rate: f64) -> Self {
+        let mut filter = Filter::new(FilterBuilder::new(ids.len().max(1) as u64, fp_rate));
+        for id in ids {
+            match id {
+                Digest::Blake3Digest32(d) => filter.add(d),
+            }
+        }
+        BloomFilter {
+            k: filter.config().hashes,
+            f: filter.get_u8_array().to_vec(),
+        }
+    }
+
+    /// Whether the ID is probably in the filter.
+    /// An empty filter contains nothing
+    pub fn contains(&self, id: &Digest) -> bool {
+        if self.f.is_empty() || self.k == 0 {
+            return false;
+        }
+        let filter = Filter::from_u8_array(self.f.as_slice(), self.k.into());
+        match id {
+            Digest::Blake3Digest32(d) => filter.contains(d),
+        }
+    }
+
+    /// Estimated false positive rate of the filter, from the proportion of bits set
+    pub fn estimated_fp_rate(&self) -> f64 {
+        if self.f.is_empty() {
+            return 0.0;
+        }
+        let set: u32 = self.f.iter().map(|b| b.count_ones()).sum();
+        let ratio = set as f64 / (self.f.len() * 8) as f64;
+        ratio.powi(self.k as i32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::errors::*;
+    use crate::types::*;
+    use crate::utils::*;
+
+    #[test]
+    pub fn test_keypair_recovery() {
+        let (privkey, pubkey) = keypair_from_seed(&[7; 32]);
+        for _ in 0..3 {
+            assert_eq!(keypair_from_seed(&[7; 32]), (privkey, pubkey));
+        }
+        assert_eq!(generate_keypair_from_seed([7; 32]), (privkey, pubkey));
+        assert_eq!(pubkey_from_privkey(privkey), pubkey);
+        assert_ne!(keypair_from_seed(&[8; 32]).1, pubkey);
+
+        // the derived keys sign and verify like generated ones
+        let content = vec![1, 2, 3];
+        let sig = sign(privkey, pubkey, &content).unwrap();
+        assert!(verify(&content, sig, pubkey).is_ok());
+        assert!(verify(&vec![1, 2], sig, pubkey).is_err());
+
+        let pubkey_of = |words: &str| keypair_from_mnemonic(words).ok().map(|(_, pk)| pk);
+        let phrase = "legal winner thank year wave sausage worth useful legal winner thank yellow";
+        let from_phrase = pubkey_of(phrase);
+        assert!(from_phrase.is_some());
+        assert_eq!(pubkey_of(phrase), from_phrase);
+        // spacing and case don't matter
+        assert_eq!(
+            pubkey_of(&format!("  {}\n", phrase.to_uppercase().replace(' ', "  "))),
+            from_phrase
+        );
+        let zero_entropy = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        assert!(pubkey_of(zero_entropy).is_some());
+        assert_ne!(pubkey_of(zero_entropy), from_phrase);
+        assert!(matches!(
+            keypair_from_mnemonic("legal winner thank year"),
+            Err(LofireError::InvalidKey)
+        ));
+        // a word out of the wordlist
+        assert!(matches!(
+            keypair_from_mnemonic(&phrase.replace("yellow", "yellowish")),
+            Err(LofireError::InvalidKey)
+        ));
+        // a wordlist word that breaks the checksum
+        assert!(matches!(
+            keypair_from_mnemonic(&zero_entropy.replace("about", "abandon")),
+            Err(LofireError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    pub fn test_reltime() {
+        assert_eq!(RelTime::Seconds(0).as_secs(), 0);
+        assert_eq!(RelTime::Seconds(59).as_secs(), 59);
+        assert_eq!(RelTime::Minutes(3).as_secs(), 180);
+        assert_eq!(RelTime::Hours(2).as_secs(), 7200);
+        assert_eq!(RelTime::Days(255).as_secs(), 255 * 86400);
+
+        assert_eq!(RelTime::Seconds(90).as_duration(), Duration::from_secs(90));
+        assert_eq!(RelTime::Minutes(3).as_duration(), Duration::from_secs(180));
+        assert_eq!(RelTime::Hours(1).as_duration(), Duration::from_secs(3600));
+        assert_eq!(RelTime::Days(1).as_duration(), Duration::from_secs(86400));
+
+        assert_eq!(RelTime::Seconds(0).as_minutes(), 0);
+        assert_eq!(RelTime::Seconds(1).as_minutes(), 1);
+        assert_eq!(RelTime::Seconds(255).as_minutes(), 5);
+        assert_eq!(RelTime::Minutes(255).as_minutes(), 255);
+        assert_eq!(RelTime::Hours(255).as_minutes(), 255 * 60);
+        assert_eq!(RelTime::Days(255).as_minutes(), 255 * 1440);
+    }
+
+    #[test]
+    pub fn test_keypair_from_seed() {
+        let (priv1, pub1) = generate_keypair_from_seed([1; 32]);
+        let (priv2, pub2) = generate_keypair_from_seed([1; 32]);
+        assert_eq!(priv1, priv2);
+        assert_eq!(pub1, pub2);
+        assert_eq!(pubkey_from_privkey(priv1), pub1);
+
+        let (priv3, pub3) = generate_keypair_from_seed([2; 32]);
+        assert_ne!(priv1, priv3);
+        assert_ne!(pub1, pub3);
+
+        let content = vec![1, 2, 3];
+        let sig = sign(priv1, pub1, &content).unwrap();
+        assert_eq!(sig, sign(priv2, pub2, &content).unwrap());
+        assert!(verify(&content, sig, pub1).is_ok());
+    }
+
+    #[test]
+    pub fn test_bloom_filter() {
+        let ids: Vec<Digest> = (0..100u8)
+            .map(|i| Digest::Blake3Digest32([i; 32]))
+            .collect();
+        let filter = BloomFilter::from_ids(ids.iter(), 0.01);
+        for id in &ids {
+            assert!(filter.contains(id));
+        }
+        let false_positives = (100..=255u8)
+            .filter(|i| filter.contains(&Digest::Blake3Digest32([*i; 32])))
+            .count();
+        assert!(false_positives < 10);
+
+        let fp_rate = filter.estimated_fp_rate();
+        assert!(fp_rate > 0.0 && fp_rate < 0.05);
+
+        let empty = BloomFilter { k: 0, f: vec![] };
+        assert!(!empty.contains(&ids[0]));
+        assert_eq!(empty.estimated_fp_rate(), 0.0);
+        let full = BloomFilter {
+            k: 3,
+            f: vec![0xff; 16],
+        };
+        assert_eq!(full.estimated_fp_rate(), 1.0);
+    }
+
+    #[test]
+    pub fn test_deadline() {
+        let now = Timestamp::from_minutes(1000);
+        let at = Timestamp::from_minutes;
+        assert_eq!(now + RelTime::Seconds(0), at(1000));
+        assert_eq!(now + RelTime::Seconds(30), at(1001));
+        assert_eq!(now + RelTime::Minutes(3), at(1003));
+        assert_eq!(now + RelTime::Hours(1), at(1060));
+        assert_eq!(now + RelTime::Days(1), at(2440));
+        assert_eq!(now - RelTime::Minutes(3), at(997));
+
+        // overflow saturates
+        assert_eq!(Timestamp::MAX + RelTime::Minutes(1), Timestamp::MAX);
+        assert_eq!(
+            (Timestamp::MAX - RelTime::Minutes(10)) + RelTime::Days(255),
+            Timestamp::MAX
+        );
+        assert_eq!(Timestamp::MIN - RelTime::Days(1), Timestamp::MIN);
+
+        // unless checked
+        assert_eq!(now.checked_add(RelTime::Hours(1)), Some(at(1060)));
+        assert_eq!(
+            Timestamp::MAX.checked_add(RelTime::Seconds(0)),
+            Some(Timestamp::MAX)
+        );
+        assert_eq!(Timestamp::MAX.checked_add(RelTime::Minutes(1)), None);
+        assert_eq!(
+            at(u32::MAX - 10).checked_add(RelTime::Minutes(10)),
+            Some(Timestamp::MAX)
+        );
+        assert_eq!(at(u32::MAX - 10).checked_add(RelTime::Minutes(11)), None);
+    }
+
+    #[test]
+    pub fn test_timestamp() {
+        let now = Timestamp::from_minutes(1000);
+
+        // the minute of the timestamp is already past
+        assert!(Timestamp::from_minutes(999).is_past(now));
+        assert!(now.is_past(now));
+        assert!(!Timestamp::from_minutes(1001).is_past(now));
+        assert!(!(now + RelTime::Seconds(1)).is_past(now));
+        assert!(Timestamp::MIN.is_past(now));
+        assert!(!Timestamp::MAX.is_past(now));
+
+        assert_eq!(now.minutes_since(Timestamp::from_minutes(990)), 10);
+        assert_eq!(now.minutes_since(Timestamp::from_minutes(1010)), 0);
+
+        // unix time, rounded down to the minute
+        let unix = EPOCH_AS_UNIX_TIMESTAMP + 1000 * 60;
+        assert_eq!(Timestamp::from_unix_secs(unix), now);
+        assert_eq!(Timestamp::from_unix_secs(unix + 59), now);
+        assert_eq!(now.to_unix_secs(), unix);
+        assert_eq!(Timestamp::from_unix_secs(0), Timestamp::MIN);
+        assert_eq!(Timestamp::from_unix_secs(u64::MAX), Timestamp::MAX);
+        assert_eq!(
+            Timestamp::from_unix_secs(Timestamp::MAX.to_unix_secs()),
+            Timestamp::MAX
+        );
+
+        // serialized as the number of minutes
+        assert_eq!(
+            serde_bare::to_vec(&now).unwrap(),
+            serde_bare::to_vec(&1000u32).unwrap()
+        );
+        assert_eq!(
+            serde_bare::from_slice::<Timestamp>(&serde_bare::to_vec(&1000u32).unwrap()).unwrap(),
+            now
+        );
+    }
+
+    #[test]
+    pub fn test_offset_clock() {
+        let behind = OffsetClock::new(MockClock::new(Timestamp::from_minutes(1000)), -60);
+        assert_eq!(behind.now(), Timestamp::from_minutes(940));
+
+        // corrected to the time of the reference clock
+        let synced = OffsetClock::synced_to(behind, Timestamp::from_minutes(1000));
+        assert_eq!(synced.offset(), 60);
+        assert_eq!(synced.now(), Timestamp::from_minutes(1000));
+
+        // saturating at the bounds of Timestamps
+        let clock = OffsetClock::new(MockClock::new(Timestamp::from_minutes(10)), -60);
+        assert_eq!(clock.now(), Timestamp::MIN);
+        let clock = OffsetClock::new(MockClock::new(Timestamp::MAX), 60);
+        assert_eq!(clock.now(), Timestamp::MAX);
+    }
 }